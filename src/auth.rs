@@ -142,7 +142,7 @@ impl Authorizer {
             }
         }
 
-        return false;
+        false
     }
 
     fn process_code(&self, req: &Request) -> Response {
@@ -160,7 +160,7 @@ impl Authorizer {
             Some(c) => c,
             None => return Response::text("missing code").with_status_code(400),
         };
-        let redirect_uri = self_uri(&req) + "/code";
+        let redirect_uri = self_uri(req) + "/code";
         let resp = ureq::post("https://oauth2.googleapis.com/token")
             .send_form(&[
                 // The authorization code that is returned from the initial request.
@@ -181,7 +181,7 @@ impl Authorizer {
             .unwrap();
         let parsed_resp: CodeResponse = resp.into_json().unwrap();
         let jsonclaims = URL_SAFE
-            .decode(&parsed_resp.id_token.split(".").skip(1).next().unwrap())
+            .decode(parsed_resp.id_token.split(".").nth(1).unwrap())
             .unwrap();
         let claims: Claims = serde_json::from_slice(&jsonclaims).unwrap();
 
@@ -222,7 +222,7 @@ impl Authorizer {
             return self.process_code(req);
         }
 
-        if self.is_authorized(&req) {
+        if self.is_authorized(req) {
             // No need to do any more auth, call our normal function.
             return next(req);
         }
@@ -231,7 +231,7 @@ impl Authorizer {
             return next(req);
         }
 
-        let redirect_uri = self_uri(&req) + "/code";
+        let redirect_uri = self_uri(req) + "/code";
         // Construct a message for OIDC.
         // We omit state because CSRF attacks don't seem like a meaningful problem
         // for this specific application.
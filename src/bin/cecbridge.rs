@@ -0,0 +1,39 @@
+use cecvol::cec;
+
+use clap::Parser;
+use log::info;
+use std::net::TcpListener;
+
+#[derive(Parser, Debug)]
+#[command(author, version, about = "Serve the local CEC adapter to a remote cecvol", long_about = None)]
+struct Args {
+    /// Address to listen on. The link isn't encrypted, so only listen beyond
+    /// localhost on a trusted network.
+    #[arg(long, default_value = "127.0.0.1:8081")]
+    listen_addr: String,
+
+    /// Token clients must know to connect.
+    #[arg(long, env = "CEC_BRIDGE_TOKEN")]
+    token: String,
+
+    #[command(flatten)]
+    backend: cec::backend::BackendArgs,
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Args::parse();
+
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("INFO"))
+        .format_timestamp(Some(env_logger::fmt::TimestampPrecision::Millis))
+        .init();
+
+    let osd_name = "cecvol";
+    // LG's vendor code seems to be required for UserControl commands to work.
+    let vendor_id = 0x00e091;
+    let conn = cec::backend::open_backend(&args.backend, osd_name, vendor_id)?.conn;
+
+    let bridge = cec::bridge::Bridge::new(conn, args.token);
+    info!("Listening on {}", args.listen_addr);
+    bridge.serve(TcpListener::bind(&args.listen_addr)?)?;
+    Ok(())
+}
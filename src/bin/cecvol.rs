@@ -3,6 +3,7 @@ use cecvol::auth;
 use cecvol::cec;
 use cecvol::lgip;
 use cecvol::tv;
+use cecvol::uinput;
 use cecvol::wol;
 
 use action::devices::{
@@ -10,15 +11,19 @@ use action::devices::{
 };
 
 use clap::Parser;
-use log::info;
+use log::{info, warn};
 use rouille::router;
 use rouille::Request;
 use rouille::Response;
 use rouille::ResponseBody;
 use serde_json::json;
 use std::collections::HashSet;
+use std::sync::mpsc;
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::thread;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
 
 const DEVICE_ID: &str = "1";
 
@@ -49,7 +54,7 @@ fn fulfillment(app_state: AppState, request: &Request) -> Response {
         match input {
             RequestPayload::Sync => {
                 return Response::json(&FulfillmentResponse {
-                    request_id: request_id,
+                    request_id,
                     payload: json!({
                         // TODO(stvn): Switch to oauth identity
                         "agentUserId": "cecvol-stvn-user",
@@ -100,7 +105,7 @@ fn fulfillment(app_state: AppState, request: &Request) -> Response {
             RequestPayload::Query { devices: _ } => {
                 // let mut device_data = HashMap::new();
                 return Response::json(&FulfillmentResponse {
-                    request_id: request_id,
+                    request_id,
                     payload: json!({
                         // TODO
                         // "devices": device_data,
@@ -109,6 +114,7 @@ fn fulfillment(app_state: AppState, request: &Request) -> Response {
             }
             RequestPayload::Execute { commands } => {
                 let mut cec = cec.lock().unwrap();
+                let mut results = vec![];
                 for c in commands {
                     for e in &c.execution {
                         match e {
@@ -140,7 +146,7 @@ fn fulfillment(app_state: AppState, request: &Request) -> Response {
                                     "4" | "HDMI 4" => tv::Input::HDMI4,
                                     _ => {
                                         return Response::json(&FulfillmentResponse {
-                                            request_id: request_id,
+                                            request_id,
                                             payload: json!({
                                                 "errorCode": ErrorCodes::NotSupported,
                                                 "debugString": "unsupported input",
@@ -154,7 +160,7 @@ fn fulfillment(app_state: AppState, request: &Request) -> Response {
                             }
                             _ => {
                                 return Response::json(&FulfillmentResponse {
-                                    request_id: request_id,
+                                    request_id,
                                     payload: json!({
                                         "errorCode": ErrorCodes::NotSupported,
                                         "debugString": "unknown command",
@@ -162,20 +168,21 @@ fn fulfillment(app_state: AppState, request: &Request) -> Response {
                                 })
                             }
                         }
-                        // TODO(stvn): Do all executions in the array, improve error handling
-                        return Response::json(&FulfillmentResponse {
-                            request_id: request_id,
-                            payload: json!({
-                                "commands": [
-                                    {
-                                        "ids":  c.devices.iter().map(|d| d.id.clone()).collect::<Vec<String>>(),
-                                        "status": "SUCCESS",
-                                        // "states": device_state(&cec)
-                                    }
-                                ],
-                            }),
-                        });
                     }
+                    // TODO(stvn): improve error handling
+                    results.push(json!({
+                        "ids":  c.devices.iter().map(|d| d.id.clone()).collect::<Vec<String>>(),
+                        "status": "SUCCESS",
+                        // "states": device_state(&cec)
+                    }));
+                }
+                if !results.is_empty() {
+                    return Response::json(&FulfillmentResponse {
+                        request_id,
+                        payload: json!({
+                            "commands": results,
+                        }),
+                    });
                 }
             }
             RequestPayload::Disconnect => println!("Disconnect"),
@@ -183,7 +190,7 @@ fn fulfillment(app_state: AppState, request: &Request) -> Response {
     }
 
     Response::json(&FulfillmentResponse {
-        request_id: request_id,
+        request_id,
         payload: json!({
             "errorCode": ErrorCodes::NotSupported,
             "debugString": "no inputs provided",
@@ -191,6 +198,126 @@ fn fulfillment(app_state: AppState, request: &Request) -> Response {
     })
 }
 
+fn cec_log(app_state: &AppState, request: &Request) -> Response {
+    let journal = match &app_state.journal {
+        Some(j) => j,
+        None => return Response::text("not using cec").with_status_code(404),
+    };
+    let mut filter = cec::journal::Filter::default();
+    if let Some(opcode) = request.get_param("opcode") {
+        match cec::fmt::parse_opcode(&opcode) {
+            Some(o) => filter.opcode = Some(o),
+            None => return Response::text("unknown opcode").with_status_code(400),
+        }
+    }
+    if let Some(addr) = request.get_param("addr") {
+        match cec::fmt::parse_logical_address(&addr) {
+            Some(a) => filter.address = Some(a),
+            None => return Response::text("unknown logical address").with_status_code(400),
+        }
+    }
+    Response::json(&journal.entries(&filter))
+}
+
+fn cec_devices(app_state: &AppState) -> Response {
+    let devices = match &app_state.devices {
+        Some(d) => d,
+        None => return Response::text("not using cec").with_status_code(404),
+    };
+    fn node_json(node: &cec::topology::DeviceNode) -> serde_json::Value {
+        let device = &node.device;
+        json!({
+            "logical_address": cec::fmt::logical_address_name(device.logical_address),
+            "physical_address": device.physical_address.map(|a| a.to_string()),
+            "device_type": device.device_type.map(|t| format!("{:?}", t)),
+            "osd_name": device.osd_name,
+            "vendor_id": device.vendor_id.map(|id| format!("{:06x}", id)),
+            "cec_version": device.cec_version.map(|v| format!("{:?}", v)),
+            "power_status": device.power_status.map(|p| format!("{:?}", p)),
+            "last_seen": OffsetDateTime::from(device.last_seen).format(&Rfc3339).ok(),
+            "children": node.children.iter().map(node_json).collect::<Vec<_>>(),
+        })
+    }
+    let tree = devices.lock().unwrap().tree();
+    Response::json(&tree.iter().map(node_json).collect::<Vec<_>>())
+}
+
+fn cec_display(app_state: &AppState) -> Response {
+    let display = match &app_state.display {
+        Some(d) => d,
+        None => return Response::text("not using the VideoCore").with_status_code(404),
+    };
+    let vchi = match display.interface() {
+        Ok(vchi) => vchi,
+        Err(e) => return Response::text(e.to_string()).with_status_code(503),
+    };
+    let state = match vchi.get_display_state() {
+        Ok(state) => state,
+        Err(e) => return Response::text(e.to_string()).with_status_code(500),
+    };
+    // There's no EDID to read without a display.
+    let edid = match state.is_attached() {
+        true => read_edid(&vchi).ok(),
+        false => None,
+    };
+    Response::json(&json!({
+        "attached": state.is_attached(),
+        "on": state.is_on(),
+        "width": state.width,
+        "height": state.height,
+        "frame_rate": state.frame_rate,
+        "interlaced": state.interlaced,
+        "edid": edid.map(|edid| json!({
+            "manufacturer": edid.manufacturer,
+            "product_code": edid.product_code,
+            "name": edid.name,
+            "physical_address": edid.physical_address.map(|a| a.to_string()),
+        })),
+    }))
+}
+
+fn read_edid(
+    vchi: &cec::vchi::HardwareInterface,
+) -> Result<cec::edid::Edid, Box<dyn std::error::Error>> {
+    Ok(cec::edid::Edid::parse(&vchi.read_edid()?)?)
+}
+
+// Powers the HDMI output off while the TV shows another input, and back on
+// once it switches to us.
+fn blank_when_inactive(
+    cec_conn: &cec::CEC,
+    display: Arc<cec::vchi::ReconnectingInterface>,
+) -> std::io::Result<()> {
+    let (switched, inputs) = mpsc::channel();
+    cec_conn.set_input_callback(Box::new(move |address| {
+        let _ = switched.send(address);
+    }));
+    thread::Builder::new()
+        .name("Blanking".into())
+        .spawn(move || {
+            for address in inputs {
+                let result = display.interface().and_then(|vchi| {
+                    let ours = address == vchi.get_physical_addr()?;
+                    match (ours, vchi.get_display_state()?.is_on()) {
+                        (true, false) => {
+                            info!("TV switched to us, powering on HDMI");
+                            vchi.hdmi_power_on_preferred()
+                        }
+                        (false, true) => {
+                            info!("TV switched to {}, powering off HDMI", address);
+                            vchi.power_off()
+                        }
+                        _ => Ok(()),
+                    }
+                });
+                if let Err(e) = result {
+                    warn!("couldn't change HDMI power: {}", e);
+                }
+            }
+        })?;
+    Ok(())
+}
+
 fn varz() -> Response {
     let metrics = prometheus::gather();
     let encoder = prometheus::TextEncoder::new();
@@ -207,9 +334,52 @@ struct Args {
     #[arg(long, default_value = "0.0.0.0:8080")]
     http_addr: String,
 
-    /// If true, use a fake cec connection instead of directly using the hardware.
+    #[command(flatten)]
+    backend: cec::backend::BackendArgs,
+
+    /// Recording made with --cec-record-file to play back instead of using a
+    /// real adapter.
+    #[arg(long)]
+    cec_replay_file: Option<String>,
+
+    /// File to record all the CEC traffic to, with its timing, for replaying
+    /// with --cec-replay-file.
+    #[arg(long)]
+    cec_record_file: Option<String>,
+
+    /// Address of a cecbridge to use instead of a local adapter.
+    #[arg(long)]
+    cec_bridge: Option<String>,
+
+    /// Token for --cec-bridge.
+    #[arg(long, env = "CEC_BRIDGE_TOKEN")]
+    cec_bridge_token: Option<String>,
+
+    /// If true, power off the HDMI output while the TV shows another input.
+    /// Only works with the VideoCore service.
+    #[arg(long)]
+    blank_when_inactive: bool,
+
+    /// If true, forward TV remote keys to a uinput virtual keyboard.
+    #[arg(long)]
+    uinput: bool,
+
+    /// Keymap for --uinput, with lines of <user control>=<linux keycode>.
     #[arg(long)]
-    use_fake_cec_conn: bool,
+    uinput_keymap: Option<String>,
+
+    /// File to append the CEC traffic journal to, as JSON lines.
+    #[arg(long)]
+    cec_log_file: Option<String>,
+
+    /// Size at which --cec-log-file is moved aside to <file>.1.
+    #[arg(long, default_value_t = 1 << 20)]
+    cec_log_max_bytes: u64,
+
+    /// If true, also journal the frames other devices send each other, seen
+    /// through a second handle on --cec-device.
+    #[arg(long, requires = "cec_device")]
+    cec_monitor: bool,
 
     /// If true, control over ip.
     #[arg(long)]
@@ -248,6 +418,9 @@ struct Args {
 struct AppState {
     server_mac_addr: [u8; 6],
     cec: Arc<Mutex<Box<dyn tv::TVConnection + Sync + Send>>>,
+    journal: Option<Arc<cec::journal::Journal>>,
+    devices: Option<Arc<Mutex<cec::topology::DeviceTable>>>,
+    display: Option<Arc<cec::vchi::ReconnectingInterface>>,
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -257,6 +430,11 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         .format_timestamp(Some(env_logger::fmt::TimestampPrecision::Millis))
         .init();
 
+    let mut journal = None;
+    let mut devices = None;
+    let mut display = None;
+    // Kept for as long as the server runs.
+    let mut _monitor = None;
     let tv: Box<dyn tv::TVConnection + Sync + Send> = if args.use_lg_ip_control {
         let mut tv_mac_addr = [0u8; 6];
         for (i, s) in args.lg_mac_addr.unwrap().split(":").enumerate() {
@@ -272,22 +450,66 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         let osd_name = "cecvol";
         // LG's vendor code seems to be required for UserControl commands to work.
         let vendor_id = 0x00e091;
-        let vchi: Arc<dyn cec::CECConnection> = if args.use_fake_cec_conn {
-            Arc::new(cec::noop::LogOnlyConn {})
+        let vchi: Arc<dyn cec::CECConnection> = if let Some(path) = &args.cec_replay_file {
+            Arc::new(cec::replay::ReplayConnection::open(path)?)
+        } else if let Some(addr) = &args.cec_bridge {
+            Arc::new(cec::bridge::RemoteCECConnection::connect(
+                addr,
+                args.cec_bridge_token.clone().unwrap_or_default(),
+            )?)
         } else {
-            let vchi = cec::vchi::HardwareInterface::init()?;
-            vchi.set_osd_name(osd_name)?;
-            vchi.set_vendor_id(vendor_id)?;
-
-            if vchi.get_logical_addr()? == cec::LogicalAddress::Broadcast
-                && vchi.get_physical_addr()? != 0xffff
-            {
-                vchi.alloc_logical_addr()?;
+            let backend = cec::backend::open_backend(&args.backend, osd_name, vendor_id)?;
+            if let Some(vchi) = &backend.display {
+                match vchi
+                    .interface()
+                    .map_err(|e| e.into())
+                    .and_then(|v| read_edid(&v))
+                {
+                    Ok(edid) => info!(
+                        "connected to {} {}",
+                        edid.manufacturer,
+                        edid.name.as_deref().unwrap_or("display")
+                    ),
+                    Err(e) => info!("couldn't read the display's EDID: {}", e),
+                }
             }
-            Arc::new(vchi)
+            display = backend.display;
+            backend.conn
+        };
+        let vchi: Arc<dyn cec::CECConnection> = match &args.cec_record_file {
+            Some(path) => Arc::new(cec::replay::RecordingConnection::create(vchi, path)?),
+            None => vchi,
         };
         let cec_conn = cec::CEC::new(vchi, osd_name, vendor_id)?;
+        if let Some(path) = &args.cec_log_file {
+            cec_conn.journal().rotate_to(path, args.cec_log_max_bytes)?;
+        }
+        journal = Some(cec_conn.journal());
+        if let (true, Some(path)) = (args.cec_monitor, &args.backend.cec_device) {
+            _monitor = Some(cec::linux::Monitor::open(
+                path,
+                cec_conn.monitor_callback(),
+            )?);
+        }
+        devices = Some(cec_conn.device_table());
+        match (&display, args.blank_when_inactive) {
+            (Some(display), true) => blank_when_inactive(&cec_conn, display.clone())?,
+            (None, true) => warn!("--blank-when-inactive needs the VideoCore service"),
+            _ => (),
+        }
         cec_conn.poll_all()?;
+        if args.uinput {
+            let keymap = match &args.uinput_keymap {
+                Some(path) => uinput::Keymap::parse(&std::fs::read_to_string(path)?)?,
+                None => uinput::Keymap::default(),
+            };
+            let keyboard = uinput::VirtualKeyboard::new(osd_name, keymap.keycodes())?;
+            let forwarder = uinput::KeyForwarder::new(Box::new(keyboard), keymap)?;
+            cec_conn.set_key_callback(Box::new(move |event| match event {
+                cec::KeyEvent::Pressed(control) => forwarder.pressed(control),
+                cec::KeyEvent::Released => forwarder.released(),
+            }));
+        }
         Box::new(cec_conn)
     };
 
@@ -330,6 +552,9 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     let app_state = AppState {
         cec: conn,
         server_mac_addr,
+        journal,
+        devices,
+        display,
     };
 
     info!("Starting server...");
@@ -345,6 +570,9 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 (GET) ["/"] => {index()},
                 (GET) ["/manifest.json"] => {manifest()},
                 (GET) ["/varz"] => {varz()},
+                (GET) ["/cec/log"] => {cec_log(&app_state, req)},
+                (GET) ["/cec/devices"] => {cec_devices(&app_state)},
+                (GET) ["/cec/display"] => {cec_display(&app_state)},
                 (POST) ["/fulfillment"] => {fulfillment(app_state.clone(), req)},
                 _ => rouille::Response::empty_404()
             )
@@ -1,18 +1,68 @@
+pub mod adapter;
+pub mod arc;
+pub mod backend;
+pub mod bridge;
+pub mod edid;
+pub mod fmt;
+mod follower;
+pub mod journal;
+pub mod linux;
+pub mod linux_ioctl;
 pub mod noop;
+pub mod pulse_eight;
+pub mod replay;
+mod reply;
+pub mod sim;
+pub mod topology;
+pub mod tx;
 pub mod vchi;
 pub mod vchiq_ioctl;
+pub mod vendor;
 
 use crate::tv;
 use crate::tv::TVError;
-use log::info;
+use arc::{ARCLink, ARCState};
+use journal::Journal;
+use log::{info, warn};
 use num_enum::{TryFromPrimitive, TryFromPrimitiveError};
+use reply::PendingReplies;
 use rouille::Response;
+use serde::{Deserialize, Serialize};
 use std::array::TryFromSliceError;
 use std::convert::{TryFrom, TryInto};
-use std::fmt;
 use std::str;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
 use std::sync::{Arc, Condvar, Mutex};
-use std::time::Duration;
+use std::thread;
+use std::time::{Duration, Instant};
+use topology::{DeviceNode, DeviceTable, KnownDevice};
+use tx::{TxConfig, TxQueue};
+use vendor::VendorHandler;
+
+// How long to wait for a reply to a request, following the spec's guidance
+// that followers respond within a second.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(1);
+
+// How often to ask the TV for its power status, so we notice when it's turned
+// on or off with its own remote. It's asked more often while it's changing.
+const POWER_POLL_INTERVAL: Duration = Duration::from_secs(30);
+const POWER_TRANSITION_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+// Hotplug and power changes come in bursts, so we wait for things to settle
+// before announcing ourselves again.
+const REANNOUNCE_DELAY: Duration = Duration::from_secs(1);
+
+// The most text a <Set OSD String> can carry, and how long each piece of a
+// longer string is shown before the next replaces it.
+const OSD_STRING_MAX_LEN: usize = 13;
+const OSD_CHUNK_INTERVAL: Duration = Duration::from_secs(2);
+
+// The version of the spec we report to <Get CEC Version> and in <Report
+// Features>. Only the 2.0 additions that concern us are supported, features
+// and latency, and everything else behaves as in 1.4, which is what most TVs
+// still expect.
+const CEC_VERSION: CECVersion = CECVersion::V2_0;
 
 #[repr(u8)]
 #[derive(Copy, Clone, Debug, PartialEq, TryFromPrimitive)]
@@ -109,6 +159,10 @@ pub enum Opcode {
     SetExternalTimer = 0xA2,
     ReportShortAudioDescriptor = 0xA3,
     RequestShortAudioDescriptor = 0xA4,
+    GiveFeatures = 0xA5,
+    ReportFeatures = 0xA6,
+    RequestCurrentLatency = 0xA7,
+    ReportCurrentLatency = 0xA8,
     InitARC = 0xC0,
     ReportARCInited = 0xC1,
     ReportARCTerminated = 0xC2,
@@ -172,6 +226,39 @@ pub enum DeviceType {
     VideoProcessor = 7,
 }
 
+impl DeviceType {
+    /// The logical addresses a device of this type may claim, in the order the
+    /// spec says to try them.
+    pub fn logical_addresses(&self) -> &'static [LogicalAddress] {
+        use LogicalAddress::*;
+        match self {
+            Self::TV => &[TV, FreeUse],
+            Self::RecordingDevice => &[RecordingDevice1, RecordingDevice2, RecordingDevice3],
+            Self::Tuner => &[Tuner1, Tuner2, Tuner3, Tuner4],
+            Self::PlaybackDevice => &[PlaybackDevice1, PlaybackDevice2, PlaybackDevice3],
+            Self::AudioSystem => &[AudioSystem],
+            Self::VideoProcessor => &[FreeUse],
+            Self::Reserved | Self::Switch => &[],
+        }
+    }
+}
+
+/// Runs the spec's logical address allocation, where each address for the
+/// device type is polled in turn and the first that nobody acknowledges is
+/// ours. `poll` returns whether an address is taken. If they all are, the
+/// device stays unregistered and can only send broadcasts.
+pub fn claim_logical_address<E>(
+    device_type: DeviceType,
+    mut poll: impl FnMut(LogicalAddress) -> Result<bool, E>,
+) -> Result<LogicalAddress, E> {
+    for &addr in device_type.logical_addresses() {
+        if !poll(addr)? {
+            return Ok(addr);
+        }
+    }
+    Ok(LogicalAddress::Broadcast)
+}
+
 #[repr(u8)]
 #[derive(Copy, Clone, Debug, PartialEq, TryFromPrimitive)]
 pub enum UserControl {
@@ -248,19 +335,142 @@ pub enum UserControl {
     F5 = 0x75,
 }
 
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, PartialEq, TryFromPrimitive)]
+pub enum DeckInfo {
+    Play = 0x11,
+    Record = 0x12,
+    PlayReverse = 0x13,
+    Still = 0x14,
+    Slow = 0x15,
+    SlowReverse = 0x16,
+    FastForward = 0x17,
+    FastReverse = 0x18,
+    NoMedia = 0x19,
+    Stop = 0x1A,
+    SkipForward = 0x1B,
+    SkipReverse = 0x1C,
+    IndexSearchForward = 0x1D,
+    IndexSearchReverse = 0x1E,
+    OtherStatus = 0x1F,
+}
+
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, PartialEq, TryFromPrimitive)]
+pub enum DeckControlMode {
+    SkipForward = 1,
+    SkipReverse = 2,
+    Stop = 3,
+    Eject = 4,
+}
+
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, PartialEq, TryFromPrimitive)]
+pub enum PlayMode {
+    FastForwardMin = 0x05,
+    FastForwardMedium = 0x06,
+    FastForwardMax = 0x07,
+    FastReverseMin = 0x09,
+    FastReverseMedium = 0x0A,
+    FastReverseMax = 0x0B,
+    SlowForwardMin = 0x15,
+    SlowForwardMedium = 0x16,
+    SlowForwardMax = 0x17,
+    SlowReverseMin = 0x19,
+    SlowReverseMedium = 0x1A,
+    SlowReverseMax = 0x1B,
+    PlayReverse = 0x20,
+    PlayForward = 0x24,
+    PlayStill = 0x25,
+}
+
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, PartialEq, TryFromPrimitive)]
+pub enum MenuRequestType {
+    Activate = 0,
+    Deactivate = 1,
+    Query = 2,
+}
+
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, PartialEq, TryFromPrimitive)]
+pub enum MenuState {
+    Activated = 0,
+    Deactivated = 1,
+}
+
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, PartialEq, TryFromPrimitive)]
+pub enum DisplayControl {
+    DefaultTime = 0x00,
+    UntilCleared = 0x40,
+    ClearPrevious = 0x80,
+}
+
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, PartialEq, TryFromPrimitive)]
+pub enum AudioRate {
+    Off = 0,
+    WideStandard = 1,
+    WideFast = 2,
+    WideSlow = 3,
+    NarrowStandard = 4,
+    NarrowFast = 5,
+    NarrowSlow = 6,
+}
+
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, PartialEq, TryFromPrimitive)]
+pub enum CECVersion {
+    V1_1 = 0x00,
+    V1_2 = 0x01,
+    V1_2a = 0x02,
+    V1_3 = 0x03,
+    V1_3a = 0x04,
+    V1_4 = 0x05,
+    V2_0 = 0x06,
+}
+
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, PartialEq, TryFromPrimitive)]
+pub enum TimerClearedStatus {
+    NotClearedRecording = 0x00,
+    NotClearedNoMatching = 0x01,
+    NotClearedNoInfo = 0x02,
+    Cleared = 0x80,
+}
+
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, PartialEq, TryFromPrimitive)]
+pub enum AnalogueBroadcastType {
+    Cable = 0,
+    Satellite = 1,
+    Terrestrial = 2,
+}
+
 #[derive(Debug)]
 pub enum CECError {
     UnknownInputDevice(String),
     ParsingError(Error),
+    Aborted { opcode: Opcode, reason: AbortReason },
+    NoReply(Opcode),
+    NoAck,
+    Busy,
     Other(Box<dyn std::error::Error + Sync + Send>),
 }
 
 impl std::error::Error for CECError {}
-impl fmt::Display for CECError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+impl std::fmt::Display for CECError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
             Self::UnknownInputDevice(err) => write!(f, "Unknown input device: {}", err),
             Self::ParsingError(err) => write!(f, "Parsing error: {}", err),
+            Self::Aborted { opcode, reason } => {
+                write!(f, "Request {:?} was aborted: {:?}", opcode, reason)
+            }
+            Self::NoReply(opcode) => write!(f, "No reply to request {:?}", opcode),
+            Self::NoAck => write!(f, "Message was not acknowledged"),
+            Self::Busy => write!(f, "Bus is busy"),
             Self::Other(err) => write!(f, "Application-specific error: {}", err),
         }
     }
@@ -283,9 +493,664 @@ impl From<CECError> for TVError {
     }
 }
 
-type PhysicalAddress = u16;
+/// Where a device is plugged in, as the port numbers taken at each hop from
+/// the TV. Written `a.b.c.d`, so 1.2.0.0 is port 2 of the device on the TV's
+/// HDMI 1. The TV itself is 0.0.0.0.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct PhysicalAddress(u16);
+
+impl PhysicalAddress {
+    pub const ROOT: Self = PhysicalAddress(0x0000);
+    /// Used by devices that haven't been given an address yet.
+    pub const INVALID: Self = PhysicalAddress(0xffff);
+
+    pub fn from_ports(ports: [u8; 4]) -> Result<Self, Error> {
+        if ports.iter().any(|&p| p > 0xf) {
+            return Err(Error::BadPhysicalAddress(format!("{:?}", ports)));
+        }
+        let addr = PhysicalAddress(
+            (ports[0] as u16) << 12
+                | (ports[1] as u16) << 8
+                | (ports[2] as u16) << 4
+                | ports[3] as u16,
+        );
+        if !addr.is_valid() {
+            return Err(Error::BadPhysicalAddress(addr.to_string()));
+        }
+        Ok(addr)
+    }
+
+    pub fn ports(&self) -> [u8; 4] {
+        [
+            (self.0 >> 12) as u8,
+            (self.0 >> 8) as u8 & 0xf,
+            (self.0 >> 4) as u8 & 0xf,
+            self.0 as u8 & 0xf,
+        ]
+    }
+
+    /// How many hops the address is from the TV.
+    pub fn depth(&self) -> usize {
+        self.ports().iter().take_while(|&&p| p != 0).count()
+    }
+
+    /// Whether the address could belong to a device, meaning it isn't
+    /// INVALID and has no ports after the first zero.
+    pub fn is_valid(&self) -> bool {
+        *self != Self::INVALID && self.ports()[self.depth()..].iter().all(|&p| p == 0)
+    }
+
+    /// The address of the device this one is plugged into, or None for the
+    /// TV.
+    pub fn parent(&self) -> Option<Self> {
+        match self.depth() {
+            0 => None,
+            depth => Some(PhysicalAddress(self.0 & !(0xf000 >> ((depth - 1) * 4)))),
+        }
+    }
+
+    /// The port on the parent that this address is plugged into, or None for
+    /// the TV.
+    pub fn port(&self) -> Option<u8> {
+        match self.depth() {
+            0 => None,
+            depth => Some(self.ports()[depth - 1]),
+        }
+    }
+
+    /// The address of whatever is plugged into `port` of this device, if
+    /// there's room for another hop.
+    pub fn child(&self, port: u8) -> Option<Self> {
+        let depth = self.depth();
+        if !(1..=0xf).contains(&port) || depth >= 4 || !self.is_valid() {
+            return None;
+        }
+        Some(PhysicalAddress(self.0 | (port as u16) << (12 - depth * 4)))
+    }
+
+    /// Whether `other` is downstream of this address.
+    pub fn contains(&self, other: PhysicalAddress) -> bool {
+        let mut addr = other.parent();
+        while let Some(a) = addr {
+            if a == *self {
+                return true;
+            }
+            addr = a.parent();
+        }
+        false
+    }
+
+    fn to_be_bytes(self) -> [u8; 2] {
+        self.0.to_be_bytes()
+    }
+}
+
+impl From<u16> for PhysicalAddress {
+    fn from(addr: u16) -> Self {
+        PhysicalAddress(addr)
+    }
+}
+
+impl From<PhysicalAddress> for u16 {
+    fn from(addr: PhysicalAddress) -> Self {
+        addr.0
+    }
+}
+
+impl std::fmt::Display for PhysicalAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let [a, b, c, d] = self.ports();
+        write!(f, "{:x}.{:x}.{:x}.{:x}", a, b, c, d)
+    }
+}
+
+impl str::FromStr for PhysicalAddress {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bad = || Error::BadPhysicalAddress(s.to_string());
+        let mut ports = [0; 4];
+        let mut parts = s.split('.');
+        for port in ports.iter_mut() {
+            let part = parts.next().ok_or_else(bad)?;
+            if part.len() != 1 {
+                return Err(bad());
+            }
+            *port = u8::from_str_radix(part, 16).map_err(|_| bad())?;
+        }
+        if parts.next().is_some() {
+            return Err(bad());
+        }
+        Self::from_ports(ports).map_err(|_| bad())
+    }
+}
+
 fn physical_address_from_bytes(b: &[u8]) -> Result<PhysicalAddress, TryFromSliceError> {
-    Ok(u16::from_be_bytes(b.try_into()?))
+    Ok(PhysicalAddress(u16::from_be_bytes(b.try_into()?)))
+}
+
+fn vendor_id_from_bytes(b: &[u8]) -> u32 {
+    (b[0] as u32) << 16 | (b[1] as u32) << 8 | (b[2] as u32)
+}
+
+fn to_bcd(n: u8) -> u8 {
+    (n / 10) << 4 | (n % 10)
+}
+
+fn from_bcd(b: u8) -> u8 {
+    (b >> 4) * 10 + (b & 0x0f)
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct AudioStatus {
+    pub muted: bool,
+    // Volume in percent, or 0x7f if unknown.
+    pub volume: u8,
+}
+impl AudioStatus {
+    const VOLUME_UNKNOWN: u8 = 0x7f;
+
+    pub fn volume_percent(&self) -> Option<u8> {
+        match self.volume {
+            Self::VOLUME_UNKNOWN => None,
+            v => Some(v.min(100)),
+        }
+    }
+    fn to_byte(self) -> u8 {
+        (self.muted as u8) << 7 | (self.volume & 0x7f)
+    }
+    fn from_byte(b: u8) -> Self {
+        AudioStatus {
+            muted: b & 0x80 != 0,
+            volume: b & 0x7f,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct AnalogueService {
+    pub broadcast_type: AnalogueBroadcastType,
+    pub frequency: u16,
+    pub broadcast_system: u8,
+}
+impl AnalogueService {
+    const LEN: usize = 4;
+    fn to_bytes(self) -> Vec<u8> {
+        let mut b = vec![self.broadcast_type as u8];
+        b.extend(&self.frequency.to_be_bytes());
+        b.push(self.broadcast_system);
+        b
+    }
+    fn from_bytes(b: &[u8]) -> Result<Self, Error> {
+        if b.len() < Self::LEN {
+            return Err(Error::InputTooShort);
+        }
+        Ok(AnalogueService {
+            broadcast_type: AnalogueBroadcastType::try_from(b[0])?,
+            frequency: u16::from_be_bytes(b[1..3].try_into()?),
+            broadcast_system: b[3],
+        })
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct DigitalService {
+    // Whether the service is identified by channel rather than by digital ids.
+    pub by_channel: bool,
+    pub broadcast_system: u8,
+    pub service_id: [u8; 6],
+}
+impl DigitalService {
+    const LEN: usize = 7;
+    fn to_bytes(self) -> Vec<u8> {
+        let mut b = vec![(self.by_channel as u8) << 7 | (self.broadcast_system & 0x7f)];
+        b.extend(&self.service_id);
+        b
+    }
+    fn from_bytes(b: &[u8]) -> Result<Self, Error> {
+        if b.len() < Self::LEN {
+            return Err(Error::InputTooShort);
+        }
+        Ok(DigitalService {
+            by_channel: b[0] & 0x80 != 0,
+            broadcast_system: b[0] & 0x7f,
+            service_id: b[1..7].try_into()?,
+        })
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ExternalSource {
+    Plug(u8),
+    PhysicalAddress(PhysicalAddress),
+}
+impl ExternalSource {
+    fn to_bytes(self) -> Vec<u8> {
+        match self {
+            Self::Plug(plug) => vec![4, plug],
+            Self::PhysicalAddress(addr) => {
+                let mut b = vec![5];
+                b.extend(&addr.to_be_bytes());
+                b
+            }
+        }
+    }
+    fn from_bytes(b: &[u8]) -> Result<Self, Error> {
+        match b {
+            [4, plug, ..] => Ok(Self::Plug(*plug)),
+            [5, addr @ ..] if addr.len() >= 2 => Ok(Self::PhysicalAddress(
+                physical_address_from_bytes(&addr[..2])?,
+            )),
+            [4 | 5, ..] | [] => Err(Error::InputTooShort),
+            _ => Err(Error::InvalidOperand),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum RecordSource {
+    OwnSource,
+    DigitalService(DigitalService),
+    AnalogueService(AnalogueService),
+    External(ExternalSource),
+}
+impl RecordSource {
+    fn to_bytes(self) -> Vec<u8> {
+        match self {
+            Self::OwnSource => vec![1],
+            Self::DigitalService(service) => {
+                let mut b = vec![2];
+                b.extend(service.to_bytes());
+                b
+            }
+            Self::AnalogueService(service) => {
+                let mut b = vec![3];
+                b.extend(service.to_bytes());
+                b
+            }
+            // External sources share their specifier with the record source type.
+            Self::External(source) => source.to_bytes(),
+        }
+    }
+    fn from_bytes(b: &[u8]) -> Result<Self, Error> {
+        match b.first() {
+            Some(1) => Ok(Self::OwnSource),
+            Some(2) => Ok(Self::DigitalService(DigitalService::from_bytes(&b[1..])?)),
+            Some(3) => Ok(Self::AnalogueService(AnalogueService::from_bytes(&b[1..])?)),
+            Some(4 | 5) => Ok(Self::External(ExternalSource::from_bytes(b)?)),
+            Some(_) => Err(Error::InvalidOperand),
+            None => Err(Error::InputTooShort),
+        }
+    }
+}
+
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, PartialEq, TryFromPrimitive)]
+pub enum TunerDisplayInfo {
+    Digital = 0,
+    NotDisplaying = 1,
+    Analogue = 2,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum TunerService {
+    Analogue(AnalogueService),
+    Digital(DigitalService),
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct TunerDeviceInfo {
+    pub recording: bool,
+    pub display_info: TunerDisplayInfo,
+    pub service: TunerService,
+}
+impl TunerDeviceInfo {
+    fn to_bytes(self) -> Vec<u8> {
+        let mut b = vec![(self.recording as u8) << 7 | self.display_info as u8];
+        match self.service {
+            TunerService::Analogue(service) => b.extend(service.to_bytes()),
+            TunerService::Digital(service) => b.extend(service.to_bytes()),
+        }
+        b
+    }
+    fn from_bytes(b: &[u8]) -> Result<Self, Error> {
+        if b.len() < 1 + AnalogueService::LEN {
+            return Err(Error::InputTooShort);
+        }
+        // The service identification can only be told apart by its length.
+        let service = if b.len() > AnalogueService::LEN + 1 {
+            TunerService::Digital(DigitalService::from_bytes(&b[1..])?)
+        } else {
+            TunerService::Analogue(AnalogueService::from_bytes(&b[1..])?)
+        };
+        Ok(TunerDeviceInfo {
+            recording: b[0] & 0x80 != 0,
+            display_info: TunerDisplayInfo::try_from(b[0] & 0x7f)?,
+            service,
+        })
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct TimerSchedule {
+    pub day_of_month: u8,
+    pub month_of_year: u8,
+    pub start_hour: u8,
+    pub start_minute: u8,
+    pub duration_hours: u8,
+    pub duration_minutes: u8,
+    pub recording_sequence: u8,
+}
+impl TimerSchedule {
+    const LEN: usize = 7;
+    fn to_bytes(self) -> Vec<u8> {
+        vec![
+            self.day_of_month,
+            self.month_of_year,
+            to_bcd(self.start_hour),
+            to_bcd(self.start_minute),
+            to_bcd(self.duration_hours),
+            to_bcd(self.duration_minutes),
+            self.recording_sequence,
+        ]
+    }
+    fn from_bytes(b: &[u8]) -> Result<Self, Error> {
+        if b.len() < Self::LEN {
+            return Err(Error::InputTooShort);
+        }
+        Ok(TimerSchedule {
+            day_of_month: b[0],
+            month_of_year: b[1],
+            start_hour: from_bcd(b[2]),
+            start_minute: from_bcd(b[3]),
+            duration_hours: from_bcd(b[4]),
+            duration_minutes: from_bcd(b[5]),
+            recording_sequence: b[6],
+        })
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct TimerStatusData {
+    pub overlap_warning: bool,
+    pub media_info: u8,
+    pub programmed: bool,
+    // Programmed info if programmed, otherwise the not-programmed error info.
+    pub info: u8,
+    // Available recording time as hours and minutes.
+    pub duration_available: Option<(u8, u8)>,
+}
+impl TimerStatusData {
+    fn to_bytes(self) -> Vec<u8> {
+        let mut b = vec![
+            (self.overlap_warning as u8) << 7
+                | (self.media_info & 0x3) << 5
+                | (self.programmed as u8) << 4
+                | (self.info & 0xf),
+        ];
+        if let Some((hours, minutes)) = self.duration_available {
+            b.extend(&[to_bcd(hours), to_bcd(minutes)]);
+        }
+        b
+    }
+    fn from_bytes(b: &[u8]) -> Result<Self, Error> {
+        if b.is_empty() {
+            return Err(Error::InputTooShort);
+        }
+        Ok(TimerStatusData {
+            overlap_warning: b[0] & 0x80 != 0,
+            media_info: (b[0] >> 5) & 0x3,
+            programmed: b[0] & 0x10 != 0,
+            info: b[0] & 0xf,
+            duration_available: match b {
+                [_, hours, minutes, ..] => Some((from_bcd(*hours), from_bcd(*minutes))),
+                _ => None,
+            },
+        })
+    }
+}
+
+/// The types of every logical address a device has claimed, from
+/// <Report Features>.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct AllDeviceTypes {
+    pub tv: bool,
+    pub recording: bool,
+    pub tuner: bool,
+    pub playback: bool,
+    pub audio_system: bool,
+    pub switch: bool,
+}
+impl AllDeviceTypes {
+    pub fn of(device_type: DeviceType) -> Self {
+        let mut types = AllDeviceTypes::default();
+        match device_type {
+            DeviceType::TV => types.tv = true,
+            DeviceType::RecordingDevice => types.recording = true,
+            DeviceType::Tuner => types.tuner = true,
+            DeviceType::PlaybackDevice => types.playback = true,
+            DeviceType::AudioSystem => types.audio_system = true,
+            DeviceType::Switch => types.switch = true,
+            DeviceType::Reserved | DeviceType::VideoProcessor => {}
+        }
+        types
+    }
+    fn to_byte(self) -> u8 {
+        (self.tv as u8) << 7
+            | (self.recording as u8) << 6
+            | (self.tuner as u8) << 5
+            | (self.playback as u8) << 4
+            | (self.audio_system as u8) << 3
+            | (self.switch as u8) << 2
+    }
+    fn from_byte(b: u8) -> Self {
+        AllDeviceTypes {
+            tv: b & 0x80 != 0,
+            recording: b & 0x40 != 0,
+            tuner: b & 0x20 != 0,
+            playback: b & 0x10 != 0,
+            audio_system: b & 0x08 != 0,
+            switch: b & 0x04 != 0,
+        }
+    }
+}
+
+/// The remote control profile a TV supports.
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, PartialEq, TryFromPrimitive)]
+pub enum RCProfileTV {
+    None = 0x00,
+    Profile1 = 0x02,
+    Profile2 = 0x06,
+    Profile3 = 0x0a,
+    Profile4 = 0x0e,
+}
+
+/// Which remote control keys a device responds to, from <Report Features>.
+/// TVs give a profile, while sources list the menus they can bring up.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum RCProfile {
+    TV(RCProfileTV),
+    Source {
+        root_menu: bool,
+        setup_menu: bool,
+        contents_menu: bool,
+        media_top_menu: bool,
+        media_context_menu: bool,
+    },
+}
+impl RCProfile {
+    fn to_byte(self) -> u8 {
+        match self {
+            Self::TV(profile) => profile as u8,
+            Self::Source {
+                root_menu,
+                setup_menu,
+                contents_menu,
+                media_top_menu,
+                media_context_menu,
+            } => {
+                0x40 | (root_menu as u8) << 4
+                    | (setup_menu as u8) << 3
+                    | (contents_menu as u8) << 2
+                    | (media_top_menu as u8) << 1
+                    | media_context_menu as u8
+            }
+        }
+    }
+    fn from_byte(b: u8) -> Result<Self, Error> {
+        if b & 0x40 == 0 {
+            return Ok(Self::TV(RCProfileTV::try_from(b & 0x0f)?));
+        }
+        Ok(Self::Source {
+            root_menu: b & 0x10 != 0,
+            setup_menu: b & 0x08 != 0,
+            contents_menu: b & 0x04 != 0,
+            media_top_menu: b & 0x02 != 0,
+            media_context_menu: b & 0x01 != 0,
+        })
+    }
+}
+
+/// Optional features a device supports, from <Report Features>.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct DeviceFeatures {
+    pub record_tv_screen: bool,
+    pub set_osd_string: bool,
+    pub deck_control: bool,
+    pub set_audio_rate: bool,
+    // A TV that can send audio to an audio system over ARC.
+    pub arc_tx: bool,
+    // An audio system that can receive audio from the TV over ARC.
+    pub arc_rx: bool,
+    pub set_audio_volume_level: bool,
+}
+impl DeviceFeatures {
+    fn to_byte(self) -> u8 {
+        (self.record_tv_screen as u8) << 6
+            | (self.set_osd_string as u8) << 5
+            | (self.deck_control as u8) << 4
+            | (self.set_audio_rate as u8) << 3
+            | (self.arc_tx as u8) << 2
+            | (self.arc_rx as u8) << 1
+            | self.set_audio_volume_level as u8
+    }
+    fn from_byte(b: u8) -> Self {
+        DeviceFeatures {
+            record_tv_screen: b & 0x40 != 0,
+            set_osd_string: b & 0x20 != 0,
+            deck_control: b & 0x10 != 0,
+            set_audio_rate: b & 0x08 != 0,
+            arc_tx: b & 0x04 != 0,
+            arc_rx: b & 0x02 != 0,
+            set_audio_volume_level: b & 0x01 != 0,
+        }
+    }
+}
+
+// Set on a features operand byte that's followed by another. No extensions are
+// defined yet, so they're skipped when decoding.
+const FEATURES_EXTENSION: u8 = 0x80;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Features {
+    pub cec_version: CECVersion,
+    pub device_types: AllDeviceTypes,
+    pub rc_profile: RCProfile,
+    pub device_features: DeviceFeatures,
+}
+impl Features {
+    fn to_bytes(self) -> Vec<u8> {
+        vec![
+            self.cec_version as u8,
+            self.device_types.to_byte(),
+            self.rc_profile.to_byte(),
+            self.device_features.to_byte(),
+        ]
+    }
+    fn from_bytes(b: &[u8]) -> Result<Self, Error> {
+        // Each of the last two operands runs up to the first byte without
+        // the extension bit.
+        let operand_end = |from: usize| {
+            b[from..]
+                .iter()
+                .position(|b| b & FEATURES_EXTENSION == 0)
+                .map(|i| from + i)
+                .ok_or(Error::InputTooShort)
+        };
+        if b.len() < 4 {
+            return Err(Error::InputTooShort);
+        }
+        let rc_profile_end = operand_end(2)?;
+        if rc_profile_end + 1 >= b.len() {
+            return Err(Error::InputTooShort);
+        }
+        operand_end(rc_profile_end + 1)?;
+        Ok(Features {
+            cec_version: CECVersion::try_from(b[0])?,
+            device_types: AllDeviceTypes::from_byte(b[1]),
+            rc_profile: RCProfile::from_byte(b[2] & !FEATURES_EXTENSION)?,
+            device_features: DeviceFeatures::from_byte(b[rc_profile_end + 1] & !FEATURES_EXTENSION),
+        })
+    }
+}
+
+/// Whether a device delays its audio to make up for its own video latency.
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, PartialEq, TryFromPrimitive)]
+pub enum AudioOutputCompensated {
+    NotApplicable = 0,
+    Compensated = 1,
+    NotCompensated = 2,
+    // Delayed by the amount in audio_output_delay.
+    PartiallyCompensated = 3,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Latency {
+    // Latency in units of 2ms, offset by one.
+    pub video_latency: u8,
+    pub low_latency_mode: bool,
+    pub audio_output_compensated: AudioOutputCompensated,
+    // Present when the audio is partially compensated, in the same units as
+    // video_latency.
+    pub audio_output_delay: Option<u8>,
+}
+impl Latency {
+    fn to_bytes(self) -> Vec<u8> {
+        let mut b = vec![
+            self.video_latency,
+            (self.low_latency_mode as u8) << 2 | self.audio_output_compensated as u8,
+        ];
+        b.extend(self.audio_output_delay);
+        b
+    }
+    fn from_bytes(b: &[u8]) -> Result<Self, Error> {
+        if b.len() < 2 {
+            return Err(Error::InputTooShort);
+        }
+        let audio_output_compensated = AudioOutputCompensated::try_from(b[1] & 0x3)?;
+        let audio_output_delay = match audio_output_compensated {
+            AudioOutputCompensated::PartiallyCompensated => {
+                Some(*b.get(2).ok_or(Error::InputTooShort)?)
+            }
+            _ => None,
+        };
+        Ok(Latency {
+            video_latency: b[0],
+            low_latency_mode: b[1] & 0x4 != 0,
+            audio_output_compensated,
+            audio_output_delay,
+        })
+    }
+
+    /// The video latency in milliseconds, if the device knows it.
+    pub fn video_latency_ms(&self) -> Option<u16> {
+        match self.video_latency {
+            1..=251 => Some((self.video_latency as u16 - 1) * 2),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -335,19 +1200,161 @@ pub enum CECMessage {
     VendorCommand {
         vendor_data: Vec<u8>,
     },
-}
-
-impl CECMessage {
-    fn get_opcode(&self) -> Opcode {
-        match &self {
-            CECMessage::FeatureAbort { .. } => Opcode::FeatureAbort,
-            CECMessage::ImageViewOn => Opcode::ImageViewOn,
-            CECMessage::Standby => Opcode::Standby,
-            CECMessage::ActiveSource { .. } => Opcode::ActiveSource,
-            CECMessage::RequestActiveSource => Opcode::RequestActiveSource,
-            CECMessage::GivePhysicalAddress => Opcode::GivePhysicalAddress,
-            CECMessage::ReportPhysicalAddress { .. } => Opcode::ReportPhysicalAddress,
-            CECMessage::SetStreamPath { .. } => Opcode::SetStreamPath,
+    TunerStepIncrement,
+    TunerStepDecrement,
+    TunerDeviceStatus {
+        tuner_device_info: TunerDeviceInfo,
+    },
+    GiveTunerDeviceStatus {
+        status_request: DeckStatus,
+    },
+    RecordOn {
+        record_source: RecordSource,
+    },
+    RecordStatus {
+        record_status_info: u8,
+    },
+    RecordOff,
+    TextViewOn,
+    RecordTVScreen,
+    DeckStatus {
+        deck_info: DeckInfo,
+    },
+    SetMenuLanguage {
+        language: String,
+    },
+    ClearAnalogTimer {
+        schedule: TimerSchedule,
+        service: AnalogueService,
+    },
+    SetAnalogTimer {
+        schedule: TimerSchedule,
+        service: AnalogueService,
+    },
+    TimerStatus {
+        timer_status: TimerStatusData,
+    },
+    Play {
+        play_mode: PlayMode,
+    },
+    DeckControl {
+        deck_control_mode: DeckControlMode,
+    },
+    TimerClearedStatus {
+        timer_cleared_status: TimerClearedStatus,
+    },
+    SetOSDString {
+        display_control: DisplayControl,
+        text: String,
+    },
+    SetTimerProgramTitle {
+        title: String,
+    },
+    SystemAudioModeRequest {
+        physical_address: Option<PhysicalAddress>,
+    },
+    SetSystemAudioMode {
+        system_audio_mode: bool,
+    },
+    ReportAudioStatus {
+        audio_status: AudioStatus,
+    },
+    GiveSystemAudioModeStatus,
+    SystemAudioModeStatus {
+        system_audio_mode: bool,
+    },
+    RoutingInformation {
+        physical_address: PhysicalAddress,
+    },
+    VendorRemoteButtonDown {
+        rc_code: Vec<u8>,
+    },
+    VendorRemoteButtonUp,
+    MenuRequest {
+        menu_request_type: MenuRequestType,
+    },
+    MenuStatus {
+        menu_state: MenuState,
+    },
+    GetMenuLanguage,
+    SelectAnalogService {
+        service: AnalogueService,
+    },
+    SelectDigitalService {
+        service: DigitalService,
+    },
+    SetDigitalTimer {
+        schedule: TimerSchedule,
+        service: DigitalService,
+    },
+    ClearDigitalTimer {
+        schedule: TimerSchedule,
+        service: DigitalService,
+    },
+    SetAudioRate {
+        audio_rate: AudioRate,
+    },
+    InactiveSource {
+        physical_address: PhysicalAddress,
+    },
+    CECVersion {
+        cec_version: CECVersion,
+    },
+    GetCECVersion,
+    VendorCommandWithID {
+        vendor_id: u32,
+        vendor_data: Vec<u8>,
+    },
+    ClearExternalTimer {
+        schedule: TimerSchedule,
+        source: ExternalSource,
+    },
+    SetExternalTimer {
+        schedule: TimerSchedule,
+        source: ExternalSource,
+    },
+    ReportShortAudioDescriptor {
+        descriptors: Vec<[u8; 3]>,
+    },
+    RequestShortAudioDescriptor {
+        audio_formats: Vec<u8>,
+    },
+    GiveFeatures,
+    ReportFeatures {
+        features: Features,
+    },
+    RequestCurrentLatency {
+        physical_address: PhysicalAddress,
+    },
+    ReportCurrentLatency {
+        physical_address: PhysicalAddress,
+        latency: Latency,
+    },
+    InitARC,
+    ReportARCInited,
+    ReportARCTerminated,
+    RequestARCInit,
+    RequestARCTermination,
+    TerminateARC,
+    CDC {
+        physical_address: PhysicalAddress,
+        cdc_opcode: u8,
+        parameters: Vec<u8>,
+    },
+    Abort,
+}
+
+impl CECMessage {
+    fn get_opcode(&self) -> Opcode {
+        match &self {
+            CECMessage::FeatureAbort { .. } => Opcode::FeatureAbort,
+            CECMessage::ImageViewOn => Opcode::ImageViewOn,
+            CECMessage::Standby => Opcode::Standby,
+            CECMessage::ActiveSource { .. } => Opcode::ActiveSource,
+            CECMessage::RequestActiveSource => Opcode::RequestActiveSource,
+            CECMessage::GivePhysicalAddress => Opcode::GivePhysicalAddress,
+            CECMessage::ReportPhysicalAddress { .. } => Opcode::ReportPhysicalAddress,
+            CECMessage::SetStreamPath { .. } => Opcode::SetStreamPath,
             CECMessage::GiveOSDName => Opcode::GiveOSDName,
             CECMessage::SetOSDName { .. } => Opcode::SetOSDName,
             CECMessage::GiveDevicePowerStatus => Opcode::GiveDevicePowerStatus,
@@ -360,6 +1367,61 @@ impl CECMessage {
             CECMessage::UserControlReleased => Opcode::UserControlReleased,
             CECMessage::VendorCommand { .. } => Opcode::VendorCommand,
             CECMessage::GiveDeckStatus { .. } => Opcode::GiveDeckStatus,
+            CECMessage::TunerStepIncrement => Opcode::TunerStepIncrement,
+            CECMessage::TunerStepDecrement => Opcode::TunerStepDecrement,
+            CECMessage::TunerDeviceStatus { .. } => Opcode::TunerDeviceStatus,
+            CECMessage::GiveTunerDeviceStatus { .. } => Opcode::GiveTunerDeviceStatus,
+            CECMessage::RecordOn { .. } => Opcode::RecordOn,
+            CECMessage::RecordStatus { .. } => Opcode::RecordStatus,
+            CECMessage::RecordOff => Opcode::RecordOff,
+            CECMessage::TextViewOn => Opcode::TextViewOn,
+            CECMessage::RecordTVScreen => Opcode::RecordTVScreen,
+            CECMessage::DeckStatus { .. } => Opcode::DeckStatus,
+            CECMessage::SetMenuLanguage { .. } => Opcode::SetMenuLanguage,
+            CECMessage::ClearAnalogTimer { .. } => Opcode::ClearAnalogTimer,
+            CECMessage::SetAnalogTimer { .. } => Opcode::SetAnalogTimer,
+            CECMessage::TimerStatus { .. } => Opcode::TimerStatus,
+            CECMessage::Play { .. } => Opcode::Play,
+            CECMessage::DeckControl { .. } => Opcode::DeckControl,
+            CECMessage::TimerClearedStatus { .. } => Opcode::TimerClearedStatus,
+            CECMessage::SetOSDString { .. } => Opcode::SetOSDString,
+            CECMessage::SetTimerProgramTitle { .. } => Opcode::SetTimerProgramTitle,
+            CECMessage::SystemAudioModeRequest { .. } => Opcode::SystemAudioModeRequest,
+            CECMessage::SetSystemAudioMode { .. } => Opcode::SetSystemAudioMode,
+            CECMessage::ReportAudioStatus { .. } => Opcode::ReportAudioStatus,
+            CECMessage::GiveSystemAudioModeStatus => Opcode::GiveSystemAudioModeStatus,
+            CECMessage::SystemAudioModeStatus { .. } => Opcode::SystemAudioModeStatus,
+            CECMessage::RoutingInformation { .. } => Opcode::RoutingInformation,
+            CECMessage::VendorRemoteButtonDown { .. } => Opcode::VendorRemoteButtonDown,
+            CECMessage::VendorRemoteButtonUp => Opcode::VendorRemoteButtonUp,
+            CECMessage::MenuRequest { .. } => Opcode::MenuRequest,
+            CECMessage::MenuStatus { .. } => Opcode::MenuStatus,
+            CECMessage::GetMenuLanguage => Opcode::GetMenuLanguage,
+            CECMessage::SelectAnalogService { .. } => Opcode::SelectAnalogService,
+            CECMessage::SelectDigitalService { .. } => Opcode::SelectDigitalService,
+            CECMessage::SetDigitalTimer { .. } => Opcode::SetDigitalTimer,
+            CECMessage::ClearDigitalTimer { .. } => Opcode::ClearDigitalTimer,
+            CECMessage::SetAudioRate { .. } => Opcode::SetAudioRate,
+            CECMessage::InactiveSource { .. } => Opcode::InactiveSource,
+            CECMessage::CECVersion { .. } => Opcode::CECVersion,
+            CECMessage::GetCECVersion => Opcode::GetCECVersion,
+            CECMessage::VendorCommandWithID { .. } => Opcode::VendorCommandWithID,
+            CECMessage::ClearExternalTimer { .. } => Opcode::ClearExternalTimer,
+            CECMessage::SetExternalTimer { .. } => Opcode::SetExternalTimer,
+            CECMessage::ReportShortAudioDescriptor { .. } => Opcode::ReportShortAudioDescriptor,
+            CECMessage::RequestShortAudioDescriptor { .. } => Opcode::RequestShortAudioDescriptor,
+            CECMessage::GiveFeatures => Opcode::GiveFeatures,
+            CECMessage::ReportFeatures { .. } => Opcode::ReportFeatures,
+            CECMessage::RequestCurrentLatency { .. } => Opcode::RequestCurrentLatency,
+            CECMessage::ReportCurrentLatency { .. } => Opcode::ReportCurrentLatency,
+            CECMessage::InitARC => Opcode::InitARC,
+            CECMessage::ReportARCInited => Opcode::ReportARCInited,
+            CECMessage::ReportARCTerminated => Opcode::ReportARCTerminated,
+            CECMessage::RequestARCInit => Opcode::RequestARCInit,
+            CECMessage::RequestARCTermination => Opcode::RequestARCTermination,
+            CECMessage::TerminateARC => Opcode::TerminateARC,
+            CECMessage::CDC { .. } => Opcode::CDC,
+            CECMessage::Abort => Opcode::Abort,
         }
     }
     fn get_parameters(&self) -> Vec<u8> {
@@ -369,7 +1431,10 @@ impl CECMessage {
                 abort_reason,
             } => vec![*feature_opcode as u8, *abort_reason as u8],
             CECMessage::ActiveSource { physical_address }
-            | CECMessage::SetStreamPath { physical_address } => {
+            | CECMessage::SetStreamPath { physical_address }
+            | CECMessage::RoutingInformation { physical_address }
+            | CECMessage::InactiveSource { physical_address }
+            | CECMessage::RequestCurrentLatency { physical_address } => {
                 physical_address.to_be_bytes().to_vec()
             }
             CECMessage::ReportPhysicalAddress {
@@ -380,10 +1445,12 @@ impl CECMessage {
                 params.push(*device_type as u8);
                 params
             }
-            CECMessage::SetOSDName { name } => name.as_bytes().to_vec(),
+            CECMessage::SetOSDName { name: text }
+            | CECMessage::SetMenuLanguage { language: text }
+            | CECMessage::SetTimerProgramTitle { title: text } => text.as_bytes().to_vec(),
             CECMessage::ReportPowerStatus { power_status } => vec![*power_status as u8],
             CECMessage::DeviceVendorID { vendor_id } => {
-                let code = *vendor_id as u32;
+                let code = *vendor_id;
                 code.to_be_bytes()[1..].to_vec()
             }
             CECMessage::RoutingChange {
@@ -396,7 +1463,90 @@ impl CECMessage {
             }
             CECMessage::UserControlPressed { user_control_code } => vec![*user_control_code as u8],
             CECMessage::VendorCommand { vendor_data } => vendor_data.to_vec(),
-            CECMessage::GiveDeckStatus { status_request } => vec![*status_request as u8],
+            CECMessage::GiveDeckStatus { status_request }
+            | CECMessage::GiveTunerDeviceStatus { status_request } => vec![*status_request as u8],
+            CECMessage::TunerDeviceStatus { tuner_device_info } => tuner_device_info.to_bytes(),
+            CECMessage::RecordOn { record_source } => record_source.to_bytes(),
+            CECMessage::RecordStatus { record_status_info } => vec![*record_status_info],
+            CECMessage::DeckStatus { deck_info } => vec![*deck_info as u8],
+            CECMessage::ClearAnalogTimer { schedule, service }
+            | CECMessage::SetAnalogTimer { schedule, service } => {
+                let mut params = schedule.to_bytes();
+                params.extend(service.to_bytes());
+                params
+            }
+            CECMessage::SetDigitalTimer { schedule, service }
+            | CECMessage::ClearDigitalTimer { schedule, service } => {
+                let mut params = schedule.to_bytes();
+                params.extend(service.to_bytes());
+                params
+            }
+            CECMessage::ClearExternalTimer { schedule, source }
+            | CECMessage::SetExternalTimer { schedule, source } => {
+                let mut params = schedule.to_bytes();
+                params.extend(source.to_bytes());
+                params
+            }
+            CECMessage::TimerStatus { timer_status } => timer_status.to_bytes(),
+            CECMessage::Play { play_mode } => vec![*play_mode as u8],
+            CECMessage::DeckControl { deck_control_mode } => vec![*deck_control_mode as u8],
+            CECMessage::TimerClearedStatus {
+                timer_cleared_status,
+            } => vec![*timer_cleared_status as u8],
+            CECMessage::SetOSDString {
+                display_control,
+                text,
+            } => {
+                let mut params = vec![*display_control as u8];
+                params.extend(text.as_bytes());
+                params
+            }
+            CECMessage::SystemAudioModeRequest { physical_address } => physical_address
+                .map(|addr| addr.to_be_bytes().to_vec())
+                .unwrap_or_default(),
+            CECMessage::SetSystemAudioMode { system_audio_mode }
+            | CECMessage::SystemAudioModeStatus { system_audio_mode } => {
+                vec![*system_audio_mode as u8]
+            }
+            CECMessage::ReportAudioStatus { audio_status } => vec![audio_status.to_byte()],
+            CECMessage::VendorRemoteButtonDown { rc_code } => rc_code.to_vec(),
+            CECMessage::MenuRequest { menu_request_type } => vec![*menu_request_type as u8],
+            CECMessage::MenuStatus { menu_state } => vec![*menu_state as u8],
+            CECMessage::SelectAnalogService { service } => service.to_bytes(),
+            CECMessage::SelectDigitalService { service } => service.to_bytes(),
+            CECMessage::SetAudioRate { audio_rate } => vec![*audio_rate as u8],
+            CECMessage::CECVersion { cec_version } => vec![*cec_version as u8],
+            CECMessage::VendorCommandWithID {
+                vendor_id,
+                vendor_data,
+            } => {
+                let mut params = vendor_id.to_be_bytes()[1..].to_vec();
+                params.extend(vendor_data);
+                params
+            }
+            CECMessage::ReportShortAudioDescriptor { descriptors } => {
+                descriptors.iter().flatten().copied().collect()
+            }
+            CECMessage::RequestShortAudioDescriptor { audio_formats } => audio_formats.to_vec(),
+            CECMessage::ReportFeatures { features } => features.to_bytes(),
+            CECMessage::ReportCurrentLatency {
+                physical_address,
+                latency,
+            } => {
+                let mut params = physical_address.to_be_bytes().to_vec();
+                params.extend(latency.to_bytes());
+                params
+            }
+            CECMessage::CDC {
+                physical_address,
+                cdc_opcode,
+                parameters,
+            } => {
+                let mut params = physical_address.to_be_bytes().to_vec();
+                params.push(*cdc_opcode);
+                params.extend(parameters);
+                params
+            }
             CECMessage::ImageViewOn
             | CECMessage::Standby
             | CECMessage::RequestActiveSource
@@ -405,7 +1555,24 @@ impl CECMessage {
             | CECMessage::UserControlReleased
             | CECMessage::GiveDevicePowerStatus
             | CECMessage::GiveDeviceVendorID
-            | CECMessage::GiveAudioStatus => vec![],
+            | CECMessage::GiveAudioStatus
+            | CECMessage::TunerStepIncrement
+            | CECMessage::TunerStepDecrement
+            | CECMessage::RecordOff
+            | CECMessage::TextViewOn
+            | CECMessage::RecordTVScreen
+            | CECMessage::GiveSystemAudioModeStatus
+            | CECMessage::VendorRemoteButtonUp
+            | CECMessage::GetMenuLanguage
+            | CECMessage::GetCECVersion
+            | CECMessage::GiveFeatures
+            | CECMessage::InitARC
+            | CECMessage::ReportARCInited
+            | CECMessage::ReportARCTerminated
+            | CECMessage::RequestARCInit
+            | CECMessage::RequestARCTermination
+            | CECMessage::TerminateARC
+            | CECMessage::Abort => vec![],
         }
     }
 
@@ -420,10 +1587,16 @@ impl CECMessage {
 pub enum Error {
     #[error("Command is too short")]
     InputTooShort,
-    #[error("Parsing for this opcode hasn't been implemented")]
-    OpcodeNotImplemented,
+    #[error("Command has invalid operand")]
+    InvalidOperand,
     #[error("Command has invalid logical address")]
     BadLogicalAddr(#[from] TryFromPrimitiveError<LogicalAddress>),
+    #[error("Invalid physical address {0:?}")]
+    BadPhysicalAddress(String),
+    #[error("Can't parse CEC frame {0:?}")]
+    BadFrameText(String),
+    #[error("Can't show {0:?} on screen, which only takes printable ASCII")]
+    BadOSDText(String),
     #[error("Command has invalid opcode")]
     BadOpcode(#[from] TryFromPrimitiveError<Opcode>),
     #[error("Command has invalid abort reason")]
@@ -436,6 +1609,32 @@ pub enum Error {
     BadDeviceType(#[from] TryFromPrimitiveError<DeviceType>),
     #[error("Command has invalid device type")]
     BadDeckStatus(#[from] TryFromPrimitiveError<DeckStatus>),
+    #[error("Command has invalid deck info")]
+    BadDeckInfo(#[from] TryFromPrimitiveError<DeckInfo>),
+    #[error("Command has invalid deck control mode")]
+    BadDeckControlMode(#[from] TryFromPrimitiveError<DeckControlMode>),
+    #[error("Command has invalid play mode")]
+    BadPlayMode(#[from] TryFromPrimitiveError<PlayMode>),
+    #[error("Command has invalid menu request type")]
+    BadMenuRequestType(#[from] TryFromPrimitiveError<MenuRequestType>),
+    #[error("Command has invalid menu state")]
+    BadMenuState(#[from] TryFromPrimitiveError<MenuState>),
+    #[error("Command has invalid display control")]
+    BadDisplayControl(#[from] TryFromPrimitiveError<DisplayControl>),
+    #[error("Command has invalid audio rate")]
+    BadAudioRate(#[from] TryFromPrimitiveError<AudioRate>),
+    #[error("Command has invalid CEC version")]
+    BadCECVersion(#[from] TryFromPrimitiveError<CECVersion>),
+    #[error("Command has invalid timer cleared status")]
+    BadTimerClearedStatus(#[from] TryFromPrimitiveError<TimerClearedStatus>),
+    #[error("Command has invalid analogue broadcast type")]
+    BadAnalogueBroadcastType(#[from] TryFromPrimitiveError<AnalogueBroadcastType>),
+    #[error("Command has invalid tuner display info")]
+    BadTunerDisplayInfo(#[from] TryFromPrimitiveError<TunerDisplayInfo>),
+    #[error("Command has invalid RC profile")]
+    BadRCProfile(#[from] TryFromPrimitiveError<RCProfileTV>),
+    #[error("Command has invalid audio output compensation")]
+    BadAudioOutputCompensated(#[from] TryFromPrimitiveError<AudioOutputCompensated>),
     #[error("Bad internal slicing")]
     BadInternalSlicing(#[from] TryFromSliceError),
     #[error("Command has invalid string")]
@@ -450,7 +1649,7 @@ pub struct CECCommand {
 }
 impl CECCommand {
     pub fn from_raw(input: &[u8]) -> Result<CECCommand, Error> {
-        if input.len() == 0 {
+        if input.is_empty() {
             return Err(Error::InputTooShort);
         }
         let initiator = LogicalAddress::try_from((input[0] & 0xf0) >> 4)?;
@@ -465,18 +1664,73 @@ impl CECCommand {
             | Opcode::GivePhysicalAddress
             | Opcode::RequestActiveSource
             | Opcode::GiveOSDName
+            | Opcode::GiveDevicePowerStatus
             | Opcode::GiveDeviceVendorID
             | Opcode::GiveAudioStatus
-            | Opcode::UserControlReleased => 2,
+            | Opcode::UserControlReleased
+            | Opcode::TunerStepIncrement
+            | Opcode::TunerStepDecrement
+            | Opcode::RecordOff
+            | Opcode::TextViewOn
+            | Opcode::RecordTVScreen
+            | Opcode::SystemAudioModeRequest
+            | Opcode::GiveSystemAudioModeStatus
+            | Opcode::VendorRemoteButtonUp
+            | Opcode::GetMenuLanguage
+            | Opcode::GetCECVersion
+            | Opcode::GiveFeatures
+            | Opcode::InitARC
+            | Opcode::ReportARCInited
+            | Opcode::ReportARCTerminated
+            | Opcode::RequestARCInit
+            | Opcode::RequestARCTermination
+            | Opcode::TerminateARC
+            | Opcode::Abort => 2,
             Opcode::SetOSDName
             | Opcode::GiveDeckStatus
             | Opcode::ReportPowerStatus
             | Opcode::UserControlPressed
-            | Opcode::VendorCommand => 3,
-            Opcode::ActiveSource | Opcode::SetStreamPath | Opcode::FeatureAbort => 4,
-            Opcode::ReportPhysicalAddress | Opcode::DeviceVendorID => 5,
-            Opcode::RoutingChange => 6,
-            _ => 0,
+            | Opcode::VendorCommand
+            | Opcode::GiveTunerDeviceStatus
+            | Opcode::RecordOn
+            | Opcode::RecordStatus
+            | Opcode::DeckStatus
+            | Opcode::TimerStatus
+            | Opcode::Play
+            | Opcode::DeckControl
+            | Opcode::TimerClearedStatus
+            | Opcode::SetOSDString
+            | Opcode::SetTimerProgramTitle
+            | Opcode::SetSystemAudioMode
+            | Opcode::ReportAudioStatus
+            | Opcode::SystemAudioModeStatus
+            | Opcode::VendorRemoteButtonDown
+            | Opcode::MenuRequest
+            | Opcode::MenuStatus
+            | Opcode::SetAudioRate
+            | Opcode::CECVersion
+            | Opcode::RequestShortAudioDescriptor => 3,
+            Opcode::ActiveSource
+            | Opcode::SetStreamPath
+            | Opcode::FeatureAbort
+            | Opcode::RoutingInformation
+            | Opcode::InactiveSource
+            | Opcode::RequestCurrentLatency => 4,
+            Opcode::ReportPhysicalAddress
+            | Opcode::DeviceVendorID
+            | Opcode::SetMenuLanguage
+            | Opcode::VendorCommandWithID
+            | Opcode::ReportShortAudioDescriptor
+            | Opcode::CDC => 5,
+            Opcode::RoutingChange
+            | Opcode::SelectAnalogService
+            | Opcode::ReportFeatures
+            | Opcode::ReportCurrentLatency => 6,
+            Opcode::TunerDeviceStatus => 7,
+            Opcode::SelectDigitalService => 9,
+            Opcode::ClearExternalTimer | Opcode::SetExternalTimer => 11,
+            Opcode::ClearAnalogTimer | Opcode::SetAnalogTimer => 13,
+            Opcode::ClearDigitalTimer | Opcode::SetDigitalTimer => 16,
         };
         if input.len() < min_len {
             return Err(Error::InputTooShort);
@@ -513,7 +1767,7 @@ impl CECCommand {
             },
             Opcode::GiveDeviceVendorID => CECMessage::GiveDeviceVendorID,
             Opcode::DeviceVendorID => CECMessage::DeviceVendorID {
-                vendor_id: (input[2] as u32) << 16 | (input[3] as u32) << 8 | (input[4] as u32),
+                vendor_id: vendor_id_from_bytes(&input[2..5]),
             },
             Opcode::GiveAudioStatus => CECMessage::GiveAudioStatus,
             Opcode::RoutingChange => CECMessage::RoutingChange {
@@ -527,22 +1781,401 @@ impl CECCommand {
             Opcode::VendorCommand => CECMessage::VendorCommand {
                 vendor_data: input[2..].to_vec(),
             },
-            _ => return Err(Error::OpcodeNotImplemented),
+            Opcode::TunerStepIncrement => CECMessage::TunerStepIncrement,
+            Opcode::TunerStepDecrement => CECMessage::TunerStepDecrement,
+            Opcode::TunerDeviceStatus => CECMessage::TunerDeviceStatus {
+                tuner_device_info: TunerDeviceInfo::from_bytes(&input[2..])?,
+            },
+            Opcode::GiveTunerDeviceStatus => CECMessage::GiveTunerDeviceStatus {
+                status_request: DeckStatus::try_from(input[2])?,
+            },
+            Opcode::RecordOn => CECMessage::RecordOn {
+                record_source: RecordSource::from_bytes(&input[2..])?,
+            },
+            Opcode::RecordStatus => CECMessage::RecordStatus {
+                record_status_info: input[2],
+            },
+            Opcode::RecordOff => CECMessage::RecordOff,
+            Opcode::TextViewOn => CECMessage::TextViewOn,
+            Opcode::RecordTVScreen => CECMessage::RecordTVScreen,
+            Opcode::DeckStatus => CECMessage::DeckStatus {
+                deck_info: DeckInfo::try_from(input[2])?,
+            },
+            Opcode::SetMenuLanguage => CECMessage::SetMenuLanguage {
+                language: str::from_utf8(&input[2..5])?.to_string(),
+            },
+            Opcode::ClearAnalogTimer => CECMessage::ClearAnalogTimer {
+                schedule: TimerSchedule::from_bytes(&input[2..])?,
+                service: AnalogueService::from_bytes(&input[2 + TimerSchedule::LEN..])?,
+            },
+            Opcode::SetAnalogTimer => CECMessage::SetAnalogTimer {
+                schedule: TimerSchedule::from_bytes(&input[2..])?,
+                service: AnalogueService::from_bytes(&input[2 + TimerSchedule::LEN..])?,
+            },
+            Opcode::TimerStatus => CECMessage::TimerStatus {
+                timer_status: TimerStatusData::from_bytes(&input[2..])?,
+            },
+            Opcode::Play => CECMessage::Play {
+                play_mode: PlayMode::try_from(input[2])?,
+            },
+            Opcode::DeckControl => CECMessage::DeckControl {
+                deck_control_mode: DeckControlMode::try_from(input[2])?,
+            },
+            Opcode::TimerClearedStatus => CECMessage::TimerClearedStatus {
+                timer_cleared_status: TimerClearedStatus::try_from(input[2])?,
+            },
+            Opcode::SetOSDString => CECMessage::SetOSDString {
+                display_control: DisplayControl::try_from(input[2])?,
+                text: str::from_utf8(&input[3..])?.to_string(),
+            },
+            Opcode::SetTimerProgramTitle => CECMessage::SetTimerProgramTitle {
+                title: str::from_utf8(&input[2..])?.to_string(),
+            },
+            Opcode::SystemAudioModeRequest => CECMessage::SystemAudioModeRequest {
+                physical_address: match input.len() {
+                    2 => None,
+                    3 => return Err(Error::InputTooShort),
+                    _ => Some(physical_address_from_bytes(&input[2..4])?),
+                },
+            },
+            Opcode::SetSystemAudioMode => CECMessage::SetSystemAudioMode {
+                system_audio_mode: input[2] != 0,
+            },
+            Opcode::ReportAudioStatus => CECMessage::ReportAudioStatus {
+                audio_status: AudioStatus::from_byte(input[2]),
+            },
+            Opcode::GiveSystemAudioModeStatus => CECMessage::GiveSystemAudioModeStatus,
+            Opcode::SystemAudioModeStatus => CECMessage::SystemAudioModeStatus {
+                system_audio_mode: input[2] != 0,
+            },
+            Opcode::RoutingInformation => CECMessage::RoutingInformation {
+                physical_address: physical_address_from_bytes(&input[2..4])?,
+            },
+            Opcode::VendorRemoteButtonDown => CECMessage::VendorRemoteButtonDown {
+                rc_code: input[2..].to_vec(),
+            },
+            Opcode::VendorRemoteButtonUp => CECMessage::VendorRemoteButtonUp,
+            Opcode::MenuRequest => CECMessage::MenuRequest {
+                menu_request_type: MenuRequestType::try_from(input[2])?,
+            },
+            Opcode::MenuStatus => CECMessage::MenuStatus {
+                menu_state: MenuState::try_from(input[2])?,
+            },
+            Opcode::GetMenuLanguage => CECMessage::GetMenuLanguage,
+            Opcode::SelectAnalogService => CECMessage::SelectAnalogService {
+                service: AnalogueService::from_bytes(&input[2..])?,
+            },
+            Opcode::SelectDigitalService => CECMessage::SelectDigitalService {
+                service: DigitalService::from_bytes(&input[2..])?,
+            },
+            Opcode::SetDigitalTimer => CECMessage::SetDigitalTimer {
+                schedule: TimerSchedule::from_bytes(&input[2..])?,
+                service: DigitalService::from_bytes(&input[2 + TimerSchedule::LEN..])?,
+            },
+            Opcode::ClearDigitalTimer => CECMessage::ClearDigitalTimer {
+                schedule: TimerSchedule::from_bytes(&input[2..])?,
+                service: DigitalService::from_bytes(&input[2 + TimerSchedule::LEN..])?,
+            },
+            Opcode::SetAudioRate => CECMessage::SetAudioRate {
+                audio_rate: AudioRate::try_from(input[2])?,
+            },
+            Opcode::InactiveSource => CECMessage::InactiveSource {
+                physical_address: physical_address_from_bytes(&input[2..4])?,
+            },
+            Opcode::CECVersion => CECMessage::CECVersion {
+                cec_version: CECVersion::try_from(input[2])?,
+            },
+            Opcode::GetCECVersion => CECMessage::GetCECVersion,
+            Opcode::VendorCommandWithID => CECMessage::VendorCommandWithID {
+                vendor_id: vendor_id_from_bytes(&input[2..5]),
+                vendor_data: input[5..].to_vec(),
+            },
+            Opcode::ClearExternalTimer => CECMessage::ClearExternalTimer {
+                schedule: TimerSchedule::from_bytes(&input[2..])?,
+                source: ExternalSource::from_bytes(&input[2 + TimerSchedule::LEN..])?,
+            },
+            Opcode::SetExternalTimer => CECMessage::SetExternalTimer {
+                schedule: TimerSchedule::from_bytes(&input[2..])?,
+                source: ExternalSource::from_bytes(&input[2 + TimerSchedule::LEN..])?,
+            },
+            Opcode::ReportShortAudioDescriptor => CECMessage::ReportShortAudioDescriptor {
+                descriptors: input[2..]
+                    .chunks_exact(3)
+                    .map(|d| [d[0], d[1], d[2]])
+                    .collect(),
+            },
+            Opcode::RequestShortAudioDescriptor => CECMessage::RequestShortAudioDescriptor {
+                audio_formats: input[2..].to_vec(),
+            },
+            Opcode::GiveFeatures => CECMessage::GiveFeatures,
+            Opcode::ReportFeatures => CECMessage::ReportFeatures {
+                features: Features::from_bytes(&input[2..])?,
+            },
+            Opcode::RequestCurrentLatency => CECMessage::RequestCurrentLatency {
+                physical_address: physical_address_from_bytes(&input[2..4])?,
+            },
+            Opcode::ReportCurrentLatency => CECMessage::ReportCurrentLatency {
+                physical_address: physical_address_from_bytes(&input[2..4])?,
+                latency: Latency::from_bytes(&input[4..])?,
+            },
+            Opcode::InitARC => CECMessage::InitARC,
+            Opcode::ReportARCInited => CECMessage::ReportARCInited,
+            Opcode::ReportARCTerminated => CECMessage::ReportARCTerminated,
+            Opcode::RequestARCInit => CECMessage::RequestARCInit,
+            Opcode::RequestARCTermination => CECMessage::RequestARCTermination,
+            Opcode::TerminateARC => CECMessage::TerminateARC,
+            Opcode::CDC => CECMessage::CDC {
+                physical_address: physical_address_from_bytes(&input[2..4])?,
+                cdc_opcode: input[4],
+                parameters: input[5..].to_vec(),
+            },
+            Opcode::Abort => CECMessage::Abort,
         };
         Ok(CECCommand {
             initiator: Some(initiator),
-            destination: destination,
-            message: message,
+            destination,
+            message,
         })
     }
 }
 
+fn transmit_on(
+    tx: &TxQueue,
+    destination: LogicalAddress,
+    message: CECMessage,
+) -> Result<(), CECError> {
+    let cmd = CECCommand {
+        initiator: None,
+        destination,
+        message,
+    };
+    info!("sending {}", cmd);
+    tx.send(cmd)
+}
+
+// Finds out who's on the bus, from the adapter's own record if it keeps one
+// and otherwise by polling every address.
+fn discover(
+    conn: &dyn CECConnection,
+    tx: &TxQueue,
+    devices: &Mutex<DeviceTable>,
+) -> Result<(), CECError> {
+    let known = match conn.known_devices() {
+        Ok(Some(known)) => known,
+        Ok(None) => return poll_bus(tx),
+        Err(e) => {
+            info!(
+                "couldn't get the adapter's topology, polling instead: {}",
+                e
+            );
+            return poll_bus(tx);
+        }
+    };
+    let own = conn.get_logical_address().ok();
+    let others: Vec<KnownDevice> = known
+        .into_iter()
+        .filter(|d| Some(d.logical_address) != own)
+        .collect();
+    devices.lock().unwrap().set_known(&others);
+    // The table already has their addresses, so answers won't count as news
+    // and set off the usual questions. Ask them here instead.
+    for device in &others {
+        for message in [
+            CECMessage::GiveOSDName,
+            CECMessage::GiveDeviceVendorID,
+            CECMessage::GetCECVersion,
+            CECMessage::GiveDevicePowerStatus,
+        ] {
+            match transmit_on(tx, device.logical_address, message) {
+                // The adapter's record may be out of date.
+                Err(CECError::NoAck) => break,
+                result => result?,
+            }
+        }
+    }
+    Ok(())
+}
+
+// Asks every device that could be on the bus for its name and address.
+fn poll_bus(tx: &TxQueue) -> Result<(), CECError> {
+    for &addr in &[
+        LogicalAddress::TV,
+        LogicalAddress::AudioSystem,
+        LogicalAddress::PlaybackDevice1,
+        LogicalAddress::PlaybackDevice2,
+        LogicalAddress::PlaybackDevice3,
+        LogicalAddress::RecordingDevice1,
+        LogicalAddress::RecordingDevice2,
+        LogicalAddress::RecordingDevice3,
+        LogicalAddress::Tuner1,
+        LogicalAddress::Tuner2,
+        LogicalAddress::Tuner3,
+        LogicalAddress::Tuner4,
+    ] {
+        // Nothing acknowledges an address that nobody has.
+        match transmit_on(tx, addr, CECMessage::GiveOSDName) {
+            Err(CECError::NoAck) => continue,
+            result => result?,
+        }
+        transmit_on(tx, addr, CECMessage::GivePhysicalAddress)?;
+    }
+    Ok(())
+}
+
+// Tells the TV who we are again and finds out who else is around, for when a
+// hotplug or the TV waking up may have made it forget.
+fn reannounce(
+    conn: &dyn CECConnection,
+    tx: &TxQueue,
+    devices: &Mutex<DeviceTable>,
+    osd_name: &str,
+) -> Result<(), CECError> {
+    info!("announcing ourselves again");
+    transmit_on(
+        tx,
+        LogicalAddress::Broadcast,
+        CECMessage::ReportPhysicalAddress {
+            physical_address: conn.get_physical_address()?,
+            device_type: conn.get_logical_address()?.to_device_type(),
+        },
+    )?;
+    transmit_on(
+        tx,
+        LogicalAddress::TV,
+        CECMessage::SetOSDName {
+            name: osd_name.to_string(),
+        },
+    )?;
+    discover(conn, tx, devices)
+}
+
+// Sends a message from within a callback, where there's nobody to hand an
+// error back to. It's only queued, since the callback's thread may be the one
+// that has to report how the frames before it went.
+fn send_or_log(tx: &Arc<TxQueue>, destination: LogicalAddress, message: CECMessage) {
+    tx.post(CECCommand {
+        initiator: None,
+        destination,
+        message,
+    });
+}
+
+// Splits text into pieces that fit in a <Set OSD String>, breaking between
+// words where it can. The spec only allows printable ASCII, and there's no
+// telling how a TV would show anything else, so other text is refused.
+fn osd_chunks(text: &str) -> Result<Vec<String>, Error> {
+    if !text
+        .chars()
+        .all(|c| c.is_ascii_whitespace() || c.is_ascii_graphic())
+    {
+        return Err(Error::BadOSDText(text.to_string()));
+    }
+    let mut chunks = vec![];
+    let mut chunk = String::new();
+    for word in text.split_whitespace() {
+        if !chunk.is_empty() && chunk.len() + 1 + word.len() > OSD_STRING_MAX_LEN {
+            chunks.push(std::mem::take(&mut chunk));
+        }
+        if !chunk.is_empty() {
+            chunk.push(' ');
+        }
+        chunk.push_str(word);
+        while chunk.len() > OSD_STRING_MAX_LEN {
+            let rest = chunk.split_off(OSD_STRING_MAX_LEN);
+            chunks.push(std::mem::replace(&mut chunk, rest));
+        }
+    }
+    if !chunk.is_empty() || chunks.is_empty() {
+        chunks.push(chunk);
+    }
+    Ok(chunks)
+}
+
+// What we report in <Report Features>. None of the optional features apply to
+// us, and we have no menus of our own for the TV's remote to bring up.
+fn own_features(device_type: DeviceType) -> Features {
+    Features {
+        cec_version: CEC_VERSION,
+        device_types: AllDeviceTypes::of(device_type),
+        rc_profile: RCProfile::Source {
+            root_menu: false,
+            setup_menu: false,
+            contents_menu: false,
+            media_top_menu: false,
+            media_context_menu: false,
+        },
+        device_features: DeviceFeatures::default(),
+    }
+}
+
+pub type TopologyCallback = Box<dyn FnMut(&[LogicalAddress]) + Send>;
+
+/// A change on the HDMI link itself, rather than on the CEC bus.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HdmiEvent {
+    Unplugged,
+    /// The cable is plugged in, but the TV isn't showing our output.
+    Attached,
+    /// Our output is on screen, in HDMI mode.
+    Hdmi,
+    /// Our output is on screen, in DVI mode without audio.
+    Dvi,
+    HdcpAuthenticated,
+    HdcpUnauthenticated,
+    /// The output is about to switch to a new video mode.
+    ChangingMode,
+}
+
+pub type HdmiCallback = Box<dyn FnMut(HdmiEvent) + Send>;
+
+/// Called after a connection has had to reopen the adapter, which forgets
+/// anything we told the bus before.
+pub type ReconnectCallback = Box<dyn FnMut() + Send>;
+
+/// Called with each frame the connection has sent, and whether it was
+/// acknowledged.
+pub type TxCallback = Box<dyn FnMut(&CECCommand, Result<(), CECError>) + Send>;
+pub(crate) type SharedTxCallback = Arc<Mutex<Option<TxCallback>>>;
+
+/// A remote control key going down or coming back up.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum KeyEvent {
+    Pressed(UserControl),
+    Released,
+}
+
+pub type KeyCallback = Box<dyn FnMut(KeyEvent) + Send>;
+
+/// Called with the physical address of the input the TV has switched to.
+pub type InputCallback = Box<dyn FnMut(PhysicalAddress) + Send>;
+
 pub trait CECConnection: Sync + Send {
     fn transmit(&self, cmd: CECCommand) -> Result<(), CECError>;
     fn get_logical_address(&self) -> Result<LogicalAddress, CECError>;
     fn get_physical_address(&self) -> Result<PhysicalAddress, CECError>;
-    fn set_tx_callback(&self, func: Box<dyn FnMut(&CECCommand) + Send>);
+    fn set_tx_callback(&self, func: TxCallback);
     fn set_rx_callback(&self, func: Box<dyn FnMut(&CECCommand) + Send>);
+    // Called with the logical addresses present on the bus, for connections
+    // that keep track of the topology themselves.
+    fn set_topology_callback(&self, _func: TopologyCallback) {}
+    // Called with hotplug and HDCP changes, for connections that can see
+    // them.
+    fn set_hdmi_callback(&self, _func: HdmiCallback) {}
+    // Called after the connection has reopened the adapter, for connections
+    // that recover from failures themselves. It runs on the connection's own
+    // thread, so it mustn't block.
+    fn set_reconnect_callback(&self, _func: ReconnectCallback) {}
+    // Asks the adapter to pass on only messages with these opcodes, for
+    // adapters that filter them. The adapter answers the rest itself.
+    fn register_opcodes(&self, _opcodes: &[Opcode]) -> Result<(), CECError> {
+        Ok(())
+    }
+    // The other devices on the bus, for adapters that keep track of them, so
+    // that they needn't all be polled.
+    fn known_devices(&self) -> Result<Option<Vec<KnownDevice>>, CECError> {
+        Ok(None)
+    }
 }
 
 impl tv::TVConnection for CEC {
@@ -560,13 +2193,88 @@ impl tv::TVConnection for CEC {
     }
 }
 
+// Everything the rx handler in CEC::new does something with, so adapters that
+// filter messages can answer the rest themselves. Keep the two in step;
+// handles_the_registered_opcodes checks that they are.
+const HANDLED_OPCODES: &[Opcode] = &[
+    Opcode::FeatureAbort,
+    Opcode::ImageViewOn,
+    Opcode::TunerDeviceStatus,
+    Opcode::RecordStatus,
+    Opcode::GiveDeckStatus,
+    Opcode::DeckStatus,
+    Opcode::SetMenuLanguage,
+    Opcode::TimerStatus,
+    Opcode::Standby,
+    Opcode::TimerClearedStatus,
+    Opcode::UserControlPressed,
+    Opcode::UserControlReleased,
+    Opcode::GiveOSDName,
+    Opcode::SetOSDName,
+    Opcode::SetSystemAudioMode,
+    Opcode::ReportAudioStatus,
+    Opcode::SystemAudioModeStatus,
+    Opcode::RoutingChange,
+    Opcode::RoutingInformation,
+    Opcode::ActiveSource,
+    Opcode::GivePhysicalAddress,
+    Opcode::ReportPhysicalAddress,
+    Opcode::RequestActiveSource,
+    Opcode::SetStreamPath,
+    Opcode::DeviceVendorID,
+    Opcode::VendorCommand,
+    Opcode::VendorRemoteButtonDown,
+    Opcode::VendorRemoteButtonUp,
+    Opcode::GiveDeviceVendorID,
+    Opcode::MenuRequest,
+    Opcode::MenuStatus,
+    Opcode::GiveDevicePowerStatus,
+    Opcode::ReportPowerStatus,
+    Opcode::GetMenuLanguage,
+    Opcode::InactiveSource,
+    Opcode::CECVersion,
+    Opcode::GetCECVersion,
+    Opcode::VendorCommandWithID,
+    Opcode::ReportShortAudioDescriptor,
+    Opcode::GiveFeatures,
+    Opcode::ReportFeatures,
+    Opcode::RequestCurrentLatency,
+    Opcode::ReportCurrentLatency,
+    Opcode::InitARC,
+    Opcode::ReportARCInited,
+    Opcode::ReportARCTerminated,
+    Opcode::RequestARCInit,
+    Opcode::RequestARCTermination,
+    Opcode::TerminateARC,
+    Opcode::CDC,
+    Opcode::Abort,
+];
+
 pub struct CEC {
     conn: Arc<dyn CECConnection>,
-    tx_signal: Arc<(Mutex<Option<CECCommand>>, Condvar)>,
+    tx: Arc<TxQueue>,
 
     // Internal state.
-    power_state: Arc<Mutex<bool>>,
+    power_status: Arc<(Mutex<PowerStatus>, Condvar)>,
+    // Our own, as reported to <Give Device Power Status>.
+    own_power_status: Arc<Mutex<PowerStatus>>,
     input_state: Arc<Mutex<PhysicalAddress>>,
+    audio_status: Arc<Mutex<Option<AudioStatus>>>,
+    system_audio_mode: Arc<Mutex<bool>>,
+    arc_state: Arc<Mutex<ARCLink>>,
+    devices: Arc<Mutex<DeviceTable>>,
+    replies: Arc<PendingReplies>,
+    key_callback: Arc<Mutex<Option<KeyCallback>>>,
+    input_callback: Arc<Mutex<Option<InputCallback>>>,
+    hdmi_subscribers: Arc<Mutex<Vec<HdmiCallback>>>,
+    // Text for the OSD thread to show. Dropped on shutdown to stop it.
+    osd_requests: Option<mpsc::Sender<(Vec<String>, DisplayControl)>>,
+    journal: Arc<Journal>,
+    menu_language: Arc<Mutex<Option<String>>>,
+    // Tells the power poller to stop. Only changed with the power status
+    // locked, so the poller can't miss it between checking and waiting.
+    stopping: Arc<AtomicBool>,
+    threads: Vec<thread::JoinHandle<()>>,
 }
 
 impl CEC {
@@ -575,198 +2283,585 @@ impl CEC {
         osd_name: &str,
         vendor_id: u32,
     ) -> Result<Self, CECError> {
-        let tx_signal = Arc::new((Mutex::new(None), Condvar::new()));
-        let power_state = Arc::new(Mutex::new(false));
-        let input_state = Arc::new(Mutex::new(0));
-        let inner_tx_signal = tx_signal.clone();
+        let tx = Arc::new(TxQueue::new(conn.clone()));
+        let power_status = Arc::new((Mutex::new(PowerStatus::Unknown), Condvar::new()));
+        // There's nothing to power down, but we go along with <Standby> until
+        // something wakes us again.
+        let own_power_status = Arc::new(Mutex::new(PowerStatus::On));
+        let input_state = Arc::new(Mutex::new(PhysicalAddress::ROOT));
+        let audio_status = Arc::new(Mutex::new(None));
+        let system_audio_mode = Arc::new(Mutex::new(false));
+        let arc_state = Arc::new(Mutex::new(ARCLink::new()));
+        let devices = Arc::new(Mutex::new(DeviceTable::default()));
+        let replies = Arc::new(PendingReplies::default());
+        let journal = Arc::new(Journal::default());
+        // Learned from the TV, so we can answer <Get Menu Language> for it.
+        let menu_language = Arc::new(Mutex::new(None::<String>));
+        let inner_menu_language = menu_language.clone();
+        let mut vendor: Option<Box<dyn VendorHandler>> = None;
+        let key_callback: Arc<Mutex<Option<KeyCallback>>> = Arc::new(Mutex::new(None));
+        let inner_key_callback = key_callback.clone();
+        let input_callback: Arc<Mutex<Option<InputCallback>>> = Arc::new(Mutex::new(None));
+        let inner_input_callback = input_callback.clone();
+        let inner_tx = tx.clone();
         let inner_conn = conn.clone();
         let inner_input_state = input_state.clone();
-        let inner_power_state = power_state.clone();
+        let inner_power_status = power_status.clone();
+        let inner_own_power_status = own_power_status.clone();
+        let inner_audio_status = audio_status.clone();
+        let inner_system_audio_mode = system_audio_mode.clone();
+        let inner_arc_state = arc_state.clone();
+        let inner_devices = devices.clone();
+        let inner_replies = replies.clone();
+        let inner_journal = journal.clone();
         let osd_name = osd_name.to_string();
-        let mut logical_to_physical = [0; 0xf];
+        let inner_osd_name = osd_name.clone();
+        let (request_reannounce, reannounce_requests) = mpsc::channel();
+        let inner_request_reannounce = request_reannounce.clone();
         conn.set_rx_callback(Box::new(move |msg| {
-            info!("rx {:x?} {:02x?}", msg, msg.message.payload());
+            info!("rx {}", msg);
+            inner_journal.record(journal::Direction::Rx, msg, None);
+            if !follower::has_valid_addressing(msg) {
+                info!("ignoring message with invalid addressing");
+                return;
+            }
+            // Adapters that can't filter by destination pass on frames for
+            // other devices too, which aren't ours to answer.
+            if msg.destination != LogicalAddress::Broadcast {
+                match inner_conn.get_logical_address() {
+                    Ok(own) if own != msg.destination => {
+                        info!("ignoring message for {:?}", msg.destination);
+                        return;
+                    }
+                    _ => (),
+                }
+            }
+            inner_replies.deliver(msg);
+            let initiator = msg.initiator.unwrap();
+            if inner_devices.lock().unwrap().update(msg) {
+                // Fill in the rest of the details for newly discovered devices.
+                for message in [
+                    CECMessage::GivePhysicalAddress,
+                    CECMessage::GiveOSDName,
+                    CECMessage::GiveDeviceVendorID,
+                    CECMessage::GetCECVersion,
+                    CECMessage::GiveDevicePowerStatus,
+                ] {
+                    send_or_log(&inner_tx, initiator, message);
+                }
+            }
+            let reply = |message| send_or_log(&inner_tx, initiator, message);
+            let broadcast = |message| send_or_log(&inner_tx, LogicalAddress::Broadcast, message);
+            let key = |event| {
+                info!("key {:?}", event);
+                if let Some(func) = &mut *inner_key_callback.lock().unwrap() {
+                    func(event)
+                }
+            };
+            let switched = |address| {
+                *inner_input_state.lock().unwrap() = address;
+                if let Some(func) = &mut *inner_input_callback.lock().unwrap() {
+                    func(address)
+                }
+            };
+            let abort = |reason| {
+                if let Some(message) = follower::feature_abort(msg, reason) {
+                    reply(message)
+                }
+            };
+            if initiator == LogicalAddress::TV {
+                if let CECMessage::DeviceVendorID { vendor_id } = msg.message {
+                    if vendor.as_ref().map(|v| v.vendor_id()) != Some(vendor_id) {
+                        vendor = vendor::for_vendor(vendor_id);
+                        if let Some(handler) = &mut vendor {
+                            info!("using vendor extensions for TV vendor {:06x}", vendor_id);
+                            handler.handshake().into_iter().for_each(reply);
+                        }
+                    }
+                }
+                if let Some(replies) = vendor.as_mut().and_then(|v| v.handle(msg)) {
+                    replies.into_iter().for_each(reply);
+                    return;
+                }
+            }
             match &msg.message {
-                CECMessage::GiveOSDName => inner_conn
-                    .transmit(CECCommand {
-                        initiator: None,
-                        destination: msg.initiator.unwrap(),
-                        message: CECMessage::SetOSDName {
-                            name: osd_name.clone(),
-                        },
-                    })
-                    .unwrap(),
-                CECMessage::GiveDeviceVendorID => inner_conn
-                    .transmit(CECCommand {
-                        initiator: None,
-                        destination: msg.initiator.unwrap(),
-                        message: CECMessage::DeviceVendorID {
-                            vendor_id: vendor_id,
-                        },
-                    })
-                    .unwrap(),
-                CECMessage::GiveDevicePowerStatus => inner_conn
-                    .transmit(CECCommand {
-                        initiator: None,
-                        destination: msg.initiator.unwrap(),
-                        message: CECMessage::ReportPowerStatus {
-                            power_status: PowerStatus::On,
-                        },
-                    })
-                    .unwrap(),
-                CECMessage::GivePhysicalAddress => inner_conn
-                    .transmit(CECCommand {
-                        initiator: None,
-                        destination: msg.initiator.unwrap(),
-                        message: CECMessage::ReportPhysicalAddress {
-                            physical_address: inner_conn.get_physical_address().unwrap(),
-                            device_type: inner_conn.get_logical_address().unwrap().to_device_type(),
-                        },
-                    })
-                    .unwrap(),
-                CECMessage::ReportPhysicalAddress {
-                    physical_address, ..
-                } => {
-                    logical_to_physical[msg.initiator.unwrap() as usize] = *physical_address;
+                CECMessage::GiveOSDName => reply(CECMessage::SetOSDName {
+                    name: inner_osd_name.clone(),
+                }),
+                CECMessage::GiveDeviceVendorID => {
+                    broadcast(CECMessage::DeviceVendorID { vendor_id })
                 }
+                CECMessage::GiveDevicePowerStatus => reply(CECMessage::ReportPowerStatus {
+                    power_status: *inner_own_power_status.lock().unwrap(),
+                }),
+                CECMessage::GetCECVersion => reply(CECMessage::CECVersion {
+                    cec_version: CEC_VERSION,
+                }),
+                CECMessage::GiveDeckStatus { status_request } => {
+                    // Nothing ever plays, so a request to stop reporting
+                    // changes needs no answer.
+                    if *status_request != DeckStatus::Off {
+                        reply(CECMessage::DeckStatus {
+                            deck_info: DeckInfo::Stop,
+                        })
+                    }
+                }
+                CECMessage::GetMenuLanguage => match &*inner_menu_language.lock().unwrap() {
+                    Some(language) => broadcast(CECMessage::SetMenuLanguage {
+                        language: language.clone(),
+                    }),
+                    None => abort(AbortReason::Undetermined),
+                },
+                CECMessage::SetMenuLanguage { language } => {
+                    *inner_menu_language.lock().unwrap() = Some(language.clone());
+                }
+                CECMessage::MenuRequest { .. } => reply(CECMessage::MenuStatus {
+                    menu_state: MenuState::Deactivated,
+                }),
+                CECMessage::GivePhysicalAddress => match (
+                    inner_conn.get_physical_address(),
+                    inner_conn.get_logical_address(),
+                ) {
+                    (Ok(physical_address), Ok(logical_address)) => {
+                        broadcast(CECMessage::ReportPhysicalAddress {
+                            physical_address,
+                            device_type: logical_address.to_device_type(),
+                        })
+                    }
+                    _ => abort(AbortReason::Undetermined),
+                },
+                CECMessage::GiveFeatures => match inner_conn.get_logical_address() {
+                    Ok(logical_address) => broadcast(CECMessage::ReportFeatures {
+                        features: own_features(logical_address.to_device_type()),
+                    }),
+                    Err(_) => abort(AbortReason::Undetermined),
+                },
+                CECMessage::Abort => abort(AbortReason::Refused),
                 CECMessage::RoutingChange {
                     original_address: _,
                     new_address,
                 } => {
-                    *inner_input_state.lock().unwrap() = *new_address;
-                    *inner_power_state.lock().unwrap() = true;
+                    switched(*new_address);
+                    *inner_power_status.0.lock().unwrap() = PowerStatus::On;
                 }
                 CECMessage::SetStreamPath { physical_address } => {
-                    *inner_input_state.lock().unwrap() = *physical_address;
-                    *inner_power_state.lock().unwrap() = true;
+                    switched(*physical_address);
+                    *inner_power_status.0.lock().unwrap() = PowerStatus::On;
+                    if inner_conn.get_physical_address().ok() == Some(*physical_address) {
+                        *inner_own_power_status.lock().unwrap() = PowerStatus::On;
+                    }
                 }
-                CECMessage::ActiveSource { physical_address } => {
-                    *inner_input_state.lock().unwrap() = *physical_address;
-                    *inner_power_state.lock().unwrap() = true;
+                CECMessage::ActiveSource { physical_address }
+                | CECMessage::RoutingInformation { physical_address } => {
+                    switched(*physical_address);
+                    *inner_power_status.0.lock().unwrap() = PowerStatus::On;
                 }
                 CECMessage::Standby => {
-                    *inner_power_state.lock().unwrap() = false;
+                    *inner_power_status.0.lock().unwrap() = PowerStatus::Standby;
+                    *inner_own_power_status.lock().unwrap() = PowerStatus::Standby;
+                }
+                CECMessage::ReportPowerStatus { power_status } => {
+                    if initiator == LogicalAddress::TV {
+                        let was = std::mem::replace(
+                            &mut *inner_power_status.0.lock().unwrap(),
+                            *power_status,
+                        );
+                        // A TV coming out of standby may have forgotten us.
+                        if *power_status == PowerStatus::On
+                            && !matches!(was, PowerStatus::On | PowerStatus::Unknown)
+                        {
+                            let _ = inner_request_reannounce.send(());
+                        }
+                    }
                 }
                 CECMessage::ImageViewOn => {
-                    *inner_power_state.lock().unwrap() = true;
+                    *inner_power_status.0.lock().unwrap() = PowerStatus::On;
                 }
-                CECMessage::VendorCommand { vendor_data } => {
-                    // See https://github.com/Pulse-Eight/libcec/blob/master/src/libcec/implementations/SLCommandHandler.cpp
-                    match vendor_data[0] {
-                        0x01 => {
-                            inner_conn
-                                .transmit(CECCommand {
-                                    initiator: None,
-                                    destination: msg.initiator.unwrap(),
-                                    message: CECMessage::VendorCommand {
-                                        vendor_data: vec![0x02, 0x05],
-                                    },
-                                })
-                                .unwrap();
-                        }
-                        0x04 => {
-                            inner_conn
-                                .transmit(CECCommand {
-                                    initiator: None,
-                                    destination: msg.initiator.unwrap(),
-                                    message: CECMessage::VendorCommand {
-                                        vendor_data: vec![0x05, DeviceType::RecordingDevice as u8],
-                                    },
-                                })
-                                .unwrap();
-                            inner_conn
-                                .transmit(CECCommand {
-                                    initiator: None,
-                                    destination: msg.initiator.unwrap(),
-                                    message: CECMessage::ReportPowerStatus {
-                                        power_status: PowerStatus::On,
-                                    },
-                                })
-                                .unwrap();
-                        }
-                        0x03 | 0x0b | 0xa0 => {
-                            inner_conn
-                                .transmit(CECCommand {
-                                    initiator: None,
-                                    destination: msg.initiator.unwrap(),
-                                    message: CECMessage::ReportPowerStatus {
-                                        power_status: PowerStatus::InTransitionStandbyToOn,
-                                    },
-                                })
-                                .unwrap();
-                            inner_conn
-                                .transmit(CECCommand {
-                                    initiator: None,
-                                    destination: msg.initiator.unwrap(),
-                                    message: CECMessage::ReportPowerStatus {
-                                        power_status: PowerStatus::On,
-                                    },
-                                })
-                                .unwrap();
+                CECMessage::ReportAudioStatus { audio_status } => {
+                    *inner_audio_status.lock().unwrap() = Some(*audio_status);
+                }
+                CECMessage::SetSystemAudioMode { system_audio_mode }
+                | CECMessage::SystemAudioModeStatus { system_audio_mode } => {
+                    let was_on = std::mem::replace(
+                        &mut *inner_system_audio_mode.lock().unwrap(),
+                        *system_audio_mode,
+                    );
+                    if *system_audio_mode && !was_on {
+                        // The audio system has taken over volume control, so
+                        // find out where it's at.
+                        send_or_log(
+                            &inner_tx,
+                            LogicalAddress::AudioSystem,
+                            CECMessage::GiveAudioStatus,
+                        );
+                    } else if !*system_audio_mode {
+                        *inner_audio_status.lock().unwrap() = None;
+                    }
+                }
+                CECMessage::InitARC
+                | CECMessage::ReportARCInited
+                | CECMessage::ReportARCTerminated
+                | CECMessage::RequestARCInit
+                | CECMessage::RequestARCTermination
+                | CECMessage::TerminateARC => match inner_conn.get_logical_address() {
+                    Ok(local @ (LogicalAddress::TV | LogicalAddress::AudioSystem)) => {
+                        let link = &mut inner_arc_state.lock().unwrap();
+                        if let Some(message) = arc::handle_message(
+                            link,
+                            local,
+                            initiator,
+                            &msg.message,
+                            Instant::now(),
+                        ) {
+                            reply(message)
                         }
-                        _ => {}
                     }
+                    Ok(_) => abort(AbortReason::UnrecognisedOpcode),
+                    Err(e) => {
+                        warn!("can't handle ARC without a logical address: {}", e);
+                        abort(AbortReason::Undetermined)
+                    }
+                },
+                CECMessage::UserControlPressed { user_control_code } => {
+                    key(KeyEvent::Pressed(*user_control_code))
                 }
-                _ => {}
+                CECMessage::UserControlReleased | CECMessage::VendorRemoteButtonUp => {
+                    key(KeyEvent::Released)
+                }
+                CECMessage::VendorRemoteButtonDown { rc_code } => {
+                    match vendor.as_ref().and_then(|v| v.remote_button(rc_code)) {
+                        Some(control) => key(KeyEvent::Pressed(control)),
+                        None => abort(AbortReason::UnrecognisedOpcode),
+                    }
+                }
+                CECMessage::FeatureAbort { feature_opcode, .. } => arc::handle_abort(
+                    &mut inner_arc_state.lock().unwrap(),
+                    *feature_opcode,
+                    Instant::now(),
+                ),
+                // Replies and notifications that need no response of their
+                // own. The device table has already kept what they report.
+                CECMessage::ReportPhysicalAddress { .. }
+                | CECMessage::SetOSDName { .. }
+                | CECMessage::DeviceVendorID { .. }
+                | CECMessage::CECVersion { .. }
+                | CECMessage::DeckStatus { .. }
+                | CECMessage::MenuStatus { .. }
+                | CECMessage::TunerDeviceStatus { .. }
+                | CECMessage::RecordStatus { .. }
+                | CECMessage::TimerStatus { .. }
+                | CECMessage::TimerClearedStatus { .. }
+                | CECMessage::ReportShortAudioDescriptor { .. }
+                | CECMessage::RequestActiveSource
+                | CECMessage::InactiveSource { .. }
+                | CECMessage::ReportFeatures { .. }
+                | CECMessage::ReportCurrentLatency { .. }
+                | CECMessage::CDC { .. } => {}
+                // Only displays and audio systems have latency to report.
+                CECMessage::RequestCurrentLatency { .. } => {}
+                _ => abort(AbortReason::UnrecognisedOpcode),
+            }
+        }));
+        let inner_journal = journal.clone();
+        let report_tx = tx.clone();
+        conn.set_tx_callback(Box::new(move |msg, status| {
+            match &status {
+                Ok(()) => info!("tx {}", msg),
+                Err(e) => info!("tx {} ({})", msg, e),
+            }
+            inner_journal.record(journal::Direction::Tx, msg, Some(status.is_ok()));
+            report_tx.report(msg, status);
+        }));
+        let inner_devices = devices.clone();
+        let topology_conn = conn.clone();
+        conn.set_topology_callback(Box::new(move |present| {
+            info!("devices present: {:?}", present);
+            // The adapter counts us among them, but we're not in our own table.
+            let own = topology_conn.get_logical_address().ok();
+            let others: Vec<LogicalAddress> = present
+                .iter()
+                .copied()
+                .filter(|&addr| Some(addr) != own)
+                .collect();
+            inner_devices.lock().unwrap().set_present(&others);
+        }));
+        // Only a cable coming back needs us to announce ourselves. Mode and
+        // HDCP changes happen while it stays plugged in.
+        let mut plugged_in = true;
+        let hdmi_request_reannounce = request_reannounce.clone();
+        let hdmi_subscribers: Arc<Mutex<Vec<HdmiCallback>>> =
+            Arc::new(Mutex::new(vec![Box::new(move |event| match event {
+                HdmiEvent::Unplugged => plugged_in = false,
+                HdmiEvent::Attached | HdmiEvent::Hdmi | HdmiEvent::Dvi if !plugged_in => {
+                    plugged_in = true;
+                    let _ = hdmi_request_reannounce.send(());
+                }
+                _ => (),
+            })]));
+        let inner_hdmi_subscribers = hdmi_subscribers.clone();
+        conn.set_hdmi_callback(Box::new(move |event| {
+            info!("HDMI {:?}", event);
+            for func in inner_hdmi_subscribers.lock().unwrap().iter_mut() {
+                func(event)
             }
         }));
-        conn.set_tx_callback(Box::new(move |msg| {
-            info!("tx {:x?} {:02x?}", msg, msg.message.payload());
-            let (lock, cvar) = &*inner_tx_signal;
-            *lock.lock().unwrap() = Some(msg.clone());
-            cvar.notify_all();
+        conn.set_reconnect_callback(Box::new(move || {
+            info!("adapter reconnected");
+            let _ = request_reannounce.send(());
         }));
+        if let Err(e) = conn.register_opcodes(HANDLED_OPCODES) {
+            warn!("couldn't register the opcodes we handle: {}", e);
+        }
+        let (osd_requests, osd_texts) = mpsc::channel::<(Vec<String>, DisplayControl)>();
         let mut cec = CEC {
             conn,
-            tx_signal,
-            input_state: input_state,
-            power_state: power_state,
+            tx,
+            input_state,
+            power_status,
+            own_power_status,
+            audio_status,
+            system_audio_mode,
+            arc_state,
+            devices,
+            replies,
+            key_callback,
+            input_callback,
+            hdmi_subscribers,
+            osd_requests: Some(osd_requests),
+            journal,
+            menu_language,
+            stopping: Arc::new(AtomicBool::new(false)),
+            threads: vec![],
         };
         // Force the tv into a well-known state
         cec.on_off(true)?;
+        // Not every setup has an audio system, so don't fail if it's missing.
+        if let Err(e) = cec.transmit(
+            LogicalAddress::AudioSystem,
+            CECMessage::GiveSystemAudioModeStatus,
+        ) {
+            info!("no audio system found: {}", e);
+        }
+        // Picks the vendor extensions to use once the TV answers.
+        cec.transmit(LogicalAddress::TV, CECMessage::GiveDeviceVendorID)?;
+        // CEC 2.0 devices announce their features once they have an address.
+        // 1.4 devices don't know the message, so it's no loss if it fails.
+        match cec.conn.get_logical_address() {
+            Ok(logical_address) => {
+                if let Err(e) = cec.broadcast(CECMessage::ReportFeatures {
+                    features: own_features(logical_address.to_device_type()),
+                }) {
+                    info!("couldn't report our features: {}", e);
+                }
+            }
+            Err(e) => info!("couldn't get logical address to report features: {}", e),
+        }
+        // The TV answers with a broadcast <Set Menu Language>.
+        if let Err(e) = cec.transmit(LogicalAddress::TV, CECMessage::GetMenuLanguage) {
+            info!("couldn't ask for the menu language: {}", e);
+        }
+        let poll_tx = cec.tx.clone();
+        let poll_power_status = cec.power_status.clone();
+        let poll_stopping = cec.stopping.clone();
+        let poller = thread::Builder::new()
+            .name("Power poll".into())
+            .spawn(move || {
+                let (lock, cvar) = &*poll_power_status;
+                loop {
+                    if let Err(e) = transmit_on(
+                        &poll_tx,
+                        LogicalAddress::TV,
+                        CECMessage::GiveDevicePowerStatus,
+                    ) {
+                        warn!("failed to ask the TV for its power status: {}", e);
+                    }
+                    let status = lock.lock().unwrap();
+                    if poll_stopping.load(Ordering::SeqCst) {
+                        return;
+                    }
+                    let interval = match *status {
+                        PowerStatus::InTransitionStandbyToOn
+                        | PowerStatus::InTransitionOnToStandby => POWER_TRANSITION_POLL_INTERVAL,
+                        _ => POWER_POLL_INTERVAL,
+                    };
+                    drop(cvar.wait_timeout(status, interval).unwrap());
+                    if poll_stopping.load(Ordering::SeqCst) {
+                        return;
+                    }
+                }
+            })
+            .map_err(|e| CECError::Other(Box::new(e)))?;
+        cec.threads.push(poller);
+        // Only a weak handle, so the thread never keeps the connection open
+        // by itself.
+        let reannounce_conn = Arc::downgrade(&cec.conn);
+        let reannounce_tx = cec.tx.clone();
+        let reannounce_devices = cec.devices.clone();
+        // Stops once shutdown() has dropped the callbacks that hold the
+        // other ends of the channel.
+        let reannouncer = thread::Builder::new()
+            .name("Reannounce".into())
+            .spawn(move || {
+                while reannounce_requests.recv().is_ok() {
+                    thread::sleep(REANNOUNCE_DELAY);
+                    while reannounce_requests.try_recv().is_ok() {}
+                    let Some(conn) = reannounce_conn.upgrade() else {
+                        return;
+                    };
+                    if let Err(e) =
+                        reannounce(&*conn, &reannounce_tx, &reannounce_devices, &osd_name)
+                    {
+                        warn!("failed to announce ourselves again: {}", e);
+                    }
+                }
+            })
+            .map_err(|e| CECError::Other(Box::new(e)))?;
+        cec.threads.push(reannouncer);
+        // Shows each piece of text in turn, giving up on the rest as soon as
+        // there's new text to show.
+        let osd_tx = cec.tx.clone();
+        let osd = thread::Builder::new()
+            .name("OSD".into())
+            .spawn(move || {
+                let mut next = osd_texts.recv().ok();
+                while let Some((chunks, display_control)) = next.take() {
+                    for (i, text) in chunks.into_iter().enumerate() {
+                        if i > 0 {
+                            match osd_texts.recv_timeout(OSD_CHUNK_INTERVAL) {
+                                Ok(request) => {
+                                    next = Some(request);
+                                    break;
+                                }
+                                Err(mpsc::RecvTimeoutError::Timeout) => (),
+                                Err(mpsc::RecvTimeoutError::Disconnected) => return,
+                            }
+                        }
+                        let message = CECMessage::SetOSDString {
+                            display_control,
+                            text,
+                        };
+                        if let Err(e) = transmit_on(&osd_tx, LogicalAddress::TV, message) {
+                            warn!("failed to show text on the TV: {}", e);
+                        }
+                    }
+                    if next.is_none() {
+                        next = osd_texts.recv().ok();
+                    }
+                }
+            })
+            .map_err(|e| CECError::Other(Box::new(e)))?;
+        cec.threads.push(osd);
 
         Ok(cec)
     }
 
-    pub fn poll_all(&self) -> Result<(), CECError> {
-        for &addr in &[
-            LogicalAddress::TV,
-            LogicalAddress::AudioSystem,
-            LogicalAddress::PlaybackDevice1,
-            LogicalAddress::PlaybackDevice2,
-            LogicalAddress::PlaybackDevice3,
-            LogicalAddress::RecordingDevice1,
-            LogicalAddress::RecordingDevice2,
-            LogicalAddress::RecordingDevice3,
-            LogicalAddress::Tuner1,
-            LogicalAddress::Tuner2,
-            LogicalAddress::Tuner3,
-            LogicalAddress::Tuner4,
-        ] {
-            self.transmit(addr, CECMessage::GiveOSDName)?;
-            self.transmit(addr, CECMessage::GivePhysicalAddress)?;
+    /// Stops polling and reannouncing and takes our callbacks back off the
+    /// connection, so that it shuts down once the last reference to it goes.
+    /// Called when a CEC is dropped; nothing else works afterwards.
+    pub fn shutdown(&mut self) {
+        {
+            let _status = self.power_status.0.lock().unwrap();
+            if self.stopping.swap(true, Ordering::SeqCst) {
+                return;
+            }
         }
-        Ok(())
+        self.power_status.1.notify_all();
+        self.tx.stop();
+        // The callbacks and HDMI subscribers hold the transmit queue, and the
+        // senders that keep the reannounce thread going.
+        self.conn.set_rx_callback(Box::new(|_| {}));
+        self.conn.set_tx_callback(Box::new(|_, _| {}));
+        self.conn.set_topology_callback(Box::new(|_| {}));
+        self.conn.set_hdmi_callback(Box::new(|_| {}));
+        self.conn.set_reconnect_callback(Box::new(|| {}));
+        self.hdmi_subscribers.lock().unwrap().clear();
+        self.osd_requests = None;
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+        self.tx.flush();
+    }
+
+    /// Finds out which devices are on the bus and asks them about
+    /// themselves. Adapters that keep track of the bus are asked rather than
+    /// polling every address.
+    pub fn poll_all(&self) -> Result<(), CECError> {
+        discover(&*self.conn, &self.tx, &self.devices)
     }
 
     fn transmit(&self, destination: LogicalAddress, message: CECMessage) -> Result<(), CECError> {
-        info!("sending {:x?} to {:?}", message, destination);
-        let payload = message.payload();
-        self.conn.transmit(CECCommand {
-            initiator: None,
-            destination: destination,
-            message: message,
-        })?;
-        let (lock, cvar) = &*self.tx_signal;
-        let _ = cvar
-            .wait_timeout_while(lock.lock().unwrap(), Duration::from_millis(200), |tx| {
-                if let Some(CECCommand { message: sent, .. }) = tx {
-                    return !sent.payload().eq(&payload);
-                }
-                true
-            })
-            .unwrap();
-        Ok(())
+        transmit_on(&self.tx, destination, message)
+    }
+
+    /// Changes how hard transmits try before giving up.
+    pub fn set_tx_config(&self, config: TxConfig) {
+        self.tx.set_config(config);
+    }
+
+    /// Sends a message and waits for the destination to reply with the
+    /// expected opcode.
+    pub fn request(
+        &self,
+        destination: LogicalAddress,
+        message: CECMessage,
+        reply: Opcode,
+        timeout: Duration,
+    ) -> Result<CECMessage, CECError> {
+        let id = self
+            .replies
+            .expect(destination, message.get_opcode(), reply);
+        if let Err(e) = self.transmit(destination, message) {
+            self.replies.cancel(id);
+            return Err(e);
+        }
+        self.replies.wait(id, timeout)
+    }
+
+    /// Asks a device which CEC 2.0 features it supports.
+    pub fn request_features(&self, destination: LogicalAddress) -> Result<Features, CECError> {
+        match self.request(
+            destination,
+            CECMessage::GiveFeatures,
+            Opcode::ReportFeatures,
+            REQUEST_TIMEOUT,
+        )? {
+            CECMessage::ReportFeatures { features } => Ok(features),
+            msg => unreachable!("unexpected reply {:?}", msg),
+        }
+    }
+
+    /// Asks the display or audio system at `address` for its current latency,
+    /// for keeping audio in sync with its picture.
+    pub fn request_current_latency(&self, address: PhysicalAddress) -> Result<Latency, CECError> {
+        // The request is broadcast, but only the device at the address
+        // answers.
+        let from = match self
+            .devices
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|d| d.physical_address == Some(address))
+        {
+            Some(device) => device.logical_address,
+            None if address == PhysicalAddress::ROOT => LogicalAddress::TV,
+            None => return Err(CECError::UnknownInputDevice(address.to_string())),
+        };
+        let message = CECMessage::RequestCurrentLatency {
+            physical_address: address,
+        };
+        let id = self.replies.expect(
+            from,
+            Opcode::RequestCurrentLatency,
+            Opcode::ReportCurrentLatency,
+        );
+        if let Err(e) = self.broadcast(message) {
+            self.replies.cancel(id);
+            return Err(e);
+        }
+        match self.replies.wait(id, REQUEST_TIMEOUT)? {
+            CECMessage::ReportCurrentLatency { latency, .. } => Ok(latency),
+            msg => unreachable!("unexpected reply {:?}", msg),
+        }
     }
 
     fn broadcast(&self, code: CECMessage) -> Result<(), CECError> {
@@ -774,31 +2869,63 @@ impl CEC {
     }
 
     fn press_key(&self, code: UserControl) -> Result<(), CECError> {
+        self.press_key_on(LogicalAddress::TV, code)
+    }
+
+    fn press_key_on(&self, destination: LogicalAddress, code: UserControl) -> Result<(), CECError> {
         self.transmit(
-            LogicalAddress::TV,
+            destination,
             CECMessage::UserControlPressed {
                 user_control_code: code,
             },
         )?;
-        self.transmit(LogicalAddress::TV, CECMessage::UserControlReleased)
+        self.transmit(destination, CECMessage::UserControlReleased)
+    }
+
+    // Volume keys go to the audio system directly when it's handling audio.
+    fn volume_destination(&self) -> LogicalAddress {
+        if self.system_audio_mode() {
+            LogicalAddress::AudioSystem
+        } else {
+            LogicalAddress::TV
+        }
     }
 
     pub fn volume_change(&mut self, relative_steps: i32) -> Result<(), CECError> {
+        let destination = self.volume_destination();
         if relative_steps > 0 {
             for _ in 0..relative_steps {
-                self.press_key(UserControl::VolumeUp)?;
+                self.press_key_on(destination, UserControl::VolumeUp)?;
             }
         } else if relative_steps < 0 {
             for _ in relative_steps..0 {
-                self.press_key(UserControl::VolumeDown)?;
+                self.press_key_on(destination, UserControl::VolumeDown)?;
             }
         }
         Ok(())
     }
 
     pub fn mute(&mut self, mute: bool) -> Result<(), CECError> {
-        // Volume changes always cause the tv to become unmuted, so use them to
-        // force the tv into the proper state.
+        let status = if self.system_audio_mode() {
+            self.request_audio_status()
+                .ok()
+                .or_else(|| self.audio_status())
+        } else {
+            self.audio_status()
+        };
+        if let Some(status) = status {
+            if status.muted != mute {
+                self.press_key_on(self.volume_destination(), UserControl::Mute)?;
+                // Nothing reports the change unless asked, so remember it
+                // rather than press again next time.
+                if let Some(status) = &mut *self.audio_status.lock().unwrap() {
+                    status.muted = mute;
+                }
+            }
+            return Ok(());
+        }
+        // Without an audio status, rely on volume changes always causing the
+        // tv to become unmuted to force it into the proper state.
         if mute {
             self.volume_change(-1)?;
             self.volume_change(1)?;
@@ -811,44 +2938,223 @@ impl CEC {
         Ok(())
     }
     pub fn on_off(&mut self, on: bool) -> Result<(), CECError> {
-        *self.power_state.lock().unwrap() = on;
         if on {
-            self.transmit(LogicalAddress::TV, CECMessage::ImageViewOn)
+            self.transmit(LogicalAddress::TV, CECMessage::ImageViewOn)?;
         } else {
-            self.transmit(LogicalAddress::TV, CECMessage::Standby)
+            self.transmit(LogicalAddress::TV, CECMessage::Standby)?;
         }
+        *self.own_power_status.lock().unwrap() = if on {
+            PowerStatus::On
+        } else {
+            PowerStatus::Standby
+        };
+        // Assume the TV will do as it's told until it reports otherwise, and
+        // have the poller check up on it soon. Only now that the TV has the
+        // message, so the poll can't get to it first.
+        let (lock, cvar) = &*self.power_status;
+        *lock.lock().unwrap() = if on {
+            PowerStatus::InTransitionStandbyToOn
+        } else {
+            PowerStatus::InTransitionOnToStandby
+        };
+        cvar.notify_all();
+        Ok(())
     }
     pub fn set_input(&mut self, new_input: tv::Input) -> Result<(), CECError> {
-        let new_addr = match new_input {
-            tv::Input::HDMI1 => 0x1000,
-            tv::Input::HDMI2 => 0x2000,
-            tv::Input::HDMI3 => 0x3000,
-            tv::Input::HDMI4 => 0x4000,
+        let port = match new_input {
+            tv::Input::HDMI1 => 1,
+            tv::Input::HDMI2 => 2,
+            tv::Input::HDMI3 => 3,
+            tv::Input::HDMI4 => 4,
         };
-        self.broadcast(CECMessage::ReportPhysicalAddress {
-            physical_address: new_addr,
-            device_type: DeviceType::RecordingDevice,
+        self.switch_to(PhysicalAddress::ROOT.child(port).unwrap())
+    }
+
+    /// Switches the TV to show the device at `address`, which may be behind
+    /// any number of switches or audio systems.
+    pub fn switch_to(&mut self, address: PhysicalAddress) -> Result<(), CECError> {
+        if !address.is_valid() {
+            return Err(Error::BadPhysicalAddress(address.to_string()).into());
+        }
+        // Switches along the way pick the right input from <Routing Change>
+        // and answer with <Routing Information>, while <Set Stream Path> asks
+        // whatever's at the address to become the active source.
+        self.broadcast(CECMessage::RoutingChange {
+            original_address: self.current_input(),
+            new_address: address,
         })?;
-        self.broadcast(CECMessage::ActiveSource {
-            physical_address: new_addr,
+        self.broadcast(CECMessage::SetStreamPath {
+            physical_address: address,
         })?;
-        *self.input_state.lock().unwrap() = new_addr;
+        *self.input_state.lock().unwrap() = address;
+        if let Some(func) = &mut *self.input_callback.lock().unwrap() {
+            func(address)
+        }
         Ok(())
     }
 
     pub fn transmit_raw(&self, input: &[u8]) -> Result<(), CECError> {
         let cmd = CECCommand::from_raw(input)?;
-        info!("sending {:x?}", cmd);
-        self.conn.transmit(cmd)?;
-        Ok(())
+        info!("sending {}", cmd);
+        self.tx.send(cmd)
+    }
+
+    /// Sends a frame given as text, either as raw bytes like `10:44:41` or as
+    /// a line like `tx 0 user-control-pressed volume-up`. See [`fmt`].
+    pub fn transmit_text(&self, input: &str) -> Result<(), CECError> {
+        let cmd: CECCommand = input.parse()?;
+        info!("sending {}", cmd);
+        self.tx.send(cmd)
+    }
+
+    /// The TV's menu language, as an ISO 639-2 code like "eng", once it has
+    /// told us.
+    pub fn menu_language(&self) -> Option<String> {
+        self.menu_language.lock().unwrap().clone()
+    }
+
+    /// Shows text on the TV, which only takes printable ASCII. Text too long
+    /// for one <Set OSD String> is split into pieces that are shown one after
+    /// another in the background, until they run out or new text replaces
+    /// them. `duration` applies to every piece, and
+    /// `DisplayControl::ClearPrevious` with empty text clears the screen.
+    pub fn display_osd(&self, text: &str, duration: DisplayControl) -> Result<(), CECError> {
+        let chunks = osd_chunks(text)?;
+        match &self.osd_requests {
+            Some(requests) if requests.send((chunks, duration)).is_ok() => Ok(()),
+            _ => Err(tx::TxError::Stopped.into()),
+        }
     }
 
     pub fn is_on(&self) -> bool {
-        *self.power_state.lock().unwrap()
+        matches!(
+            self.power_status(),
+            PowerStatus::On | PowerStatus::InTransitionStandbyToOn
+        )
+    }
+
+    /// The TV's power status, as last reported by the TV or inferred from the
+    /// messages on the bus.
+    pub fn power_status(&self) -> PowerStatus {
+        *self.power_status.0.lock().unwrap()
     }
     pub fn current_input(&self) -> PhysicalAddress {
         *self.input_state.lock().unwrap()
     }
+
+    /// Asks the audio system to report its volume and mute state.
+    pub fn request_audio_status(&self) -> Result<AudioStatus, CECError> {
+        match self.request(
+            LogicalAddress::AudioSystem,
+            CECMessage::GiveAudioStatus,
+            Opcode::ReportAudioStatus,
+            REQUEST_TIMEOUT,
+        )? {
+            CECMessage::ReportAudioStatus { audio_status } => Ok(audio_status),
+            msg => unreachable!("unexpected reply {:?}", msg),
+        }
+    }
+
+    /// The most recent volume and mute state reported by the audio system.
+    pub fn audio_status(&self) -> Option<AudioStatus> {
+        *self.audio_status.lock().unwrap()
+    }
+    pub fn system_audio_mode(&self) -> bool {
+        *self.system_audio_mode.lock().unwrap()
+    }
+    pub fn set_system_audio_mode(&self, on: bool) -> Result<(), CECError> {
+        let physical_address = if on { Some(self.current_input()) } else { None };
+        self.transmit(
+            LogicalAddress::AudioSystem,
+            CECMessage::SystemAudioModeRequest { physical_address },
+        )
+    }
+
+    /// Starts or stops the Audio Return Channel between the TV and the audio
+    /// system.
+    pub fn set_arc(&self, on: bool) -> Result<(), CECError> {
+        match arc::request(self.conn.get_logical_address()?, on) {
+            Some((destination, message, state)) => {
+                self.arc_state.lock().unwrap().set(state, Instant::now());
+                self.transmit(destination, message)
+            }
+            // We're not part of the ARC link, but requesting System Audio Mode
+            // makes the audio system renegotiate ARC with the TV.
+            None => self.set_system_audio_mode(on),
+        }
+    }
+    pub fn arc_state(&self) -> ARCState {
+        self.arc_state.lock().unwrap().state(Instant::now())
+    }
+
+    /// Sets a function to call when a remote control key sent to us goes
+    /// down or comes back up.
+    pub fn set_key_callback(&self, func: KeyCallback) {
+        *self.key_callback.lock().unwrap() = Some(func);
+    }
+
+    /// Sets a function to call when the TV switches inputs, whether we asked
+    /// it to or another device did.
+    pub fn set_input_callback(&self, func: InputCallback) {
+        *self.input_callback.lock().unwrap() = Some(func);
+    }
+
+    /// Adds a function to call with each hotplug and HDCP change, for
+    /// connections that can see them. Every function added gets every event.
+    pub fn subscribe_hdmi(&self, func: HdmiCallback) {
+        self.hdmi_subscribers.lock().unwrap().push(func);
+    }
+
+    /// The devices seen on the bus, arranged by where they're plugged in.
+    pub fn devices(&self) -> Vec<DeviceNode> {
+        self.devices.lock().unwrap().tree()
+    }
+
+    /// The table behind [`CEC::devices`], which is kept up to date for as
+    /// long as this CEC is around.
+    pub fn device_table(&self) -> Arc<Mutex<DeviceTable>> {
+        self.devices.clone()
+    }
+
+    /// The frames sent and received since startup, up to the journal's size.
+    pub fn journal(&self) -> Arc<Journal> {
+        self.journal.clone()
+    }
+
+    /// Adds what a bus monitor sees passing between other devices to the
+    /// journal. Frames to or from us are in it already.
+    pub fn monitor_callback(&self) -> Box<dyn FnMut(&CECCommand) + Send> {
+        let journal = self.journal.clone();
+        let conn = self.conn.clone();
+        Box::new(move |cmd| {
+            let Ok(own) = conn.get_logical_address() else {
+                return;
+            };
+            if cmd.initiator != Some(own)
+                && cmd.destination != own
+                && cmd.destination != LogicalAddress::Broadcast
+            {
+                journal.record(journal::Direction::Rx, cmd, None);
+            }
+        })
+    }
+}
+
+impl Drop for CEC {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+/// A <Standby> to `destination` from the connection's own address, for tests
+/// that need some frame to send.
+#[cfg(test)]
+pub(crate) fn standby(destination: LogicalAddress) -> CECCommand {
+    CECCommand {
+        initiator: None,
+        destination,
+        message: CECMessage::Standby,
+    }
 }
 
 #[cfg(test)]
@@ -867,11 +3173,37 @@ mod tests {
     }
 
     test_cec_msg! {image_view, CECMessage::ImageViewOn, "04"}
+    test_cec_msg! {report_features, CECMessage::ReportFeatures{
+        features: Features{
+            cec_version: CECVersion::V2_0,
+            device_types: AllDeviceTypes::of(DeviceType::PlaybackDevice),
+            rc_profile: RCProfile::Source{
+                root_menu: true,
+                setup_menu: true,
+                contents_menu: false,
+                media_top_menu: false,
+                media_context_menu: false,
+            },
+            device_features: DeviceFeatures{
+                arc_rx: true,
+                ..DeviceFeatures::default()
+            },
+        },
+    }, "a6:06:10:58:02"}
+    test_cec_msg! {report_current_latency, CECMessage::ReportCurrentLatency{
+        physical_address: PhysicalAddress(0x0000),
+        latency: Latency{
+            video_latency: 21,
+            low_latency_mode: true,
+            audio_output_compensated: AudioOutputCompensated::PartiallyCompensated,
+            audio_output_delay: Some(6),
+        },
+    }, "a8:00:00:15:07:06"}
     test_cec_msg! {active_source, CECMessage::ActiveSource{
-        physical_address:0x1000,
+        physical_address:PhysicalAddress(0x1000),
     }, "82:10:00"}
     test_cec_msg! {report_physical_address, CECMessage::ReportPhysicalAddress{
-        physical_address:0x1000,
+        physical_address:PhysicalAddress(0x1000),
         device_type: DeviceType::Tuner,
     }, "84:10:00:03"}
     test_cec_msg! {report_power_status, CECMessage::ReportPowerStatus{
@@ -883,4 +3215,658 @@ mod tests {
     test_cec_msg! {user_control_pressed, CECMessage::UserControlPressed{
         user_control_code:UserControl::Enter,
     }, "44:2b"}
+    test_cec_msg! {set_menu_language, CECMessage::SetMenuLanguage{
+        language:"eng".to_string(),
+    }, "32:65:6e:67"}
+    test_cec_msg! {report_audio_status, CECMessage::ReportAudioStatus{
+        audio_status: AudioStatus{muted: true, volume: 50},
+    }, "7a:b2"}
+    test_cec_msg! {set_osd_string, CECMessage::SetOSDString{
+        display_control: DisplayControl::UntilCleared,
+        text:"hi".to_string(),
+    }, "64:40:68:69"}
+    test_cec_msg! {set_analog_timer, CECMessage::SetAnalogTimer{
+        schedule: TimerSchedule{
+            day_of_month: 12,
+            month_of_year: 3,
+            start_hour: 21,
+            start_minute: 45,
+            duration_hours: 1,
+            duration_minutes: 30,
+            recording_sequence: 0,
+        },
+        service: AnalogueService{
+            broadcast_type: AnalogueBroadcastType::Cable,
+            frequency: 0x1234,
+            broadcast_system: 0x1f,
+        },
+    }, "34:0c:03:21:45:01:30:00:00:12:34:1f"}
+    test_cec_msg! {vendor_command_with_id, CECMessage::VendorCommandWithID{
+        vendor_id: 0x00e091,
+        vendor_data: vec![0x01, 0x02],
+    }, "a0:00:e0:91:01:02"}
+
+    #[test]
+    fn audio_status_volume_percent() {
+        let status = AudioStatus::from_byte(0x7f);
+        assert_eq!(status.volume_percent(), None);
+        let status = AudioStatus::from_byte(0x8c);
+        assert!(status.muted);
+        assert_eq!(status.volume_percent(), Some(12));
+    }
+
+    #[test]
+    fn physical_address_parsing() {
+        let addr: PhysicalAddress = "1.2.0.0".parse().unwrap();
+        assert_eq!(addr, PhysicalAddress(0x1200));
+        assert_eq!(addr.to_string(), "1.2.0.0");
+        assert_eq!(
+            "a.f.0.0".parse::<PhysicalAddress>().unwrap(),
+            PhysicalAddress(0xaf00)
+        );
+        for bad in [
+            "1.0.2.0",
+            "1.2.0",
+            "1.2.0.0.0",
+            "12.0.0.0",
+            "f.f.f.f",
+            "x.0.0.0",
+        ] {
+            assert!(bad.parse::<PhysicalAddress>().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn claims_first_free_address() {
+        let taken = [LogicalAddress::PlaybackDevice1];
+        let poll = |addr| Ok::<_, ()>(taken.contains(&addr));
+        assert_eq!(
+            claim_logical_address(DeviceType::PlaybackDevice, poll),
+            Ok(LogicalAddress::PlaybackDevice2)
+        );
+        assert_eq!(
+            claim_logical_address(DeviceType::AudioSystem, |_| Ok::<_, ()>(true)),
+            Ok(LogicalAddress::Broadcast)
+        );
+        assert_eq!(
+            claim_logical_address(DeviceType::Tuner, |_| Err("bus error")),
+            Err("bus error")
+        );
+    }
+
+    #[test]
+    fn report_features_skips_extensions() {
+        let cmd = CECCommand::from_raw(&[0x0f, 0xa6, 0x06, 0x80, 0x86, 0x00, 0x84, 0x00]).unwrap();
+        match cmd.message {
+            CECMessage::ReportFeatures { features } => {
+                assert!(features.device_types.tv);
+                assert_eq!(features.rc_profile, RCProfile::TV(RCProfileTV::Profile2));
+                assert!(features.device_features.arc_tx);
+            }
+            msg => panic!("unexpected message {:?}", msg),
+        }
+        assert!(CECCommand::from_raw(&[0x0f, 0xa6, 0x06, 0x80, 0x86, 0x80]).is_err());
+    }
+
+    #[test]
+    fn osd_text_chunks() {
+        assert_eq!(
+            osd_chunks("Volume limited by quiet hours").unwrap(),
+            vec!["Volume", "limited by", "quiet hours"]
+        );
+        assert_eq!(
+            osd_chunks("Overlongwordthatneedssplitting").unwrap(),
+            vec!["Overlongwordt", "hatneedssplit", "ting"]
+        );
+        assert_eq!(osd_chunks("").unwrap(), vec![""]);
+        assert!(matches!(osd_chunks("Überlong"), Err(Error::BadOSDText(_))));
+    }
+
+    #[test]
+    fn physical_address_hops() {
+        let addr = PhysicalAddress(0x1200);
+        assert_eq!(addr.depth(), 2);
+        assert_eq!(addr.port(), Some(2));
+        assert_eq!(addr.parent(), Some(PhysicalAddress(0x1000)));
+        assert_eq!(PhysicalAddress::ROOT.parent(), None);
+        assert_eq!(addr.child(3), Some(PhysicalAddress(0x1230)));
+        assert_eq!(PhysicalAddress(0x1234).child(1), None);
+        assert!(PhysicalAddress(0x1000).contains(PhysicalAddress(0x1230)));
+        assert!(!PhysicalAddress(0x2000).contains(PhysicalAddress(0x1230)));
+        assert!(!PhysicalAddress(0x1020).is_valid());
+    }
+
+    // Knows who is on the bus and records what is sent.
+    struct TopologyConn {
+        sent: Mutex<Vec<CECCommand>>,
+        tx: Mutex<Option<TxCallback>>,
+    }
+
+    impl CECConnection for TopologyConn {
+        fn transmit(&self, cmd: CECCommand) -> Result<(), CECError> {
+            if let Some(cb) = &mut *self.tx.lock().unwrap() {
+                cb(&cmd, Ok(()));
+            }
+            self.sent.lock().unwrap().push(cmd);
+            Ok(())
+        }
+        fn get_logical_address(&self) -> Result<LogicalAddress, CECError> {
+            Ok(LogicalAddress::PlaybackDevice1)
+        }
+        fn get_physical_address(&self) -> Result<PhysicalAddress, CECError> {
+            Ok(0x1100.into())
+        }
+        fn set_tx_callback(&self, func: TxCallback) {
+            *self.tx.lock().unwrap() = Some(func)
+        }
+        fn set_rx_callback(&self, _: Box<dyn FnMut(&CECCommand) + Send>) {}
+        fn known_devices(&self) -> Result<Option<Vec<KnownDevice>>, CECError> {
+            let known = |logical_address, physical_address: u16, device_type| KnownDevice {
+                logical_address,
+                physical_address: physical_address.into(),
+                device_type: Some(device_type),
+            };
+            Ok(Some(vec![
+                known(LogicalAddress::TV, 0x0000, DeviceType::TV),
+                known(LogicalAddress::AudioSystem, 0x1000, DeviceType::AudioSystem),
+                known(
+                    LogicalAddress::PlaybackDevice1,
+                    0x1100,
+                    DeviceType::PlaybackDevice,
+                ),
+            ]))
+        }
+    }
+
+    #[test]
+    fn discovers_from_known_devices() {
+        let conn = Arc::new(TopologyConn {
+            sent: Mutex::new(vec![]),
+            tx: Mutex::new(None),
+        });
+        let tx = Arc::new(TxQueue::new(conn.clone()));
+        let queue = Arc::downgrade(&tx);
+        conn.set_tx_callback(Box::new(move |cmd, result| {
+            if let Some(queue) = queue.upgrade() {
+                queue.report(cmd, result)
+            }
+        }));
+        let devices = Mutex::new(DeviceTable::default());
+        discover(&*conn, &tx, &devices).unwrap();
+
+        let sent = conn.sent.lock().unwrap();
+        assert_eq!(sent.len(), 8);
+        assert!(sent.iter().all(|cmd| matches!(
+            cmd.destination,
+            LogicalAddress::TV | LogicalAddress::AudioSystem
+        )));
+        assert!(sent
+            .iter()
+            .any(|cmd| cmd.message == CECMessage::GiveOSDName));
+
+        let devices = devices.lock().unwrap();
+        assert!(devices.get(LogicalAddress::PlaybackDevice1).is_none());
+        assert_eq!(
+            devices
+                .get(LogicalAddress::AudioSystem)
+                .unwrap()
+                .physical_address,
+            Some(PhysicalAddress(0x1000))
+        );
+    }
+
+    type RxCallback = Box<dyn FnMut(&CECCommand) + Send>;
+
+    // Hands frames to CEC as if they came off the bus, and passes on
+    // everything CEC sends, acknowledged.
+    struct LoopbackConn {
+        sent: Mutex<mpsc::Sender<CECCommand>>,
+        rx: Mutex<Option<RxCallback>>,
+        tx: Mutex<Option<TxCallback>>,
+        topology: Mutex<Option<TopologyCallback>>,
+    }
+
+    impl LoopbackConn {
+        fn new() -> (Arc<Self>, mpsc::Receiver<CECCommand>) {
+            let (sent, receiver) = mpsc::channel();
+            let conn = LoopbackConn {
+                sent: Mutex::new(sent),
+                rx: Mutex::new(None),
+                tx: Mutex::new(None),
+                topology: Mutex::new(None),
+            };
+            (Arc::new(conn), receiver)
+        }
+
+        fn report_present(&self, present: &[LogicalAddress]) {
+            if let Some(cb) = &mut *self.topology.lock().unwrap() {
+                cb(present)
+            }
+        }
+
+        fn receive(
+            &self,
+            initiator: LogicalAddress,
+            destination: LogicalAddress,
+            message: CECMessage,
+        ) {
+            if let Some(cb) = &mut *self.rx.lock().unwrap() {
+                cb(&CECCommand {
+                    initiator: Some(initiator),
+                    destination,
+                    message,
+                })
+            }
+        }
+    }
+
+    impl CECConnection for LoopbackConn {
+        fn transmit(&self, cmd: CECCommand) -> Result<(), CECError> {
+            if let Some(cb) = &mut *self.tx.lock().unwrap() {
+                cb(&cmd, Ok(()));
+            }
+            let _ = self.sent.lock().unwrap().send(cmd);
+            Ok(())
+        }
+        fn get_logical_address(&self) -> Result<LogicalAddress, CECError> {
+            Ok(LogicalAddress::PlaybackDevice1)
+        }
+        fn get_physical_address(&self) -> Result<PhysicalAddress, CECError> {
+            Ok(0x1000.into())
+        }
+        fn set_tx_callback(&self, func: TxCallback) {
+            *self.tx.lock().unwrap() = Some(func)
+        }
+        fn set_rx_callback(&self, func: RxCallback) {
+            *self.rx.lock().unwrap() = Some(func)
+        }
+        fn set_topology_callback(&self, func: TopologyCallback) {
+            *self.topology.lock().unwrap() = Some(func)
+        }
+    }
+
+    // The next message CEC sends that `pick` accepts, skipping the rest.
+    fn next_sent<T>(
+        sent: &mpsc::Receiver<CECCommand>,
+        mut pick: impl FnMut(&CECCommand) -> Option<T>,
+    ) -> T {
+        loop {
+            let cmd = sent.recv_timeout(Duration::from_secs(5)).unwrap();
+            if let Some(found) = pick(&cmd) {
+                return found;
+            }
+        }
+    }
+
+    #[test]
+    fn ignores_messages_for_other_devices() {
+        let (conn, sent) = LoopbackConn::new();
+        let _cec = CEC::new(conn.clone(), "cecvol", vendor::VENDOR_LG).unwrap();
+        conn.receive(
+            LogicalAddress::AudioSystem,
+            LogicalAddress::PlaybackDevice2,
+            CECMessage::GiveOSDName,
+        );
+        conn.receive(
+            LogicalAddress::TV,
+            LogicalAddress::PlaybackDevice1,
+            CECMessage::GiveOSDName,
+        );
+        let answered = next_sent(&sent, |cmd| match cmd.message {
+            CECMessage::SetOSDName { .. } => Some(cmd.destination),
+            _ => None,
+        });
+        assert_eq!(answered, LogicalAddress::TV);
+    }
+
+    #[test]
+    fn handles_the_registered_opcodes() {
+        let (conn, sent) = LoopbackConn::new();
+        let _cec = CEC::new(conn.clone(), "cecvol", vendor::VENDOR_LG).unwrap();
+        let mut tried = vec![];
+        for code in 0..=u8::MAX {
+            let Ok(opcode) = Opcode::try_from(code) else {
+                continue;
+            };
+            if follower::addressing(opcode) == follower::Addressing::Broadcast {
+                continue;
+            }
+            // Any operands will do, as long as they parse.
+            let Some(cmd) = (0..14).find_map(|len| {
+                let mut raw = vec![0x54, code];
+                raw.resize(2 + len, 0);
+                CECCommand::from_raw(&raw).ok()
+            }) else {
+                continue;
+            };
+            conn.receive(
+                LogicalAddress::AudioSystem,
+                LogicalAddress::PlaybackDevice1,
+                cmd.message,
+            );
+            tried.push(opcode);
+        }
+        // Replies go out in order, so every abort has been sent once this is
+        // answered.
+        conn.receive(
+            LogicalAddress::Tuner1,
+            LogicalAddress::PlaybackDevice1,
+            CECMessage::GiveOSDName,
+        );
+        let mut unrecognised = vec![];
+        loop {
+            let cmd = sent.recv_timeout(Duration::from_secs(5)).unwrap();
+            match cmd.message {
+                CECMessage::SetOSDName { .. } if cmd.destination == LogicalAddress::Tuner1 => break,
+                CECMessage::FeatureAbort {
+                    feature_opcode,
+                    abort_reason: AbortReason::UnrecognisedOpcode,
+                } => unrecognised.push(feature_opcode),
+                _ => (),
+            }
+        }
+        // These are registered for when we're part of the ARC link, or the
+        // vendor handler for the TV wants them, neither of which applies to a
+        // playback device hearing from an audio system.
+        let conditional = [
+            Opcode::VendorCommand,
+            Opcode::VendorRemoteButtonDown,
+            Opcode::VendorCommandWithID,
+            Opcode::InitARC,
+            Opcode::ReportARCInited,
+            Opcode::ReportARCTerminated,
+            Opcode::RequestARCInit,
+            Opcode::RequestARCTermination,
+            Opcode::TerminateARC,
+        ];
+        let mismatched: Vec<Opcode> = tried
+            .into_iter()
+            .filter(|o| !conditional.contains(o))
+            .filter(|o| HANDLED_OPCODES.contains(o) == unrecognised.contains(o))
+            .collect();
+        assert_eq!(mismatched, []);
+    }
+
+    #[test]
+    fn asks_new_devices_about_themselves() {
+        let (conn, sent) = LoopbackConn::new();
+        let _cec = CEC::new(conn.clone(), "cecvol", vendor::VENDOR_LG).unwrap();
+        conn.receive(
+            LogicalAddress::RecordingDevice1,
+            LogicalAddress::Broadcast,
+            CECMessage::ActiveSource {
+                physical_address: PhysicalAddress::from(0x3000),
+            },
+        );
+        let mut asked = vec![];
+        while asked.len() < 2 {
+            asked.push(next_sent(&sent, |cmd| {
+                match (cmd.destination, &cmd.message) {
+                    (
+                        LogicalAddress::RecordingDevice1,
+                        CECMessage::GivePhysicalAddress | CECMessage::GiveOSDName,
+                    ) => Some(cmd.message.clone()),
+                    _ => None,
+                }
+            }));
+        }
+        assert_eq!(
+            asked,
+            [CECMessage::GivePhysicalAddress, CECMessage::GiveOSDName]
+        );
+    }
+
+    #[test]
+    fn replaces_osd_text_without_waiting() {
+        let (conn, sent) = LoopbackConn::new();
+        let cec = CEC::new(conn.clone(), "cecvol", vendor::VENDOR_LG).unwrap();
+        let start = Instant::now();
+        cec.display_osd("Volume limited by quiet hours", DisplayControl::DefaultTime)
+            .unwrap();
+        cec.display_osd("Hi", DisplayControl::DefaultTime).unwrap();
+        assert!(start.elapsed() < OSD_CHUNK_INTERVAL);
+        let shown = || {
+            next_sent(&sent, |cmd| match &cmd.message {
+                CECMessage::SetOSDString { text, .. } => Some(text.clone()),
+                _ => None,
+            })
+        };
+        assert_eq!(shown(), "Volume");
+        assert_eq!(shown(), "Hi");
+    }
+
+    #[test]
+    fn journals_monitored_frames_between_others() {
+        let (conn, _sent) = LoopbackConn::new();
+        let cec = CEC::new(conn, "cecvol", vendor::VENDOR_LG).unwrap();
+        let mut monitor = cec.monitor_callback();
+        for (initiator, destination) in [
+            (LogicalAddress::TV, LogicalAddress::Tuner1),
+            (LogicalAddress::TV, LogicalAddress::PlaybackDevice1),
+            (LogicalAddress::PlaybackDevice1, LogicalAddress::TV),
+            (LogicalAddress::TV, LogicalAddress::Broadcast),
+        ] {
+            monitor(&CECCommand {
+                initiator: Some(initiator),
+                destination,
+                message: CECMessage::Standby,
+            });
+        }
+        let entries = cec.journal().entries(&journal::Filter {
+            opcode: Some(Opcode::Standby),
+            address: None,
+        });
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].frame, "03:36");
+    }
+
+    #[test]
+    fn transmits_frames_given_as_text() {
+        let (conn, sent) = LoopbackConn::new();
+        let cec = CEC::new(conn, "cecvol", vendor::VENDOR_LG).unwrap();
+        cec.transmit_text("tx 5 user-control-pressed volume-up")
+            .unwrap();
+        assert_eq!(
+            next_sent(&sent, |cmd| match cmd.message {
+                CECMessage::UserControlPressed { user_control_code } =>
+                    Some((cmd.initiator, cmd.destination, user_control_code)),
+                _ => None,
+            }),
+            (None, LogicalAddress::AudioSystem, UserControl::VolumeUp)
+        );
+        cec.transmit_text("40:36").unwrap();
+        let standby = next_sent(&sent, |cmd| {
+            (cmd.message == CECMessage::Standby).then_some(cmd.clone())
+        });
+        assert_eq!(standby.initiator, Some(LogicalAddress::PlaybackDevice1));
+        assert_eq!(standby.destination, LogicalAddress::TV);
+        assert!(cec.transmit_text("tx 0 no-such-opcode").is_err());
+    }
+
+    #[test]
+    fn leaves_itself_out_of_the_reported_topology() {
+        let (conn, _sent) = LoopbackConn::new();
+        let cec = CEC::new(conn.clone(), "cecvol", vendor::VENDOR_LG).unwrap();
+        conn.report_present(&[LogicalAddress::TV, LogicalAddress::PlaybackDevice1]);
+        let devices = cec.device_table();
+        let devices = devices.lock().unwrap();
+        assert!(devices.get(LogicalAddress::TV).is_some());
+        assert!(devices.get(LogicalAddress::PlaybackDevice1).is_none());
+    }
+
+    #[test]
+    fn aborts_unknown_vendor_commands() {
+        let (conn, sent) = LoopbackConn::new();
+        let _cec = CEC::new(conn.clone(), "cecvol", vendor::VENDOR_LG).unwrap();
+        conn.receive(
+            LogicalAddress::TV,
+            LogicalAddress::Broadcast,
+            CECMessage::DeviceVendorID {
+                vendor_id: vendor::VENDOR_LG,
+            },
+        );
+        conn.receive(
+            LogicalAddress::TV,
+            LogicalAddress::PlaybackDevice1,
+            CECMessage::VendorCommand {
+                vendor_data: vec![0x7f],
+            },
+        );
+        assert_eq!(
+            next_sent(&sent, |cmd| match cmd.message {
+                CECMessage::FeatureAbort {
+                    feature_opcode,
+                    abort_reason,
+                } => Some((cmd.destination, feature_opcode, abort_reason)),
+                _ => None,
+            }),
+            (
+                LogicalAddress::TV,
+                Opcode::VendorCommand,
+                AbortReason::UnrecognisedOpcode
+            )
+        );
+    }
+
+    #[test]
+    fn reports_own_power_status() {
+        let (conn, sent) = LoopbackConn::new();
+        let _cec = CEC::new(conn.clone(), "cecvol", vendor::VENDOR_LG).unwrap();
+        let ask = || {
+            conn.receive(
+                LogicalAddress::TV,
+                LogicalAddress::PlaybackDevice1,
+                CECMessage::GiveDevicePowerStatus,
+            );
+            next_sent(&sent, |cmd| match cmd.message {
+                CECMessage::ReportPowerStatus { power_status } => Some(power_status),
+                _ => None,
+            })
+        };
+        assert_eq!(ask(), PowerStatus::On);
+        conn.receive(
+            LogicalAddress::TV,
+            LogicalAddress::Broadcast,
+            CECMessage::Standby,
+        );
+        assert_eq!(ask(), PowerStatus::Standby);
+        conn.receive(
+            LogicalAddress::TV,
+            LogicalAddress::Broadcast,
+            CECMessage::SetStreamPath {
+                physical_address: PhysicalAddress::from(0x1000),
+            },
+        );
+        assert_eq!(ask(), PowerStatus::On);
+    }
+
+    #[test]
+    fn mutes_only_once() {
+        let (conn, sent) = LoopbackConn::new();
+        let mut cec = CEC::new(conn.clone(), "cecvol", vendor::VENDOR_LG).unwrap();
+        conn.receive(
+            LogicalAddress::TV,
+            LogicalAddress::PlaybackDevice1,
+            CECMessage::ReportAudioStatus {
+                audio_status: AudioStatus {
+                    muted: false,
+                    volume: 20,
+                },
+            },
+        );
+        cec.mute(true).unwrap();
+        cec.mute(true).unwrap();
+        let presses = sent
+            .try_iter()
+            .filter(|cmd| {
+                cmd.message
+                    == CECMessage::UserControlPressed {
+                        user_control_code: UserControl::Mute,
+                    }
+            })
+            .count();
+        assert_eq!(presses, 1);
+    }
+
+    macro_rules! test_cec_roundtrip {
+        ($name:ident, $s:expr) => {
+            #[test]
+            fn $name() {
+                let msg: CECMessage = $s;
+                let mut raw = vec![0x40];
+                raw.extend(msg.payload());
+                assert_eq!(CECCommand::from_raw(&raw).unwrap().message, msg);
+            }
+        };
+    }
+
+    test_cec_roundtrip! {roundtrip_abort, CECMessage::Abort}
+    test_cec_roundtrip! {roundtrip_give_features, CECMessage::GiveFeatures}
+    test_cec_roundtrip! {roundtrip_report_features, CECMessage::ReportFeatures{
+        features: Features{
+            cec_version: CECVersion::V2_0,
+            device_types: AllDeviceTypes{tv: true, audio_system: true, ..AllDeviceTypes::default()},
+            rc_profile: RCProfile::TV(RCProfileTV::Profile2),
+            device_features: DeviceFeatures{arc_tx: true, set_osd_string: true, ..DeviceFeatures::default()},
+        },
+    }}
+    test_cec_roundtrip! {roundtrip_request_current_latency, CECMessage::RequestCurrentLatency{
+        physical_address: PhysicalAddress(0x1000),
+    }}
+    test_cec_roundtrip! {roundtrip_report_current_latency, CECMessage::ReportCurrentLatency{
+        physical_address: PhysicalAddress(0x1000),
+        latency: Latency{
+            video_latency: 0,
+            low_latency_mode: false,
+            audio_output_compensated: AudioOutputCompensated::NotApplicable,
+            audio_output_delay: None,
+        },
+    }}
+    test_cec_roundtrip! {roundtrip_give_device_power_status, CECMessage::GiveDevicePowerStatus}
+    test_cec_roundtrip! {roundtrip_record_on_external, CECMessage::RecordOn{
+        record_source: RecordSource::External(ExternalSource::PhysicalAddress(PhysicalAddress(0x2100))),
+    }}
+    test_cec_roundtrip! {roundtrip_tuner_device_status, CECMessage::TunerDeviceStatus{
+        tuner_device_info: TunerDeviceInfo{
+            recording: true,
+            display_info: TunerDisplayInfo::Digital,
+            service: TunerService::Digital(DigitalService{
+                by_channel: false,
+                broadcast_system: 0x10,
+                service_id: [1, 2, 3, 4, 5, 6],
+            }),
+        },
+    }}
+    test_cec_roundtrip! {roundtrip_set_external_timer, CECMessage::SetExternalTimer{
+        schedule: TimerSchedule{
+            day_of_month: 31,
+            month_of_year: 12,
+            start_hour: 23,
+            start_minute: 59,
+            duration_hours: 99,
+            duration_minutes: 0,
+            recording_sequence: 0x7f,
+        },
+        source: ExternalSource::Plug(2),
+    }}
+    test_cec_roundtrip! {roundtrip_timer_status, CECMessage::TimerStatus{
+        timer_status: TimerStatusData{
+            overlap_warning: false,
+            media_info: 1,
+            programmed: true,
+            info: 0x0a,
+            duration_available: Some((2, 15)),
+        },
+    }}
+    test_cec_roundtrip! {roundtrip_system_audio_mode_request, CECMessage::SystemAudioModeRequest{
+        physical_address: None,
+    }}
+    test_cec_roundtrip! {roundtrip_short_audio_descriptor, CECMessage::ReportShortAudioDescriptor{
+        descriptors: vec![[0x09, 0x07, 0x07], [0x15, 0x07, 0x50]],
+    }}
+    test_cec_roundtrip! {roundtrip_cdc, CECMessage::CDC{
+        physical_address: PhysicalAddress(0x1000),
+        cdc_opcode: 0x01,
+        parameters: vec![0xaa],
+    }}
 }
@@ -0,0 +1,105 @@
+// What the connections that drive an adapter directly have in common, whether
+// through the Linux kernel or a Pulse-Eight serial port.
+//
+// Both keep a thread reading from the adapter for as long as the connection is
+// open, and both put frames on the bus themselves, telling the tx callback how
+// each one went.
+
+use crate::cec::pulse_eight::MessageCode;
+use crate::cec::{
+    CECCommand, CECError, DeviceType, LogicalAddress, PhysicalAddress, SharedTxCallback,
+};
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum AdapterError {
+    #[error("Adapter I/O failed: {0}")]
+    Io(#[from] io::Error),
+    #[error("ioctl failed: {0}")]
+    Ioctl(#[from] nix::Error),
+    #[error("Adapter can't {0}")]
+    Unsupported(&'static str),
+    #[error("No reply to {0:?}")]
+    NoReply(MessageCode),
+    #[error("Adapter rejected {0:?}")]
+    Rejected(MessageCode),
+    #[error("Transmit failed with status {0:#x}")]
+    TxFailed(u8),
+}
+
+impl From<AdapterError> for CECError {
+    fn from(val: AdapterError) -> Self {
+        CECError::Other(Box::new(val))
+    }
+}
+
+/// How to present ourselves on the bus.
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub device_type: DeviceType,
+    /// For adapters that answer <Give OSD Name> themselves.
+    pub osd_name: String,
+    /// For adapters that answer <Give Device Vendor ID> themselves.
+    pub vendor_id: u32,
+    /// Where the adapter is plugged in, for adapters that can't find out. It's
+    /// asked for if left out.
+    pub physical_address: Option<PhysicalAddress>,
+}
+
+pub(crate) type SharedRxCallback = Arc<Mutex<Option<Box<dyn FnMut(&CECCommand) + Send>>>>;
+
+/// A thread that runs until it's dropped. It's handed a flag to check, which
+/// is set when it should return.
+pub(crate) struct Worker {
+    stop: Arc<AtomicBool>,
+    handle: Option<thread::JoinHandle<()>>,
+}
+
+impl Worker {
+    pub(crate) fn spawn(body: impl FnOnce(&AtomicBool) + Send + 'static) -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+        let flag = stop.clone();
+        let handle = thread::spawn(move || body(&flag));
+        Worker {
+            stop,
+            handle: Some(handle),
+        }
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// Sends `cmd` from `own` unless it names another initiator. `send` puts the
+/// frame on the bus and `verdict` reads whether it was acknowledged from what
+/// `send` returned, which also goes to the tx callback.
+pub(crate) fn transmit<T, E: Into<CECError>>(
+    cmd: CECCommand,
+    own: LogicalAddress,
+    tx_callback: &SharedTxCallback,
+    send: impl FnOnce(&[u8]) -> Result<T, E>,
+    verdict: impl Fn(&T) -> Result<(), CECError>,
+) -> Result<(), CECError> {
+    let initiator = cmd.initiator.unwrap_or(own);
+    let mut frame = vec![(initiator as u8) << 4 | cmd.destination as u8];
+    frame.extend(cmd.message.payload());
+    let sent = send(&frame).map_err(Into::into)?;
+    if let Some(cb) = &mut *tx_callback.lock().unwrap() {
+        let sent_cmd = CECCommand {
+            initiator: Some(initiator),
+            ..cmd
+        };
+        cb(&sent_cmd, verdict(&sent));
+    }
+    verdict(&sent)
+}
@@ -0,0 +1,256 @@
+// Audio Return Channel negotiation.
+//
+// ARC is only ever set up between the TV and the device directly connected to
+// it, usually an audio system. The audio system asks for ARC to be started or
+// stopped and the TV makes it happen, with each side confirming the result:
+//
+//   Audio system -> TV: <Request ARC Initiation>
+//   TV -> Audio system: <Initiate ARC>
+//   Audio system -> TV: <Report ARC Initiated>
+//
+// Termination follows the same pattern with the corresponding opcodes. A side
+// that can't do what's asked answers with a <Feature Abort>, and one that
+// never answers at all is given up on after a while, leaving ARC off.
+
+use crate::cec::{AbortReason, CECMessage, LogicalAddress, Opcode};
+use std::time::{Duration, Instant};
+
+// How long the other side has to confirm a start or stop.
+const ARC_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ARCState {
+    Inactive,
+    Initiating,
+    Active,
+    Terminating,
+}
+
+/// Where negotiation is at, and since when.
+#[derive(Copy, Clone, Debug)]
+pub(crate) struct ARCLink {
+    state: ARCState,
+    since: Instant,
+}
+
+impl ARCLink {
+    pub(crate) fn new() -> Self {
+        ARCLink {
+            state: ARCState::Inactive,
+            since: Instant::now(),
+        }
+    }
+
+    /// The state as of `now`. A start or stop that hasn't been confirmed in
+    /// time has failed, and ARC is taken to be off.
+    pub(crate) fn state(&self, now: Instant) -> ARCState {
+        match self.state {
+            ARCState::Initiating | ARCState::Terminating
+                if now.saturating_duration_since(self.since) >= ARC_TIMEOUT =>
+            {
+                ARCState::Inactive
+            }
+            state => state,
+        }
+    }
+
+    pub(crate) fn set(&mut self, state: ARCState, now: Instant) {
+        self.state = state;
+        self.since = now;
+    }
+}
+
+/// Returns the message that starts or stops ARC when sent from `local`, along
+/// with who it should go to and the state to move to.
+///
+/// Only the TV and the audio system take part in ARC, so any other device gets
+/// `None`.
+pub(crate) fn request(
+    local: LogicalAddress,
+    start: bool,
+) -> Option<(LogicalAddress, CECMessage, ARCState)> {
+    match (local, start) {
+        (LogicalAddress::TV, true) => Some((
+            LogicalAddress::AudioSystem,
+            CECMessage::InitARC,
+            ARCState::Initiating,
+        )),
+        (LogicalAddress::TV, false) => Some((
+            LogicalAddress::AudioSystem,
+            CECMessage::TerminateARC,
+            ARCState::Terminating,
+        )),
+        (LogicalAddress::AudioSystem, true) => Some((
+            LogicalAddress::TV,
+            CECMessage::RequestARCInit,
+            ARCState::Initiating,
+        )),
+        (LogicalAddress::AudioSystem, false) => Some((
+            LogicalAddress::TV,
+            CECMessage::RequestARCTermination,
+            ARCState::Terminating,
+        )),
+        _ => None,
+    }
+}
+
+/// Updates `link` for an ARC message sent to `local` by `initiator` and
+/// returns the reply that should go back to the initiator, if any.
+pub(crate) fn handle_message(
+    link: &mut ARCLink,
+    local: LogicalAddress,
+    initiator: LogicalAddress,
+    msg: &CECMessage,
+    now: Instant,
+) -> Option<CECMessage> {
+    // Only the TV starts and stops ARC, and only the audio system asks it to
+    // or reports back.
+    let partner = match msg {
+        CECMessage::InitARC | CECMessage::TerminateARC => LogicalAddress::TV,
+        _ => LogicalAddress::AudioSystem,
+    };
+    if initiator != partner {
+        return Some(CECMessage::FeatureAbort {
+            feature_opcode: msg.get_opcode(),
+            abort_reason: AbortReason::Refused,
+        });
+    }
+    let (state, reply) = match (local, msg) {
+        (LogicalAddress::TV, CECMessage::RequestARCInit) => {
+            (ARCState::Initiating, Some(CECMessage::InitARC))
+        }
+        (LogicalAddress::TV, CECMessage::RequestARCTermination) => {
+            (ARCState::Terminating, Some(CECMessage::TerminateARC))
+        }
+        (LogicalAddress::TV, CECMessage::ReportARCInited) => (ARCState::Active, None),
+        (LogicalAddress::TV, CECMessage::ReportARCTerminated) => (ARCState::Inactive, None),
+        (LogicalAddress::AudioSystem, CECMessage::InitARC) => {
+            (ARCState::Active, Some(CECMessage::ReportARCInited))
+        }
+        (LogicalAddress::AudioSystem, CECMessage::TerminateARC) => {
+            (ARCState::Inactive, Some(CECMessage::ReportARCTerminated))
+        }
+        _ => return None,
+    };
+    link.set(state, now);
+    reply
+}
+
+/// Updates `link` for a <Feature Abort> of `opcode`. A start the other side
+/// refused leaves ARC off, and a stop it refused leaves it on.
+pub(crate) fn handle_abort(link: &mut ARCLink, opcode: Opcode, now: Instant) {
+    match (link.state(now), opcode) {
+        (ARCState::Initiating, Opcode::InitARC | Opcode::RequestARCInit) => {
+            link.set(ARCState::Inactive, now)
+        }
+        (ARCState::Terminating, Opcode::TerminateARC | Opcode::RequestARCTermination) => {
+            link.set(ARCState::Active, now)
+        }
+        _ => (),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cec::arc::*;
+
+    #[test]
+    fn tv_initiates_on_request() {
+        let mut link = ARCLink::new();
+        let now = Instant::now();
+        let reply = handle_message(
+            &mut link,
+            LogicalAddress::TV,
+            LogicalAddress::AudioSystem,
+            &CECMessage::RequestARCInit,
+            now,
+        );
+        assert_eq!(reply, Some(CECMessage::InitARC));
+        assert_eq!(link.state(now), ARCState::Initiating);
+        let reply = handle_message(
+            &mut link,
+            LogicalAddress::TV,
+            LogicalAddress::AudioSystem,
+            &CECMessage::ReportARCInited,
+            now,
+        );
+        assert_eq!(reply, None);
+        assert_eq!(link.state(now), ARCState::Active);
+    }
+
+    #[test]
+    fn audio_system_confirms_termination() {
+        let now = Instant::now();
+        let mut link = ARCLink::new();
+        link.set(ARCState::Active, now);
+        let reply = handle_message(
+            &mut link,
+            LogicalAddress::AudioSystem,
+            LogicalAddress::TV,
+            &CECMessage::TerminateARC,
+            now,
+        );
+        assert_eq!(reply, Some(CECMessage::ReportARCTerminated));
+        assert_eq!(link.state(now), ARCState::Inactive);
+    }
+
+    #[test]
+    fn refuses_arc_from_others() {
+        let now = Instant::now();
+        let mut link = ARCLink::new();
+        let reply = handle_message(
+            &mut link,
+            LogicalAddress::AudioSystem,
+            LogicalAddress::PlaybackDevice1,
+            &CECMessage::InitARC,
+            now,
+        );
+        assert_eq!(
+            reply,
+            Some(CECMessage::FeatureAbort {
+                feature_opcode: Opcode::InitARC,
+                abort_reason: AbortReason::Refused,
+            })
+        );
+        assert_eq!(link.state(now), ARCState::Inactive);
+    }
+
+    #[test]
+    fn gives_up_on_unanswered_requests() {
+        let now = Instant::now();
+        let mut link = ARCLink::new();
+        link.set(ARCState::Initiating, now);
+        assert_eq!(link.state(now + ARC_TIMEOUT / 2), ARCState::Initiating);
+        assert_eq!(link.state(now + ARC_TIMEOUT), ARCState::Inactive);
+    }
+
+    #[test]
+    fn falls_back_when_refused() {
+        let now = Instant::now();
+        let mut link = ARCLink::new();
+        link.set(ARCState::Initiating, now);
+        handle_abort(&mut link, Opcode::RequestARCInit, now);
+        assert_eq!(link.state(now), ARCState::Inactive);
+        link.set(ARCState::Terminating, now);
+        handle_abort(&mut link, Opcode::TerminateARC, now);
+        assert_eq!(link.state(now), ARCState::Active);
+        handle_abort(&mut link, Opcode::InitARC, now);
+        assert_eq!(link.state(now), ARCState::Active);
+    }
+
+    #[test]
+    fn playback_device_ignores_arc() {
+        let now = Instant::now();
+        let mut link = ARCLink::new();
+        let reply = handle_message(
+            &mut link,
+            LogicalAddress::PlaybackDevice1,
+            LogicalAddress::TV,
+            &CECMessage::InitARC,
+            now,
+        );
+        assert_eq!(reply, None);
+        assert_eq!(link.state(now), ARCState::Inactive);
+        assert_eq!(request(LogicalAddress::PlaybackDevice1, true), None);
+    }
+}
@@ -0,0 +1,118 @@
+// Choosing and opening a local CEC adapter from the command line, shared by
+// the binaries that drive one.
+//
+// The VideoCore service is the default. The kernel's CEC framework, a
+// Pulse-Eight adapter, or a fake connection can be asked for instead.
+
+use crate::cec::adapter::Config;
+use crate::cec::linux::LinuxConnection;
+use crate::cec::noop::LogOnlyConn;
+use crate::cec::pulse_eight::PulseEight;
+use crate::cec::sim::Bus;
+use crate::cec::vchi::ReconnectingInterface;
+use crate::cec::{self, CECConnection, PhysicalAddress};
+use log::info;
+use std::error::Error;
+use std::sync::Arc;
+
+/// Options that pick the adapter and how we appear on its bus.
+#[derive(clap::Args, Debug)]
+pub struct BackendArgs {
+    /// Use a fake cec connection instead of directly using the hardware,
+    /// either one that only logs or one to a simulated TV and audio system.
+    #[arg(long, value_enum, num_args = 0..=1, default_missing_value = "log")]
+    pub use_fake_cec_conn: Option<FakeConn>,
+
+    /// Kernel CEC device, such as /dev/cec0, to use instead of the VideoCore
+    /// service.
+    #[arg(long)]
+    pub cec_device: Option<String>,
+
+    /// Serial port of a Pulse-Eight USB-CEC adapter, such as /dev/ttyACM0, to
+    /// use instead of the VideoCore service.
+    #[arg(long)]
+    pub pulse_eight_device: Option<String>,
+
+    /// Kind of device to appear as on the CEC bus.
+    #[arg(long, value_enum, default_value_t = DeviceType::Playback)]
+    pub device_type: DeviceType,
+}
+
+#[derive(clap::ValueEnum, Copy, Clone, Debug)]
+pub enum FakeConn {
+    Log,
+    Sim,
+}
+
+#[derive(clap::ValueEnum, Copy, Clone, Debug)]
+pub enum DeviceType {
+    Playback,
+    Recording,
+    Tuner,
+    AudioSystem,
+}
+
+impl From<DeviceType> for cec::DeviceType {
+    fn from(val: DeviceType) -> Self {
+        match val {
+            DeviceType::Playback => cec::DeviceType::PlaybackDevice,
+            DeviceType::Recording => cec::DeviceType::RecordingDevice,
+            DeviceType::Tuner => cec::DeviceType::Tuner,
+            DeviceType::AudioSystem => cec::DeviceType::AudioSystem,
+        }
+    }
+}
+
+/// An opened adapter.
+pub struct Backend {
+    pub conn: Arc<dyn CECConnection>,
+    /// The VideoCore service, when that's the adapter, for the display
+    /// controls only it has.
+    pub display: Option<Arc<ReconnectingInterface>>,
+}
+
+/// Opens the adapter `args` asks for, claiming a logical address on it.
+pub fn open_backend(
+    args: &BackendArgs,
+    osd_name: &str,
+    vendor_id: u32,
+) -> Result<Backend, Box<dyn Error>> {
+    let config = Config {
+        device_type: args.device_type.into(),
+        osd_name: osd_name.to_string(),
+        vendor_id,
+        physical_address: None,
+    };
+    let conn: Arc<dyn CECConnection> = if let Some(fake) = args.use_fake_cec_conn {
+        match fake {
+            FakeConn::Log => Arc::new(LogOnlyConn::default()),
+            FakeConn::Sim => Arc::new(
+                Bus::living_room().connect(config.device_type, PhysicalAddress::from(0x2000)),
+            ),
+        }
+    } else if let Some(path) = &args.cec_device {
+        Arc::new(LinuxConnection::open(path, &config)?)
+    } else if let Some(path) = &args.pulse_eight_device {
+        Arc::new(PulseEight::open(path, &config)?)
+    } else {
+        // Run again each time the interface has to be reopened.
+        let vchi = Arc::new(ReconnectingInterface::init(move |vchi| {
+            vchi.set_osd_name(&config.osd_name)?;
+            vchi.set_vendor_id(config.vendor_id)?;
+
+            if vchi.get_physical_addr()? != PhysicalAddress::INVALID {
+                let addr = vchi.claim_logical_addr(config.device_type, config.vendor_id)?;
+                info!("claimed logical address {:?}", addr);
+            }
+            Ok(())
+        })?);
+        return Ok(Backend {
+            conn: vchi.clone(),
+            display: Some(vchi),
+        });
+    };
+    Ok(Backend {
+        conn,
+        display: None,
+    })
+}
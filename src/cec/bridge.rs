@@ -0,0 +1,829 @@
+// Carries a CECConnection over the network, so the control server doesn't
+// have to run on the machine plugged into the TV.
+//
+// The bridge side wraps a local connection and accepts clients on a TCP
+// socket; RemoteCECConnection is a CECConnection on the other end. Messages
+// are JSON, one per line. A client first proves it knows the shared token by
+// hashing it with a challenge from the bridge, so the token itself is never
+// sent. Nothing after that is encrypted or signed, so anyone on the path can
+// read and inject frames: keep the bridge on a trusted network, or reach it
+// through a tunnel. Both ends ping while idle and give up on a peer that's
+// gone quiet, and the client keeps reconnecting until the bridge is back.
+
+use crate::cec::{
+    CECCommand, CECConnection, CECError, HdmiCallback, HdmiEvent, LogicalAddress, PhysicalAddress,
+    ReconnectCallback, TopologyCallback, TxCallback,
+};
+use base64::engine::general_purpose::URL_SAFE;
+use base64::Engine;
+use log::{info, warn};
+use rand::distributions::{Alphanumeric, DistString};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(5);
+// A peer that's sent nothing for this long is gone.
+const KEEPALIVE_TIMEOUT: Duration = Duration::from_secs(15);
+const WRITE_TIMEOUT: Duration = Duration::from_secs(2);
+const CALL_TIMEOUT: Duration = Duration::from_secs(10);
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+#[derive(Error, Debug)]
+pub enum BridgeError {
+    #[error("Connection error: {0}")]
+    Io(#[from] io::Error),
+    #[error("Bad message: {0}")]
+    BadMessage(#[from] serde_json::Error),
+    #[error("Unexpected message during handshake")]
+    Handshake,
+    #[error("Bridge rejected the token")]
+    AuthFailed,
+    #[error("Not connected to the bridge")]
+    Disconnected,
+    #[error("Bridge didn't answer in time")]
+    Timeout,
+    #[error("Bridge reported: {0}")]
+    Remote(String),
+}
+
+impl From<BridgeError> for CECError {
+    fn from(val: BridgeError) -> Self {
+        CECError::Other(Box::new(val))
+    }
+}
+
+/// A frame as sent over the wire. The initiator is left out when the
+/// connection should fill in its own.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct Frame {
+    initiator: Option<u8>,
+    destination: u8,
+    payload: Vec<u8>,
+}
+
+impl From<&CECCommand> for Frame {
+    fn from(cmd: &CECCommand) -> Self {
+        Frame {
+            initiator: cmd.initiator.map(|addr| addr as u8),
+            destination: cmd.destination as u8,
+            payload: cmd.message.payload(),
+        }
+    }
+}
+
+impl TryFrom<&Frame> for CECCommand {
+    type Error = CECError;
+
+    fn try_from(frame: &Frame) -> Result<Self, CECError> {
+        let header = frame.initiator.unwrap_or(0xf) << 4 | frame.destination & 0xf;
+        let mut raw = vec![header];
+        raw.extend(&frame.payload);
+        let mut cmd = CECCommand::from_raw(&raw).map_err(CECError::ParsingError)?;
+        if frame.initiator.is_none() {
+            cmd.initiator = None;
+        }
+        Ok(cmd)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+enum WireError {
+    NoAck,
+    Busy,
+    Other(String),
+}
+
+impl From<&CECError> for WireError {
+    fn from(e: &CECError) -> Self {
+        match e {
+            CECError::NoAck => WireError::NoAck,
+            CECError::Busy => WireError::Busy,
+            e => WireError::Other(e.to_string()),
+        }
+    }
+}
+
+impl From<WireError> for CECError {
+    fn from(e: WireError) -> Self {
+        match e {
+            WireError::NoAck => CECError::NoAck,
+            WireError::Busy => CECError::Busy,
+            WireError::Other(msg) => BridgeError::Remote(msg).into(),
+        }
+    }
+}
+
+fn to_wire<T>(result: &Result<T, CECError>) -> Result<(), WireError> {
+    result.as_ref().map(|_| ()).map_err(WireError::from)
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Request {
+    Auth { digest: String },
+    Transmit { id: u64, frame: Frame },
+    GetLogicalAddress { id: u64 },
+    GetPhysicalAddress { id: u64 },
+    Ping,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Answer {
+    Sent,
+    LogicalAddress(u8),
+    PhysicalAddress(u16),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Event {
+    Challenge {
+        nonce: String,
+    },
+    Welcome,
+    Reply {
+        id: u64,
+        result: Result<Answer, WireError>,
+    },
+    Rx {
+        frame: Frame,
+    },
+    Tx {
+        frame: Frame,
+        result: Result<(), WireError>,
+    },
+    Topology {
+        addresses: Vec<u8>,
+    },
+    Hdmi {
+        event: HdmiEvent,
+    },
+    // The adapter, or the link to the bridge, was reopened.
+    Reconnected,
+    Pong,
+}
+
+fn digest(nonce: &str, token: &str) -> String {
+    URL_SAFE.encode(Sha256::digest(format!("{}{}", nonce, token)))
+}
+
+// Compares digests in a time that doesn't depend on where they first differ,
+// so a client can't find the right one a byte at a time.
+fn digests_match(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |acc, (x, y)| acc | (x ^ y))
+            == 0
+}
+
+fn send<T: Serialize>(mut stream: &TcpStream, msg: &T) -> Result<(), BridgeError> {
+    let mut line = serde_json::to_vec(msg)?;
+    line.push(b'\n');
+    stream.write_all(&line)?;
+    Ok(())
+}
+
+/// Reads one message per line, keeping partial lines across read timeouts.
+struct LineReader {
+    reader: BufReader<TcpStream>,
+    line: Vec<u8>,
+}
+
+impl LineReader {
+    fn new(stream: TcpStream) -> Self {
+        LineReader {
+            reader: BufReader::new(stream),
+            line: vec![],
+        }
+    }
+
+    /// Returns None if the read timed out before a whole line arrived.
+    fn next<T: for<'de> Deserialize<'de>>(&mut self) -> Result<Option<T>, BridgeError> {
+        match self.reader.read_until(b'\n', &mut self.line) {
+            Ok(0) => Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+            Ok(_) if self.line.ends_with(b"\n") => {
+                let msg = serde_json::from_slice(&self.line);
+                self.line.clear();
+                Ok(Some(msg?))
+            }
+            Ok(_) => Ok(None),
+            Err(e)
+                if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut =>
+            {
+                Ok(None)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // Waits for one message, for the handshake.
+    fn expect<T: for<'de> Deserialize<'de>>(&mut self) -> Result<T, BridgeError> {
+        self.next()?.ok_or(BridgeError::Timeout)
+    }
+}
+
+type Clients = Arc<Mutex<Vec<TcpStream>>>;
+
+// Sends `event` to every client, dropping any that can't keep up.
+fn broadcast(clients: &Clients, event: &Event) {
+    clients
+        .lock()
+        .unwrap()
+        .retain(|stream| match send(stream, event) {
+            Ok(()) => true,
+            Err(e) => {
+                warn!("dropping bridge client: {}", e);
+                let _ = stream.shutdown(Shutdown::Both);
+                false
+            }
+        });
+}
+
+/// Serves a local connection to authenticated clients.
+pub struct Bridge {
+    conn: Arc<dyn CECConnection>,
+    token: String,
+    clients: Clients,
+}
+
+impl Bridge {
+    /// Takes over the callbacks of `conn`, passing what they see on to every
+    /// client.
+    pub fn new(conn: Arc<dyn CECConnection>, token: impl Into<String>) -> Arc<Self> {
+        let clients: Clients = Arc::new(Mutex::new(vec![]));
+        let rx_clients = clients.clone();
+        conn.set_rx_callback(Box::new(move |cmd| {
+            broadcast(&rx_clients, &Event::Rx { frame: cmd.into() })
+        }));
+        let tx_clients = clients.clone();
+        conn.set_tx_callback(Box::new(move |cmd, result| {
+            broadcast(
+                &tx_clients,
+                &Event::Tx {
+                    frame: cmd.into(),
+                    result: to_wire(&result),
+                },
+            )
+        }));
+        let topology_clients = clients.clone();
+        conn.set_topology_callback(Box::new(move |addrs| {
+            broadcast(
+                &topology_clients,
+                &Event::Topology {
+                    addresses: addrs.iter().map(|&addr| addr as u8).collect(),
+                },
+            )
+        }));
+        let hdmi_clients = clients.clone();
+        conn.set_hdmi_callback(Box::new(move |event| {
+            broadcast(&hdmi_clients, &Event::Hdmi { event })
+        }));
+        let reconnect_clients = clients.clone();
+        conn.set_reconnect_callback(Box::new(move || {
+            broadcast(&reconnect_clients, &Event::Reconnected)
+        }));
+        Arc::new(Bridge {
+            conn,
+            token: token.into(),
+            clients,
+        })
+    }
+
+    /// Accepts clients on `listener` until it fails.
+    pub fn serve(self: &Arc<Self>, listener: TcpListener) -> io::Result<()> {
+        for stream in listener.incoming() {
+            let stream = stream?;
+            let bridge = self.clone();
+            thread::spawn(move || {
+                let peer = stream.peer_addr();
+                if let Err(e) = bridge.handle(stream) {
+                    info!("bridge client {:?} went away: {}", peer, e);
+                }
+            });
+        }
+        Ok(())
+    }
+
+    fn handle(&self, stream: TcpStream) -> Result<(), BridgeError> {
+        stream.set_read_timeout(Some(KEEPALIVE_TIMEOUT))?;
+        stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+        let mut reader = LineReader::new(stream.try_clone()?);
+        let nonce = Alphanumeric.sample_string(&mut rand::thread_rng(), 32);
+        send(
+            &stream,
+            &Event::Challenge {
+                nonce: nonce.clone(),
+            },
+        )?;
+        match reader.expect()? {
+            Request::Auth { digest: d } if digests_match(&d, &digest(&nonce, &self.token)) => (),
+            _ => {
+                warn!(
+                    "bridge client {:?} failed to authenticate",
+                    stream.peer_addr()
+                );
+                return Err(BridgeError::AuthFailed);
+            }
+        }
+        send(&stream, &Event::Welcome)?;
+        info!("bridge client {:?} connected", stream.peer_addr());
+        self.clients.lock().unwrap().push(stream.try_clone()?);
+
+        let result = self.handle_requests(&stream, &mut reader);
+        let local = stream.local_addr()?;
+        let peer = stream.peer_addr().ok();
+        self.clients
+            .lock()
+            .unwrap()
+            .retain(|s| !(s.local_addr().ok() == Some(local) && s.peer_addr().ok() == peer));
+        result
+    }
+
+    fn handle_requests(
+        &self,
+        stream: &TcpStream,
+        reader: &mut LineReader,
+    ) -> Result<(), BridgeError> {
+        loop {
+            let (id, result) = match reader.expect()? {
+                Request::Ping => {
+                    self.reply(stream, &Event::Pong)?;
+                    continue;
+                }
+                Request::Transmit { id, frame } => (
+                    id,
+                    CECCommand::try_from(&frame)
+                        .and_then(|cmd| self.conn.transmit(cmd))
+                        .map(|_| Answer::Sent),
+                ),
+                Request::GetLogicalAddress { id } => (
+                    id,
+                    self.conn
+                        .get_logical_address()
+                        .map(|addr| Answer::LogicalAddress(addr as u8)),
+                ),
+                Request::GetPhysicalAddress { id } => (
+                    id,
+                    self.conn
+                        .get_physical_address()
+                        .map(|addr| Answer::PhysicalAddress(addr.into())),
+                ),
+                Request::Auth { .. } => return Err(BridgeError::Handshake),
+            };
+            let result = result.map_err(|e| WireError::from(&e));
+            self.reply(stream, &Event::Reply { id, result })?;
+        }
+    }
+
+    // Replies share the stream with broadcasts, so they're written under the
+    // same lock to keep lines whole.
+    fn reply(&self, stream: &TcpStream, event: &Event) -> Result<(), BridgeError> {
+        let _clients = self.clients.lock().unwrap();
+        send(stream, event)
+    }
+}
+
+type RxCallback = Box<dyn FnMut(&CECCommand) + Send>;
+type Pending = Mutex<HashMap<u64, Sender<Result<Answer, WireError>>>>;
+
+#[derive(Default)]
+struct Callbacks {
+    rx: Mutex<Option<RxCallback>>,
+    tx: Mutex<Option<TxCallback>>,
+    topology: Mutex<Option<TopologyCallback>>,
+    hdmi: Mutex<Option<HdmiCallback>>,
+    reconnect: Mutex<Option<ReconnectCallback>>,
+}
+
+impl Callbacks {
+    fn dispatch(&self, event: Event) -> Result<(), CECError> {
+        match event {
+            Event::Rx { frame } => {
+                let cmd = CECCommand::try_from(&frame)?;
+                if let Some(cb) = &mut *self.rx.lock().unwrap() {
+                    cb(&cmd)
+                }
+            }
+            Event::Tx { frame, result } => {
+                let cmd = CECCommand::try_from(&frame)?;
+                if let Some(cb) = &mut *self.tx.lock().unwrap() {
+                    cb(&cmd, result.map_err(CECError::from))
+                }
+            }
+            Event::Topology { addresses } => {
+                let addrs: Vec<LogicalAddress> = addresses
+                    .into_iter()
+                    .filter_map(|addr| LogicalAddress::try_from(addr).ok())
+                    .collect();
+                if let Some(cb) = &mut *self.topology.lock().unwrap() {
+                    cb(&addrs)
+                }
+            }
+            Event::Hdmi { event } => {
+                if let Some(cb) = &mut *self.hdmi.lock().unwrap() {
+                    cb(event)
+                }
+            }
+            Event::Reconnected => {
+                if let Some(cb) = &mut *self.reconnect.lock().unwrap() {
+                    cb()
+                }
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+}
+
+// State shared between the connection and its session thread.
+struct Session {
+    addr: String,
+    token: String,
+    writer: Mutex<Option<TcpStream>>,
+    pending: Pending,
+    next_id: AtomicU64,
+    stop: AtomicBool,
+}
+
+impl Session {
+    // Connects and authenticates, leaving the stream ready for calls.
+    fn handshake(&self) -> Result<LineReader, BridgeError> {
+        let stream = TcpStream::connect(&self.addr)?;
+        stream.set_read_timeout(Some(KEEPALIVE_INTERVAL))?;
+        stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+        let mut reader = LineReader::new(stream.try_clone()?);
+        let nonce = match reader.expect()? {
+            Event::Challenge { nonce } => nonce,
+            _ => return Err(BridgeError::Handshake),
+        };
+        send(
+            &stream,
+            &Request::Auth {
+                digest: digest(&nonce, &self.token),
+            },
+        )?;
+        match reader.expect() {
+            Ok(Event::Welcome) => {
+                *self.writer.lock().unwrap() = Some(stream);
+                Ok(reader)
+            }
+            Ok(_) => Err(BridgeError::Handshake),
+            // The bridge hangs up on a bad token.
+            Err(BridgeError::Io(_)) => Err(BridgeError::AuthFailed),
+            Err(e) => Err(e),
+        }
+    }
+
+    // Reads from the bridge until the link fails, pinging while it's quiet.
+    fn run(&self, mut reader: LineReader, events: &Sender<Event>) -> BridgeError {
+        let mut last_heard = Instant::now();
+        let mut last_ping = Instant::now();
+        let err = loop {
+            match reader.next::<Event>() {
+                Ok(Some(event)) => {
+                    last_heard = Instant::now();
+                    match event {
+                        Event::Reply { id, result } => {
+                            if let Some(reply) = self.pending.lock().unwrap().remove(&id) {
+                                let _ = reply.send(result);
+                            }
+                        }
+                        Event::Pong => (),
+                        event => {
+                            let _ = events.send(event);
+                        }
+                    }
+                }
+                Ok(None) if last_heard.elapsed() > KEEPALIVE_TIMEOUT => break BridgeError::Timeout,
+                Ok(None) => (),
+                Err(e) => break e,
+            }
+            if last_ping.elapsed() >= KEEPALIVE_INTERVAL {
+                last_ping = Instant::now();
+                if let Err(e) = self.send(&Request::Ping) {
+                    break e;
+                }
+            }
+        };
+        if let Some(stream) = self.writer.lock().unwrap().take() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+        // Dropping the senders wakes the callers with an error.
+        self.pending.lock().unwrap().clear();
+        err
+    }
+
+    fn send(&self, request: &Request) -> Result<(), BridgeError> {
+        match &*self.writer.lock().unwrap() {
+            Some(stream) => send(stream, request),
+            None => Err(BridgeError::Disconnected),
+        }
+    }
+
+    fn call(&self, request: impl FnOnce(u64) -> Request) -> Result<Answer, CECError> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (sender, reply) = mpsc::channel();
+        self.pending.lock().unwrap().insert(id, sender);
+        let result = self
+            .send(&request(id))
+            .and_then(|()| wait(&reply, CALL_TIMEOUT));
+        self.pending.lock().unwrap().remove(&id);
+        Ok(result??)
+    }
+}
+
+fn wait(
+    reply: &Receiver<Result<Answer, WireError>>,
+    timeout: Duration,
+) -> Result<Result<Answer, WireError>, BridgeError> {
+    reply.recv_timeout(timeout).map_err(|e| match e {
+        RecvTimeoutError::Timeout => BridgeError::Timeout,
+        RecvTimeoutError::Disconnected => BridgeError::Disconnected,
+    })
+}
+
+/// A connection to a CEC adapter on another machine, through a [`Bridge`].
+pub struct RemoteCECConnection {
+    session: Arc<Session>,
+    callbacks: Arc<Callbacks>,
+}
+
+impl RemoteCECConnection {
+    /// Connects to the bridge at `addr`, failing if it can't be reached or
+    /// doesn't accept `token`. Later drops are reconnected in the background.
+    pub fn connect(addr: impl Into<String>, token: impl Into<String>) -> Result<Self, BridgeError> {
+        let session = Arc::new(Session {
+            addr: addr.into(),
+            token: token.into(),
+            writer: Mutex::new(None),
+            pending: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(0),
+            stop: AtomicBool::new(false),
+        });
+        let mut reader = session.handshake()?;
+        info!("connected to CEC bridge at {}", session.addr);
+
+        // Callbacks run on their own thread, since they may transmit and
+        // then wait for a reply the session thread has to read.
+        let callbacks = Arc::new(Callbacks::default());
+        let (events, received) = mpsc::channel();
+        let dispatcher = callbacks.clone();
+        thread::spawn(move || {
+            for event in received {
+                if let Err(e) = dispatcher.dispatch(event) {
+                    info!("ignoring frame from bridge: {}", e);
+                }
+            }
+        });
+
+        let link = session.clone();
+        thread::spawn(move || {
+            let mut delay = RECONNECT_DELAY;
+            loop {
+                let err = link.run(reader, &events);
+                if link.stop.load(Ordering::Relaxed) {
+                    return;
+                }
+                warn!("lost CEC bridge at {}: {}", link.addr, err);
+                loop {
+                    thread::sleep(delay);
+                    if link.stop.load(Ordering::Relaxed) {
+                        return;
+                    }
+                    match link.handshake() {
+                        Ok(connection) => {
+                            info!("reconnected to CEC bridge at {}", link.addr);
+                            // The bridge may have missed our frames, and
+                            // we its, while we were gone.
+                            let _ = events.send(Event::Reconnected);
+                            reader = connection;
+                            delay = RECONNECT_DELAY;
+                            break;
+                        }
+                        Err(e) => {
+                            warn!("reconnecting to CEC bridge failed: {}", e);
+                            delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+                        }
+                    }
+                }
+            }
+        });
+        Ok(RemoteCECConnection { session, callbacks })
+    }
+}
+
+impl Drop for RemoteCECConnection {
+    fn drop(&mut self) {
+        self.session.stop.store(true, Ordering::Relaxed);
+        if let Some(stream) = &*self.session.writer.lock().unwrap() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+impl CECConnection for RemoteCECConnection {
+    fn transmit(&self, cmd: CECCommand) -> Result<(), CECError> {
+        let frame = Frame::from(&cmd);
+        self.session
+            .call(|id| Request::Transmit { id, frame })
+            .map(|_| ())
+    }
+
+    fn get_logical_address(&self) -> Result<LogicalAddress, CECError> {
+        match self.session.call(|id| Request::GetLogicalAddress { id })? {
+            Answer::LogicalAddress(addr) => {
+                LogicalAddress::try_from(addr).map_err(|e| CECError::ParsingError(e.into()))
+            }
+            other => Err(BridgeError::Remote(format!("unexpected answer {:?}", other)).into()),
+        }
+    }
+
+    fn get_physical_address(&self) -> Result<PhysicalAddress, CECError> {
+        match self.session.call(|id| Request::GetPhysicalAddress { id })? {
+            Answer::PhysicalAddress(addr) => Ok(PhysicalAddress::from(addr)),
+            other => Err(BridgeError::Remote(format!("unexpected answer {:?}", other)).into()),
+        }
+    }
+
+    fn set_rx_callback(&self, func: Box<dyn FnMut(&CECCommand) + Send>) {
+        *self.callbacks.rx.lock().unwrap() = Some(func)
+    }
+
+    fn set_tx_callback(&self, func: TxCallback) {
+        *self.callbacks.tx.lock().unwrap() = Some(func)
+    }
+
+    fn set_topology_callback(&self, func: TopologyCallback) {
+        *self.callbacks.topology.lock().unwrap() = Some(func)
+    }
+
+    fn set_hdmi_callback(&self, func: HdmiCallback) {
+        *self.callbacks.hdmi.lock().unwrap() = Some(func)
+    }
+
+    fn set_reconnect_callback(&self, func: ReconnectCallback) {
+        *self.callbacks.reconnect.lock().unwrap() = Some(func)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cec::bridge::*;
+    use crate::cec::{standby, CECMessage};
+
+    // A local connection that acknowledges everything except the audio system.
+    #[derive(Default)]
+    struct Loopback {
+        rx: Mutex<Option<RxCallback>>,
+        tx: Mutex<Option<TxCallback>>,
+        sent: Mutex<Vec<String>>,
+    }
+
+    impl Loopback {
+        fn receive(&self, cmd: &CECCommand) {
+            if let Some(cb) = &mut *self.rx.lock().unwrap() {
+                cb(cmd)
+            }
+        }
+    }
+
+    impl CECConnection for Loopback {
+        fn transmit(&self, cmd: CECCommand) -> Result<(), CECError> {
+            self.sent.lock().unwrap().push(cmd.to_string());
+            let result = || match cmd.destination {
+                LogicalAddress::AudioSystem => Err(CECError::NoAck),
+                _ => Ok(()),
+            };
+            if let Some(cb) = &mut *self.tx.lock().unwrap() {
+                cb(&cmd, result())
+            }
+            result()
+        }
+        fn get_logical_address(&self) -> Result<LogicalAddress, CECError> {
+            Ok(LogicalAddress::PlaybackDevice1)
+        }
+        fn get_physical_address(&self) -> Result<PhysicalAddress, CECError> {
+            Ok(PhysicalAddress::from(0x1000))
+        }
+        fn set_rx_callback(&self, func: RxCallback) {
+            *self.rx.lock().unwrap() = Some(func)
+        }
+        fn set_tx_callback(&self, func: TxCallback) {
+            *self.tx.lock().unwrap() = Some(func)
+        }
+    }
+
+    fn start_bridge() -> (Arc<Loopback>, Arc<Bridge>, String) {
+        let local = Arc::new(Loopback::default());
+        let bridge = Bridge::new(local.clone(), "secret");
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = bridge.clone();
+        thread::spawn(move || server.serve(listener));
+        (local, bridge, addr)
+    }
+
+    #[test]
+    fn forwards_calls_and_callbacks() {
+        let (local, _bridge, addr) = start_bridge();
+        let remote = RemoteCECConnection::connect(addr, "secret").unwrap();
+        let (sender, events) = mpsc::channel();
+        let tx_sender = sender.clone();
+        remote.set_rx_callback(Box::new(move |cmd| sender.send(cmd.to_string()).unwrap()));
+        remote.set_tx_callback(Box::new(move |cmd, result| {
+            tx_sender
+                .send(format!("{} {}", cmd, result.is_ok()))
+                .unwrap()
+        }));
+
+        assert_eq!(
+            remote.get_logical_address().unwrap(),
+            LogicalAddress::PlaybackDevice1
+        );
+        assert_eq!(
+            remote.get_physical_address().unwrap(),
+            PhysicalAddress::from(0x1000)
+        );
+        remote.transmit(standby(LogicalAddress::TV)).unwrap();
+        assert!(matches!(
+            remote.transmit(standby(LogicalAddress::AudioSystem)),
+            Err(CECError::NoAck)
+        ));
+        assert_eq!(
+            *local.sent.lock().unwrap(),
+            ["Local -> TV: STANDBY", "Local -> Audio System: STANDBY"]
+        );
+        let timeout = Duration::from_secs(5);
+        assert_eq!(
+            events.recv_timeout(timeout).unwrap(),
+            "Local -> TV: STANDBY true"
+        );
+        assert_eq!(
+            events.recv_timeout(timeout).unwrap(),
+            "Local -> Audio System: STANDBY false"
+        );
+
+        local.receive(&CECCommand {
+            initiator: Some(LogicalAddress::TV),
+            destination: LogicalAddress::Broadcast,
+            message: CECMessage::Standby,
+        });
+        assert_eq!(
+            events.recv_timeout(timeout).unwrap(),
+            "TV -> Broadcast: STANDBY"
+        );
+    }
+
+    #[test]
+    fn matches_digests() {
+        let right = digest("nonce", "token");
+        assert!(digests_match(&right, &digest("nonce", "token")));
+        assert!(!digests_match(&right, &digest("nonce", "tokem")));
+        assert!(!digests_match(&right, &right[1..]));
+    }
+
+    #[test]
+    fn rejects_wrong_token() {
+        let (_local, _bridge, addr) = start_bridge();
+        assert!(matches!(
+            RemoteCECConnection::connect(addr, "guess"),
+            Err(BridgeError::AuthFailed)
+        ));
+    }
+
+    #[test]
+    fn reconnects_after_drop() {
+        let (_local, bridge, addr) = start_bridge();
+        let remote = RemoteCECConnection::connect(addr, "secret").unwrap();
+        let (sender, reconnects) = mpsc::channel();
+        remote.set_reconnect_callback(Box::new(move || sender.send(()).unwrap()));
+        // The bridge only lists the client once it has answered the handshake.
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while bridge.clients.lock().unwrap().is_empty() {
+            assert!(Instant::now() < deadline, "never listed the client");
+            thread::sleep(Duration::from_millis(10));
+        }
+        for stream in bridge.clients.lock().unwrap().iter() {
+            stream.shutdown(Shutdown::Both).unwrap();
+        }
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while remote.transmit(standby(LogicalAddress::TV)).is_err() {
+            assert!(Instant::now() < deadline, "never reconnected");
+            thread::sleep(Duration::from_millis(100));
+        }
+        reconnects.recv_timeout(Duration::from_secs(5)).unwrap();
+    }
+}
@@ -0,0 +1,187 @@
+// Decoding the EDID a display reports over HDMI.
+//
+// Only the parts cecvol has a use for are decoded: who made the display and
+// what it calls itself, from the base block, and the CEC physical address the
+// sink assigned us, from the HDMI vendor-specific data block of a CEA-861
+// extension.
+
+use crate::cec::{CECError, PhysicalAddress};
+use thiserror::Error;
+
+pub const BLOCK_SIZE: usize = 128;
+const HEADER: [u8; 8] = [0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x00];
+const MONITOR_NAME: u8 = 0xfc;
+const CEA_EXTENSION: u8 = 0x02;
+const VENDOR_SPECIFIC_BLOCK: u8 = 3;
+const HDMI_OUI: u32 = 0x000c03;
+
+#[derive(Error, Debug, PartialEq)]
+pub enum EdidError {
+    #[error("EDID is {0} bytes, not a whole number of blocks")]
+    BadLength(usize),
+    #[error("EDID header is missing")]
+    BadHeader,
+    #[error("Checksum of EDID block {0} is wrong")]
+    BadChecksum(usize),
+}
+
+impl From<EdidError> for CECError {
+    fn from(val: EdidError) -> Self {
+        CECError::Other(Box::new(val))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Edid {
+    /// Three-letter PNP ID of the manufacturer, such as "GSM" for LG.
+    pub manufacturer: String,
+    pub product_code: u16,
+    pub serial_number: u32,
+    /// The name from the display's monitor name descriptor, if it has one.
+    pub name: Option<String>,
+    pub physical_address: Option<PhysicalAddress>,
+}
+
+impl Edid {
+    /// The number of extension blocks that follow `base`, the first block.
+    pub fn extension_count(base: &[u8]) -> usize {
+        base.get(126).copied().unwrap_or(0) as usize
+    }
+
+    /// Decodes a base block followed by any extension blocks.
+    pub fn parse(data: &[u8]) -> Result<Edid, EdidError> {
+        if data.is_empty() || data.len() % BLOCK_SIZE != 0 {
+            return Err(EdidError::BadLength(data.len()));
+        }
+        if data[..HEADER.len()] != HEADER {
+            return Err(EdidError::BadHeader);
+        }
+        for (i, block) in data.chunks(BLOCK_SIZE).enumerate() {
+            if block.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) != 0 {
+                return Err(EdidError::BadChecksum(i));
+            }
+        }
+
+        // Three letters of five bits each, with 1 meaning 'A'.
+        let id = u16::from_be_bytes([data[8], data[9]]);
+        let manufacturer = [10, 5, 0]
+            .iter()
+            .map(|shift| (b'A' - 1 + ((id >> shift) & 0x1f) as u8) as char)
+            .collect();
+        let name = data[54..126]
+            .chunks(18)
+            .find(|d| d[..3] == [0, 0, 0] && d[3] == MONITOR_NAME)
+            .map(|d| descriptor_text(&d[5..]));
+        let physical_address = data[BLOCK_SIZE..]
+            .chunks(BLOCK_SIZE)
+            .find_map(hdmi_physical_address);
+        Ok(Edid {
+            manufacturer,
+            product_code: u16::from_le_bytes([data[10], data[11]]),
+            serial_number: u32::from_le_bytes([data[12], data[13], data[14], data[15]]),
+            name,
+            physical_address,
+        })
+    }
+}
+
+// Descriptor text ends at a newline and is padded out with spaces.
+fn descriptor_text(bytes: &[u8]) -> String {
+    let end = bytes
+        .iter()
+        .position(|&b| b == b'\n')
+        .unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end])
+        .trim_end()
+        .to_string()
+}
+
+fn hdmi_physical_address(block: &[u8]) -> Option<PhysicalAddress> {
+    if block[0] != CEA_EXTENSION {
+        return None;
+    }
+    // Data blocks run from byte 4 up to where the detailed timings start.
+    let end = (block[2] as usize).min(BLOCK_SIZE - 1);
+    let mut i = 4;
+    while i < end {
+        let tag = block[i] >> 5;
+        let len = (block[i] & 0x1f) as usize;
+        let payload = &block[i + 1..(i + 1 + len).min(end)];
+        if tag == VENDOR_SPECIFIC_BLOCK
+            && payload.len() >= 5
+            && u32::from_le_bytes([payload[0], payload[1], payload[2], 0]) == HDMI_OUI
+        {
+            return Some(u16::from_be_bytes([payload[3], payload[4]]).into());
+        }
+        i += 1 + len;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cec::edid::*;
+
+    fn finish(block: &mut [u8]) {
+        let sum = block[..BLOCK_SIZE - 1]
+            .iter()
+            .fold(0u8, |sum, b| sum.wrapping_add(*b));
+        block[BLOCK_SIZE - 1] = sum.wrapping_neg();
+    }
+
+    // An LG TV's EDID, cut down to the parts that are decoded.
+    fn lg_tv() -> Vec<u8> {
+        let mut base = vec![0; BLOCK_SIZE];
+        base[..8].copy_from_slice(&HEADER);
+        base[8..10].copy_from_slice(&[0x1e, 0x6d]);
+        base[10..12].copy_from_slice(&[0x01, 0xc1]);
+        base[12..16].copy_from_slice(&[0x01, 0x01, 0x01, 0x01]);
+        let name = &mut base[54 + 18 * 3..54 + 18 * 4];
+        name[3] = MONITOR_NAME;
+        name[5..].copy_from_slice(b"LG TV SSCR2\n ");
+        base[126] = 1;
+        finish(&mut base);
+
+        let mut cea = vec![0; BLOCK_SIZE];
+        cea[..4].copy_from_slice(&[CEA_EXTENSION, 0x03, 0x0f, 0x00]);
+        // A video block, then the HDMI vendor-specific block.
+        cea[4..8].copy_from_slice(&[0x43, 0x10, 0x04, 0x03]);
+        cea[8..14].copy_from_slice(&[0x65, 0x03, 0x0c, 0x00, 0x20, 0x00]);
+        finish(&mut cea);
+        [base, cea].concat()
+    }
+
+    #[test]
+    fn decodes_tv() {
+        let data = lg_tv();
+        assert_eq!(Edid::extension_count(&data[..BLOCK_SIZE]), 1);
+        assert_eq!(
+            Edid::parse(&data).unwrap(),
+            Edid {
+                manufacturer: "GSM".to_string(),
+                product_code: 0xc101,
+                serial_number: 0x01010101,
+                name: Some("LG TV SSCR2".to_string()),
+                physical_address: Some(PhysicalAddress::from(0x2000)),
+            }
+        );
+    }
+
+    #[test]
+    fn base_block_alone_has_no_address() {
+        let data = lg_tv();
+        let edid = Edid::parse(&data[..BLOCK_SIZE]).unwrap();
+        assert_eq!(edid.name.as_deref(), Some("LG TV SSCR2"));
+        assert_eq!(edid.physical_address, None);
+    }
+
+    #[test]
+    fn rejects_corruption() {
+        let mut data = lg_tv();
+        assert_eq!(Edid::parse(&data[..100]), Err(EdidError::BadLength(100)));
+        data[BLOCK_SIZE + 9] ^= 1;
+        assert_eq!(Edid::parse(&data), Err(EdidError::BadChecksum(1)));
+        data[0] = 0xff;
+        assert_eq!(Edid::parse(&data), Err(EdidError::BadHeader));
+    }
+}
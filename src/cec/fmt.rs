@@ -0,0 +1,612 @@
+// Text forms of CEC frames, for logs and for typing frames in by hand.
+//
+// Frames are displayed in the style of cec-ctl, with each operand named:
+//
+//   TV -> Playback 1: SET_STREAM_PATH phys-addr=1.0.0.0
+//
+// and can be parsed back from either raw bytes ("10:44:41") or a command
+// line of the destination, opcode and operands, where the operand names are
+// optional ("tx 0 user-control-pressed volume-up").
+//
+// Operands are described per opcode at the byte level, so both directions
+// share one table and parsed frames are checked by CECCommand::from_raw.
+
+use crate::cec::{
+    AbortReason, AudioRate, CECCommand, CECVersion, DeckControlMode, DeckInfo, DeckStatus,
+    DeviceType, DisplayControl, Error, LogicalAddress, MenuRequestType, MenuState, Opcode,
+    PhysicalAddress, PlayMode, PowerStatus, TimerClearedStatus, UserControl,
+};
+use num_enum::TryFromPrimitive;
+use std::convert::TryFrom;
+use std::fmt;
+use std::str;
+
+type NameFn = fn(u8) -> Option<String>;
+type ValueFn = fn(&str) -> Option<u8>;
+
+#[derive(Copy, Clone)]
+enum Operand {
+    PhysAddr(&'static str),
+    // A physical address that may be left out entirely.
+    OptPhysAddr(&'static str),
+    Enum(&'static str, NameFn, ValueFn),
+    Bool(&'static str),
+    Byte(&'static str),
+    OptByte(&'static str),
+    // Bytes up to and including the first without the extension bit set, as
+    // used by <Report Features>.
+    Extensible(&'static str),
+    AudioStatus,
+    VendorId,
+    // ASCII text running to the end of the frame.
+    Text(&'static str),
+    // Bytes running to the end of the frame, for operands with no simpler
+    // text form.
+    Data(&'static str),
+}
+
+macro_rules! named {
+    ($name:expr, $t:ty) => {
+        Operand::Enum($name, name_of::<$t>, value_of::<$t>)
+    };
+}
+
+fn operands(opcode: Opcode) -> Vec<Operand> {
+    use Operand::*;
+    match opcode {
+        Opcode::FeatureAbort => vec![named!("opcode", Opcode), named!("reason", AbortReason)],
+        Opcode::ActiveSource
+        | Opcode::SetStreamPath
+        | Opcode::RoutingInformation
+        | Opcode::InactiveSource => vec![PhysAddr("phys-addr")],
+        Opcode::ReportPhysicalAddress => {
+            vec![PhysAddr("phys-addr"), named!("prim-devtype", DeviceType)]
+        }
+        Opcode::RoutingChange => vec![PhysAddr("orig-phys-addr"), PhysAddr("new-phys-addr")],
+        Opcode::SetOSDName => vec![Text("name")],
+        Opcode::SetMenuLanguage => vec![Text("language")],
+        Opcode::SetTimerProgramTitle => vec![Text("title")],
+        Opcode::SetOSDString => vec![named!("disp-ctl", DisplayControl), Text("osd")],
+        Opcode::ReportPowerStatus => vec![named!("pwr-state", PowerStatus)],
+        Opcode::DeviceVendorID => vec![VendorId],
+        Opcode::VendorCommandWithID => vec![VendorId, Data("vendor-data")],
+        Opcode::VendorCommand => vec![Data("vendor-data")],
+        Opcode::VendorRemoteButtonDown => vec![Data("rc-code")],
+        Opcode::GiveDeckStatus | Opcode::GiveTunerDeviceStatus => {
+            vec![named!("status-req", DeckStatus)]
+        }
+        Opcode::DeckStatus => vec![named!("deck-info", DeckInfo)],
+        Opcode::DeckControl => vec![named!("deck-control-mode", DeckControlMode)],
+        Opcode::Play => vec![named!("play-mode", PlayMode)],
+        Opcode::UserControlPressed => vec![named!("ui-cmd", UserControl)],
+        Opcode::TunerDeviceStatus => vec![Data("tuner-dev-info")],
+        Opcode::RecordOn => vec![Data("rec-src")],
+        Opcode::RecordStatus => vec![Byte("rec-status")],
+        Opcode::ClearAnalogTimer
+        | Opcode::SetAnalogTimer
+        | Opcode::ClearDigitalTimer
+        | Opcode::SetDigitalTimer
+        | Opcode::ClearExternalTimer
+        | Opcode::SetExternalTimer => vec![Data("timer")],
+        Opcode::TimerStatus => vec![Data("timer-status")],
+        Opcode::TimerClearedStatus => vec![named!("timer-cleared-status", TimerClearedStatus)],
+        Opcode::SystemAudioModeRequest => vec![OptPhysAddr("phys-addr")],
+        Opcode::SetSystemAudioMode | Opcode::SystemAudioModeStatus => {
+            vec![Bool("sys-aud-status")]
+        }
+        Opcode::ReportAudioStatus => vec![AudioStatus],
+        Opcode::MenuRequest => vec![named!("menu-req", MenuRequestType)],
+        Opcode::MenuStatus => vec![named!("menu-state", MenuState)],
+        Opcode::SelectAnalogService | Opcode::SelectDigitalService => vec![Data("service")],
+        Opcode::SetAudioRate => vec![named!("audio-rate", AudioRate)],
+        Opcode::CECVersion => vec![named!("cec-version", CECVersion)],
+        Opcode::ReportShortAudioDescriptor => vec![Data("descriptors")],
+        Opcode::RequestShortAudioDescriptor => vec![Data("audio-formats")],
+        Opcode::ReportFeatures => vec![
+            named!("cec-version", CECVersion),
+            Byte("all-device-types"),
+            Extensible("rc-profile"),
+            Extensible("dev-features"),
+        ],
+        Opcode::RequestCurrentLatency => vec![PhysAddr("phys-addr")],
+        Opcode::ReportCurrentLatency => vec![
+            PhysAddr("phys-addr"),
+            Byte("video-latency"),
+            Byte("latency-flags"),
+            OptByte("audio-out-delay"),
+        ],
+        Opcode::CDC => vec![PhysAddr("phys-addr"), Byte("cdc-opcode"), Data("params")],
+        Opcode::ImageViewOn
+        | Opcode::TextViewOn
+        | Opcode::Standby
+        | Opcode::RequestActiveSource
+        | Opcode::GivePhysicalAddress
+        | Opcode::GiveOSDName
+        | Opcode::GiveDevicePowerStatus
+        | Opcode::GiveDeviceVendorID
+        | Opcode::GiveAudioStatus
+        | Opcode::GiveSystemAudioModeStatus
+        | Opcode::UserControlReleased
+        | Opcode::VendorRemoteButtonUp
+        | Opcode::TunerStepIncrement
+        | Opcode::TunerStepDecrement
+        | Opcode::RecordOff
+        | Opcode::RecordTVScreen
+        | Opcode::GetMenuLanguage
+        | Opcode::GetCECVersion
+        | Opcode::GiveFeatures
+        | Opcode::InitARC
+        | Opcode::ReportARCInited
+        | Opcode::ReportARCTerminated
+        | Opcode::RequestARCInit
+        | Opcode::RequestARCTermination
+        | Opcode::TerminateARC
+        | Opcode::Abort => vec![],
+    }
+}
+
+// Splits a CamelCase name into words, keeping acronyms like "OSD" together.
+fn words(name: &str, sep: char) -> String {
+    let chars: Vec<char> = name.chars().collect();
+    let mut out = String::new();
+    for (i, &c) in chars.iter().enumerate() {
+        if c == '_' {
+            out.push(sep);
+            continue;
+        }
+        if i > 0 && c.is_ascii_uppercase() {
+            let prev = chars[i - 1];
+            let next_lower = chars.get(i + 1).is_some_and(|n| n.is_ascii_lowercase());
+            if prev.is_ascii_lowercase()
+                || prev.is_ascii_digit()
+                || (prev.is_ascii_uppercase() && next_lower)
+            {
+                out.push(sep);
+            }
+        }
+        out.push(c.to_ascii_lowercase());
+    }
+    out
+}
+
+fn name_of<T: TryFromPrimitive<Primitive = u8> + fmt::Debug>(b: u8) -> Option<String> {
+    T::try_from_primitive(b)
+        .ok()
+        .map(|v| words(&format!("{:?}", v), '-'))
+}
+
+fn value_of<T: TryFromPrimitive<Primitive = u8> + fmt::Debug>(s: &str) -> Option<u8> {
+    let s = s.to_ascii_lowercase().replace('_', "-");
+    (0..=u8::MAX).find(|&b| name_of::<T>(b).as_deref() == Some(s.as_str()))
+}
+
+/// The name of an opcode as cec-ctl prints it, like `SET_STREAM_PATH`.
+pub fn opcode_name(opcode: Opcode) -> String {
+    words(&format!("{:?}", opcode), '_').to_ascii_uppercase()
+}
+
+pub fn logical_address_name(addr: LogicalAddress) -> &'static str {
+    match addr {
+        LogicalAddress::TV => "TV",
+        LogicalAddress::RecordingDevice1 => "Recording 1",
+        LogicalAddress::RecordingDevice2 => "Recording 2",
+        LogicalAddress::Tuner1 => "Tuner 1",
+        LogicalAddress::PlaybackDevice1 => "Playback 1",
+        LogicalAddress::AudioSystem => "Audio System",
+        LogicalAddress::Tuner2 => "Tuner 2",
+        LogicalAddress::Tuner3 => "Tuner 3",
+        LogicalAddress::PlaybackDevice2 => "Playback 2",
+        LogicalAddress::RecordingDevice3 => "Recording 3",
+        LogicalAddress::Tuner4 => "Tuner 4",
+        LogicalAddress::PlaybackDevice3 => "Playback 3",
+        LogicalAddress::Reserved1 => "Reserved 1",
+        LogicalAddress::Reserved2 => "Reserved 2",
+        LogicalAddress::FreeUse => "Free Use",
+        LogicalAddress::Broadcast => "Broadcast",
+    }
+}
+
+pub(crate) fn hex(b: &[u8]) -> String {
+    b.iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// A frame's raw bytes as colon-separated hex. A frame that hasn't been given
+/// an initiator yet is shown as coming from the broadcast address.
+pub(crate) fn frame_hex(cmd: &CECCommand) -> String {
+    let header =
+        (cmd.initiator.unwrap_or(LogicalAddress::Broadcast) as u8) << 4 | cmd.destination as u8;
+    let mut raw = vec![header];
+    raw.extend(cmd.message.payload());
+    hex(&raw)
+}
+
+fn take<'a>(params: &mut &'a [u8], n: usize) -> Option<&'a [u8]> {
+    if params.len() < n {
+        return None;
+    }
+    let (head, tail) = params.split_at(n);
+    *params = tail;
+    Some(head)
+}
+
+impl Operand {
+    // Writes the operand's text form, consuming its bytes.
+    fn describe(&self, params: &mut &[u8], out: &mut String) -> Option<()> {
+        let (name, value) = match *self {
+            Operand::PhysAddr(name) => {
+                let b = take(params, 2)?;
+                (
+                    name,
+                    PhysicalAddress::from(u16::from_be_bytes([b[0], b[1]])).to_string(),
+                )
+            }
+            Operand::OptPhysAddr(_) if params.is_empty() => return Some(()),
+            Operand::OptPhysAddr(name) => return Operand::PhysAddr(name).describe(params, out),
+            Operand::Enum(name, name_of, _) => {
+                let b = take(params, 1)?[0];
+                (name, name_of(b).unwrap_or_else(|| format!("0x{:02x}", b)))
+            }
+            Operand::Bool(name) => {
+                let on = take(params, 1)?[0] != 0;
+                (name, if on { "on" } else { "off" }.to_string())
+            }
+            Operand::Byte(name) => (name, format!("0x{:02x}", take(params, 1)?[0])),
+            Operand::OptByte(_) if params.is_empty() => return Some(()),
+            Operand::OptByte(name) => return Operand::Byte(name).describe(params, out),
+            Operand::Extensible(name) => {
+                let len = params
+                    .iter()
+                    .position(|b| b & 0x80 == 0)
+                    .map_or(params.len(), |i| i + 1);
+                (name, hex(take(params, len)?))
+            }
+            Operand::AudioStatus => {
+                let b = take(params, 1)?[0];
+                let mute = if b & 0x80 != 0 { "on" } else { "off" };
+                out.push_str(&format!(" aud-mute-status={}", mute));
+                ("aud-vol-status", (b & 0x7f).to_string())
+            }
+            Operand::VendorId => {
+                let b = take(params, 3)?;
+                (
+                    "vendor-id",
+                    format!("0x{:02x}{:02x}{:02x}", b[0], b[1], b[2]),
+                )
+            }
+            Operand::Text(name) => {
+                let b = take(params, params.len())?;
+                (name, String::from_utf8_lossy(b).into_owned())
+            }
+            Operand::Data(name) => (name, hex(take(params, params.len())?)),
+        };
+        out.push_str(&format!(" {}={}", name, value));
+        Some(())
+    }
+
+    // Appends the operand's bytes, parsed from the remaining words.
+    fn encode<'a>(
+        &self,
+        words: &mut impl Iterator<Item = &'a str>,
+        raw: &mut Vec<u8>,
+    ) -> Result<(), Error> {
+        // The operand name is optional, and only there for the reader.
+        let value = |s: &'a str| s.split_once('=').map_or(s, |(_, v)| v);
+        let mut next = || words.next().map(value).ok_or(Error::InputTooShort);
+        match *self {
+            Operand::PhysAddr(_) => {
+                raw.extend(&u16::from(next()?.parse::<PhysicalAddress>()?).to_be_bytes())
+            }
+            Operand::OptPhysAddr(_) => {
+                if let Some(addr) = words.next() {
+                    raw.extend(&u16::from(value(addr).parse::<PhysicalAddress>()?).to_be_bytes())
+                }
+            }
+            Operand::Enum(_, _, value_of) => {
+                let s = next()?;
+                raw.push(
+                    value_of(s)
+                        .or_else(|| parse_byte(s))
+                        .ok_or(Error::InvalidOperand)?,
+                )
+            }
+            Operand::Bool(_) => raw.push(match next()? {
+                "on" | "1" => 1,
+                "off" | "0" => 0,
+                _ => return Err(Error::InvalidOperand),
+            }),
+            Operand::Byte(_) => raw.push(parse_byte(next()?).ok_or(Error::InvalidOperand)?),
+            Operand::OptByte(_) => {
+                if let Some(b) = words.next() {
+                    raw.push(parse_byte(value(b)).ok_or(Error::InvalidOperand)?)
+                }
+            }
+            Operand::Extensible(_) => {
+                raw.extend(parse_hex(&next()?.replace(':', "")).ok_or(Error::InvalidOperand)?)
+            }
+            Operand::AudioStatus => {
+                let mute = match next()? {
+                    "on" | "1" => 0x80,
+                    "off" | "0" => 0,
+                    _ => return Err(Error::InvalidOperand),
+                };
+                let volume: u8 = next()?.parse().map_err(|_| Error::InvalidOperand)?;
+                raw.push(mute | (volume & 0x7f));
+            }
+            Operand::VendorId => {
+                let s = next()?;
+                let id = u32::from_str_radix(s.trim_start_matches("0x"), 16)
+                    .map_err(|_| Error::InvalidOperand)?;
+                raw.extend(&id.to_be_bytes()[1..]);
+            }
+            Operand::Text(_) => raw.extend(rest(words).join(" ").as_bytes()),
+            Operand::Data(_) => {
+                let data = rest(words).concat().replace(':', "");
+                raw.extend(parse_hex(&data).ok_or(Error::InvalidOperand)?);
+            }
+        }
+        Ok(())
+    }
+}
+
+// The remaining words, without the operand name on the first.
+fn rest<'a>(words: &mut impl Iterator<Item = &'a str>) -> Vec<&'a str> {
+    words
+        .enumerate()
+        .map(|(i, w)| match w.split_once('=') {
+            Some((_, v)) if i == 0 => v,
+            _ => w,
+        })
+        .collect()
+}
+
+fn parse_byte(s: &str) -> Option<u8> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u8::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+fn parse_hex(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 2 != 0 {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Parses an opcode from its name in any case, like `set-stream-path` or
+/// `SET_STREAM_PATH`, or from its value, like `0x86`.
+pub fn parse_opcode(s: &str) -> Option<Opcode> {
+    let opcode = value_of::<Opcode>(s).or_else(|| parse_byte(s))?;
+    Opcode::try_from(opcode).ok()
+}
+
+/// Parses a logical address from its number or its name, like `playback-1`.
+pub fn parse_logical_address(s: &str) -> Option<LogicalAddress> {
+    if let Ok(n) = s.parse::<u8>() {
+        return LogicalAddress::try_from(n).ok();
+    }
+    let s = s.to_ascii_lowercase().replace('_', "-");
+    (0..=0xf)
+        .filter_map(|n| LogicalAddress::try_from(n).ok())
+        .find(|&addr| {
+            logical_address_name(addr)
+                .to_ascii_lowercase()
+                .replace(' ', "-")
+                == s
+        })
+}
+
+impl fmt::Display for CECCommand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let initiator = match self.initiator {
+            Some(LogicalAddress::Broadcast) => "Unregistered",
+            Some(addr) => logical_address_name(addr),
+            None => "Local",
+        };
+        let opcode = self.message.get_opcode();
+        let mut line = format!(
+            "{} -> {}: {}",
+            initiator,
+            logical_address_name(self.destination),
+            opcode_name(opcode)
+        );
+        let params = self.message.get_parameters();
+        let mut rest = &params[..];
+        for operand in operands(opcode) {
+            if operand.describe(&mut rest, &mut line).is_none() {
+                break;
+            }
+        }
+        if !rest.is_empty() {
+            line.push_str(&format!(" extra={}", hex(rest)));
+        }
+        f.write_str(&line)
+    }
+}
+
+impl str::FromStr for CECCommand {
+    type Err = Error;
+
+    /// Parses a frame from its raw bytes, like `10:44:41`, or from a line like
+    /// `tx 0 user-control-pressed volume-up`. Frames parsed from a line have
+    /// no initiator, as that's filled in when they're sent.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let bad = || Error::BadFrameText(s.to_string());
+        if s.split(':').all(|b| b.len() == 2) {
+            if let Some(raw) = parse_hex(&s.replace(':', "")) {
+                return CECCommand::from_raw(&raw);
+            }
+        }
+        let mut words = s.split_whitespace().peekable();
+        if words.peek() == Some(&"tx") {
+            words.next();
+        }
+        let destination = words
+            .next()
+            .and_then(parse_logical_address)
+            .ok_or_else(bad)?;
+        let opcode = words.next().and_then(value_of::<Opcode>).ok_or_else(bad)?;
+        let mut raw = vec![0xf0 | destination as u8, opcode];
+        for operand in operands(Opcode::try_from(opcode)?) {
+            operand.encode(&mut words, &mut raw)?;
+        }
+        if words.next().is_some() {
+            return Err(bad());
+        }
+        Ok(CECCommand {
+            initiator: None,
+            ..CECCommand::from_raw(&raw)?
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cec::fmt::*;
+    use crate::cec::{AudioOutputCompensated, AudioStatus, CECMessage, Latency};
+
+    fn cmd(
+        initiator: LogicalAddress,
+        destination: LogicalAddress,
+        message: CECMessage,
+    ) -> CECCommand {
+        CECCommand {
+            initiator: Some(initiator),
+            destination,
+            message,
+        }
+    }
+
+    #[test]
+    fn names_opcodes_like_cec_ctl() {
+        assert_eq!(opcode_name(Opcode::SetStreamPath), "SET_STREAM_PATH");
+        assert_eq!(opcode_name(Opcode::GiveOSDName), "GIVE_OSD_NAME");
+        assert_eq!(opcode_name(Opcode::CECVersion), "CEC_VERSION");
+        assert_eq!(opcode_name(Opcode::ReportARCInited), "REPORT_ARC_INITED");
+        assert_eq!(name_of::<UserControl>(0x71).unwrap(), "f1-blue");
+    }
+
+    #[test]
+    fn describes_frames() {
+        assert_eq!(
+            cmd(
+                LogicalAddress::TV,
+                LogicalAddress::PlaybackDevice1,
+                CECMessage::SetStreamPath {
+                    physical_address: 0x1000.into()
+                }
+            )
+            .to_string(),
+            "TV -> Playback 1: SET_STREAM_PATH phys-addr=1.0.0.0"
+        );
+        assert_eq!(
+            cmd(
+                LogicalAddress::AudioSystem,
+                LogicalAddress::TV,
+                CECMessage::ReportAudioStatus {
+                    audio_status: AudioStatus {
+                        muted: true,
+                        volume: 12
+                    }
+                }
+            )
+            .to_string(),
+            "Audio System -> TV: REPORT_AUDIO_STATUS aud-mute-status=on aud-vol-status=12"
+        );
+        assert_eq!(
+            cmd(
+                LogicalAddress::TV,
+                LogicalAddress::RecordingDevice1,
+                CECMessage::FeatureAbort {
+                    feature_opcode: Opcode::GiveOSDName,
+                    abort_reason: AbortReason::Refused,
+                }
+            )
+            .to_string(),
+            "TV -> Recording 1: FEATURE_ABORT opcode=give-osd-name reason=refused"
+        );
+    }
+
+    #[test]
+    fn parses_raw_frames() {
+        let cmd: CECCommand = "10:44:41".parse().unwrap();
+        assert_eq!(cmd.initiator, Some(LogicalAddress::RecordingDevice1));
+        assert_eq!(cmd.destination, LogicalAddress::TV);
+        assert_eq!(
+            cmd.message,
+            CECMessage::UserControlPressed {
+                user_control_code: UserControl::VolumeUp
+            }
+        );
+    }
+
+    #[test]
+    fn parses_command_lines() {
+        let cmd: CECCommand = "tx 0 user-control-pressed volume-up".parse().unwrap();
+        assert_eq!(cmd.initiator, None);
+        assert_eq!(cmd.destination, LogicalAddress::TV);
+        assert_eq!(
+            cmd.message,
+            CECMessage::UserControlPressed {
+                user_control_code: UserControl::VolumeUp
+            }
+        );
+        let cmd: CECCommand = "broadcast SET_STREAM_PATH phys-addr=1.2.0.0"
+            .parse()
+            .unwrap();
+        assert_eq!(cmd.destination, LogicalAddress::Broadcast);
+        assert_eq!(
+            cmd.message,
+            CECMessage::SetStreamPath {
+                physical_address: 0x1200.into()
+            }
+        );
+        assert!("tx 0 standby now".parse::<CECCommand>().is_err());
+        assert!("tx 0 user-control-pressed".parse::<CECCommand>().is_err());
+    }
+
+    #[test]
+    fn text_round_trips() {
+        for message in [
+            CECMessage::SetOSDName {
+                name: "living room".to_string(),
+            },
+            CECMessage::VendorCommandWithID {
+                vendor_id: 0x00e091,
+                vendor_data: vec![0x01, 0x02],
+            },
+            CECMessage::ReportAudioStatus {
+                audio_status: AudioStatus {
+                    muted: false,
+                    volume: 42,
+                },
+            },
+            CECMessage::SystemAudioModeRequest {
+                physical_address: None,
+            },
+            CECMessage::ReportCurrentLatency {
+                physical_address: 0x0000.into(),
+                latency: Latency {
+                    video_latency: 21,
+                    low_latency_mode: false,
+                    audio_output_compensated: AudioOutputCompensated::PartiallyCompensated,
+                    audio_output_delay: Some(4),
+                },
+            },
+            CECMessage::Standby,
+        ] {
+            let sent = cmd(LogicalAddress::PlaybackDevice1, LogicalAddress::TV, message);
+            let line = sent.to_string();
+            let (_, rest) = line.split_once(": ").unwrap();
+            let parsed: CECCommand = format!("tx 0 {}", rest).parse().unwrap();
+            assert_eq!(parsed.message, sent.message, "{}", line);
+        }
+    }
+}
@@ -0,0 +1,141 @@
+// Rules a follower has to obey when handling incoming messages.
+//
+// Every opcode is defined as either directly addressed, broadcast, or allowed
+// to be both. Messages that arrive with the wrong addressing must be ignored,
+// and directly addressed messages that a device can't handle must be answered
+// with a <Feature Abort> rather than dropped, or the initiator may keep
+// retrying.
+
+use crate::cec::{AbortReason, CECCommand, CECMessage, LogicalAddress, Opcode};
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub(crate) enum Addressing {
+    Directed,
+    Broadcast,
+    Either,
+}
+
+pub(crate) fn addressing(opcode: Opcode) -> Addressing {
+    match opcode {
+        Opcode::ActiveSource
+        | Opcode::RequestActiveSource
+        | Opcode::RoutingChange
+        | Opcode::RoutingInformation
+        | Opcode::SetStreamPath
+        | Opcode::ReportPhysicalAddress
+        | Opcode::DeviceVendorID
+        | Opcode::SetMenuLanguage
+        | Opcode::ReportFeatures
+        | Opcode::RequestCurrentLatency
+        | Opcode::ReportCurrentLatency
+        | Opcode::CDC => Addressing::Broadcast,
+        // CEC 2.0 lets devices broadcast their power status when it changes.
+        Opcode::Standby
+        | Opcode::ReportPowerStatus
+        | Opcode::SetSystemAudioMode
+        | Opcode::VendorCommandWithID
+        | Opcode::VendorRemoteButtonDown
+        | Opcode::VendorRemoteButtonUp => Addressing::Either,
+        _ => Addressing::Directed,
+    }
+}
+
+/// Whether a received message used the addressing its opcode requires.
+pub(crate) fn has_valid_addressing(cmd: &CECCommand) -> bool {
+    let broadcast = cmd.destination == LogicalAddress::Broadcast;
+    match addressing(cmd.message.get_opcode()) {
+        Addressing::Directed => !broadcast,
+        Addressing::Broadcast => broadcast,
+        Addressing::Either => true,
+    }
+}
+
+/// The <Feature Abort> to send back for a message we can't handle, or None if
+/// the spec forbids replying to it.
+pub(crate) fn feature_abort(cmd: &CECCommand, reason: AbortReason) -> Option<CECMessage> {
+    match (&cmd.message, cmd.destination) {
+        (_, LogicalAddress::Broadcast) => None,
+        // Aborting an abort could loop forever.
+        (CECMessage::FeatureAbort { .. }, _) => None,
+        (msg, _) => Some(CECMessage::FeatureAbort {
+            feature_opcode: msg.get_opcode(),
+            abort_reason: reason,
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cec::follower::*;
+    use crate::cec::PowerStatus;
+
+    fn cmd(destination: LogicalAddress, message: CECMessage) -> CECCommand {
+        CECCommand {
+            initiator: Some(LogicalAddress::TV),
+            destination,
+            message,
+        }
+    }
+
+    #[test]
+    fn rejects_wrong_addressing() {
+        assert!(!has_valid_addressing(&cmd(
+            LogicalAddress::Broadcast,
+            CECMessage::GiveOSDName
+        )));
+        assert!(!has_valid_addressing(&cmd(
+            LogicalAddress::PlaybackDevice1,
+            CECMessage::ActiveSource {
+                physical_address: 0x1000.into()
+            }
+        )));
+        assert!(has_valid_addressing(&cmd(
+            LogicalAddress::Broadcast,
+            CECMessage::Standby
+        )));
+        assert!(has_valid_addressing(&cmd(
+            LogicalAddress::PlaybackDevice1,
+            CECMessage::Standby
+        )));
+        assert!(has_valid_addressing(&cmd(
+            LogicalAddress::Broadcast,
+            CECMessage::ReportPowerStatus {
+                power_status: PowerStatus::Standby
+            }
+        )));
+    }
+
+    #[test]
+    fn never_aborts_broadcasts_or_aborts() {
+        assert_eq!(
+            feature_abort(
+                &cmd(LogicalAddress::Broadcast, CECMessage::Standby),
+                AbortReason::UnrecognisedOpcode
+            ),
+            None
+        );
+        assert_eq!(
+            feature_abort(
+                &cmd(
+                    LogicalAddress::PlaybackDevice1,
+                    CECMessage::FeatureAbort {
+                        feature_opcode: Opcode::Play,
+                        abort_reason: AbortReason::Refused,
+                    }
+                ),
+                AbortReason::UnrecognisedOpcode
+            ),
+            None
+        );
+        assert_eq!(
+            feature_abort(
+                &cmd(LogicalAddress::PlaybackDevice1, CECMessage::RecordOff),
+                AbortReason::UnrecognisedOpcode
+            ),
+            Some(CECMessage::FeatureAbort {
+                feature_opcode: Opcode::RecordOff,
+                abort_reason: AbortReason::UnrecognisedOpcode,
+            })
+        );
+    }
+}
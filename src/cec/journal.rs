@@ -0,0 +1,273 @@
+// A bounded record of the frames sent and received, for working out after the
+// fact what the TV and the other devices on the bus were doing.
+//
+// Entries can also be appended to a JSONL file on disk, which is moved aside to
+// "<path>.1" once it grows past a size limit so it can't fill the SD card.
+
+use crate::cec::fmt::frame_hex;
+use crate::cec::{CECCommand, LogicalAddress, Opcode};
+use log::warn;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+/// How many entries are kept in memory by default.
+pub const DEFAULT_CAPACITY: usize = 1000;
+
+#[derive(Copy, Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Rx,
+    Tx,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Entry {
+    pub time: String,
+    pub direction: Direction,
+    /// Whether a sent frame was acknowledged. Not set for received frames.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ack: Option<bool>,
+    /// The raw frame, as colon-separated hex.
+    pub frame: String,
+    /// The frame decoded by [`crate::cec::fmt`].
+    pub message: String,
+    #[serde(skip)]
+    cmd: CECCommand,
+}
+
+/// Selects entries from the journal. Fields that are None match everything.
+#[derive(Clone, Debug, Default)]
+pub struct Filter {
+    pub opcode: Option<Opcode>,
+    /// Matches frames sent either to or from this address.
+    pub address: Option<LogicalAddress>,
+}
+
+impl Filter {
+    fn matches(&self, entry: &Entry) -> bool {
+        self.opcode
+            .map_or(true, |op| entry.cmd.message.get_opcode() == op)
+            && self.address.map_or(true, |addr| {
+                entry.cmd.initiator == Some(addr) || entry.cmd.destination == addr
+            })
+    }
+}
+
+struct Rotation {
+    path: PathBuf,
+    max_bytes: u64,
+    file: File,
+}
+
+impl Rotation {
+    fn open(path: PathBuf, max_bytes: u64) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        Ok(Rotation {
+            path,
+            max_bytes,
+            file,
+        })
+    }
+
+    fn write(&mut self, line: &str) -> io::Result<()> {
+        if self.file.metadata()?.len() + line.len() as u64 > self.max_bytes {
+            let mut old = self.path.clone().into_os_string();
+            old.push(".1");
+            fs::rename(&self.path, old)?;
+            *self = Rotation::open(self.path.clone(), self.max_bytes)?;
+        }
+        self.file.write_all(line.as_bytes())
+    }
+}
+
+pub struct Journal {
+    capacity: usize,
+    entries: Mutex<VecDeque<Entry>>,
+    rotation: Mutex<Option<Rotation>>,
+}
+
+impl Default for Journal {
+    fn default() -> Self {
+        Journal::new(DEFAULT_CAPACITY)
+    }
+}
+
+impl Journal {
+    /// A journal that keeps the last `capacity` entries in memory. With a
+    /// capacity of 0 nothing is kept, though entries still go to the file
+    /// given to rotate_to.
+    pub fn new(capacity: usize) -> Self {
+        Journal {
+            capacity,
+            entries: Mutex::new(VecDeque::with_capacity(capacity)),
+            rotation: Mutex::new(None),
+        }
+    }
+
+    /// Also appends every entry from now on to a JSONL file at `path`, moving
+    /// it to `<path>.1` when it would grow past `max_bytes`.
+    pub fn rotate_to(&self, path: impl Into<PathBuf>, max_bytes: u64) -> io::Result<()> {
+        *self.rotation.lock().unwrap() = Some(Rotation::open(path.into(), max_bytes)?);
+        Ok(())
+    }
+
+    pub fn record(&self, direction: Direction, cmd: &CECCommand, ack: Option<bool>) {
+        let entry = Entry {
+            time: OffsetDateTime::now_utc()
+                .format(&Rfc3339)
+                .unwrap_or_default(),
+            direction,
+            ack,
+            frame: frame_hex(cmd),
+            message: cmd.to_string(),
+            cmd: cmd.clone(),
+        };
+        if let Some(rotation) = &mut *self.rotation.lock().unwrap() {
+            let written = serde_json::to_string(&entry)
+                .map_err(io::Error::from)
+                .and_then(|line| rotation.write(&(line + "\n")));
+            if let Err(e) = written {
+                warn!("failed to write to {:?}: {}", rotation.path, e);
+            }
+        }
+        if self.capacity == 0 {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() == self.capacity {
+            entries.pop_front();
+        }
+        entries.push_back(entry);
+    }
+
+    /// The entries that match `filter`, oldest first.
+    pub fn entries(&self, filter: &Filter) -> Vec<Entry> {
+        self.entries
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|e| filter.matches(e))
+            .cloned()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cec::journal::*;
+    use crate::cec::CECMessage;
+
+    fn cmd(
+        initiator: LogicalAddress,
+        destination: LogicalAddress,
+        message: CECMessage,
+    ) -> CECCommand {
+        CECCommand {
+            initiator: Some(initiator),
+            destination,
+            message,
+        }
+    }
+
+    #[test]
+    fn drops_oldest_entries() {
+        let journal = Journal::new(2);
+        for addr in [
+            LogicalAddress::TV,
+            LogicalAddress::Tuner1,
+            LogicalAddress::AudioSystem,
+        ] {
+            journal.record(
+                Direction::Rx,
+                &cmd(addr, LogicalAddress::Broadcast, CECMessage::Standby),
+                None,
+            );
+        }
+        let entries = journal.entries(&Filter::default());
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].frame, "3f:36");
+        assert_eq!(entries[1].frame, "5f:36");
+
+        let disabled = Journal::new(0);
+        disabled.record(
+            Direction::Rx,
+            &cmd(
+                LogicalAddress::TV,
+                LogicalAddress::Broadcast,
+                CECMessage::Standby,
+            ),
+            None,
+        );
+        assert!(disabled.entries(&Filter::default()).is_empty());
+    }
+
+    #[test]
+    fn filters_by_opcode_and_address() {
+        let journal = Journal::default();
+        journal.record(
+            Direction::Rx,
+            &cmd(
+                LogicalAddress::TV,
+                LogicalAddress::Broadcast,
+                CECMessage::Standby,
+            ),
+            None,
+        );
+        journal.record(
+            Direction::Tx,
+            &cmd(
+                LogicalAddress::PlaybackDevice1,
+                LogicalAddress::AudioSystem,
+                CECMessage::GiveAudioStatus,
+            ),
+            Some(false),
+        );
+        let by_opcode = journal.entries(&Filter {
+            opcode: Some(Opcode::Standby),
+            address: None,
+        });
+        assert_eq!(by_opcode.len(), 1);
+        assert_eq!(by_opcode[0].message, "TV -> Broadcast: STANDBY");
+        let by_address = journal.entries(&Filter {
+            opcode: None,
+            address: Some(LogicalAddress::AudioSystem),
+        });
+        assert_eq!(by_address.len(), 1);
+        assert_eq!(by_address[0].direction, Direction::Tx);
+        assert_eq!(by_address[0].ack, Some(false));
+    }
+
+    #[test]
+    fn rotates_file() {
+        let dir = std::env::temp_dir().join(format!("cecvol-journal-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("cec.jsonl");
+        let journal = Journal::default();
+        journal.rotate_to(&path, 250).unwrap();
+        for _ in 0..3 {
+            journal.record(
+                Direction::Rx,
+                &cmd(
+                    LogicalAddress::TV,
+                    LogicalAddress::Broadcast,
+                    CECMessage::Standby,
+                ),
+                None,
+            );
+        }
+        let current = fs::read_to_string(&path).unwrap();
+        let rotated = fs::read_to_string(dir.join("cec.jsonl.1")).unwrap();
+        assert_eq!(current.lines().count() + rotated.lines().count(), 3);
+        let line: serde_json::Value =
+            serde_json::from_str(current.lines().next().unwrap()).unwrap();
+        assert_eq!(line["direction"], "rx");
+        assert_eq!(line["frame"], "0f:36");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,635 @@
+// A connection through the Linux kernel's CEC framework, for any adapter with
+// a driver that exposes /dev/cecN.
+//
+// The kernel claims the logical address for us, and the adapter is opened as
+// the exclusive follower in passthrough mode, so we answer every message
+// ourselves just like with the VideoCore service. A Monitor can watch the
+// rest of the bus through a second handle.
+//
+// See https://docs.kernel.org/userspace-api/media/cec/cec-api.html
+
+use crate::cec::adapter::{self, AdapterError, Config, SharedRxCallback, Worker};
+use crate::cec::linux_ioctl::{self, Caps, Event, LogAddrs, Msg};
+use crate::cec::{
+    own_features, AllDeviceTypes, CECCommand, CECConnection, CECError, DeviceType, LogicalAddress,
+    PhysicalAddress, SharedTxCallback, TxCallback, CEC_VERSION,
+};
+use log::{info, warn};
+use nix::errno::Errno;
+use nix::poll::{poll, PollFd, PollFlags};
+use std::convert::TryFrom;
+use std::fs::{File, OpenOptions};
+use std::os::raw::c_int;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+// Failed ioctls in a row after which the device is taken to be gone, like a
+// USB adapter that was unplugged.
+const MAX_FAILURES: u32 = 10;
+const OSD_NAME_LENGTH: usize = 14;
+
+/// Which of a message and an event a device has waiting.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Ready {
+    pub message: bool,
+    pub event: bool,
+}
+
+/// The ioctls used on a CEC device, so that tests can stand in for the kernel.
+pub trait CecDevice: Send + Sync {
+    fn caps(&self) -> nix::Result<Caps>;
+    fn phys_addr(&self) -> nix::Result<u16>;
+    fn log_addrs(&self) -> nix::Result<LogAddrs>;
+    fn set_log_addrs(&self, addrs: &mut LogAddrs) -> nix::Result<()>;
+    fn set_mode(&self, mode: u32) -> nix::Result<()>;
+    /// Sends a message, blocking until its tx_status is filled in.
+    fn transmit(&self, msg: &mut Msg) -> nix::Result<()>;
+    fn receive(&self, msg: &mut Msg) -> nix::Result<()>;
+    fn dequeue_event(&self, event: &mut Event) -> nix::Result<()>;
+    /// Waits up to `timeout` for a message or an event to be ready.
+    fn poll(&self, timeout: Duration) -> nix::Result<Ready>;
+}
+
+fn retry<F>(mut func: F) -> nix::Result<c_int>
+where
+    F: FnMut() -> nix::Result<c_int>,
+{
+    let r = func();
+    match r {
+        Err(Errno::EINTR) => retry(func),
+        _ => r,
+    }
+}
+
+/// A CEC device node such as /dev/cec0.
+pub struct DevCec {
+    file: File,
+}
+
+impl DevCec {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, AdapterError> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        Ok(DevCec { file })
+    }
+
+    fn fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+impl CecDevice for DevCec {
+    fn caps(&self) -> nix::Result<Caps> {
+        let mut caps = Caps::default();
+        retry(|| unsafe { linux_ioctl::adap_g_caps(self.fd(), &mut caps) })?;
+        Ok(caps)
+    }
+
+    fn phys_addr(&self) -> nix::Result<u16> {
+        let mut addr = 0;
+        retry(|| unsafe { linux_ioctl::adap_g_phys_addr(self.fd(), &mut addr) })?;
+        Ok(addr)
+    }
+
+    fn log_addrs(&self) -> nix::Result<LogAddrs> {
+        let mut addrs = LogAddrs::default();
+        retry(|| unsafe { linux_ioctl::adap_g_log_addrs(self.fd(), &mut addrs) })?;
+        Ok(addrs)
+    }
+
+    fn set_log_addrs(&self, addrs: &mut LogAddrs) -> nix::Result<()> {
+        retry(|| unsafe { linux_ioctl::adap_s_log_addrs(self.fd(), addrs) }).map(|_| ())
+    }
+
+    fn set_mode(&self, mode: u32) -> nix::Result<()> {
+        retry(|| unsafe { linux_ioctl::s_mode(self.fd(), &mode) }).map(|_| ())
+    }
+
+    fn transmit(&self, msg: &mut Msg) -> nix::Result<()> {
+        retry(|| unsafe { linux_ioctl::transmit(self.fd(), msg) }).map(|_| ())
+    }
+
+    fn receive(&self, msg: &mut Msg) -> nix::Result<()> {
+        retry(|| unsafe { linux_ioctl::receive(self.fd(), msg) }).map(|_| ())
+    }
+
+    fn dequeue_event(&self, event: &mut Event) -> nix::Result<()> {
+        retry(|| unsafe { linux_ioctl::dqevent(self.fd(), event) }).map(|_| ())
+    }
+
+    fn poll(&self, timeout: Duration) -> nix::Result<Ready> {
+        let mut fds = [PollFd::new(
+            self.fd(),
+            PollFlags::POLLIN | PollFlags::POLLPRI,
+        )];
+        match poll(&mut fds, timeout.as_millis() as c_int) {
+            Err(Errno::EINTR) => return Ok(Ready::default()),
+            r => r?,
+        };
+        let revents = fds[0].revents().unwrap_or(PollFlags::empty());
+        Ok(Ready {
+            message: revents.contains(PollFlags::POLLIN),
+            event: revents.contains(PollFlags::POLLPRI),
+        })
+    }
+}
+
+fn log_addr_type(device_type: DeviceType) -> u8 {
+    match device_type {
+        DeviceType::TV => linux_ioctl::LOG_ADDR_TYPE_TV,
+        DeviceType::RecordingDevice => linux_ioctl::LOG_ADDR_TYPE_RECORD,
+        DeviceType::Tuner => linux_ioctl::LOG_ADDR_TYPE_TUNER,
+        DeviceType::PlaybackDevice => linux_ioctl::LOG_ADDR_TYPE_PLAYBACK,
+        DeviceType::AudioSystem => linux_ioctl::LOG_ADDR_TYPE_AUDIOSYSTEM,
+        DeviceType::VideoProcessor => linux_ioctl::LOG_ADDR_TYPE_SPECIFIC,
+        DeviceType::Reserved | DeviceType::Switch => linux_ioctl::LOG_ADDR_TYPE_UNREGISTERED,
+    }
+}
+
+impl Config {
+    // The kernel finds out the physical address itself.
+    fn to_log_addrs(&self) -> LogAddrs {
+        let mut addrs = LogAddrs {
+            cec_version: CEC_VERSION as u8,
+            num_log_addrs: 1,
+            vendor_id: self.vendor_id,
+            flags: linux_ioctl::LOG_ADDRS_FL_ALLOW_UNREG_FALLBACK,
+            ..Default::default()
+        };
+        let name = self.osd_name.as_bytes();
+        let name = &name[..name.len().min(OSD_NAME_LENGTH)];
+        addrs.osd_name[..name.len()].copy_from_slice(name);
+        addrs.primary_device_type[0] = self.device_type as u8;
+        addrs.log_addr_type[0] = log_addr_type(self.device_type);
+        addrs.all_device_types[0] = AllDeviceTypes::of(self.device_type).to_byte();
+        let features = own_features(self.device_type).to_bytes();
+        // The first two bytes are the version and device types, set above.
+        addrs.features[0][..features.len() - 2].copy_from_slice(&features[2..]);
+        addrs
+    }
+}
+
+// The lowest logical address in a mask from the kernel, which is the one we
+// send from.
+fn primary_address(log_addr_mask: u16) -> LogicalAddress {
+    (0..15)
+        .find(|i| log_addr_mask & (1 << i) != 0)
+        .and_then(|i| LogicalAddress::try_from(i as u8).ok())
+        .unwrap_or(LogicalAddress::Broadcast)
+}
+
+fn tx_result(tx_status: u8) -> Result<(), CECError> {
+    if tx_status & linux_ioctl::TX_STATUS_OK != 0 {
+        Ok(())
+    } else if tx_status & linux_ioctl::TX_STATUS_NACK != 0 {
+        Err(CECError::NoAck)
+    } else if tx_status & (linux_ioctl::TX_STATUS_ARB_LOST | linux_ioctl::TX_STATUS_LOW_DRIVE) != 0
+    {
+        Err(CECError::Busy)
+    } else {
+        Err(AdapterError::TxFailed(tx_status).into())
+    }
+}
+
+// Polls `device` until dropped, passing on each message and event, or until
+// it keeps failing.
+fn spawn_receiver(
+    device: Arc<dyn CecDevice>,
+    mut on_msg: impl FnMut(&Msg) + Send + 'static,
+    mut on_event: impl FnMut(&Event) + Send + 'static,
+) -> Worker {
+    Worker::spawn(move |stop| {
+        let mut failures = 0;
+        while !stop.load(Ordering::Relaxed) {
+            if failures >= MAX_FAILURES {
+                warn!(
+                    "giving up on CEC device after {} failures in a row",
+                    failures
+                );
+                return;
+            }
+            let ready = match device.poll(POLL_INTERVAL) {
+                Ok(ready) => ready,
+                Err(e) => {
+                    warn!("polling CEC device failed: {}", e);
+                    failures += 1;
+                    thread::sleep(POLL_INTERVAL);
+                    continue;
+                }
+            };
+            let mut failed = false;
+            if ready.event {
+                let mut event = Event::default();
+                match device.dequeue_event(&mut event) {
+                    Ok(()) => on_event(&event),
+                    Err(Errno::EAGAIN) => (),
+                    Err(e) => {
+                        warn!("dequeuing CEC event failed: {}", e);
+                        failed = true;
+                    }
+                }
+            }
+            if ready.message {
+                let mut msg = Msg::default();
+                match device.receive(&mut msg) {
+                    Ok(()) => on_msg(&msg),
+                    Err(Errno::EAGAIN) | Err(Errno::ETIMEDOUT) => (),
+                    Err(e) => {
+                        warn!("receiving CEC message failed: {}", e);
+                        failed = true;
+                    }
+                }
+            }
+            failures = if failed { failures + 1 } else { 0 };
+        }
+    })
+}
+
+pub struct LinuxConnection {
+    device: Arc<dyn CecDevice>,
+    logical_address: Arc<Mutex<LogicalAddress>>,
+    physical_address: Arc<Mutex<PhysicalAddress>>,
+    rx_callback: SharedRxCallback,
+    tx_callback: SharedTxCallback,
+    _receiver: Worker,
+}
+
+impl LinuxConnection {
+    pub fn open(path: impl AsRef<Path>, config: &Config) -> Result<Self, AdapterError> {
+        Self::with_device(Arc::new(DevCec::open(path)?), config)
+    }
+
+    /// Sets up `device` as the only follower, claiming a logical address for
+    /// `config` if the adapter leaves that to us.
+    pub fn with_device(device: Arc<dyn CecDevice>, config: &Config) -> Result<Self, AdapterError> {
+        let caps = device.caps()?;
+        info!(
+            "CEC adapter {} ({})",
+            String::from_utf8_lossy(&caps.name).trim_end_matches('\0'),
+            String::from_utf8_lossy(&caps.driver).trim_end_matches('\0')
+        );
+        if caps.capabilities & linux_ioctl::CAP_TRANSMIT == 0 {
+            return Err(AdapterError::Unsupported("transmit"));
+        }
+        device.set_mode(linux_ioctl::MODE_INITIATOR | linux_ioctl::MODE_EXCL_FOLLOWER_PASSTHRU)?;
+        if caps.capabilities & linux_ioctl::CAP_LOG_ADDRS != 0 {
+            // Addresses left from an earlier run have to be cleared before
+            // they can be set again.
+            device.set_log_addrs(&mut LogAddrs::default())?;
+            device.set_log_addrs(&mut config.to_log_addrs())?;
+        }
+        let logical_address = primary_address(device.log_addrs()?.log_addr_mask);
+        let physical_address = PhysicalAddress::from(device.phys_addr()?);
+        info!(
+            "logical address {:?}, physical address {}",
+            logical_address, physical_address
+        );
+
+        let logical_address = Arc::new(Mutex::new(logical_address));
+        let physical_address = Arc::new(Mutex::new(physical_address));
+        let rx_callback: SharedRxCallback = Arc::new(Mutex::new(None));
+        let receiver = {
+            let rx_callback = rx_callback.clone();
+            let logical_address = logical_address.clone();
+            let physical_address = physical_address.clone();
+            spawn_receiver(
+                device.clone(),
+                move |msg| match CECCommand::from_raw(msg.frame()) {
+                    Ok(cmd) => {
+                        if let Some(cb) = &mut *rx_callback.lock().unwrap() {
+                            cb(&cmd)
+                        }
+                    }
+                    Err(e) => info!("ignoring frame {:02x?}: {}", msg.frame(), e),
+                },
+                move |event| match event.event {
+                    linux_ioctl::EVENT_STATE_CHANGE => {
+                        let (phys, mask) = event.state_change();
+                        let addr = primary_address(mask);
+                        info!(
+                            "adapter now has logical address {:?}, physical address {}",
+                            addr,
+                            PhysicalAddress::from(phys)
+                        );
+                        *logical_address.lock().unwrap() = addr;
+                        *physical_address.lock().unwrap() = PhysicalAddress::from(phys);
+                    }
+                    linux_ioctl::EVENT_LOST_MSGS => {
+                        warn!("kernel dropped {} CEC messages", event.lost_msgs())
+                    }
+                    other => info!("ignoring CEC event {}", other),
+                },
+            )
+        };
+        Ok(LinuxConnection {
+            device,
+            logical_address,
+            physical_address,
+            rx_callback,
+            tx_callback: Arc::new(Mutex::new(None)),
+            _receiver: receiver,
+        })
+    }
+}
+
+impl CECConnection for LinuxConnection {
+    fn transmit(&self, cmd: CECCommand) -> Result<(), CECError> {
+        let own = *self.logical_address.lock().unwrap();
+        adapter::transmit(
+            cmd,
+            own,
+            &self.tx_callback,
+            |frame| {
+                let mut msg = Msg::new(frame);
+                self.device
+                    .transmit(&mut msg)
+                    .map(|()| msg.tx_status)
+                    .map_err(AdapterError::from)
+            },
+            |&tx_status| tx_result(tx_status),
+        )
+    }
+
+    fn get_logical_address(&self) -> Result<LogicalAddress, CECError> {
+        Ok(*self.logical_address.lock().unwrap())
+    }
+
+    fn get_physical_address(&self) -> Result<PhysicalAddress, CECError> {
+        Ok(*self.physical_address.lock().unwrap())
+    }
+
+    fn set_rx_callback(&self, func: Box<dyn FnMut(&CECCommand) + Send>) {
+        *self.rx_callback.lock().unwrap() = Some(func)
+    }
+
+    fn set_tx_callback(&self, func: TxCallback) {
+        *self.tx_callback.lock().unwrap() = Some(func)
+    }
+}
+
+/// Watches every frame on the bus, including those between other devices,
+/// through a second handle on the adapter that never transmits.
+pub struct Monitor {
+    _receiver: Worker,
+}
+
+impl Monitor {
+    pub fn open(
+        path: impl AsRef<Path>,
+        func: Box<dyn FnMut(&CECCommand) + Send>,
+    ) -> Result<Self, AdapterError> {
+        Self::with_device(Arc::new(DevCec::open(path)?), func)
+    }
+
+    /// Monitoring every frame needs CAP_NET_ADMIN and support in the adapter;
+    /// otherwise only broadcasts and frames addressed to us are seen.
+    pub fn with_device(
+        device: Arc<dyn CecDevice>,
+        mut func: Box<dyn FnMut(&CECCommand) + Send>,
+    ) -> Result<Self, AdapterError> {
+        let caps = device.caps()?;
+        let all = caps.capabilities & linux_ioctl::CAP_MONITOR_ALL != 0
+            && device
+                .set_mode(linux_ioctl::MODE_NO_INITIATOR | linux_ioctl::MODE_MONITOR_ALL)
+                .is_ok();
+        if !all {
+            device.set_mode(linux_ioctl::MODE_NO_INITIATOR | linux_ioctl::MODE_MONITOR)?;
+        }
+        let receiver = spawn_receiver(
+            device,
+            move |msg| {
+                if msg.rx_status & linux_ioctl::RX_STATUS_OK == 0 {
+                    return;
+                }
+                match CECCommand::from_raw(msg.frame()) {
+                    Ok(cmd) => func(&cmd),
+                    Err(e) => info!("monitor saw frame {:02x?}: {}", msg.frame(), e),
+                }
+            },
+            |_| (),
+        );
+        Ok(Monitor {
+            _receiver: receiver,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cec::linux::*;
+    use crate::cec::standby;
+    use std::collections::VecDeque;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::mpsc;
+    use std::sync::Condvar;
+    use std::time::Instant;
+
+    // Stands in for the kernel: records what's configured and sent, and hands
+    // out queued frames.
+    #[derive(Default)]
+    struct MockDevice {
+        capabilities: u32,
+        mode: Mutex<u32>,
+        log_addrs: Mutex<LogAddrs>,
+        tx_status: Mutex<u8>,
+        sent: Mutex<Vec<Vec<u8>>>,
+        incoming: Mutex<VecDeque<Vec<u8>>>,
+        arrived: Condvar,
+        // Makes every receive fail, as if the adapter had gone.
+        broken: AtomicBool,
+    }
+
+    impl MockDevice {
+        fn new() -> Arc<Self> {
+            Arc::new(MockDevice {
+                capabilities: linux_ioctl::CAP_TRANSMIT | linux_ioctl::CAP_LOG_ADDRS,
+                tx_status: Mutex::new(linux_ioctl::TX_STATUS_OK),
+                ..Default::default()
+            })
+        }
+
+        fn deliver(&self, frame: &[u8]) {
+            self.incoming.lock().unwrap().push_back(frame.to_vec());
+            self.arrived.notify_all();
+        }
+    }
+
+    impl CecDevice for MockDevice {
+        fn caps(&self) -> nix::Result<Caps> {
+            Ok(Caps {
+                capabilities: self.capabilities,
+                ..Default::default()
+            })
+        }
+        fn phys_addr(&self) -> nix::Result<u16> {
+            Ok(0x1000)
+        }
+        fn log_addrs(&self) -> nix::Result<LogAddrs> {
+            Ok(*self.log_addrs.lock().unwrap())
+        }
+        fn set_log_addrs(&self, addrs: &mut LogAddrs) -> nix::Result<()> {
+            if addrs.num_log_addrs > 0 {
+                // Pretend the first address for the type was free.
+                addrs.log_addr[0] = 4;
+                addrs.log_addr_mask = 1 << 4;
+            }
+            *self.log_addrs.lock().unwrap() = *addrs;
+            Ok(())
+        }
+        fn set_mode(&self, mode: u32) -> nix::Result<()> {
+            *self.mode.lock().unwrap() = mode;
+            Ok(())
+        }
+        fn transmit(&self, msg: &mut Msg) -> nix::Result<()> {
+            self.sent.lock().unwrap().push(msg.frame().to_vec());
+            msg.tx_status = *self.tx_status.lock().unwrap();
+            Ok(())
+        }
+        fn receive(&self, msg: &mut Msg) -> nix::Result<()> {
+            if self.broken.load(Ordering::Relaxed) {
+                return Err(Errno::EIO);
+            }
+            let frame = self
+                .incoming
+                .lock()
+                .unwrap()
+                .pop_front()
+                .ok_or(Errno::EAGAIN)?;
+            *msg = Msg::new(&frame);
+            msg.rx_status = linux_ioctl::RX_STATUS_OK;
+            Ok(())
+        }
+        fn dequeue_event(&self, _: &mut Event) -> nix::Result<()> {
+            Err(Errno::EAGAIN)
+        }
+        fn poll(&self, timeout: Duration) -> nix::Result<Ready> {
+            let incoming = self.incoming.lock().unwrap();
+            let (incoming, _) = self
+                .arrived
+                .wait_timeout_while(incoming, timeout, |q| {
+                    q.is_empty() && !self.broken.load(Ordering::Relaxed)
+                })
+                .unwrap();
+            Ok(Ready {
+                message: !incoming.is_empty() || self.broken.load(Ordering::Relaxed),
+                event: false,
+            })
+        }
+    }
+
+    fn config() -> Config {
+        Config {
+            device_type: DeviceType::PlaybackDevice,
+            osd_name: "cecvol".to_string(),
+            vendor_id: 0x00e091,
+            physical_address: None,
+        }
+    }
+
+    #[test]
+    fn claims_address_for_device_type() {
+        let device = MockDevice::new();
+        let conn = LinuxConnection::with_device(device.clone(), &config()).unwrap();
+        assert_eq!(
+            *device.mode.lock().unwrap(),
+            linux_ioctl::MODE_INITIATOR | linux_ioctl::MODE_EXCL_FOLLOWER_PASSTHRU
+        );
+        let addrs = *device.log_addrs.lock().unwrap();
+        assert_eq!(addrs.log_addr_type[0], linux_ioctl::LOG_ADDR_TYPE_PLAYBACK);
+        assert_eq!(addrs.vendor_id, 0x00e091);
+        assert_eq!(&addrs.osd_name[..7], b"cecvol\0");
+        assert_eq!(
+            conn.get_logical_address().unwrap(),
+            LogicalAddress::PlaybackDevice1
+        );
+        assert_eq!(
+            conn.get_physical_address().unwrap(),
+            PhysicalAddress::from(0x1000)
+        );
+    }
+
+    #[test]
+    fn transmit_reports_status() {
+        let device = MockDevice::new();
+        let conn = LinuxConnection::with_device(device.clone(), &config()).unwrap();
+        let (tx, rx) = mpsc::channel();
+        conn.set_tx_callback(Box::new(move |cmd, result| {
+            tx.send((cmd.initiator, result.is_ok())).unwrap()
+        }));
+        let standby = standby(LogicalAddress::TV);
+        conn.transmit(standby.clone()).unwrap();
+        assert_eq!(
+            rx.recv().unwrap(),
+            (Some(LogicalAddress::PlaybackDevice1), true)
+        );
+        *device.tx_status.lock().unwrap() =
+            linux_ioctl::TX_STATUS_NACK | linux_ioctl::TX_STATUS_MAX_RETRIES;
+        assert!(matches!(conn.transmit(standby), Err(CECError::NoAck)));
+        assert_eq!(
+            rx.recv().unwrap(),
+            (Some(LogicalAddress::PlaybackDevice1), false)
+        );
+        assert_eq!(*device.sent.lock().unwrap(), vec![vec![0x40, 0x36]; 2]);
+    }
+
+    #[test]
+    fn delivers_received_frames() {
+        let device = MockDevice::new();
+        let conn = LinuxConnection::with_device(device.clone(), &config()).unwrap();
+        let (tx, rx) = mpsc::channel();
+        conn.set_rx_callback(Box::new(move |cmd| tx.send(cmd.to_string()).unwrap()));
+        device.deliver(&[0x04, 0x46]);
+        assert_eq!(
+            rx.recv_timeout(Duration::from_secs(5)).unwrap(),
+            "TV -> Playback 1: GIVE_OSD_NAME"
+        );
+    }
+
+    #[test]
+    fn monitors_every_frame_when_it_can() {
+        let device = Arc::new(MockDevice {
+            capabilities: linux_ioctl::CAP_MONITOR_ALL,
+            ..Default::default()
+        });
+        let (tx, rx) = mpsc::channel();
+        let _monitor = Monitor::with_device(
+            device.clone(),
+            Box::new(move |cmd| tx.send(cmd.to_string()).unwrap()),
+        )
+        .unwrap();
+        assert_eq!(
+            *device.mode.lock().unwrap(),
+            linux_ioctl::MODE_NO_INITIATOR | linux_ioctl::MODE_MONITOR_ALL
+        );
+        device.deliver(&[0x05, 0x71]);
+        assert_eq!(
+            rx.recv_timeout(Duration::from_secs(5)).unwrap(),
+            "TV -> Audio System: GIVE_AUDIO_STATUS"
+        );
+
+        let device = MockDevice::new();
+        let _monitor = Monitor::with_device(device.clone(), Box::new(|_| ())).unwrap();
+        assert_eq!(
+            *device.mode.lock().unwrap(),
+            linux_ioctl::MODE_NO_INITIATOR | linux_ioctl::MODE_MONITOR
+        );
+    }
+
+    #[test]
+    fn gives_up_on_failing_device() {
+        let device = MockDevice::new();
+        let _conn = LinuxConnection::with_device(device.clone(), &config()).unwrap();
+        device.broken.store(true, Ordering::Relaxed);
+        device.arrived.notify_all();
+        // The receiver lets go of its handle on the device when it stops.
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while Arc::strong_count(&device) > 2 {
+            assert!(Instant::now() < deadline, "receiver kept going");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
@@ -0,0 +1,179 @@
+// ioctl functions for the Linux kernel's CEC framework, as used through
+// /dev/cecN.
+//
+// Mirrors the following file:
+//
+// https://github.com/torvalds/linux/blob/master/include/uapi/linux/cec.h
+
+use nix::{ioctl_read, ioctl_readwrite, ioctl_write_ptr};
+
+const CEC_IOC_MAGIC: u8 = b'a';
+ioctl_readwrite!(adap_g_caps, CEC_IOC_MAGIC, 0, Caps);
+ioctl_read!(adap_g_phys_addr, CEC_IOC_MAGIC, 1, u16);
+ioctl_read!(adap_g_log_addrs, CEC_IOC_MAGIC, 3, LogAddrs);
+ioctl_readwrite!(adap_s_log_addrs, CEC_IOC_MAGIC, 4, LogAddrs);
+ioctl_readwrite!(transmit, CEC_IOC_MAGIC, 5, Msg);
+ioctl_readwrite!(receive, CEC_IOC_MAGIC, 6, Msg);
+ioctl_readwrite!(dqevent, CEC_IOC_MAGIC, 7, Event);
+ioctl_write_ptr!(s_mode, CEC_IOC_MAGIC, 9, u32);
+
+pub const MAX_MSG_SIZE: usize = 16;
+pub const MAX_LOG_ADDRS: usize = 4;
+pub const LOG_ADDR_INVALID: u8 = 0xff;
+
+// Adapter capabilities.
+pub const CAP_PHYS_ADDR: u32 = 1 << 0;
+pub const CAP_LOG_ADDRS: u32 = 1 << 1;
+pub const CAP_TRANSMIT: u32 = 1 << 2;
+pub const CAP_PASSTHROUGH: u32 = 1 << 3;
+pub const CAP_MONITOR_ALL: u32 = 1 << 5;
+
+// Modes, made of one initiator mode and one follower mode.
+pub const MODE_NO_INITIATOR: u32 = 0x0;
+pub const MODE_INITIATOR: u32 = 0x1;
+pub const MODE_FOLLOWER: u32 = 0x1 << 4;
+// Like MODE_FOLLOWER, but the kernel leaves the core messages it would
+// otherwise answer itself to us.
+pub const MODE_EXCL_FOLLOWER_PASSTHRU: u32 = 0x3 << 4;
+pub const MODE_MONITOR: u32 = 0xe << 4;
+pub const MODE_MONITOR_ALL: u32 = 0xf << 4;
+
+// Transmit status bits.
+pub const TX_STATUS_OK: u8 = 1 << 0;
+pub const TX_STATUS_ARB_LOST: u8 = 1 << 1;
+pub const TX_STATUS_NACK: u8 = 1 << 2;
+pub const TX_STATUS_LOW_DRIVE: u8 = 1 << 3;
+pub const TX_STATUS_ERROR: u8 = 1 << 4;
+pub const TX_STATUS_MAX_RETRIES: u8 = 1 << 5;
+
+// Receive status bits.
+pub const RX_STATUS_OK: u8 = 1 << 0;
+
+// Logical address types, used when claiming addresses.
+pub const LOG_ADDR_TYPE_TV: u8 = 0;
+pub const LOG_ADDR_TYPE_RECORD: u8 = 1;
+pub const LOG_ADDR_TYPE_TUNER: u8 = 2;
+pub const LOG_ADDR_TYPE_PLAYBACK: u8 = 3;
+pub const LOG_ADDR_TYPE_AUDIOSYSTEM: u8 = 4;
+pub const LOG_ADDR_TYPE_SPECIFIC: u8 = 5;
+pub const LOG_ADDR_TYPE_UNREGISTERED: u8 = 6;
+
+pub const LOG_ADDRS_FL_ALLOW_UNREG_FALLBACK: u32 = 1 << 0;
+
+// Events.
+pub const EVENT_STATE_CHANGE: u32 = 1;
+pub const EVENT_LOST_MSGS: u32 = 2;
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Msg {
+    pub tx_ts: u64,
+    pub rx_ts: u64,
+    pub len: u32,
+    // How long to wait for a reply or a message, in ms.
+    pub timeout: u32,
+    pub sequence: u32,
+    pub flags: u32,
+    pub msg: [u8; MAX_MSG_SIZE],
+    // An opcode to wait for in reply to a transmit, or 0 not to wait.
+    pub reply: u8,
+    pub rx_status: u8,
+    pub tx_status: u8,
+    pub tx_arb_lost_cnt: u8,
+    pub tx_nack_cnt: u8,
+    pub tx_low_drive_cnt: u8,
+    pub tx_error_cnt: u8,
+}
+
+impl Msg {
+    pub fn new(frame: &[u8]) -> Self {
+        let mut msg = Msg {
+            len: frame.len() as u32,
+            ..Default::default()
+        };
+        msg.msg[..frame.len()].copy_from_slice(frame);
+        msg
+    }
+
+    pub fn frame(&self) -> &[u8] {
+        &self.msg[..(self.len as usize).min(MAX_MSG_SIZE)]
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Caps {
+    pub driver: [u8; 32],
+    pub name: [u8; 32],
+    pub available_log_addrs: u32,
+    pub capabilities: u32,
+    pub version: u32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct LogAddrs {
+    pub log_addr: [u8; MAX_LOG_ADDRS],
+    pub log_addr_mask: u16,
+    pub cec_version: u8,
+    pub num_log_addrs: u8,
+    pub vendor_id: u32,
+    pub flags: u32,
+    pub osd_name: [u8; 15],
+    pub primary_device_type: [u8; MAX_LOG_ADDRS],
+    pub log_addr_type: [u8; MAX_LOG_ADDRS],
+    // CEC 2.0 operands for <Report Features>.
+    pub all_device_types: [u8; MAX_LOG_ADDRS],
+    pub features: [[u8; 12]; MAX_LOG_ADDRS],
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct Event {
+    pub ts: u64,
+    pub event: u32,
+    pub flags: u32,
+    // A union of the event-specific structs.
+    pub data: [u8; 64],
+}
+
+impl Default for Event {
+    fn default() -> Self {
+        Event {
+            ts: 0,
+            event: 0,
+            flags: 0,
+            data: [0; 64],
+        }
+    }
+}
+
+impl Event {
+    /// The physical address and logical address mask from a state change.
+    pub fn state_change(&self) -> (u16, u16) {
+        (
+            u16::from_ne_bytes([self.data[0], self.data[1]]),
+            u16::from_ne_bytes([self.data[2], self.data[3]]),
+        )
+    }
+
+    /// How many received messages were dropped because we didn't keep up.
+    pub fn lost_msgs(&self) -> u32 {
+        u32::from_ne_bytes([self.data[0], self.data[1], self.data[2], self.data[3]])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cec::linux_ioctl::*;
+    use core::mem::size_of;
+
+    #[test]
+    fn struct_sizes_match_kernel() {
+        // The sizes are part of the ioctl numbers, so they have to be exact.
+        assert_eq!(size_of::<Msg>(), 56);
+        assert_eq!(size_of::<Caps>(), 76);
+        assert_eq!(size_of::<LogAddrs>(), 92);
+        assert_eq!(size_of::<Event>(), 80);
+    }
+}
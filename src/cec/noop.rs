@@ -1,11 +1,21 @@
-use crate::cec::{CECCommand, CECConnection, CECError, LogicalAddress, PhysicalAddress};
+use crate::cec::{
+    CECCommand, CECConnection, CECError, LogicalAddress, PhysicalAddress, TxCallback,
+};
 use log::info;
+use std::sync::Mutex;
 
-pub struct LogOnlyConn;
+#[derive(Default)]
+pub struct LogOnlyConn {
+    tx_callback: Mutex<Option<TxCallback>>,
+}
 
 impl CECConnection for LogOnlyConn {
     fn transmit(&self, cmd: CECCommand) -> Result<(), CECError> {
-        info!("faking command {:?}", cmd);
+        info!("faking command {}", cmd);
+        // Pretend it was acknowledged, so nothing waits to hear.
+        if let Some(cb) = &mut *self.tx_callback.lock().unwrap() {
+            cb(&cmd, Ok(()));
+        }
         Ok(())
     }
     fn get_logical_address(&self) -> Result<LogicalAddress, CECError> {
@@ -14,10 +24,10 @@ impl CECConnection for LogOnlyConn {
     }
     fn get_physical_address(&self) -> Result<PhysicalAddress, CECError> {
         info!("returning fake physical address");
-        Ok(0)
+        Ok(PhysicalAddress::ROOT)
     }
-    fn set_tx_callback(&self, _: Box<dyn FnMut(&CECCommand) + Send>) {
-        info!("faking tx callback");
+    fn set_tx_callback(&self, func: TxCallback) {
+        *self.tx_callback.lock().unwrap() = Some(func)
     }
     fn set_rx_callback(&self, _: Box<dyn FnMut(&CECCommand) + Send>) {
         info!("faking rx callback");
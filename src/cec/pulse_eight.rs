@@ -0,0 +1,598 @@
+// A connection through a Pulse-Eight USB-CEC adapter, for machines with no
+// CEC-capable HDMI output of their own.
+//
+// The adapter shows up as a serial port such as /dev/ttyACM0. Every message in
+// either direction is a code byte and its parameters between start and end
+// markers, with marker values in between escaped. Frames from the bus arrive a
+// byte at a time, the last one flagged as the end of the message.
+//
+// Inspired by the following files:
+//
+// https://github.com/Pulse-Eight/libcec/blob/master/src/libcec/adapter/Pulse-Eight/USBCECAdapterMessage.cpp
+// https://github.com/Pulse-Eight/libcec/blob/master/src/libcec/adapter/Pulse-Eight/USBCECAdapterCommands.cpp
+
+use crate::cec::adapter::{self, AdapterError, Config, SharedRxCallback, Worker};
+use crate::cec::{
+    claim_logical_address, CECCommand, CECConnection, CECError, DeviceType, LogicalAddress,
+    PhysicalAddress, SharedTxCallback, TxCallback,
+};
+use log::{debug, info, warn};
+use nix::errno::Errno;
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::sys::termios::{self, BaudRate, SetArg};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::raw::c_int;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const MSGSTART: u8 = 0xff;
+const MSGEND: u8 = 0xfe;
+const MSGESC: u8 = 0xfd;
+const ESCOFFSET: u8 = 3;
+
+// Flags on the code byte of a received frame byte: the end of the frame, and
+// whether it was acknowledged.
+const FRAME_EOM: u8 = 0x80;
+const CODE_MASK: u8 = 0x3f;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+const REPLY_TIMEOUT: Duration = Duration::from_secs(1);
+// Long enough for a full frame to be retried a few times.
+const TRANSMIT_TIMEOUT: Duration = Duration::from_secs(5);
+// Firmware from this version on can be told to leave the bus to us.
+const CONTROLLED_FIRMWARE_VERSION: u16 = 2;
+
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum MessageCode {
+    Nothing = 0,
+    Ping = 1,
+    TimeoutError = 2,
+    HighError = 3,
+    LowError = 4,
+    FrameStart = 5,
+    FrameData = 6,
+    ReceiveFailed = 7,
+    CommandAccepted = 8,
+    CommandRejected = 9,
+    SetAckMask = 10,
+    Transmit = 11,
+    TransmitEom = 12,
+    TransmitIdletime = 13,
+    TransmitAckPolarity = 14,
+    TransmitLineTimeout = 15,
+    TransmitSucceeded = 16,
+    TransmitFailedLine = 17,
+    TransmitFailedAck = 18,
+    TransmitFailedTimeoutData = 19,
+    TransmitFailedTimeoutLine = 20,
+    FirmwareVersion = 21,
+    StartBootloader = 22,
+    GetBuilddate = 23,
+    SetControlled = 24,
+    GetAutoEnabled = 25,
+    SetAutoEnabled = 26,
+    GetDefaultLogicalAddress = 27,
+    SetDefaultLogicalAddress = 28,
+    GetLogicalAddressMask = 29,
+    SetLogicalAddressMask = 30,
+    GetPhysicalAddress = 31,
+    SetPhysicalAddress = 32,
+}
+
+/// One message to or from the adapter.
+#[derive(Clone, Debug, PartialEq)]
+struct Message {
+    code: u8,
+    params: Vec<u8>,
+}
+
+impl Message {
+    fn new(code: MessageCode, params: &[u8]) -> Self {
+        Message {
+            code: code as u8,
+            params: params.to_vec(),
+        }
+    }
+
+    fn is(&self, code: MessageCode) -> bool {
+        self.code & CODE_MASK == code as u8
+    }
+
+    fn is_transmit_result(&self) -> bool {
+        (MessageCode::TransmitSucceeded as u8..=MessageCode::TransmitFailedTimeoutLine as u8)
+            .contains(&(self.code & CODE_MASK))
+    }
+
+    fn eom(&self) -> bool {
+        self.code & FRAME_EOM != 0
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut out = vec![MSGSTART];
+        for &b in [self.code].iter().chain(&self.params) {
+            if b >= MSGESC {
+                out.extend([MSGESC, b - ESCOFFSET]);
+            } else {
+                out.push(b);
+            }
+        }
+        out.push(MSGEND);
+        out
+    }
+}
+
+/// Splits the byte stream from the adapter into messages.
+#[derive(Default)]
+struct Parser {
+    buf: Option<Vec<u8>>,
+    escaped: bool,
+}
+
+impl Parser {
+    fn push(&mut self, b: u8) -> Option<Message> {
+        match b {
+            MSGSTART => {
+                self.buf = Some(vec![]);
+                self.escaped = false;
+            }
+            MSGEND => {
+                let buf = self.buf.take()?;
+                let (&code, params) = buf.split_first()?;
+                return Some(Message {
+                    code,
+                    params: params.to_vec(),
+                });
+            }
+            MSGESC => self.escaped = true,
+            _ => {
+                let buf = self.buf.as_mut()?;
+                if self.escaped {
+                    buf.push(b + ESCOFFSET);
+                    self.escaped = false;
+                } else {
+                    buf.push(b);
+                }
+            }
+        }
+        None
+    }
+}
+
+fn tx_result(reply: &Message) -> Result<(), CECError> {
+    if reply.is(MessageCode::TransmitSucceeded) {
+        Ok(())
+    } else if reply.is(MessageCode::TransmitFailedAck) {
+        Err(CECError::NoAck)
+    } else if reply.is(MessageCode::TransmitFailedLine) {
+        Err(CECError::Busy)
+    } else {
+        Err(AdapterError::TxFailed(reply.code).into())
+    }
+}
+
+fn open_serial(path: &Path) -> Result<File, AdapterError> {
+    let file = OpenOptions::new().read(true).write(true).open(path)?;
+    let mut tio = termios::tcgetattr(file.as_raw_fd())?;
+    termios::cfmakeraw(&mut tio);
+    termios::cfsetspeed(&mut tio, BaudRate::B38400)?;
+    termios::tcsetattr(file.as_raw_fd(), SetArg::TCSANOW, &tio)?;
+    Ok(file)
+}
+
+// Reads messages from the adapter until it's dropped. Frames from the bus that
+// are broadcast or addressed to us go to the rx callback, and everything else
+// is a reply for whoever is waiting in `PulseEight::command`.
+fn spawn_reader(
+    mut port: File,
+    logical_address: Arc<Mutex<LogicalAddress>>,
+    rx_callback: SharedRxCallback,
+    replies: Sender<Message>,
+) -> Worker {
+    Worker::spawn(move |stop| {
+        let mut parser = Parser::default();
+        let mut frame = vec![];
+        let mut buf = [0u8; 64];
+        while !stop.load(Ordering::Relaxed) {
+            let mut fds = [PollFd::new(port.as_raw_fd(), PollFlags::POLLIN)];
+            match poll(&mut fds, POLL_INTERVAL.as_millis() as c_int) {
+                Ok(0) | Err(Errno::EINTR) => continue,
+                Ok(_) => (),
+                Err(e) => {
+                    warn!("polling CEC adapter failed: {}", e);
+                    return;
+                }
+            }
+            let n = match port.read(&mut buf) {
+                Ok(0) => return,
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    warn!("reading from CEC adapter failed: {}", e);
+                    return;
+                }
+            };
+            for msg in buf[..n].iter().filter_map(|&b| parser.push(b)) {
+                if msg.is(MessageCode::FrameStart) || msg.is(MessageCode::FrameData) {
+                    if msg.is(MessageCode::FrameStart) {
+                        frame.clear();
+                    }
+                    frame.extend(&msg.params);
+                    if !msg.eom() {
+                        continue;
+                    }
+                    match CECCommand::from_raw(&frame) {
+                        // The adapter hears every frame on the bus, not just
+                        // the ones it acknowledged for us.
+                        Ok(cmd)
+                            if cmd.destination != LogicalAddress::Broadcast
+                                && cmd.destination != *logical_address.lock().unwrap() =>
+                        {
+                            debug!("ignoring frame for another device: {}", cmd)
+                        }
+                        Ok(cmd) => {
+                            if let Some(cb) = &mut *rx_callback.lock().unwrap() {
+                                cb(&cmd)
+                            }
+                        }
+                        Err(e) => info!("ignoring frame {:02x?}: {}", frame, e),
+                    }
+                    frame.clear();
+                } else if msg.is(MessageCode::HighError)
+                    || msg.is(MessageCode::LowError)
+                    || msg.is(MessageCode::ReceiveFailed)
+                {
+                    debug!("adapter dropped a frame: {:?}", msg);
+                    frame.clear();
+                } else if replies.send(msg).is_err() {
+                    return;
+                }
+            }
+        }
+    })
+}
+
+pub struct PulseEight {
+    port: Mutex<File>,
+    // Held while waiting for a reply, so only one command is in flight.
+    replies: Mutex<Receiver<Message>>,
+    firmware_version: u16,
+    logical_address: Arc<Mutex<LogicalAddress>>,
+    physical_address: PhysicalAddress,
+    rx_callback: SharedRxCallback,
+    tx_callback: SharedTxCallback,
+    _reader: Worker,
+}
+
+impl PulseEight {
+    /// Opens the adapter at `path` and claims a logical address for the
+    /// configured device type. The adapter doesn't know its physical address
+    /// unless it was saved to it, so it may need to be configured.
+    pub fn open(path: impl AsRef<Path>, config: &Config) -> Result<Self, AdapterError> {
+        let port = open_serial(path.as_ref())?;
+        let (sender, replies) = mpsc::channel();
+        let rx_callback: SharedRxCallback = Arc::new(Mutex::new(None));
+        let logical_address = Arc::new(Mutex::new(LogicalAddress::Broadcast));
+        let reader = spawn_reader(
+            port.try_clone()?,
+            logical_address.clone(),
+            rx_callback.clone(),
+            sender,
+        );
+        let mut adapter = PulseEight {
+            port: Mutex::new(port),
+            replies: Mutex::new(replies),
+            firmware_version: 0,
+            logical_address,
+            physical_address: PhysicalAddress::INVALID,
+            rx_callback,
+            tx_callback: Arc::new(Mutex::new(None)),
+            _reader: reader,
+        };
+
+        let version = adapter.command(MessageCode::FirmwareVersion, &[])?;
+        adapter.firmware_version = match version.params[..] {
+            [hi, lo, ..] => u16::from_be_bytes([hi, lo]),
+            _ => 0,
+        };
+        info!("Pulse-Eight adapter firmware v{}", adapter.firmware_version);
+        if adapter.firmware_version >= CONTROLLED_FIRMWARE_VERSION {
+            adapter.command(MessageCode::SetControlled, &[1])?;
+        }
+        adapter.physical_address = match config.physical_address {
+            Some(addr) => addr,
+            None => adapter.get_physical_addr()?,
+        };
+        let addr = adapter.claim_logical_addr(config.device_type)?;
+        info!(
+            "claimed logical address {:?}, physical address {}",
+            addr, adapter.physical_address
+        );
+        Ok(adapter)
+    }
+
+    pub fn firmware_version(&self) -> u16 {
+        self.firmware_version
+    }
+
+    fn write(&self, msgs: &[Message]) -> io::Result<()> {
+        let bytes: Vec<u8> = msgs.iter().flat_map(Message::encode).collect();
+        let mut port = self.port.lock().unwrap();
+        port.write_all(&bytes)?;
+        port.flush()
+    }
+
+    // Sends `msgs` and waits for the first reply that `done` picks out,
+    // ignoring the acceptance of each message along the way.
+    fn exchange(
+        &self,
+        request: MessageCode,
+        msgs: &[Message],
+        timeout: Duration,
+        mut done: impl FnMut(&Message) -> bool,
+    ) -> Result<Message, AdapterError> {
+        let replies = self.replies.lock().unwrap();
+        // Anything left over belongs to a request that already gave up.
+        while replies.try_recv().is_ok() {}
+        self.write(msgs)?;
+        let deadline = Instant::now() + timeout;
+        loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+            match replies.recv_timeout(left) {
+                Ok(reply) if done(&reply) => return Ok(reply),
+                Ok(reply) if reply.is(MessageCode::CommandRejected) => {
+                    return Err(AdapterError::Rejected(request))
+                }
+                Ok(_) => (),
+                Err(RecvTimeoutError::Timeout) | Err(RecvTimeoutError::Disconnected) => {
+                    return Err(AdapterError::NoReply(request))
+                }
+            }
+        }
+    }
+
+    // Sends a command to the adapter itself. Queries are answered with their
+    // own code and settings with an acceptance.
+    fn command(&self, code: MessageCode, params: &[u8]) -> Result<Message, AdapterError> {
+        self.exchange(
+            code,
+            &[Message::new(code, params)],
+            REPLY_TIMEOUT,
+            |reply| {
+                reply.is(code)
+                    || (reply.is(MessageCode::CommandAccepted)
+                        && reply.params.first() == Some(&(code as u8)))
+            },
+        )
+    }
+
+    pub fn get_physical_addr(&self) -> Result<PhysicalAddress, AdapterError> {
+        let reply = self.command(MessageCode::GetPhysicalAddress, &[])?;
+        Ok(match reply.params[..] {
+            [hi, lo, ..] => PhysicalAddress::from(u16::from_be_bytes([hi, lo])),
+            _ => PhysicalAddress::INVALID,
+        })
+    }
+
+    /// Makes the adapter acknowledge frames sent to `addr`, and send from it.
+    pub fn set_logical_addr(&self, addr: LogicalAddress) -> Result<(), AdapterError> {
+        let mask: u16 = match addr {
+            LogicalAddress::Broadcast => 0,
+            _ => 1 << addr as u8,
+        };
+        self.command(MessageCode::SetAckMask, &mask.to_be_bytes())?;
+        *self.logical_address.lock().unwrap() = addr;
+        Ok(())
+    }
+
+    /// Polls each address for `device` and takes the first that's free.
+    pub fn claim_logical_addr(&self, device: DeviceType) -> Result<LogicalAddress, AdapterError> {
+        self.set_logical_addr(LogicalAddress::Broadcast)?;
+        let addr = claim_logical_address(device, |addr| {
+            let header = (addr as u8) << 4 | addr as u8;
+            match self.send_frame(&[header]).map(|reply| tx_result(&reply)) {
+                Ok(Ok(())) => Ok(true),
+                Ok(Err(CECError::NoAck)) => Ok(false),
+                // Treat a busy bus like a taken address rather than risk a
+                // clash.
+                Ok(Err(_)) => Ok(true),
+                Err(e) => Err(e),
+            }
+        })?;
+        self.set_logical_addr(addr)?;
+        Ok(addr)
+    }
+
+    // Sends a raw frame, returning the adapter's verdict on it.
+    fn send_frame(&self, frame: &[u8]) -> Result<Message, AdapterError> {
+        let broadcast = frame[0] & 0x0f == LogicalAddress::Broadcast as u8;
+        let mut msgs = vec![Message::new(
+            MessageCode::TransmitAckPolarity,
+            &[broadcast as u8],
+        )];
+        for (i, &b) in frame.iter().enumerate() {
+            let code = if i == frame.len() - 1 {
+                MessageCode::TransmitEom
+            } else {
+                MessageCode::Transmit
+            };
+            msgs.push(Message::new(code, &[b]));
+        }
+        self.exchange(
+            MessageCode::TransmitEom,
+            &msgs,
+            TRANSMIT_TIMEOUT,
+            Message::is_transmit_result,
+        )
+    }
+}
+
+impl CECConnection for PulseEight {
+    fn transmit(&self, cmd: CECCommand) -> Result<(), CECError> {
+        let own = *self.logical_address.lock().unwrap();
+        adapter::transmit(
+            cmd,
+            own,
+            &self.tx_callback,
+            |frame| self.send_frame(frame),
+            tx_result,
+        )
+    }
+
+    fn get_logical_address(&self) -> Result<LogicalAddress, CECError> {
+        Ok(*self.logical_address.lock().unwrap())
+    }
+
+    fn get_physical_address(&self) -> Result<PhysicalAddress, CECError> {
+        Ok(self.physical_address)
+    }
+
+    fn set_rx_callback(&self, func: Box<dyn FnMut(&CECCommand) + Send>) {
+        *self.rx_callback.lock().unwrap() = Some(func)
+    }
+
+    fn set_tx_callback(&self, func: TxCallback) {
+        *self.tx_callback.lock().unwrap() = Some(func)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cec::pulse_eight::*;
+    use crate::cec::standby;
+    use nix::pty::openpty;
+    use nix::unistd::{close, ttyname};
+    use std::os::unix::io::FromRawFd;
+    use std::thread;
+
+    fn parse(bytes: &[u8]) -> Vec<Message> {
+        let mut parser = Parser::default();
+        bytes.iter().filter_map(|&b| parser.push(b)).collect()
+    }
+
+    #[test]
+    fn escapes_markers() {
+        let msg = Message::new(MessageCode::Transmit, &[0xff, 0x10, 0xfd]);
+        let encoded = msg.encode();
+        assert_eq!(encoded, [0xff, 0x0b, 0xfd, 0xfc, 0x10, 0xfd, 0xfa, 0xfe]);
+        // A message cut short by a new start marker is dropped.
+        let mut stream = vec![0xff, 0x0b, 0x10];
+        stream.extend(&encoded);
+        assert_eq!(parse(&stream), vec![msg]);
+    }
+
+    // Plays the adapter on the other end of a pty. Playback 1 is taken, and
+    // the TV acknowledges everything sent to it.
+    fn simulate_adapter(mut port: File, sent: Arc<Mutex<Vec<Vec<u8>>>>) {
+        let mut parser = Parser::default();
+        let mut frame = vec![];
+        let mut buf = [0u8; 64];
+        while let Ok(n) = port.read(&mut buf) {
+            if n == 0 {
+                return;
+            }
+            for msg in buf[..n].iter().filter_map(|&b| parser.push(b)) {
+                let accepted = Message::new(MessageCode::CommandAccepted, &[msg.code]);
+                let mut replies = vec![];
+                match msg.code {
+                    c if c == MessageCode::FirmwareVersion as u8 => {
+                        replies.push(Message::new(MessageCode::FirmwareVersion, &[0, 12]))
+                    }
+                    c if c == MessageCode::GetPhysicalAddress as u8 => {
+                        replies.push(Message::new(MessageCode::GetPhysicalAddress, &[0x10, 0]))
+                    }
+                    c if c == MessageCode::Transmit as u8 => {
+                        frame.extend(&msg.params);
+                        replies.push(accepted);
+                    }
+                    c if c == MessageCode::TransmitEom as u8 => {
+                        frame.extend(&msg.params);
+                        replies.push(accepted);
+                        let dest = frame[0] & 0x0f;
+                        let acked = dest == LogicalAddress::TV as u8
+                            || (frame.len() == 1 && dest == LogicalAddress::PlaybackDevice1 as u8);
+                        replies.push(Message::new(
+                            if acked {
+                                MessageCode::TransmitSucceeded
+                            } else {
+                                MessageCode::TransmitFailedAck
+                            },
+                            &[],
+                        ));
+                        sent.lock().unwrap().push(std::mem::take(&mut frame));
+                    }
+                    _ => replies.push(accepted),
+                }
+                for reply in replies {
+                    port.write_all(&reply.encode()).unwrap();
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn talks_to_adapter_over_pty() {
+        let pty = openpty(None, None).unwrap();
+        let path = ttyname(pty.slave).unwrap();
+        let sent = Arc::new(Mutex::new(vec![]));
+        let mut master = unsafe { File::from_raw_fd(pty.master) };
+        let sim_port = master.try_clone().unwrap();
+        let sim_sent = sent.clone();
+        thread::spawn(move || simulate_adapter(sim_port, sim_sent));
+
+        let adapter = PulseEight::open(
+            &path,
+            &Config {
+                device_type: DeviceType::PlaybackDevice,
+                osd_name: "cecvol".to_string(),
+                vendor_id: 0x00e091,
+                physical_address: None,
+            },
+        )
+        .unwrap();
+        close(pty.slave).unwrap();
+        assert_eq!(adapter.firmware_version(), 12);
+        assert_eq!(
+            adapter.get_physical_address().unwrap(),
+            PhysicalAddress::from(0x1000)
+        );
+        assert_eq!(
+            adapter.get_logical_address().unwrap(),
+            LogicalAddress::PlaybackDevice2
+        );
+
+        adapter.transmit(standby(LogicalAddress::TV)).unwrap();
+        assert!(matches!(
+            adapter.transmit(standby(LogicalAddress::AudioSystem)),
+            Err(CECError::NoAck)
+        ));
+        assert_eq!(
+            *sent.lock().unwrap(),
+            vec![vec![0x44], vec![0x88], vec![0x80, 0x36], vec![0x85, 0x36]]
+        );
+
+        let (tx, rx) = mpsc::channel();
+        adapter.set_rx_callback(Box::new(move |cmd| tx.send(cmd.to_string()).unwrap()));
+        // Only the second frame is for us.
+        for header in [0x05, 0x08] {
+            for msg in [
+                Message::new(MessageCode::FrameStart, &[header]),
+                Message {
+                    code: MessageCode::FrameData as u8 | FRAME_EOM,
+                    params: vec![0x46],
+                },
+            ] {
+                master.write_all(&msg.encode()).unwrap();
+            }
+        }
+        assert_eq!(
+            rx.recv_timeout(Duration::from_secs(5)).unwrap(),
+            "TV -> Playback 2: GIVE_OSD_NAME"
+        );
+    }
+}
@@ -0,0 +1,153 @@
+// Matching of received messages to the requests waiting on them.
+//
+// A request is registered before it's transmitted so the reply can't slip
+// past. It's answered by the first message from the destination with the
+// expected opcode, or by a <Feature Abort> that names the request's opcode.
+
+use crate::cec::{CECCommand, CECError, CECMessage, LogicalAddress, Opcode};
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
+
+struct Pending {
+    id: u64,
+    from: LogicalAddress,
+    request: Opcode,
+    reply: Opcode,
+    result: Option<Result<CECMessage, CECError>>,
+}
+
+#[derive(Default)]
+pub(crate) struct PendingReplies {
+    pending: Mutex<(u64, Vec<Pending>)>,
+    cvar: Condvar,
+}
+
+impl PendingReplies {
+    /// Registers interest in a `reply` from `from` to a `request`, returning
+    /// an id to wait on.
+    pub fn expect(&self, from: LogicalAddress, request: Opcode, reply: Opcode) -> u64 {
+        let (next_id, pending) = &mut *self.pending.lock().unwrap();
+        *next_id += 1;
+        pending.push(Pending {
+            id: *next_id,
+            from,
+            request,
+            reply,
+            result: None,
+        });
+        *next_id
+    }
+
+    /// Hands a received message to any request it answers.
+    pub fn deliver(&self, cmd: &CECCommand) {
+        let (_, pending) = &mut *self.pending.lock().unwrap();
+        let mut delivered = false;
+        for p in pending
+            .iter_mut()
+            .filter(|p| p.result.is_none() && cmd.initiator == Some(p.from))
+        {
+            match &cmd.message {
+                CECMessage::FeatureAbort {
+                    feature_opcode,
+                    abort_reason,
+                } if *feature_opcode == p.request => {
+                    p.result = Some(Err(CECError::Aborted {
+                        opcode: *feature_opcode,
+                        reason: *abort_reason,
+                    }));
+                }
+                msg if msg.get_opcode() == p.reply => p.result = Some(Ok(msg.clone())),
+                _ => continue,
+            }
+            delivered = true;
+        }
+        if delivered {
+            self.cvar.notify_all();
+        }
+    }
+
+    /// Waits for the request with the given id to be answered, giving up after
+    /// `timeout`.
+    pub fn wait(&self, id: u64, timeout: Duration) -> Result<CECMessage, CECError> {
+        let (mut guard, _) = self
+            .cvar
+            .wait_timeout_while(self.pending.lock().unwrap(), timeout, |(_, pending)| {
+                pending.iter().any(|p| p.id == id && p.result.is_none())
+            })
+            .unwrap();
+        let (_, pending) = &mut *guard;
+        let i = pending.iter().position(|p| p.id == id).unwrap();
+        let p = pending.remove(i);
+        p.result.unwrap_or(Err(CECError::NoReply(p.request)))
+    }
+
+    /// Stops waiting for a request that was never sent.
+    pub fn cancel(&self, id: u64) {
+        let (_, pending) = &mut *self.pending.lock().unwrap();
+        pending.retain(|p| p.id != id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cec::reply::*;
+    use crate::cec::{AbortReason, PowerStatus};
+
+    fn from_tv(message: CECMessage) -> CECCommand {
+        CECCommand {
+            initiator: Some(LogicalAddress::TV),
+            destination: LogicalAddress::PlaybackDevice1,
+            message,
+        }
+    }
+
+    #[test]
+    fn matches_reply_from_destination() {
+        let replies = PendingReplies::default();
+        let id = replies.expect(
+            LogicalAddress::TV,
+            Opcode::GiveDevicePowerStatus,
+            Opcode::ReportPowerStatus,
+        );
+        let report = CECMessage::ReportPowerStatus {
+            power_status: PowerStatus::Standby,
+        };
+        replies.deliver(&CECCommand {
+            initiator: Some(LogicalAddress::AudioSystem),
+            ..from_tv(report.clone())
+        });
+        replies.deliver(&from_tv(report.clone()));
+        assert_eq!(replies.wait(id, Duration::ZERO).unwrap(), report);
+    }
+
+    #[test]
+    fn feature_abort_is_an_error() {
+        let replies = PendingReplies::default();
+        let id = replies.expect(
+            LogicalAddress::TV,
+            Opcode::GiveAudioStatus,
+            Opcode::ReportAudioStatus,
+        );
+        replies.deliver(&from_tv(CECMessage::FeatureAbort {
+            feature_opcode: Opcode::GiveAudioStatus,
+            abort_reason: AbortReason::Refused,
+        }));
+        match replies.wait(id, Duration::ZERO) {
+            Err(CECError::Aborted { opcode, reason }) => {
+                assert_eq!(opcode, Opcode::GiveAudioStatus);
+                assert_eq!(reason, AbortReason::Refused);
+            }
+            r => panic!("unexpected result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn times_out_without_reply() {
+        let replies = PendingReplies::default();
+        let id = replies.expect(LogicalAddress::TV, Opcode::GiveOSDName, Opcode::SetOSDName);
+        assert!(matches!(
+            replies.wait(id, Duration::from_millis(10)),
+            Err(CECError::NoReply(Opcode::GiveOSDName))
+        ));
+    }
+}
@@ -0,0 +1,961 @@
+// A virtual CEC bus, for running the rest of the crate without hardware.
+//
+// Connections and emulated devices attach to a Bus under a logical address.
+// Like a real adapter, each one only sees broadcasts and the frames addressed
+// to it, and a directly addressed frame is only acknowledged if something has
+// the destination address. Frames are delivered on the bus's own thread, so a
+// receiver that answers from its rx callback doesn't wait on its own transmit.
+//
+// Faults can be injected to test the unhappy paths: devices that stop
+// acknowledging, that are slow to answer, or that refuse an opcode.
+//
+// A connection's callbacks usually hold on to the connection, and so to the
+// bus, so a bus has to be shut down to stop its thread and let go of what's
+// attached to it.
+
+use crate::cec::follower::feature_abort;
+use crate::cec::vendor::VENDOR_LG;
+use crate::cec::{
+    claim_logical_address, AbortReason, AudioStatus, CECCommand, CECConnection, CECError,
+    CECMessage, CECVersion, DeckInfo, DeckStatus, DeviceType, HdmiCallback, HdmiEvent,
+    LogicalAddress, Opcode, PhysicalAddress, PowerStatus, TxCallback, UserControl,
+};
+use log::info;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Condvar, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// How often the delivery thread checks whether the bus is gone.
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Something emulated on the bus.
+pub trait Device: Send {
+    fn logical_address(&self) -> LogicalAddress;
+
+    /// Reacts to a broadcast or a frame addressed to this device, returning
+    /// the frames to send in response.
+    fn handle(&mut self, cmd: &CECCommand) -> Vec<CECCommand>;
+}
+
+/// A misbehaviour to inject into the bus.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Fault {
+    /// Frames to this address go unacknowledged, as if it were unplugged.
+    NoAck(LogicalAddress),
+    /// Frames from this address are held back for a while.
+    Delay(LogicalAddress, Duration),
+    /// This address answers the opcode with a <Feature Abort>.
+    FeatureAbort(LogicalAddress, Opcode, AbortReason),
+}
+
+type RxCallback = Box<dyn FnMut(&CECCommand) + Send>;
+type SharedHdmiCallback = Arc<Mutex<Option<HdmiCallback>>>;
+
+#[derive(Clone)]
+enum Endpoint {
+    Connection(Arc<Mutex<Option<RxCallback>>>),
+    Device(Arc<Mutex<dyn Device>>),
+}
+
+struct Node {
+    logical_address: LogicalAddress,
+    endpoint: Endpoint,
+}
+
+struct Scheduled {
+    at: Instant,
+    seq: u64,
+    cmd: CECCommand,
+}
+
+#[derive(Default)]
+struct Inner {
+    nodes: Mutex<Vec<Node>>,
+    faults: Mutex<Vec<Fault>>,
+    queue: Mutex<(u64, Vec<Scheduled>)>,
+    queued: Condvar,
+    hdmi_callbacks: Mutex<Vec<SharedHdmiCallback>>,
+    // Sent every frame once it has been delivered.
+    watchers: Mutex<Vec<mpsc::Sender<CECCommand>>>,
+    // Only set with the queue locked, so the delivery thread can't miss it
+    // between checking and waiting.
+    stopped: AtomicBool,
+    thread: Mutex<Option<thread::JoinHandle<()>>>,
+}
+
+impl Inner {
+    fn delay(&self, from: LogicalAddress) -> Duration {
+        self.faults
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|f| match f {
+                Fault::Delay(addr, delay) if *addr == from => Some(*delay),
+                _ => None,
+            })
+            .sum()
+    }
+
+    // Puts a frame on the bus, returning whether it was acknowledged. A frame
+    // has to say who sent it to get on the bus at all.
+    fn send(&self, cmd: CECCommand) -> bool {
+        let Some(initiator) = cmd.initiator else {
+            info!("dropping frame without an initiator: {}", cmd);
+            return false;
+        };
+        if self.stopped.load(Ordering::SeqCst) {
+            return false;
+        }
+        let dest = cmd.destination;
+        let acked = dest == LogicalAddress::Broadcast
+            || (!self.faults.lock().unwrap().contains(&Fault::NoAck(dest))
+                && self
+                    .nodes
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .any(|n| n.logical_address == dest));
+        if acked {
+            let at = Instant::now() + self.delay(initiator);
+            let (seq, queue) = &mut *self.queue.lock().unwrap();
+            *seq += 1;
+            queue.push(Scheduled { at, seq: *seq, cmd });
+            self.queued.notify_all();
+        }
+        acked
+    }
+
+    // Takes the next frame that's due, waiting a little for one if needed.
+    fn next_due(&self) -> Option<CECCommand> {
+        let mut queue = self.queue.lock().unwrap();
+        if self.stopped.load(Ordering::SeqCst) {
+            return None;
+        }
+        let now = Instant::now();
+        let next = queue
+            .1
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, s)| (s.at, s.seq));
+        match next {
+            Some((i, s)) if s.at <= now => Some(queue.1.remove(i).cmd),
+            next => {
+                let wait = next.map_or(IDLE_CHECK_INTERVAL, |(_, s)| {
+                    (s.at - now).min(IDLE_CHECK_INTERVAL)
+                });
+                drop(self.queued.wait_timeout(queue, wait).unwrap());
+                None
+            }
+        }
+    }
+
+    fn deliver(&self, cmd: &CECCommand) {
+        let Some(initiator) = cmd.initiator else {
+            return;
+        };
+        let refused = self.faults.lock().unwrap().iter().find_map(|f| match f {
+            Fault::FeatureAbort(addr, opcode, reason)
+                if *addr == cmd.destination && *opcode == cmd.message.get_opcode() =>
+            {
+                Some(*reason)
+            }
+            _ => None,
+        });
+        if let Some(reason) = refused {
+            if let Some(message) = feature_abort(cmd, reason) {
+                self.send(reply(cmd, message));
+            }
+            return;
+        }
+        let recipients: Vec<Endpoint> = self
+            .nodes
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|n| {
+                n.logical_address != initiator
+                    && (cmd.destination == LogicalAddress::Broadcast
+                        || n.logical_address == cmd.destination)
+            })
+            .map(|n| n.endpoint.clone())
+            .collect();
+        for endpoint in recipients {
+            match endpoint {
+                Endpoint::Connection(rx) => {
+                    if let Some(cb) = &mut *rx.lock().unwrap() {
+                        cb(cmd)
+                    }
+                }
+                Endpoint::Device(device) => {
+                    let replies = device.lock().unwrap().handle(cmd);
+                    for reply in replies {
+                        self.send(reply);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// A virtual bus. Cloning it gives another handle on the same bus.
+#[derive(Clone)]
+pub struct Bus {
+    inner: Arc<Inner>,
+}
+
+impl Default for Bus {
+    fn default() -> Self {
+        Bus::new()
+    }
+}
+
+impl Bus {
+    pub fn new() -> Self {
+        let inner = Arc::new(Inner::default());
+        let weak: Weak<Inner> = Arc::downgrade(&inner);
+        let thread = thread::Builder::new()
+            .name("CEC bus".into())
+            .spawn(move || {
+                while let Some(inner) = weak.upgrade() {
+                    if inner.stopped.load(Ordering::SeqCst) {
+                        return;
+                    }
+                    if let Some(cmd) = inner.next_due() {
+                        inner.deliver(&cmd);
+                        inner
+                            .watchers
+                            .lock()
+                            .unwrap()
+                            .retain(|w| w.send(cmd.clone()).is_ok());
+                    }
+                }
+            })
+            .unwrap();
+        *inner.thread.lock().unwrap() = Some(thread);
+        Bus { inner }
+    }
+
+    /// A TV at the root with an audio system on HDMI 1 and a playback device
+    /// behind that, which is enough for everything `CEC` does.
+    pub fn living_room() -> Self {
+        let bus = Bus::new();
+        bus.add(Tv::new(VENDOR_LG));
+        bus.add(AudioSystem::new(PhysicalAddress::from(0x1000)));
+        bus.add(Playback::new(
+            LogicalAddress::PlaybackDevice1,
+            PhysicalAddress::from(0x1100),
+        ));
+        bus
+    }
+
+    /// Attaches an emulated device, returning a handle to inspect it with.
+    pub fn add<D: Device + 'static>(&self, device: D) -> Arc<Mutex<D>> {
+        let logical_address = device.logical_address();
+        let device = Arc::new(Mutex::new(device));
+        self.inner.nodes.lock().unwrap().push(Node {
+            logical_address,
+            endpoint: Endpoint::Device(device.clone()),
+        });
+        device
+    }
+
+    /// Attaches a connection at `physical_address`, with the first logical
+    /// address for `device_type` that's free.
+    pub fn connect(
+        &self,
+        device_type: DeviceType,
+        physical_address: PhysicalAddress,
+    ) -> SimConnection {
+        let logical_address = claim_logical_address(device_type, |addr| {
+            Ok::<_, ()>(
+                self.inner
+                    .nodes
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .any(|n| n.logical_address == addr),
+            )
+        })
+        .unwrap();
+        info!(
+            "simulated connection claimed {:?} at {}",
+            logical_address, physical_address
+        );
+        let rx_callback = Arc::new(Mutex::new(None));
+        let hdmi_callback: SharedHdmiCallback = Arc::new(Mutex::new(None));
+        self.inner
+            .hdmi_callbacks
+            .lock()
+            .unwrap()
+            .push(hdmi_callback.clone());
+        if logical_address != LogicalAddress::Broadcast {
+            self.inner.nodes.lock().unwrap().push(Node {
+                logical_address,
+                endpoint: Endpoint::Connection(rx_callback.clone()),
+            });
+        }
+        SimConnection {
+            bus: self.clone(),
+            logical_address,
+            physical_address,
+            rx_callback,
+            tx_callback: Mutex::new(None),
+            hdmi_callback,
+        }
+    }
+
+    /// Returns a channel that's sent every frame once it has been delivered,
+    /// and every device it went to has dealt with it.
+    pub fn watch(&self) -> mpsc::Receiver<CECCommand> {
+        let (sender, frames) = mpsc::channel();
+        self.inner.watchers.lock().unwrap().push(sender);
+        frames
+    }
+
+    /// Stops delivering frames and detaches everything from the bus. Frames
+    /// sent afterwards go unacknowledged.
+    pub fn shutdown(&self) {
+        {
+            let mut queue = self.inner.queue.lock().unwrap();
+            self.inner.stopped.store(true, Ordering::SeqCst);
+            queue.1.clear();
+        }
+        self.inner.queued.notify_all();
+        let thread = self.inner.thread.lock().unwrap().take();
+        if let Some(thread) = thread {
+            // A device or callback on the bus thread may be the one asking.
+            if thread.thread().id() != thread::current().id() {
+                let _ = thread.join();
+            }
+        }
+        let nodes = std::mem::take(&mut *self.inner.nodes.lock().unwrap());
+        let hdmi_callbacks = std::mem::take(&mut *self.inner.hdmi_callbacks.lock().unwrap());
+        self.inner.watchers.lock().unwrap().clear();
+        drop((nodes, hdmi_callbacks));
+    }
+
+    pub fn inject(&self, fault: Fault) {
+        self.inner.faults.lock().unwrap().push(fault);
+    }
+
+    pub fn clear_faults(&self) {
+        self.inner.faults.lock().unwrap().clear();
+    }
+
+    /// Puts a frame on the bus as if a device had sent it, returning whether
+    /// it was acknowledged. Frames that don't name their initiator aren't.
+    pub fn send(&self, cmd: CECCommand) -> bool {
+        self.inner.send(cmd)
+    }
+
+    /// Tells every connection that its HDMI link changed, as if its cable had
+    /// been pulled or plugged back in.
+    pub fn hdmi_event(&self, event: HdmiEvent) {
+        for callback in self.inner.hdmi_callbacks.lock().unwrap().iter() {
+            if let Some(cb) = &mut *callback.lock().unwrap() {
+                cb(event)
+            }
+        }
+    }
+}
+
+/// A connection to a virtual bus.
+pub struct SimConnection {
+    bus: Bus,
+    logical_address: LogicalAddress,
+    physical_address: PhysicalAddress,
+    rx_callback: Arc<Mutex<Option<RxCallback>>>,
+    tx_callback: Mutex<Option<TxCallback>>,
+    hdmi_callback: SharedHdmiCallback,
+}
+
+impl CECConnection for SimConnection {
+    fn transmit(&self, cmd: CECCommand) -> Result<(), CECError> {
+        let cmd = CECCommand {
+            initiator: Some(cmd.initiator.unwrap_or(self.logical_address)),
+            ..cmd
+        };
+        let acked = self.bus.inner.send(cmd.clone());
+        let result = || if acked { Ok(()) } else { Err(CECError::NoAck) };
+        if let Some(cb) = &mut *self.tx_callback.lock().unwrap() {
+            cb(&cmd, result())
+        }
+        result()
+    }
+
+    fn get_logical_address(&self) -> Result<LogicalAddress, CECError> {
+        Ok(self.logical_address)
+    }
+
+    fn get_physical_address(&self) -> Result<PhysicalAddress, CECError> {
+        Ok(self.physical_address)
+    }
+
+    fn set_rx_callback(&self, func: RxCallback) {
+        *self.rx_callback.lock().unwrap() = Some(func)
+    }
+
+    fn set_tx_callback(&self, func: TxCallback) {
+        *self.tx_callback.lock().unwrap() = Some(func)
+    }
+
+    fn set_hdmi_callback(&self, func: HdmiCallback) {
+        *self.hdmi_callback.lock().unwrap() = Some(func)
+    }
+}
+
+fn reply(cmd: &CECCommand, message: CECMessage) -> CECCommand {
+    CECCommand {
+        initiator: Some(cmd.destination),
+        // Frames only get on the bus with an initiator.
+        destination: cmd.initiator.unwrap_or(LogicalAddress::Broadcast),
+        message,
+    }
+}
+
+fn broadcast(from: LogicalAddress, message: CECMessage) -> CECCommand {
+    CECCommand {
+        initiator: Some(from),
+        destination: LogicalAddress::Broadcast,
+        message,
+    }
+}
+
+/// What every emulated device answers the same way.
+#[derive(Clone, Debug)]
+pub struct Identity {
+    pub logical_address: LogicalAddress,
+    pub physical_address: PhysicalAddress,
+    pub osd_name: String,
+    pub vendor_id: u32,
+    pub cec_version: CECVersion,
+    pub power_status: PowerStatus,
+}
+
+impl Identity {
+    // Answers the questions any device can be asked. Anything else sent
+    // directly to the device that it didn't handle itself is aborted.
+    fn answer(&self, cmd: &CECCommand) -> Vec<CECCommand> {
+        let me = self.logical_address;
+        let message = match cmd.message {
+            CECMessage::GivePhysicalAddress => {
+                return vec![broadcast(
+                    me,
+                    CECMessage::ReportPhysicalAddress {
+                        physical_address: self.physical_address,
+                        device_type: me.to_device_type(),
+                    },
+                )]
+            }
+            CECMessage::GiveDeviceVendorID => {
+                return vec![broadcast(
+                    me,
+                    CECMessage::DeviceVendorID {
+                        vendor_id: self.vendor_id,
+                    },
+                )]
+            }
+            CECMessage::GiveOSDName => CECMessage::SetOSDName {
+                name: self.osd_name.clone(),
+            },
+            CECMessage::GetCECVersion => CECMessage::CECVersion {
+                cec_version: self.cec_version,
+            },
+            CECMessage::GiveDevicePowerStatus => CECMessage::ReportPowerStatus {
+                power_status: self.power_status,
+            },
+            _ => match feature_abort(cmd, AbortReason::UnrecognisedOpcode) {
+                Some(message) => message,
+                None => return vec![],
+            },
+        };
+        vec![reply(cmd, message)]
+    }
+}
+
+/// A TV that can be turned on and off, switches to whichever source asks,
+/// and runs LG's vendor handshake with devices that ask for its vendor ID.
+pub struct Tv {
+    pub identity: Identity,
+    pub active_source: PhysicalAddress,
+    pub menu_language: String,
+    /// Devices that finished the vendor handshake, and the type they gave.
+    pub registered: Vec<(LogicalAddress, u8)>,
+    /// Text last sent with <Set OSD String>.
+    pub osd_text: String,
+    /// Names that devices have told the TV with <Set OSD Name>.
+    pub osd_names: Vec<(LogicalAddress, String)>,
+}
+
+impl Tv {
+    pub fn new(vendor_id: u32) -> Self {
+        Tv {
+            identity: Identity {
+                logical_address: LogicalAddress::TV,
+                physical_address: PhysicalAddress::ROOT,
+                osd_name: "TV".into(),
+                vendor_id,
+                cec_version: CECVersion::V1_4,
+                power_status: PowerStatus::Standby,
+            },
+            active_source: PhysicalAddress::ROOT,
+            menu_language: "eng".into(),
+            registered: vec![],
+            osd_text: String::new(),
+            osd_names: vec![],
+        }
+    }
+}
+
+impl Device for Tv {
+    fn logical_address(&self) -> LogicalAddress {
+        self.identity.logical_address
+    }
+
+    fn handle(&mut self, cmd: &CECCommand) -> Vec<CECCommand> {
+        let me = self.identity.logical_address;
+        let lg = self.identity.vendor_id == VENDOR_LG;
+        match &cmd.message {
+            CECMessage::ImageViewOn | CECMessage::TextViewOn => {
+                self.identity.power_status = PowerStatus::On
+            }
+            CECMessage::Standby => self.identity.power_status = PowerStatus::Standby,
+            CECMessage::ActiveSource { physical_address }
+            | CECMessage::SetStreamPath { physical_address } => {
+                self.active_source = *physical_address
+            }
+            CECMessage::RoutingChange { new_address, .. } => self.active_source = *new_address,
+            CECMessage::GetMenuLanguage => {
+                return vec![broadcast(
+                    me,
+                    CECMessage::SetMenuLanguage {
+                        language: self.menu_language.clone(),
+                    },
+                )]
+            }
+            CECMessage::SetOSDString { text, .. } => self.osd_text = text.clone(),
+            CECMessage::SetOSDName { name } => {
+                if let Some(from) = cmd.initiator {
+                    self.osd_names.retain(|(addr, _)| *addr != from);
+                    self.osd_names.push((from, name.clone()));
+                }
+            }
+            // SimpLink starts once a device has learned it's talking to LG.
+            CECMessage::GiveDeviceVendorID if lg => {
+                let mut replies = self.identity.answer(cmd);
+                replies.push(reply(
+                    cmd,
+                    CECMessage::VendorCommand {
+                        vendor_data: vec![0x01],
+                    },
+                ));
+                return replies;
+            }
+            CECMessage::VendorCommand { vendor_data } if lg => match vendor_data[..] {
+                [0x02, ..] => {
+                    return vec![reply(
+                        cmd,
+                        CECMessage::VendorCommand {
+                            vendor_data: vec![0x04],
+                        },
+                    )]
+                }
+                [0x05, device_type, ..] => {
+                    if let Some(from) = cmd.initiator {
+                        let device = (from, device_type);
+                        if !self.registered.contains(&device) {
+                            self.registered.push(device)
+                        }
+                    }
+                }
+                _ => (),
+            },
+            CECMessage::UserControlPressed { .. }
+            | CECMessage::UserControlReleased
+            | CECMessage::FeatureAbort { .. }
+            | CECMessage::ReportPowerStatus { .. } => (),
+            _ if cmd.destination == LogicalAddress::Broadcast => (),
+            _ => return self.identity.answer(cmd),
+        }
+        vec![]
+    }
+}
+
+/// An AV receiver with a volume control, which takes over the TV's speakers
+/// in System Audio Mode.
+pub struct AudioSystem {
+    pub identity: Identity,
+    pub audio_status: AudioStatus,
+    pub system_audio_mode: bool,
+}
+
+impl AudioSystem {
+    pub fn new(physical_address: PhysicalAddress) -> Self {
+        AudioSystem {
+            identity: Identity {
+                logical_address: LogicalAddress::AudioSystem,
+                physical_address,
+                osd_name: "AVR".into(),
+                vendor_id: 0,
+                cec_version: CECVersion::V1_4,
+                power_status: PowerStatus::On,
+            },
+            audio_status: AudioStatus {
+                muted: false,
+                volume: 20,
+            },
+            system_audio_mode: false,
+        }
+    }
+}
+
+impl Device for AudioSystem {
+    fn logical_address(&self) -> LogicalAddress {
+        self.identity.logical_address
+    }
+
+    fn handle(&mut self, cmd: &CECCommand) -> Vec<CECCommand> {
+        let me = self.identity.logical_address;
+        let status = |s: &Self| {
+            reply(
+                cmd,
+                CECMessage::ReportAudioStatus {
+                    audio_status: s.audio_status,
+                },
+            )
+        };
+        match &cmd.message {
+            CECMessage::GiveAudioStatus => vec![status(self)],
+            CECMessage::UserControlPressed { user_control_code } => {
+                let audio = &mut self.audio_status;
+                match user_control_code {
+                    UserControl::VolumeUp => audio.volume = (audio.volume + 1).min(100),
+                    UserControl::VolumeDown => audio.volume = audio.volume.saturating_sub(1),
+                    UserControl::Mute => audio.muted = !audio.muted,
+                    _ => return vec![],
+                }
+                vec![status(self)]
+            }
+            CECMessage::GiveSystemAudioModeStatus => vec![reply(
+                cmd,
+                CECMessage::SystemAudioModeStatus {
+                    system_audio_mode: self.system_audio_mode,
+                },
+            )],
+            CECMessage::SystemAudioModeRequest { physical_address } => {
+                self.system_audio_mode = physical_address.is_some();
+                vec![broadcast(
+                    me,
+                    CECMessage::SetSystemAudioMode {
+                        system_audio_mode: self.system_audio_mode,
+                    },
+                )]
+            }
+            CECMessage::UserControlReleased | CECMessage::FeatureAbort { .. } => vec![],
+            _ if cmd.destination == LogicalAddress::Broadcast => vec![],
+            _ => self.identity.answer(cmd),
+        }
+    }
+}
+
+/// A player that becomes the active source when the TV switches to it.
+pub struct Playback {
+    pub identity: Identity,
+}
+
+impl Playback {
+    pub fn new(logical_address: LogicalAddress, physical_address: PhysicalAddress) -> Self {
+        Playback {
+            identity: Identity {
+                logical_address,
+                physical_address,
+                osd_name: "Player".into(),
+                vendor_id: 0,
+                cec_version: CECVersion::V1_4,
+                power_status: PowerStatus::On,
+            },
+        }
+    }
+}
+
+impl Device for Playback {
+    fn logical_address(&self) -> LogicalAddress {
+        self.identity.logical_address
+    }
+
+    fn handle(&mut self, cmd: &CECCommand) -> Vec<CECCommand> {
+        let me = self.identity.logical_address;
+        match &cmd.message {
+            CECMessage::SetStreamPath { physical_address }
+                if *physical_address == self.identity.physical_address =>
+            {
+                vec![broadcast(
+                    me,
+                    CECMessage::ActiveSource {
+                        physical_address: self.identity.physical_address,
+                    },
+                )]
+            }
+            CECMessage::GiveDeckStatus { status_request } if *status_request != DeckStatus::Off => {
+                vec![reply(
+                    cmd,
+                    CECMessage::DeckStatus {
+                        deck_info: DeckInfo::Stop,
+                    },
+                )]
+            }
+            CECMessage::GiveDeckStatus { .. } | CECMessage::FeatureAbort { .. } => vec![],
+            _ if cmd.destination == LogicalAddress::Broadcast => vec![],
+            _ => self.identity.answer(cmd),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cec::sim::*;
+    use crate::cec::tx::TxConfig;
+    use crate::cec::CEC;
+    use std::sync::mpsc;
+
+    // How long to wait for a frame before giving up on the test.
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    struct Room {
+        bus: Bus,
+        frames: mpsc::Receiver<CECCommand>,
+        tv: Arc<Mutex<Tv>>,
+        avr: Arc<Mutex<AudioSystem>>,
+        cec: CEC,
+    }
+
+    impl Room {
+        // Waits for `cond` to hold, checking again each time a frame has been
+        // delivered, since that's all that changes anything.
+        fn eventually(&self, what: &str, cond: impl Fn() -> bool) {
+            while !cond() {
+                if self.frames.recv_timeout(TIMEOUT).is_err() {
+                    panic!("timed out waiting for {}", what);
+                }
+            }
+        }
+    }
+
+    impl Drop for Room {
+        fn drop(&mut self) {
+            self.bus.shutdown();
+        }
+    }
+
+    fn room() -> Room {
+        let bus = Bus::new();
+        let frames = bus.watch();
+        let tv = bus.add(Tv::new(VENDOR_LG));
+        let avr = bus.add(AudioSystem::new(PhysicalAddress::from(0x1000)));
+        bus.add(Playback::new(
+            LogicalAddress::PlaybackDevice1,
+            PhysicalAddress::from(0x1100),
+        ));
+        let conn = bus.connect(DeviceType::PlaybackDevice, PhysicalAddress::from(0x2000));
+        let cec = CEC::new(Arc::new(conn), "cecvol", VENDOR_LG).unwrap();
+        cec.set_tx_config(TxConfig {
+            retries: 0,
+            ..Default::default()
+        });
+        Room {
+            bus,
+            frames,
+            tv,
+            avr,
+            cec,
+        }
+    }
+
+    #[test]
+    fn starts_up_against_tv() {
+        let room = room();
+        room.eventually("TV to turn on", || {
+            room.tv.lock().unwrap().identity.power_status == PowerStatus::On
+        });
+        room.eventually("menu language", || {
+            room.cec.menu_language() == Some("eng".to_string())
+        });
+        room.eventually("SimpLink handshake", || {
+            room.tv.lock().unwrap().registered
+                == vec![(
+                    LogicalAddress::PlaybackDevice2,
+                    DeviceType::PlaybackDevice as u8,
+                )]
+        });
+        room.cec.poll_all().unwrap();
+        assert_eq!(
+            room.cec.request_audio_status().unwrap(),
+            AudioStatus {
+                muted: false,
+                volume: 20
+            }
+        );
+    }
+
+    #[test]
+    fn injects_faults() {
+        let Room { bus, cec, .. } = &room();
+        bus.inject(Fault::NoAck(LogicalAddress::AudioSystem));
+        assert!(matches!(cec.request_audio_status(), Err(CECError::NoAck)));
+
+        bus.clear_faults();
+        bus.inject(Fault::FeatureAbort(
+            LogicalAddress::AudioSystem,
+            Opcode::GiveAudioStatus,
+            AbortReason::Refused,
+        ));
+        assert!(matches!(
+            cec.request_audio_status(),
+            Err(CECError::Aborted {
+                opcode: Opcode::GiveAudioStatus,
+                reason: AbortReason::Refused
+            })
+        ));
+
+        bus.clear_faults();
+        bus.inject(Fault::Delay(
+            LogicalAddress::AudioSystem,
+            Duration::from_secs(3),
+        ));
+        assert!(matches!(
+            cec.request_audio_status(),
+            Err(CECError::NoReply(Opcode::GiveAudioStatus))
+        ));
+    }
+
+    #[test]
+    fn switches_input_and_changes_volume() {
+        let mut room = room();
+        let inputs = Arc::new(Mutex::new(vec![]));
+        let seen = inputs.clone();
+        room.cec
+            .set_input_callback(Box::new(move |address| seen.lock().unwrap().push(address)));
+        room.cec.switch_to(PhysicalAddress::from(0x1100)).unwrap();
+        room.eventually("TV to switch", || {
+            room.tv.lock().unwrap().active_source == PhysicalAddress::from(0x1100)
+        });
+        // Once for asking, and again when the player says it's active.
+        room.eventually("player to become active", || {
+            *inputs.lock().unwrap() == [PhysicalAddress::from(0x1100); 2]
+        });
+
+        room.cec.set_system_audio_mode(true).unwrap();
+        room.eventually("system audio mode", || room.cec.system_audio_mode());
+        room.cec.volume_change(2).unwrap();
+        room.eventually("volume change", || {
+            room.avr.lock().unwrap().audio_status.volume == 22
+                && room.cec.audio_status().map(|s| s.volume) == Some(22)
+        });
+    }
+
+    fn announced(tv: &Mutex<Tv>) -> bool {
+        tv.lock()
+            .unwrap()
+            .osd_names
+            .contains(&(LogicalAddress::PlaybackDevice2, "cecvol".to_string()))
+    }
+
+    #[test]
+    fn dropping_cec_releases_connection() {
+        let bus = Bus::living_room();
+        let conn = Arc::new(bus.connect(DeviceType::PlaybackDevice, PhysicalAddress::from(0x2000)));
+        let cec = CEC::new(conn.clone(), "cecvol", VENDOR_LG).unwrap();
+        drop(cec);
+        assert_eq!(Arc::strong_count(&conn), 1);
+        bus.shutdown();
+    }
+
+    #[test]
+    fn shutdown_stops_the_bus() {
+        let bus = Bus::living_room();
+        let conn = Arc::new(bus.connect(DeviceType::PlaybackDevice, PhysicalAddress::from(0x2000)));
+        let cec = CEC::new(conn.clone(), "cecvol", VENDOR_LG).unwrap();
+        let inner = Arc::downgrade(&bus.inner);
+        bus.shutdown();
+        assert!(bus.inner.thread.lock().unwrap().is_none());
+        assert!(!bus.send(CECCommand {
+            initiator: Some(LogicalAddress::TV),
+            destination: LogicalAddress::PlaybackDevice2,
+            message: CECMessage::GiveOSDName,
+        }));
+        drop((bus, cec, conn));
+        assert!(inner.upgrade().is_none());
+    }
+
+    #[test]
+    fn refuses_frames_without_an_initiator() {
+        let room = room();
+        for destination in [LogicalAddress::TV, LogicalAddress::Broadcast] {
+            assert!(!room.bus.send(CECCommand {
+                initiator: None,
+                destination,
+                message: CECMessage::GiveOSDName,
+            }));
+        }
+        // The bus carries on.
+        assert!(room.bus.send(CECCommand {
+            initiator: Some(LogicalAddress::AudioSystem),
+            destination: LogicalAddress::TV,
+            message: CECMessage::GiveOSDName,
+        }));
+        loop {
+            let cmd = room.frames.recv_timeout(TIMEOUT).unwrap();
+            if cmd.destination == LogicalAddress::AudioSystem
+                && matches!(cmd.message, CECMessage::SetOSDName { .. })
+            {
+                break;
+            }
+        }
+    }
+
+    #[test]
+    fn reannounces_after_replug() {
+        let room = room();
+        room.bus.hdmi_event(HdmiEvent::Hdmi);
+        room.bus.hdmi_event(HdmiEvent::Unplugged);
+        room.bus.hdmi_event(HdmiEvent::Attached);
+        room.eventually("name to be announced", || announced(&room.tv));
+    }
+
+    #[test]
+    fn fans_out_hdmi_events() {
+        let Room { bus, cec, .. } = &room();
+        let (events, received) = mpsc::channel();
+        for id in 0..2 {
+            let events = events.clone();
+            cec.subscribe_hdmi(Box::new(move |event| events.send((id, event)).unwrap()));
+        }
+        bus.hdmi_event(HdmiEvent::Unplugged);
+        let seen: Vec<_> = received.iter().take(2).collect();
+        assert_eq!(
+            seen,
+            vec![(0, HdmiEvent::Unplugged), (1, HdmiEvent::Unplugged)]
+        );
+    }
+
+    #[test]
+    fn reannounces_when_tv_wakes() {
+        let room = room();
+        room.eventually("TV to turn on", || room.cec.is_on());
+        assert!(!announced(&room.tv));
+        room.bus.send(CECCommand {
+            initiator: Some(LogicalAddress::TV),
+            destination: LogicalAddress::Broadcast,
+            message: CECMessage::Standby,
+        });
+        room.bus.send(CECCommand {
+            initiator: Some(LogicalAddress::TV),
+            destination: LogicalAddress::PlaybackDevice2,
+            message: CECMessage::ReportPowerStatus {
+                power_status: PowerStatus::On,
+            },
+        });
+        room.eventually("name to be announced", || announced(&room.tv));
+    }
+}
@@ -0,0 +1,253 @@
+// Table of the devices seen on the CEC bus.
+//
+// Entries are keyed by logical address and filled in from whatever the
+// devices report about themselves. Physical addresses describe where each
+// device is plugged in, so the table can also be viewed as a tree rooted at
+// the TV.
+
+use crate::cec::{
+    CECCommand, CECMessage, CECVersion, DeviceType, Features, Latency, LogicalAddress,
+    PhysicalAddress, PowerStatus,
+};
+use std::convert::TryFrom;
+use std::time::SystemTime;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Device {
+    pub logical_address: LogicalAddress,
+    pub physical_address: Option<PhysicalAddress>,
+    pub device_type: Option<DeviceType>,
+    pub osd_name: Option<String>,
+    pub vendor_id: Option<u32>,
+    pub cec_version: Option<CECVersion>,
+    pub power_status: Option<PowerStatus>,
+    pub features: Option<Features>,
+    pub latency: Option<Latency>,
+    pub last_seen: SystemTime,
+}
+
+impl Device {
+    fn new(logical_address: LogicalAddress) -> Self {
+        Device {
+            logical_address,
+            physical_address: None,
+            device_type: None,
+            osd_name: None,
+            vendor_id: None,
+            cec_version: None,
+            power_status: None,
+            features: None,
+            latency: None,
+            last_seen: SystemTime::now(),
+        }
+    }
+}
+
+/// A device as the adapter itself has recorded it, for adapters that keep
+/// track of the bus.
+#[derive(Clone, Debug, PartialEq)]
+pub struct KnownDevice {
+    pub logical_address: LogicalAddress,
+    pub physical_address: PhysicalAddress,
+    pub device_type: Option<DeviceType>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeviceNode {
+    pub device: Device,
+    pub children: Vec<DeviceNode>,
+}
+
+#[derive(Default)]
+pub struct DeviceTable {
+    devices: [Option<Device>; 15],
+}
+
+impl DeviceTable {
+    /// Records a message received from another device. Returns true if the
+    /// initiator hadn't been seen before.
+    pub fn update(&mut self, cmd: &CECCommand) -> bool {
+        let addr = match cmd.initiator {
+            Some(LogicalAddress::Broadcast) | None => return false,
+            Some(addr) => addr,
+        };
+        let is_new = self.devices[addr as usize].is_none();
+        let device = self.devices[addr as usize].get_or_insert_with(|| Device::new(addr));
+        device.last_seen = SystemTime::now();
+        match &cmd.message {
+            CECMessage::ReportPhysicalAddress {
+                physical_address,
+                device_type,
+            } => {
+                device.physical_address = Some(*physical_address);
+                device.device_type = Some(*device_type);
+            }
+            CECMessage::SetOSDName { name } => device.osd_name = Some(name.clone()),
+            CECMessage::DeviceVendorID { vendor_id } => device.vendor_id = Some(*vendor_id),
+            CECMessage::CECVersion { cec_version } => device.cec_version = Some(*cec_version),
+            CECMessage::ReportPowerStatus { power_status } => {
+                device.power_status = Some(*power_status)
+            }
+            CECMessage::ReportFeatures { features } => {
+                device.cec_version = Some(features.cec_version);
+                device.features = Some(*features);
+            }
+            CECMessage::ReportCurrentLatency { latency, .. } => device.latency = Some(*latency),
+            _ => {}
+        }
+        is_new
+    }
+
+    /// Replaces the set of present devices with those in `present`, as
+    /// reported by the connection's own view of the bus.
+    pub fn set_present(&mut self, present: &[LogicalAddress]) {
+        for (i, slot) in self.devices.iter_mut().enumerate() {
+            let addr = LogicalAddress::try_from(i as u8).unwrap_or(LogicalAddress::Broadcast);
+            if present.contains(&addr) {
+                slot.get_or_insert_with(|| Device::new(addr)).last_seen = SystemTime::now();
+            } else {
+                *slot = None;
+            }
+        }
+    }
+
+    /// Replaces the table's idea of who is present with the adapter's,
+    /// filling in what it knows about each device.
+    pub fn set_known(&mut self, known: &[KnownDevice]) {
+        let present: Vec<LogicalAddress> = known.iter().map(|d| d.logical_address).collect();
+        self.set_present(&present);
+        for known in known {
+            if let Some(Some(device)) = self.devices.get_mut(known.logical_address as usize) {
+                device.physical_address = Some(known.physical_address);
+                device.device_type = known.device_type.or(device.device_type);
+            }
+        }
+    }
+
+    pub fn get(&self, addr: LogicalAddress) -> Option<&Device> {
+        self.devices.get(addr as usize).and_then(|d| d.as_ref())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Device> {
+        self.devices.iter().flatten()
+    }
+
+    /// Arranges the devices by physical address. Devices whose upstream
+    /// device isn't known, including those without a physical address, are
+    /// returned as roots.
+    pub fn tree(&self) -> Vec<DeviceNode> {
+        let parent_of = |device: &Device| -> Option<LogicalAddress> {
+            let mut addr = device.physical_address?.parent();
+            while let Some(a) = addr {
+                if let Some(parent) = self.iter().find(|d| d.physical_address == Some(a)) {
+                    return Some(parent.logical_address);
+                }
+                addr = a.parent();
+            }
+            None
+        };
+        fn build(
+            table: &DeviceTable,
+            parent: Option<LogicalAddress>,
+            parent_of: &dyn Fn(&Device) -> Option<LogicalAddress>,
+        ) -> Vec<DeviceNode> {
+            let mut nodes: Vec<DeviceNode> = table
+                .iter()
+                .filter(|d| parent_of(d) == parent)
+                .map(|d| DeviceNode {
+                    device: d.clone(),
+                    children: build(table, Some(d.logical_address), parent_of),
+                })
+                .collect();
+            nodes.sort_by_key(|n| (n.device.physical_address, n.device.logical_address as u8));
+            nodes
+        }
+        build(self, None, &parent_of)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cec::topology::*;
+
+    fn report(from: LogicalAddress, physical_address: u16) -> CECCommand {
+        CECCommand {
+            initiator: Some(from),
+            destination: LogicalAddress::Broadcast,
+            message: CECMessage::ReportPhysicalAddress {
+                physical_address: physical_address.into(),
+                device_type: from.to_device_type(),
+            },
+        }
+    }
+
+    #[test]
+    fn builds_tree_through_audio_system() {
+        let mut table = DeviceTable::default();
+        assert!(table.update(&report(LogicalAddress::TV, 0x0000)));
+        assert!(table.update(&report(LogicalAddress::AudioSystem, 0x1000)));
+        assert!(table.update(&report(LogicalAddress::PlaybackDevice1, 0x1200)));
+        assert!(table.update(&report(LogicalAddress::PlaybackDevice2, 0x2000)));
+        assert!(!table.update(&CECCommand {
+            initiator: Some(LogicalAddress::PlaybackDevice1),
+            destination: LogicalAddress::TV,
+            message: CECMessage::SetOSDName {
+                name: "cecvol".to_string(),
+            },
+        }));
+
+        let tree = table.tree();
+        assert_eq!(tree.len(), 1);
+        let tv = &tree[0];
+        assert_eq!(tv.device.logical_address, LogicalAddress::TV);
+        assert_eq!(tv.children.len(), 2);
+        let avr = &tv.children[0];
+        assert_eq!(avr.device.logical_address, LogicalAddress::AudioSystem);
+        assert_eq!(avr.children[0].device.osd_name.as_deref(), Some("cecvol"));
+        assert_eq!(
+            tv.children[1].device.logical_address,
+            LogicalAddress::PlaybackDevice2
+        );
+    }
+
+    #[test]
+    fn set_present_prunes_missing_devices() {
+        let mut table = DeviceTable::default();
+        table.update(&report(LogicalAddress::TV, 0x0000));
+        table.update(&report(LogicalAddress::Tuner1, 0x3000));
+        table.set_present(&[LogicalAddress::TV, LogicalAddress::AudioSystem]);
+        assert!(table.get(LogicalAddress::Tuner1).is_none());
+        assert_eq!(
+            table.get(LogicalAddress::TV).unwrap().physical_address,
+            Some(PhysicalAddress::ROOT)
+        );
+        assert!(table.get(LogicalAddress::AudioSystem).is_some());
+    }
+
+    #[test]
+    fn set_known_fills_in_addresses() {
+        let mut table = DeviceTable::default();
+        table.update(&report(LogicalAddress::Tuner1, 0x3000));
+        table.set_known(&[
+            KnownDevice {
+                logical_address: LogicalAddress::TV,
+                physical_address: PhysicalAddress::ROOT,
+                device_type: Some(DeviceType::TV),
+            },
+            KnownDevice {
+                logical_address: LogicalAddress::AudioSystem,
+                physical_address: PhysicalAddress::from(0x1000),
+                device_type: None,
+            },
+        ]);
+        assert!(table.get(LogicalAddress::Tuner1).is_none());
+        let tree = table.tree();
+        assert_eq!(tree.len(), 1);
+        assert_eq!(tree[0].device.logical_address, LogicalAddress::TV);
+        assert_eq!(tree[0].device.device_type, Some(DeviceType::TV));
+        assert_eq!(
+            tree[0].children[0].device.logical_address,
+            LogicalAddress::AudioSystem
+        );
+    }
+}
@@ -0,0 +1,482 @@
+// Serialised transmission of frames.
+//
+// Only one frame can be on the bus at a time, so senders take a ticket and
+// wait for their turn, which keeps frames in the order they were queued. Some
+// adapters accept a frame straight away and only say later, through the tx
+// callback, whether it was acknowledged, so the turn isn't passed on until
+// that report arrives. A frame that isn't acknowledged, or that can't get on
+// the bus because it's busy, is retried with exponential backoff before the
+// error is passed back to the sender.
+
+use crate::cec::{CECCommand, CECConnection, CECError};
+use log::{debug, warn};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::thread;
+use std::time::Duration;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum TxError {
+    #[error("Transmit queue has stopped")]
+    Stopped,
+    #[error("Adapter didn't say whether the frame was acknowledged")]
+    Unconfirmed,
+}
+
+impl From<TxError> for CECError {
+    fn from(val: TxError) -> Self {
+        CECError::Other(Box::new(val))
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct TxConfig {
+    /// How many times to resend a frame after the first attempt fails.
+    pub retries: u32,
+    /// How long to wait before the first resend. Doubles with every attempt.
+    pub backoff: Duration,
+    /// How long to wait for the adapter to report how sending a frame went.
+    /// Frames it doesn't report on fail with [`TxError::Unconfirmed`], and
+    /// aren't retried.
+    pub report_timeout: Duration,
+}
+
+impl Default for TxConfig {
+    fn default() -> Self {
+        TxConfig {
+            retries: 2,
+            backoff: Duration::from_millis(50),
+            report_timeout: Duration::from_millis(500),
+        }
+    }
+}
+
+pub(crate) struct TxQueue {
+    conn: Arc<dyn CECConnection>,
+    config: Mutex<TxConfig>,
+    // The next ticket to hand out, and the ticket whose turn it is.
+    tickets: Mutex<(u64, u64)>,
+    turn: Condvar,
+    // The last frame the adapter reported sending, and how that went.
+    report: Mutex<Option<(CECCommand, Result<(), CECError>)>>,
+    reported: Condvar,
+    // Started by the first posted frame.
+    poster: Mutex<Option<Poster>>,
+    stopped: AtomicBool,
+}
+
+// The thread sending posted frames, and where to hand it the rest along with
+// their tickets.
+struct Poster {
+    posted: Sender<(u64, CECCommand)>,
+    thread: thread::JoinHandle<()>,
+}
+
+// Passes the turn on when dropped, even if the send panicked.
+struct Turn<'a>(&'a TxQueue);
+
+impl Drop for Turn<'_> {
+    fn drop(&mut self) {
+        self.0.tickets.lock().unwrap().1 += 1;
+        self.0.turn.notify_all();
+    }
+}
+
+impl TxQueue {
+    pub fn new(conn: Arc<dyn CECConnection>) -> Self {
+        TxQueue {
+            conn,
+            config: Mutex::new(TxConfig::default()),
+            tickets: Mutex::new((0, 0)),
+            turn: Condvar::new(),
+            report: Mutex::new(None),
+            reported: Condvar::new(),
+            poster: Mutex::new(None),
+            stopped: AtomicBool::new(false),
+        }
+    }
+
+    pub fn set_config(&self, config: TxConfig) {
+        *self.config.lock().unwrap() = config;
+    }
+
+    /// Passes on what the adapter's tx callback said about a frame.
+    pub fn report(&self, cmd: &CECCommand, result: Result<(), CECError>) {
+        *self.report.lock().unwrap() = Some((cmd.clone(), result));
+        self.reported.notify_all();
+    }
+
+    /// Sends a frame once every frame queued before it has been sent. Returns
+    /// Ok if the frame was acknowledged.
+    pub fn send(&self, cmd: CECCommand) -> Result<(), CECError> {
+        let ticket = self.take_ticket();
+        self.send_in_turn(ticket, cmd)
+    }
+
+    /// Queues a frame without waiting for it to be sent, for callbacks on the
+    /// adapter's own thread, which may be the one that has to report on the
+    /// frame before it. Failures are only logged.
+    pub fn post(self: &Arc<Self>, cmd: CECCommand) {
+        let mut poster = self.poster.lock().unwrap();
+        // Taken under the lock, so posted frames reach the poster in the
+        // order of their tickets.
+        let ticket = self.take_ticket();
+        if poster.is_none() {
+            match self.start_poster() {
+                Ok(started) => *poster = Some(started),
+                Err(e) => {
+                    warn!("failed to start a thread to send posted frames: {}", e);
+                    // Give up the turn, or everything queued after it would
+                    // wait.
+                    let _turn = self.wait_for_turn(ticket);
+                    return;
+                }
+            }
+        }
+        if let Some(poster) = &*poster {
+            // The poster only stops once its sender is gone.
+            let _ = poster.posted.send((ticket, cmd));
+        }
+    }
+
+    // Sends posted frames one at a time. It holds the queue weakly, and stops
+    // once the queue is flushed or dropped.
+    fn start_poster(self: &Arc<Self>) -> std::io::Result<Poster> {
+        let (posted, frames) = mpsc::channel::<(u64, CECCommand)>();
+        let queue: Weak<Self> = Arc::downgrade(self);
+        let thread = thread::Builder::new()
+            .name("CEC post".into())
+            .spawn(move || {
+                for (ticket, cmd) in frames {
+                    let Some(queue) = queue.upgrade() else {
+                        return;
+                    };
+                    let opcode = cmd.message.get_opcode();
+                    let destination = cmd.destination;
+                    if let Err(e) = queue.send_in_turn(ticket, cmd) {
+                        warn!("failed to send {:?} to {:?}: {}", opcode, destination, e);
+                    }
+                }
+            })?;
+        Ok(Poster { posted, thread })
+    }
+
+    /// Fails every frame that hasn't gone out yet, and any sent later.
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        self.reported.notify_all();
+    }
+
+    /// Waits for every posted frame to have been sent, or to have failed.
+    pub fn flush(&self) {
+        let poster = self.poster.lock().unwrap().take();
+        if let Some(Poster { posted, thread }) = poster {
+            drop(posted);
+            let _ = thread.join();
+        }
+    }
+
+    fn take_ticket(&self) -> u64 {
+        let (next, _) = &mut *self.tickets.lock().unwrap();
+        *next += 1;
+        *next - 1
+    }
+
+    fn wait_for_turn(&self, ticket: u64) -> Turn<'_> {
+        drop(
+            self.turn
+                .wait_while(self.tickets.lock().unwrap(), |(_, serving)| {
+                    *serving != ticket
+                })
+                .unwrap(),
+        );
+        Turn(self)
+    }
+
+    fn send_in_turn(&self, ticket: u64, cmd: CECCommand) -> Result<(), CECError> {
+        let _turn = self.wait_for_turn(ticket);
+        let config = *self.config.lock().unwrap();
+        let mut attempt = 0;
+        loop {
+            if self.stopped.load(Ordering::SeqCst) {
+                return Err(TxError::Stopped.into());
+            }
+            match self.transmit(&cmd, config.report_timeout) {
+                Err(e @ (CECError::NoAck | CECError::Busy)) if attempt < config.retries => {
+                    debug!("retrying {:x?} after error: {}", cmd, e);
+                    thread::sleep(config.backoff * 2u32.pow(attempt));
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    // Sends a frame and waits for the adapter to say whether it was
+    // acknowledged.
+    fn transmit(&self, cmd: &CECCommand, timeout: Duration) -> Result<(), CECError> {
+        self.report.lock().unwrap().take();
+        self.conn.transmit(cmd.clone())?;
+        let (mut report, waited) = self
+            .reported
+            .wait_timeout_while(self.report.lock().unwrap(), timeout, |report| {
+                !matches!(report, Some((sent, _))
+                    if sent.destination == cmd.destination && sent.message == cmd.message)
+            })
+            .unwrap();
+        if waited.timed_out() {
+            return Err(TxError::Unconfirmed.into());
+        }
+        report.take().map_or(Ok(()), |(_, result)| result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cec::tx::*;
+    use crate::cec::{standby, LogicalAddress, PhysicalAddress, TxCallback};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Weak;
+
+    // Fails every transmit with the given errors until they run out, and
+    // reports the frames that go out to its queue.
+    struct FlakyConn {
+        errors: Mutex<Vec<CECError>>,
+        sent: Mutex<Vec<CECCommand>>,
+        // The threads that sent them.
+        senders: Mutex<Vec<thread::ThreadId>>,
+        queue: Mutex<Weak<TxQueue>>,
+    }
+
+    impl FlakyConn {
+        fn new(errors: Vec<CECError>) -> Arc<Self> {
+            Arc::new(FlakyConn {
+                errors: Mutex::new(errors),
+                sent: Mutex::new(vec![]),
+                senders: Mutex::new(vec![]),
+                queue: Mutex::new(Weak::new()),
+            })
+        }
+    }
+
+    impl CECConnection for FlakyConn {
+        fn transmit(&self, cmd: CECCommand) -> Result<(), CECError> {
+            self.sent.lock().unwrap().push(cmd.clone());
+            self.senders.lock().unwrap().push(thread::current().id());
+            match self.errors.lock().unwrap().pop() {
+                Some(e) => Err(e),
+                None => {
+                    if let Some(queue) = self.queue.lock().unwrap().upgrade() {
+                        queue.report(&cmd, Ok(()));
+                    }
+                    Ok(())
+                }
+            }
+        }
+        fn get_logical_address(&self) -> Result<LogicalAddress, CECError> {
+            Ok(LogicalAddress::PlaybackDevice1)
+        }
+        fn get_physical_address(&self) -> Result<PhysicalAddress, CECError> {
+            Ok(0x1000.into())
+        }
+        fn set_tx_callback(&self, _: TxCallback) {}
+        fn set_rx_callback(&self, _: Box<dyn FnMut(&CECCommand) + Send>) {}
+    }
+
+    fn queue(conn: &Arc<FlakyConn>, retries: u32) -> Arc<TxQueue> {
+        let queue = Arc::new(TxQueue::new(conn.clone()));
+        queue.set_config(TxConfig {
+            retries,
+            backoff: Duration::from_millis(1),
+            report_timeout: Duration::from_millis(1),
+        });
+        *conn.queue.lock().unwrap() = Arc::downgrade(&queue);
+        queue
+    }
+
+    // Accepts every frame straight away and reports how it went a little
+    // later from another thread, the way the VideoCore firmware does.
+    struct LateReportingConn {
+        errors: Mutex<Vec<CECError>>,
+        sent: Mutex<Vec<CECCommand>>,
+        queue: Mutex<Weak<TxQueue>>,
+        on_bus: Arc<AtomicBool>,
+        overlapped: AtomicBool,
+    }
+
+    impl CECConnection for LateReportingConn {
+        fn transmit(&self, cmd: CECCommand) -> Result<(), CECError> {
+            if self.on_bus.swap(true, Ordering::SeqCst) {
+                self.overlapped.store(true, Ordering::SeqCst);
+            }
+            self.sent.lock().unwrap().push(cmd.clone());
+            let result = match self.errors.lock().unwrap().pop() {
+                Some(e) => Err(e),
+                None => Ok(()),
+            };
+            let queue = self.queue.lock().unwrap().clone();
+            let on_bus = self.on_bus.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(5));
+                on_bus.store(false, Ordering::SeqCst);
+                if let Some(queue) = queue.upgrade() {
+                    queue.report(&cmd, result);
+                }
+            });
+            Ok(())
+        }
+        fn get_logical_address(&self) -> Result<LogicalAddress, CECError> {
+            Ok(LogicalAddress::PlaybackDevice1)
+        }
+        fn get_physical_address(&self) -> Result<PhysicalAddress, CECError> {
+            Ok(0x1000.into())
+        }
+        fn set_tx_callback(&self, _: TxCallback) {}
+        fn set_rx_callback(&self, _: Box<dyn FnMut(&CECCommand) + Send>) {}
+    }
+
+    fn late_reporting(errors: Vec<CECError>) -> (Arc<LateReportingConn>, Arc<TxQueue>) {
+        let conn = Arc::new(LateReportingConn {
+            errors: Mutex::new(errors),
+            sent: Mutex::new(vec![]),
+            queue: Mutex::new(Weak::new()),
+            on_bus: Arc::new(AtomicBool::new(false)),
+            overlapped: AtomicBool::new(false),
+        });
+        let queue = Arc::new(TxQueue::new(conn.clone()));
+        queue.set_config(TxConfig {
+            retries: 2,
+            backoff: Duration::from_millis(1),
+            report_timeout: Duration::from_secs(5),
+        });
+        *conn.queue.lock().unwrap() = Arc::downgrade(&queue);
+        (conn, queue)
+    }
+
+    #[test]
+    fn retries_until_acknowledged() {
+        let conn = FlakyConn::new(vec![CECError::Busy, CECError::NoAck]);
+        assert!(queue(&conn, 2).send(standby(LogicalAddress::TV)).is_ok());
+        assert_eq!(conn.sent.lock().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn reports_no_ack_after_retries() {
+        let conn = FlakyConn::new(vec![CECError::NoAck, CECError::NoAck]);
+        assert!(matches!(
+            queue(&conn, 1).send(standby(LogicalAddress::TV)),
+            Err(CECError::NoAck)
+        ));
+        assert_eq!(conn.sent.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn does_not_retry_other_errors() {
+        let conn = FlakyConn::new(vec![CECError::UnknownInputDevice("x".to_string())]);
+        assert!(queue(&conn, 3).send(standby(LogicalAddress::TV)).is_err());
+        assert_eq!(conn.sent.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn fails_frames_that_are_never_reported_on() {
+        let conn = FlakyConn::new(vec![]);
+        // The connection isn't told about this queue, so it never reports.
+        let queue = TxQueue::new(conn.clone());
+        queue.set_config(TxConfig {
+            retries: 2,
+            backoff: Duration::from_millis(1),
+            report_timeout: Duration::from_millis(10),
+        });
+        assert!(matches!(
+            queue.send(standby(LogicalAddress::TV)),
+            Err(CECError::Other(e)) if matches!(e.downcast_ref(), Some(TxError::Unconfirmed))
+        ));
+        assert_eq!(conn.sent.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn retries_when_no_ack_is_reported_late() {
+        let (conn, queue) = late_reporting(vec![CECError::NoAck]);
+        assert!(queue.send(standby(LogicalAddress::TV)).is_ok());
+        assert_eq!(conn.sent.lock().unwrap().len(), 2);
+
+        let (conn, queue) = late_reporting(vec![CECError::NoAck, CECError::NoAck, CECError::NoAck]);
+        assert!(matches!(
+            queue.send(standby(LogicalAddress::TV)),
+            Err(CECError::NoAck)
+        ));
+        assert_eq!(conn.sent.lock().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn waits_for_each_report_before_sending_on() {
+        let (conn, queue) = late_reporting(vec![]);
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let queue = queue.clone();
+                thread::spawn(move || queue.send(standby(LogicalAddress::TV)))
+            })
+            .collect();
+        for _ in 0..4 {
+            queue.post(standby(LogicalAddress::TV));
+        }
+        for t in threads {
+            assert!(t.join().unwrap().is_ok());
+        }
+        assert!(queue.send(standby(LogicalAddress::TV)).is_ok());
+        assert_eq!(conn.sent.lock().unwrap().len(), 9);
+        assert!(!conn.overlapped.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn sends_from_many_threads() {
+        let conn = FlakyConn::new(vec![]);
+        let queue = queue(&conn, 0);
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                let queue = queue.clone();
+                thread::spawn(move || queue.send(standby(LogicalAddress::TV)))
+            })
+            .collect();
+        for t in threads {
+            assert!(t.join().unwrap().is_ok());
+        }
+        assert_eq!(conn.sent.lock().unwrap().len(), 8);
+        assert_eq!(*queue.tickets.lock().unwrap(), (8, 8));
+    }
+
+    #[test]
+    fn sends_posted_frames_in_order_from_one_thread() {
+        let conn = FlakyConn::new(vec![]);
+        let queue = queue(&conn, 0);
+        for destination in [
+            LogicalAddress::TV,
+            LogicalAddress::AudioSystem,
+            LogicalAddress::Tuner1,
+        ] {
+            queue.post(standby(destination));
+        }
+        queue.flush();
+        let destinations: Vec<_> = conn
+            .sent
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|cmd| cmd.destination)
+            .collect();
+        assert_eq!(
+            destinations,
+            [
+                LogicalAddress::TV,
+                LogicalAddress::AudioSystem,
+                LogicalAddress::Tuner1
+            ]
+        );
+        let senders = conn.senders.lock().unwrap();
+        assert!(senders.iter().all(|id| *id == senders[0]));
+        assert_ne!(senders[0], thread::current().id());
+        assert!(queue.poster.lock().unwrap().is_none());
+    }
+}
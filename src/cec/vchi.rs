@@ -5,10 +5,14 @@
 // https://github.com/raspberrypi/userland/blob/master/interface/vchiq_arm/vchiq_lib.c
 // https://github.com/raspberrypi/userland/blob/master/interface/vmcs_host/vc_vchi_cecservice.c
 
+use crate::cec::edid;
+use crate::cec::topology::KnownDevice;
 use crate::cec::vchiq_ioctl;
 use crate::cec::vchiq_ioctl::{Element, ServiceHandle, VersionNum};
 use crate::cec::{
-    CECCommand, CECConnection, CECError, DeviceType, LogicalAddress, PhysicalAddress,
+    claim_logical_address, CECCommand, CECConnection, CECError, DeviceType, HdmiCallback,
+    HdmiEvent, LogicalAddress, Opcode, PhysicalAddress, ReconnectCallback, SharedTxCallback,
+    TopologyCallback, TxCallback,
 };
 use lazy_static::lazy_static;
 use log::{debug, info, warn};
@@ -21,8 +25,11 @@ use std::mem::{size_of, zeroed};
 use std::os::raw::c_int;
 use std::os::unix::io::{AsRawFd, RawFd};
 use std::ptr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Sender};
 use std::sync::{Arc, Condvar, Mutex};
 use std::thread;
+use std::time::Duration;
 
 const DEV_VCHIQ: &str = "/dev/vchiq";
 const VCHIQ_SERVICE_HANDLE_INVALID: ServiceHandle = 0;
@@ -37,6 +44,13 @@ const CECSERVICE_NOTIFY_NAME: FourCC = FourCC::from_str("CECN");
 const TVSERVICE_NOTIFY_SIZE: usize = size_of::<u32>() * 3;
 const CEC_NOTIFY_SIZE: usize = size_of::<u32>() * 5;
 const OSD_NAME_LENGTH: usize = 14;
+const DISPLAY_STATE_SIZE: usize = size_of::<u32>() * 7;
+const SUPPORTED_MODES_RESP_SIZE: usize = size_of::<u32>() * 3;
+const SUPPORTED_MODE_SIZE: usize = 20;
+const MAX_SUPPORTED_MODES: usize = 128;
+const TOPOLOGY_SIZE: usize = size_of::<u16>() * 2 + size_of::<u32>() * 16;
+const REOPEN_DELAY: Duration = Duration::from_secs(1);
+const MAX_REOPEN_DELAY: Duration = Duration::from_secs(30);
 
 struct FourCC([char; 4]);
 impl FourCC {
@@ -60,9 +74,9 @@ impl From<u32> for FourCC {
         ])
     }
 }
-impl Into<u32> for FourCC {
-    fn into(self) -> u32 {
-        let FourCC(x) = self;
+impl From<FourCC> for u32 {
+    fn from(val: FourCC) -> Self {
+        let FourCC(x) = val;
         ((x[0] as u32) << 24) | ((x[1] as u32) << 16) | ((x[2] as u32) << 8) | (x[3] as u32)
     }
 }
@@ -118,7 +132,7 @@ impl VchiqIoctls {
         buffer: &mut [u8],
     ) -> Result<usize, nix::Error> {
         let mut dequeue = vchiq_ioctl::DequeueMessage {
-            handle: handle,
+            handle,
             blocking: 0,
             bufsize: buffer.len() as u32,
             buf: buffer.as_mut_ptr() as *mut c_void,
@@ -132,16 +146,13 @@ impl VchiqIoctls {
         client: FourCC,
         signal: Arc<Signal>,
         vc_version: VersionNum,
-    ) -> Result<ServiceHandle, nix::Error> {
-        let userdata = Box::new(ServiceUserdata {
-            signal: &signal,
-            handle: 0,
-        });
+    ) -> Result<(ServiceHandle, UserdataPtr), nix::Error> {
+        let userdata = Box::into_raw(Box::new(ServiceUserdata { signal, handle: 0 }));
         let mut service = vchiq_ioctl::CreateService {
             service_params: vchiq_ioctl::ServiceParams {
                 fourcc: client.into(),
                 callback: service_callback,
-                userdata: Box::into_raw(userdata) as *mut c_void,
+                userdata: userdata as *mut c_void,
                 version: vc_version,
                 version_min: vc_version,
             },
@@ -150,11 +161,38 @@ impl VchiqIoctls {
             handle: VCHIQ_SERVICE_HANDLE_INVALID, /* OUT */
         };
 
-        retry(|| unsafe { vchiq_ioctl::create_service(self.fd(), &mut service) })?;
-        let recovered_userdata = service.service_params.userdata as *mut ServiceUserdata;
-        unsafe { (*recovered_userdata).handle = service.handle };
-        retry(|| unsafe { vchiq_ioctl::release_service(self.fd(), service.handle as usize) })?;
-        Ok(service.handle)
+        if let Err(e) = retry(|| unsafe { vchiq_ioctl::create_service(self.fd(), &mut service) }) {
+            drop(unsafe { Box::from_raw(userdata) });
+            return Err(e);
+        }
+        unsafe { (*userdata).handle = service.handle };
+        retry(|| unsafe { vchiq_ioctl::release_service(self.fd(), service.handle) })?;
+        Ok((service.handle, UserdataPtr(userdata)))
+    }
+
+    // Waits for the VideoCore to send `buffer.len()` bytes.
+    pub fn bulk_receive(
+        &mut self,
+        handle: ServiceHandle,
+        buffer: &mut [u8],
+    ) -> Result<vchiq_ioctl::Status, nix::Error> {
+        let mut transfer = vchiq_ioctl::QueueBulkTransfer {
+            handle,
+            data: buffer.as_mut_ptr() as *mut c_void,
+            size: buffer.len() as u32,
+            userdata: ptr::null_mut(),
+            mode: vchiq_ioctl::BulkMode::Blocking,
+        };
+        let code = retry(|| unsafe { vchiq_ioctl::queue_bulk_receive(self.fd(), &mut transfer) })?;
+        Ok(vchiq_ioctl::Status::try_from(code as i8).unwrap_or(vchiq_ioctl::Status::Error))
+    }
+
+    pub fn remove_service(&mut self, handle: ServiceHandle) -> Result<(), nix::Error> {
+        retry(|| unsafe { vchiq_ioctl::remove_service(self.fd(), handle) }).map(|_| ())
+    }
+
+    pub fn shutdown(&mut self) -> Result<(), nix::Error> {
+        retry(|| unsafe { vchiq_ioctl::shutdown(self.fd(), 0) }).map(|_| ())
     }
 
     pub fn lib_version(&mut self, version: VersionNum) -> Result<(), nix::Error> {
@@ -174,15 +212,11 @@ impl VchiqIoctls {
     }
 
     pub fn use_service(&mut self, handle: ServiceHandle) -> Result<(), nix::Error> {
-        retry(|| unsafe { vchiq_ioctl::use_service(self.fd(), handle as usize) }).map(|_| ())
+        retry(|| unsafe { vchiq_ioctl::use_service(self.fd(), handle) }).map(|_| ())
     }
 
     pub fn release_service(&mut self, handle: ServiceHandle) -> Result<(), nix::Error> {
-        retry(|| unsafe { vchiq_ioctl::release_service(self.fd(), handle as usize) }).map(|_| ())
-    }
-
-    pub fn close_delivered(&mut self, handle: ServiceHandle) -> Result<(), nix::Error> {
-        retry(|| unsafe { vchiq_ioctl::close_delivered(self.fd(), handle as usize) }).map(|_| ())
+        retry(|| unsafe { vchiq_ioctl::release_service(self.fd(), handle) }).map(|_| ())
     }
 
     pub fn using_service<F, E>(&mut self, handle: ServiceHandle, func: F) -> Result<(), E>
@@ -204,6 +238,13 @@ impl VchiqIoctls {
             Ok(retry(|| unsafe { vchiq_ioctl::await_completion(fd, args) })? as usize)
         })
     }
+
+    // Removing a service waits for the completion thread to acknowledge the
+    // close, so that thread can't wait on the lock to do so.
+    pub fn close_delivered_fn(&self) -> impl Fn(ServiceHandle) -> Result<(), nix::Error> {
+        let fd = self.fd();
+        move |handle| retry(|| unsafe { vchiq_ioctl::close_delivered(fd, handle) }).map(|_| ())
+    }
 }
 
 fn retry<F>(mut func: F) -> nix::Result<c_int>
@@ -225,6 +266,7 @@ where
  */
 #[repr(u16)]
 #[derive(Copy, Clone, Debug, PartialEq, TryFromPrimitive)]
+#[allow(clippy::upper_case_acronyms)]
 enum HDMIReason {
     Unknown,
     Unplugged = 1 << 0,       /*<HDMI cable is detached */
@@ -238,6 +280,21 @@ enum HDMIReason {
     ChangingMode = 1 << 8,    /*<HDMI is starting to change mode, clock has not yet been set */
 }
 
+impl HDMIReason {
+    fn event(self) -> Option<HdmiEvent> {
+        match self {
+            HDMIReason::Unplugged => Some(HdmiEvent::Unplugged),
+            HDMIReason::Attached => Some(HdmiEvent::Attached),
+            HDMIReason::DVI => Some(HdmiEvent::Dvi),
+            HDMIReason::HDMI => Some(HdmiEvent::Hdmi),
+            HDMIReason::HDCPUnauth => Some(HdmiEvent::HdcpUnauthenticated),
+            HDMIReason::HDCPAuth => Some(HdmiEvent::HdcpAuthenticated),
+            HDMIReason::ChangingMode => Some(HdmiEvent::ChangingMode),
+            HDMIReason::Unknown | HDMIReason::HDCPKeyDownload | HDMIReason::HDCPSRMDownload => None,
+        }
+    }
+}
+
 /**
  * CEC related notification
  */
@@ -282,6 +339,133 @@ enum CECServiceCommand {
     SetPassive,
 }
 
+// TV service commands
+#[repr(u32)]
+#[allow(dead_code)]
+enum TVServiceCommand {
+    GetState = 0,
+    HdmiOnPreferred,
+    HdmiOnBest,
+    HdmiOnExplicit,
+    SdtvOn,
+    Off,
+    QuerySupportedModes,
+    QueryModeSupport,
+    QueryAudioSupport,
+    EnableCopyProtect,
+    DisableCopyProtect,
+    ShowInfo,
+    GetAvLatency,
+    HdcpSetKey,
+    HdcpSetSrm,
+    SetSpd,
+    SetDisplayOptions,
+    TestModeStart,
+    TestModeStop,
+    DdcRead,
+    SetAttached,
+    SetProp,
+    GetProp,
+    GetDisplayState,
+    QuerySupportedModesActual,
+    GetDeviceId,
+    GetDisplayStateId,
+}
+
+/// Which table an HDMI mode code refers to.
+#[repr(u32)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, TryFromPrimitive)]
+pub enum ResolutionGroup {
+    /// The CEA-861 modes TVs use.
+    Cea = 1,
+    /// The VESA modes monitors use.
+    Dmt = 2,
+}
+
+#[repr(u32)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum HdmiMode {
+    Dvi = 1,
+    Hdmi = 2,
+}
+
+/// The state of the HDMI output, as reported by the TV service.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DisplayState {
+    /// Bits as in the HDMI notifications, such as attached or HDCP active.
+    pub state: u32,
+    pub width: u32,
+    pub height: u32,
+    pub frame_rate: u16,
+    pub interlaced: bool,
+    pub group: Option<ResolutionGroup>,
+    pub mode: u32,
+}
+
+impl DisplayState {
+    fn from_raw(raw: &[u8]) -> DisplayState {
+        let word = |i: usize| u32::from_le_bytes(raw[i..i + 4].try_into().unwrap());
+        let half = |i: usize| u16::from_le_bytes(raw[i..i + 2].try_into().unwrap());
+        // The first word says which of HDMI and SDTV the rest describes, and
+        // we only drive HDMI.
+        DisplayState {
+            state: word(4),
+            width: word(8),
+            height: word(12),
+            frame_rate: half(16),
+            interlaced: half(18) != 0,
+            group: ResolutionGroup::try_from(word(20)).ok(),
+            mode: word(24),
+        }
+    }
+
+    /// Whether a display is plugged in.
+    pub fn is_attached(&self) -> bool {
+        self.state & HDMIReason::Unplugged as u32 == 0
+    }
+
+    /// Whether the output is powered, in either HDMI or DVI mode.
+    pub fn is_on(&self) -> bool {
+        self.state & (HDMIReason::HDMI as u32 | HDMIReason::DVI as u32) != 0
+    }
+}
+
+/// A mode the display says it supports.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SupportedMode {
+    pub group: Option<ResolutionGroup>,
+    pub code: u32,
+    pub width: u16,
+    pub height: u16,
+    pub frame_rate: u16,
+    pub interlaced: bool,
+    /// Whether the display calls this its native mode.
+    pub native: bool,
+    /// Whether the firmware would pick this mode when powering on.
+    pub preferred: bool,
+    pub pixel_freq: u32,
+}
+
+impl SupportedMode {
+    fn from_raw(raw: &[u8]) -> SupportedMode {
+        // Bitfields for scan mode, native, group, code, pixel repetition and
+        // aspect ratio, from the lowest bit up.
+        let bits = u32::from_le_bytes(raw[0..4].try_into().unwrap());
+        let half = |i: usize| u16::from_le_bytes(raw[i..i + 2].try_into().unwrap());
+        SupportedMode {
+            group: ResolutionGroup::try_from((bits >> 2) & 0x7).ok(),
+            code: (bits >> 5) & 0x7f,
+            width: half(6),
+            height: half(8),
+            frame_rate: half(4),
+            interlaced: bits & 1 != 0,
+            native: bits & 2 != 0,
+            preferred: false,
+            pixel_freq: u32::from_le_bytes(raw[12..16].try_into().unwrap()),
+        }
+    }
+}
+
 #[derive(Debug)]
 struct Signal(Mutex<bool>, Condvar);
 impl Signal {
@@ -349,6 +533,8 @@ pub enum CreationError {
     IOError(#[from] std::io::Error),
     #[error("ioctl call failed")]
     IoctlError(#[from] nix::Error),
+    #[error("Setting up the CEC service failed: {0}")]
+    SetupFailed(#[source] ServiceError),
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -371,6 +557,10 @@ pub enum ServiceError {
     InvalidArgument,
     #[error("No status returned")]
     MissingStatus,
+    #[error("VideoCore closed the service")]
+    ServiceClosed,
+    #[error("TV service failed with {0}")]
+    TVServiceFailed(i32),
     #[error("Error when queuing message")]
     VchiqError,
     #[error("Retriable error when queuing message")]
@@ -404,9 +594,13 @@ impl ServiceError {
         }
     }
 }
-impl Into<CECError> for ServiceError {
-    fn into(self) -> CECError {
-        CECError::Other(Box::new(self))
+impl From<ServiceError> for CECError {
+    fn from(val: ServiceError) -> Self {
+        match val {
+            ServiceError::NoAck => CECError::NoAck,
+            ServiceError::Busy => CECError::Busy,
+            _ => CECError::Other(Box::new(val)),
+        }
     }
 }
 
@@ -432,13 +626,30 @@ impl SendMsgParam {
 }
 
 type MessageCallback = Arc<Mutex<Option<Box<dyn FnMut(&CECCommand) + Send>>>>;
+type SharedTopologyCallback = Arc<Mutex<Option<TopologyCallback>>>;
+type SharedHdmiCallback = Arc<Mutex<Option<HdmiCallback>>>;
 
 #[derive(Debug)]
-struct ServiceUserdata<'a> {
-    signal: &'a Signal,
+struct ServiceUserdata {
+    signal: Arc<Signal>,
     handle: ServiceHandle,
 }
 
+// The userdata handed to the driver for a service. Only the completion thread
+// reads it, and it's freed once that thread has been joined.
+struct UserdataPtr(*mut ServiceUserdata);
+unsafe impl Send for UserdataPtr {}
+unsafe impl Sync for UserdataPtr {}
+
+// Passes on a failure in one of the background threads, unless it's just the
+// thread noticing that we're shutting down.
+fn report(health: &Sender<ServiceError>, stopping: &AtomicBool, thread: &str, err: ServiceError) {
+    if !stopping.load(Ordering::SeqCst) {
+        warn!("{} thread failed: {}", thread, err);
+        let _ = health.send(err);
+    }
+}
+
 #[allow(dead_code)]
 pub struct HardwareInterface {
     // File for directly interfacing with hardware.
@@ -449,35 +660,89 @@ pub struct HardwareInterface {
     tvservice_notify_handle: ServiceHandle,
     cec_client_handle: ServiceHandle,
     cec_notify_handle: ServiceHandle,
+    userdata: Vec<UserdataPtr>,
+
+    // Held across a TV service command and any bulk transfer that follows
+    // its reply.
+    tvservice_lock: Mutex<()>,
+    // Held across a CEC service command and any bulk transfer that follows
+    // its reply.
+    cecservice_lock: Mutex<()>,
 
-    // Signals to use for confirming message send.
+    // Signals to use for confirming message send, and for waking the
+    // notification threads.
     tvservice_client_signal: Arc<Signal>,
+    tvservice_notify_signal: Arc<Signal>,
     cec_client_signal: Arc<Signal>,
+    cec_notify_signal: Arc<Signal>,
 
-    // Threads to use for handling incoming messages.
-    tvservice_notify_thread: thread::JoinHandle<()>,
-    cec_notify_thread: thread::JoinHandle<()>,
-    completion_thread: thread::JoinHandle<()>,
+    // Threads to use for handling incoming messages, and a flag telling them
+    // to stop.
+    completion_thread: Mutex<Option<thread::JoinHandle<()>>>,
+    notify_threads: Mutex<Vec<thread::JoinHandle<()>>>,
+    stopping: Arc<AtomicBool>,
 
     // Callbacks to use for responding to incoming messages
     cec_rx_callback: MessageCallback,
-    cec_tx_callback: MessageCallback,
+    cec_tx_callback: SharedTxCallback,
+    cec_topology_callback: SharedTopologyCallback,
+    hdmi_callback: SharedHdmiCallback,
 }
 
 impl HardwareInterface {
     // Initialise the CEC service for use.
     pub fn init() -> Result<HardwareInterface, CreationError> {
+        HardwareInterface::init_with_health(mpsc::channel().0)
+    }
+
+    /// Initialises the CEC service, reporting failures in the background on
+    /// `health`. Once one arrives the interface has stopped working, and
+    /// should be dropped so that a new one can be opened.
+    pub fn init_with_health(
+        health: Sender<ServiceError>,
+    ) -> Result<HardwareInterface, CreationError> {
         // Ensure that only a single HardwareInterface exists.
-        let mut already_initialized = INITIALIZED.lock().unwrap();
-        if *already_initialized {
-            return Err(CreationError::AlreadyInitialized);
+        {
+            let mut already_initialized = INITIALIZED.lock().unwrap();
+            if *already_initialized {
+                return Err(CreationError::AlreadyInitialized);
+            }
+            *already_initialized = true;
         }
-        *already_initialized = true;
 
-        // Open the /dev/vchiq file and set up the correct library version
-        let vchiq = Arc::new(Mutex::new(VchiqIoctls {
-            vchiq: OpenOptions::new().read(true).write(true).open(DEV_VCHIQ)?,
-        }));
+        // Open the /dev/vchiq file. From here on, dropping the interface
+        // undoes whatever setup has been done if a later step fails.
+        let file = match OpenOptions::new().read(true).write(true).open(DEV_VCHIQ) {
+            Ok(file) => file,
+            Err(e) => {
+                *INITIALIZED.lock().unwrap() = false;
+                return Err(e.into());
+            }
+        };
+        let mut hw = HardwareInterface {
+            vchiq: Arc::new(Mutex::new(VchiqIoctls { vchiq: file })),
+            tvservice_client_handle: VCHIQ_SERVICE_HANDLE_INVALID,
+            tvservice_notify_handle: VCHIQ_SERVICE_HANDLE_INVALID,
+            cec_client_handle: VCHIQ_SERVICE_HANDLE_INVALID,
+            cec_notify_handle: VCHIQ_SERVICE_HANDLE_INVALID,
+            userdata: vec![],
+            tvservice_lock: Mutex::new(()),
+            cecservice_lock: Mutex::new(()),
+            tvservice_client_signal: Signal::new(),
+            tvservice_notify_signal: Signal::new(),
+            cec_client_signal: Signal::new(),
+            cec_notify_signal: Signal::new(),
+            completion_thread: Mutex::new(None),
+            notify_threads: Mutex::new(vec![]),
+            stopping: Arc::new(AtomicBool::new(false)),
+            cec_rx_callback: Arc::new(Mutex::new(None)),
+            cec_tx_callback: Arc::new(Mutex::new(None)),
+            cec_topology_callback: Arc::new(Mutex::new(None)),
+            hdmi_callback: Arc::new(Mutex::new(None)),
+        };
+
+        // Set up the correct library version
+        let vchiq = hw.vchiq.clone();
         let config = vchiq.lock().unwrap().get_config()?;
         if config.version < VCHIQ_VERSION_MIN || config.version_min > VCHIQ_VERSION {
             return Err(CreationError::CouldNotRetrieveDriverVersion);
@@ -491,6 +756,8 @@ impl HardwareInterface {
         // Connect and spin up a thread
         vchiq.lock().unwrap().connect()?;
         let vchiq_completion = vchiq.clone();
+        let completion_health = health.clone();
+        let completion_stopping = hw.stopping.clone();
         let completion_thread = thread::Builder::new()
             .name("VCHIQ completion".into())
             .spawn(move || {
@@ -505,30 +772,47 @@ impl HardwareInterface {
                     msgbufcount: 0,
                     msgbufs: msgbufs.as_mut_ptr(),
                 };
+                let fail = |err: ServiceError| {
+                    report(
+                        &completion_health,
+                        &completion_stopping,
+                        "VCHIQ completion",
+                        err,
+                    )
+                };
 
-                let await_completion = vchiq_completion.lock().unwrap().await_completion_fn();
+                let (await_completion, close_delivered) = {
+                    let vchiq = vchiq_completion.lock().unwrap();
+                    (vchiq.await_completion_fn(), vchiq.close_delivered_fn())
+                };
                 loop {
                     // Fill up message buffer with allocated memory.
                     // This could potentionally leak memory.
                     args.msgbufcount = msgbufs.replenish(args.msgbufcount);
-                    let size = await_completion(&mut args).unwrap();
+                    let size = match await_completion(&mut args) {
+                        Ok(size) => size,
+                        Err(e) => return fail(e.into()),
+                    };
 
                     for completion in completion_data[..size].iter() {
                         match completion.reason {
                             vchiq_ioctl::Reason::MessageAvailable
                             | vchiq_ioctl::Reason::ServiceClosed => {
                                 let userdata = unsafe {
-                                    &mut *(completion.service_userdata as *mut ServiceUserdata)
+                                    &*(completion.service_userdata as *const ServiceUserdata)
                                 };
                                 userdata.signal.notify_one();
-                                if completion.reason == vchiq_ioctl::Reason::ServiceClosed
-                                    && use_close_delivered
-                                {
-                                    vchiq_completion
-                                        .lock()
-                                        .unwrap()
-                                        .close_delivered(userdata.handle)
-                                        .unwrap();
+                                if completion.reason == vchiq_ioctl::Reason::ServiceClosed {
+                                    if use_close_delivered {
+                                        if let Err(e) = close_delivered(userdata.handle) {
+                                            return fail(e.into());
+                                        }
+                                    }
+                                    // We only close services when stopping,
+                                    // so otherwise the VideoCore closed it.
+                                    if !completion_stopping.load(Ordering::SeqCst) {
+                                        return fail(ServiceError::ServiceClosed);
+                                    }
                                 }
                             }
                             _ => {
@@ -536,60 +820,80 @@ impl HardwareInterface {
                             }
                         }
                     }
+                    // The wait returns with nothing once the driver has been
+                    // asked to shut down.
+                    if size == 0 && completion_stopping.load(Ordering::SeqCst) {
+                        return;
+                    }
                 }
             })?;
+        *hw.completion_thread.get_mut().unwrap() = Some(completion_thread);
 
         // Initialize all the clients we intend on using.
-        let tvservice_client_signal = Signal::new();
-        let tvservice_client_handle = vchiq.lock().unwrap().create_service(
+        hw.tvservice_client_handle = hw.create_service(
             TVSERVICE_CLIENT_NAME,
-            tvservice_client_signal.clone(),
+            hw.tvservice_client_signal.clone(),
             VC_TVSERVICE_VER,
         )?;
-        let tvservice_notify_signal = Signal::new();
-        let tvservice_notify_handle = vchiq.lock().unwrap().create_service(
+        hw.tvservice_notify_handle = hw.create_service(
             TVSERVICE_NOTIFY_NAME,
-            tvservice_notify_signal.clone(),
+            hw.tvservice_notify_signal.clone(),
             VC_TVSERVICE_VER,
         )?;
-
-        let cec_client_signal = Signal::new();
-        let cec_client_handle = vchiq.lock().unwrap().create_service(
+        hw.cec_client_handle = hw.create_service(
             CECSERVICE_CLIENT_NAME,
-            cec_client_signal.clone(),
+            hw.cec_client_signal.clone(),
             VC_CECSERVICE_VER,
         )?;
-        let cec_notify_signal = Signal::new();
-        let cec_notify_handle = vchiq.lock().unwrap().create_service(
+        hw.cec_notify_handle = hw.create_service(
             CECSERVICE_NOTIFY_NAME,
-            cec_notify_signal.clone(),
+            hw.cec_notify_signal.clone(),
             VC_CECSERVICE_VER,
         )?;
 
         // Spawn notification threads now that we have the handles
         let tvservice_vchiq = vchiq.clone();
+        let tvservice_notify_handle = hw.tvservice_notify_handle;
+        let tvservice_notify_signal = hw.tvservice_notify_signal.clone();
+        let tvservice_health = health.clone();
+        let tvservice_stopping = hw.stopping.clone();
+        let hdmi_callback = hw.hdmi_callback.clone();
         let tvservice_notify_thread = thread::Builder::new()
             .name("TVService Notify".into())
             .spawn(move || {
+                let fail = |err: ServiceError| {
+                    report(
+                        &tvservice_health,
+                        &tvservice_stopping,
+                        "TVService Notify",
+                        err,
+                    )
+                };
                 loop {
                     // Wait for data
                     tvservice_notify_signal.wait_for_event();
+                    if tvservice_stopping.load(Ordering::SeqCst) {
+                        return;
+                    }
 
                     // Grab all available data
                     loop {
                         let mut notify_buffer = [0; NOTIFY_BUFFER_SIZE];
-                        let num_bytes = tvservice_vchiq
+                        let num_bytes = match tvservice_vchiq
                             .lock()
                             .unwrap()
                             .dequeue_message(tvservice_notify_handle, &mut notify_buffer)
-                            .unwrap();
+                        {
+                            Ok(num_bytes) => num_bytes,
+                            Err(e) => return fail(e.into()),
+                        };
 
                         if num_bytes < TVSERVICE_NOTIFY_SIZE {
                             warn!(
                                 "tvservice returned too few bytes ({}), stopping thread...",
                                 num_bytes
                             );
-                            return ();
+                            return fail(ServiceError::MissingStatus);
                         }
 
                         // Check what notification it is and update ourselves
@@ -602,18 +906,29 @@ impl HardwareInterface {
                         let params = &notify_buffer[4..12];
                         debug!("tv_notification {:?} {:02x?}", reason, params);
 
-                        // TODO(stvn): Add callbacks
+                        if let Some(event) = reason.ok().and_then(HDMIReason::event) {
+                            if let Some(func) = &mut *hdmi_callback.lock().unwrap() {
+                                func(event)
+                            }
+                        }
                         if num_bytes == TVSERVICE_NOTIFY_SIZE {
                             break;
                         }
                     }
                 }
             })?;
-        let cec_vchiq = vchiq.clone();
-        let cec_rx_callback: MessageCallback = Arc::new(Mutex::new(None));
-        let cec_rx_callback_copy = cec_rx_callback.clone();
-        let cec_tx_callback: MessageCallback = Arc::new(Mutex::new(None));
-        let cec_tx_callback_copy = cec_tx_callback.clone();
+        hw.notify_threads
+            .get_mut()
+            .unwrap()
+            .push(tvservice_notify_thread);
+        let cec_vchiq = vchiq;
+        let cec_notify_handle = hw.cec_notify_handle;
+        let cec_notify_signal = hw.cec_notify_signal.clone();
+        let cec_health = health;
+        let cec_stopping = hw.stopping.clone();
+        let cec_rx_callback = hw.cec_rx_callback.clone();
+        let cec_tx_callback = hw.cec_tx_callback.clone();
+        let cec_topology_callback = hw.cec_topology_callback.clone();
         let cec_notify_thread =
             thread::Builder::new()
                 .name("CEC Notify".into())
@@ -621,15 +936,28 @@ impl HardwareInterface {
                     loop {
                         // Wait for data
                         cec_notify_signal.wait_for_event();
+                        if cec_stopping.load(Ordering::SeqCst) {
+                            return;
+                        }
 
                         // Grab all available data
                         loop {
                             let mut notify_buffer = [0; NOTIFY_BUFFER_SIZE];
-                            let num_bytes = cec_vchiq
+                            let num_bytes = match cec_vchiq
                                 .lock()
                                 .unwrap()
                                 .dequeue_message(cec_notify_handle, &mut notify_buffer)
-                                .unwrap();
+                            {
+                                Ok(num_bytes) => num_bytes,
+                                Err(e) => {
+                                    return report(
+                                        &cec_health,
+                                        &cec_stopping,
+                                        "CEC Notify",
+                                        e.into(),
+                                    )
+                                }
+                            };
                             if num_bytes < CEC_NOTIFY_SIZE {
                                 warn!(
                                     "cec returned too few bytes ({}), skipping message...",
@@ -651,39 +979,53 @@ impl HardwareInterface {
                                         u16::from_be_bytes(params[4..6].try_into().unwrap());
                                     info!("logical: {:?}, physical: {:x?}", logical, physical);
                                 }
-                                CECReason::Rx => match CECCommand::from_raw(params) {
+                                // The firmware reports remote control keys
+                                // separately, but they're just messages like any
+                                // other.
+                                CECReason::Rx
+                                | CECReason::ButtonPressed
+                                | CECReason::ButtonReleased
+                                | CECReason::RemotePressed
+                                | CECReason::RemoteReleased => match CECCommand::from_raw(params) {
                                     Ok(cmd) => match &mut *cec_rx_callback.lock().unwrap() {
                                         Some(func) => func(&cmd),
                                         None => {
-                                            debug!("{:?} {:x?}", reason, cmd);
+                                            debug!("{:?} {}", reason, cmd);
                                         }
                                     },
                                     Err(_) => {
                                         info!("{:?} {:02x?}", reason, params);
                                     }
                                 },
+                                // The firmware's return code says whether the
+                                // frame was acknowledged.
                                 CECReason::Tx => match CECCommand::from_raw(params) {
                                     Ok(cmd) => match &mut *cec_tx_callback.lock().unwrap() {
-                                        Some(func) => func(&cmd),
+                                        Some(func) => func(
+                                            &cmd,
+                                            ServiceError::from_ioctl_return_value(notify_buffer[3])
+                                                .map_err(CECError::from),
+                                        ),
                                         None => {
-                                            debug!("{:?} {:x?}", reason, cmd);
+                                            debug!("{:?} {}", reason, cmd);
                                         }
                                     },
                                     Err(_) => {
                                         info!("{:?} {:02x?}", reason, params);
                                     }
                                 },
-                                CECReason::ButtonPressed
-                                | CECReason::ButtonReleased
-                                | CECReason::RemotePressed
-                                | CECReason::RemoteReleased => match CECCommand::from_raw(params) {
-                                    Ok(c) => info!("{:?} {:x?}", reason, c),
-                                    Err(_) => {
-                                        info!("{:?} {:02x?}", reason, params);
-                                    }
-                                },
                                 CECReason::Topology => {
-                                    info!("devices present: {:02x?}", &params[0..2])
+                                    // The first two bytes are a bitmask of the
+                                    // logical addresses present.
+                                    let mask = u16::from_le_bytes(params[0..2].try_into().unwrap());
+                                    let present: Vec<LogicalAddress> = (0..15)
+                                        .filter(|i| mask & (1 << i) != 0)
+                                        .filter_map(|i| LogicalAddress::try_from(i).ok())
+                                        .collect();
+                                    match &mut *cec_topology_callback.lock().unwrap() {
+                                        Some(func) => func(&present),
+                                        None => info!("devices present: {:?}", present),
+                                    }
                                 }
                                 CECReason::LogicalAddrLost => {
                                     let logical = LogicalAddress::try_from(params[0])
@@ -709,38 +1051,106 @@ impl HardwareInterface {
                         }
                     }
                 })?;
+        hw.notify_threads.get_mut().unwrap().push(cec_notify_thread);
+        Ok(hw)
+    }
 
-        Ok(HardwareInterface {
-            vchiq: vchiq,
-            tvservice_client_handle: tvservice_client_handle,
-            tvservice_notify_handle: tvservice_notify_handle,
-            cec_client_handle: cec_client_handle,
-            cec_notify_handle: cec_notify_handle,
-            tvservice_client_signal: tvservice_client_signal,
-            tvservice_notify_thread: tvservice_notify_thread,
-            cec_client_signal: cec_client_signal,
-            cec_notify_thread: cec_notify_thread,
-            completion_thread: completion_thread,
-            cec_rx_callback: cec_rx_callback_copy,
-            cec_tx_callback: cec_tx_callback_copy,
-        })
+    fn create_service(
+        &mut self,
+        client: FourCC,
+        signal: Arc<Signal>,
+        vc_version: VersionNum,
+    ) -> Result<ServiceHandle, nix::Error> {
+        let (handle, userdata) = self
+            .vchiq
+            .lock()
+            .unwrap()
+            .create_service(client, signal, vc_version)?;
+        self.userdata.push(userdata);
+        Ok(handle)
     }
 
-    fn send_cec_command_with_reply(&self, elements: &[Element]) -> Result<Vec<u8>, ServiceError> {
+    /// Stops the background threads and releases the services, so that a
+    /// new interface can be opened. Commands waiting on the VideoCore fail
+    /// with ServiceError::Shutdown. Also done when the interface is dropped.
+    pub fn shutdown(&self) {
+        if self.stopping.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        // Wake everything waiting on the VideoCore, so it sees we're stopping.
+        for signal in [
+            &self.tvservice_client_signal,
+            &self.tvservice_notify_signal,
+            &self.cec_client_signal,
+            &self.cec_notify_signal,
+        ] {
+            signal.notify_one();
+        }
+        // Removing a service waits for the completion thread to acknowledge
+        // it. If that thread has already failed, closing the file releases
+        // the services instead.
+        let completing = match &*self.completion_thread.lock().unwrap() {
+            Some(thread) => !thread.is_finished(),
+            None => false,
+        };
+        {
+            let vchiq = &mut *self.vchiq.lock().unwrap();
+            for handle in [
+                self.tvservice_client_handle,
+                self.tvservice_notify_handle,
+                self.cec_client_handle,
+                self.cec_notify_handle,
+            ] {
+                if completing && handle != VCHIQ_SERVICE_HANDLE_INVALID {
+                    if let Err(e) = vchiq.remove_service(handle) {
+                        debug!("failed to remove service {}: {}", handle, e);
+                    }
+                }
+            }
+            // Also makes the completion thread's wait return.
+            if let Err(e) = vchiq.shutdown() {
+                warn!("failed to shut down vchiq: {}", e);
+            }
+        }
+        if let Some(thread) = self.completion_thread.lock().unwrap().take() {
+            let _ = thread.join();
+        }
+        // A callback may be what's shutting us down, and its thread can't
+        // wait for itself. It stops on its own once the callback returns.
+        for thread in self.notify_threads.lock().unwrap().drain(..) {
+            if thread.thread().id() != thread::current().id() {
+                let _ = thread.join();
+            }
+        }
+        *INITIALIZED.lock().unwrap() = false;
+    }
+
+    fn send_command_with_reply(
+        &self,
+        handle: ServiceHandle,
+        signal: &Signal,
+        elements: &[Element],
+    ) -> Result<Vec<u8>, ServiceError> {
         let mut vec = vec![];
         self.vchiq
             .lock()
             .unwrap()
-            .using_service(self.cec_client_handle, |vchiq| {
+            .using_service(handle, |vchiq| {
+                if self.stopping.load(Ordering::SeqCst) {
+                    return Err(ServiceError::Shutdown);
+                }
                 // Send the command.
-                let msg = vchiq_ioctl::QueueMessage::new(self.cec_client_handle, elements);
+                let msg = vchiq_ioctl::QueueMessage::new(handle, elements);
                 ServiceError::from_vchiq_status(vchiq.queue_message(msg)?)?;
 
-                // Wait for the command to be acknowledged.
-                self.cec_client_signal.wait_for_event();
+                // Wait for the command to be acknowledged, or for shutdown()
+                // to give up on it.
+                signal.wait_for_event();
+                if self.stopping.load(Ordering::SeqCst) {
+                    return Err(ServiceError::Shutdown);
+                }
                 let mut notify_buffer = [0; NOTIFY_BUFFER_SIZE];
-                let num_bytes =
-                    vchiq.dequeue_message(self.cec_client_handle, &mut notify_buffer)?;
+                let num_bytes = vchiq.dequeue_message(handle, &mut notify_buffer)?;
                 if num_bytes < 1 {
                     Err(ServiceError::MissingStatus)
                 } else {
@@ -751,6 +1161,10 @@ impl HardwareInterface {
             .map(|_| vec)
     }
 
+    fn send_cec_command_with_reply(&self, elements: &[Element]) -> Result<Vec<u8>, ServiceError> {
+        self.send_command_with_reply(self.cec_client_handle, &self.cec_client_signal, elements)
+    }
+
     fn send_cec_command(&self, elements: &[Element]) -> Result<(), ServiceError> {
         match self.send_cec_command_with_reply(elements) {
             Ok(s) => ServiceError::from_ioctl_return_value(s[0]),
@@ -763,6 +1177,9 @@ impl HardwareInterface {
             .lock()
             .unwrap()
             .using_service(self.cec_client_handle, |vchiq| {
+                if self.stopping.load(Ordering::SeqCst) {
+                    return Err(ServiceError::Shutdown);
+                }
                 // Send the command. We don't expect any acknowledgement.
                 let msg = vchiq_ioctl::QueueMessage::new(self.cec_client_handle, elements);
                 ServiceError::from_vchiq_status(vchiq.queue_message(msg)?)?;
@@ -779,7 +1196,7 @@ impl HardwareInterface {
     pub fn get_physical_addr(&self) -> Result<PhysicalAddress, ServiceError> {
         let elems = &[Element::new(&CECServiceCommand::GetPhysicalAddr)];
         let resp = self.send_cec_command_with_reply(elems)?;
-        Ok(u16::from_le_bytes(resp[0..2].try_into()?))
+        Ok(u16::from_le_bytes(resp[0..2].try_into()?).into())
     }
 
     pub fn alloc_logical_addr(&self) -> Result<(), ServiceError> {
@@ -824,7 +1241,6 @@ impl HardwareInterface {
     /// Only available when CEC is running in passive mode. The host can
     /// only call this function during logical address allocation stage.
     /// address is free if error code is VC_CEC_ERROR_NO_ACK
-    #[allow(dead_code)]
     pub fn poll_address(&self, addr: LogicalAddress) -> Result<(), ServiceError> {
         let addr_bytes = (addr as u32).to_le_bytes();
         self.send_cec_command(&[
@@ -839,7 +1255,6 @@ impl HardwareInterface {
     /// responsibility of the host to make sure the logical address
     /// is actually free (see vc_cec_poll_address). Physical address used
     /// will be what is read from EDID and cannot be set.
-    #[allow(dead_code)]
     pub fn set_logical_address(
         &self,
         addr: LogicalAddress,
@@ -857,14 +1272,243 @@ impl HardwareInterface {
         ])
     }
 
-    #[allow(dead_code)]
+    /// Stops the firmware from allocating a logical address itself, so the
+    /// host can claim one with poll_address and set_logical_address.
     pub fn set_passive(&self, enabled: bool) -> Result<(), ServiceError> {
         let param = (enabled as u32).to_le();
         self.send_cec_command(&[
-            Element::new(&CECServiceCommand::SetLogicalAddr),
+            Element::new(&CECServiceCommand::SetPassive),
             Element::new(&param),
         ])
     }
+
+    /// Switches to passive mode and claims the first free logical address
+    /// for a device of the given type.
+    pub fn claim_logical_addr(
+        &self,
+        device: DeviceType,
+        vendor_id: u32,
+    ) -> Result<LogicalAddress, ServiceError> {
+        self.set_passive(true)?;
+        let addr = claim_logical_address(device, |addr| match self.poll_address(addr) {
+            Err(ServiceError::NoAck) => Ok(false),
+            Ok(()) => Ok(true),
+            Err(e) => Err(e),
+        })?;
+        self.set_logical_address(addr, device, vendor_id)?;
+        Ok(addr)
+    }
+
+    /// Asks the firmware to pass on messages with this opcode.
+    pub fn register_command(&self, opcode: Opcode) -> Result<(), ServiceError> {
+        let param = (opcode as u32).to_le();
+        self.send_cec_command_without_reply(&[
+            Element::new(&CECServiceCommand::RegisterCmd),
+            Element::new(&param),
+        ])
+    }
+
+    /// Asks the firmware to pass on every message again.
+    pub fn register_all(&self) -> Result<(), ServiceError> {
+        self.send_cec_command_without_reply(&[Element::new(&CECServiceCommand::RegisterAll)])
+    }
+
+    /// Stops passing on messages with this opcode, leaving the firmware to
+    /// answer them itself.
+    pub fn deregister_command(&self, opcode: Opcode) -> Result<(), ServiceError> {
+        let param = (opcode as u32).to_le();
+        self.send_cec_command_without_reply(&[
+            Element::new(&CECServiceCommand::DeregisterCmd),
+            Element::new(&param),
+        ])
+    }
+
+    pub fn deregister_all(&self) -> Result<(), ServiceError> {
+        self.send_cec_command_without_reply(&[Element::new(&CECServiceCommand::DeregisterAll)])
+    }
+
+    /// The devices the firmware has seen on the bus, including us.
+    pub fn get_topology(&self) -> Result<Vec<KnownDevice>, ServiceError> {
+        let _lock = self.cecservice_lock.lock().unwrap();
+        self.send_cec_command(&[Element::new(&CECServiceCommand::GetTopology)])?;
+
+        // The topology itself follows as a bulk transfer: a bitmask of the
+        // logical addresses present and the number of them, then a word for
+        // each address with its device type in bits 4-7 and its physical
+        // address in bits 8-23.
+        let mut raw = [0; TOPOLOGY_SIZE];
+        self.bulk_receive(self.cec_client_handle, &mut raw)?;
+        let mask = u16::from_le_bytes([raw[0], raw[1]]);
+        Ok((0..15)
+            .filter(|i| mask & (1 << i) != 0)
+            .filter_map(|i| {
+                let at = 4 + i * size_of::<u32>();
+                let attr = u32::from_le_bytes(raw[at..at + 4].try_into().unwrap());
+                Some(KnownDevice {
+                    logical_address: LogicalAddress::try_from(i as u8).ok()?,
+                    physical_address: (((attr >> 8) & 0xffff) as u16).into(),
+                    device_type: DeviceType::try_from(((attr >> 4) & 0xf) as u8).ok(),
+                })
+            })
+            .collect())
+    }
+
+    /// Tells the firmware about a device it hasn't seen itself.
+    ///
+    /// Only available when CEC is running in passive mode. Set `last` on the
+    /// final device of a batch so the firmware sends out a topology update.
+    pub fn add_device(
+        &self,
+        addr: LogicalAddress,
+        physical_addr: PhysicalAddress,
+        device: DeviceType,
+        last: bool,
+    ) -> Result<(), ServiceError> {
+        let params = [
+            (addr as u32).to_le(),
+            (u16::from(physical_addr) as u32).to_le(),
+            (device as u32).to_le(),
+            (last as u32).to_le(),
+        ];
+        self.send_cec_command(&[
+            Element::new(&CECServiceCommand::AddDevice),
+            Element::new(&params),
+        ])
+    }
+
+    fn send_tv_command_with_reply(&self, elements: &[Element]) -> Result<Vec<u8>, ServiceError> {
+        self.send_command_with_reply(
+            self.tvservice_client_handle,
+            &self.tvservice_client_signal,
+            elements,
+        )
+    }
+
+    // Most TV service commands answer with just a status, where 0 is success.
+    fn send_tv_command(&self, elements: &[Element]) -> Result<(), ServiceError> {
+        let resp = self.send_tv_command_with_reply(elements)?;
+        match i32::from_le_bytes(
+            resp.get(..4)
+                .ok_or(ServiceError::MissingStatus)?
+                .try_into()?,
+        ) {
+            0 => Ok(()),
+            status => Err(ServiceError::TVServiceFailed(status)),
+        }
+    }
+
+    fn bulk_receive(&self, handle: ServiceHandle, buffer: &mut [u8]) -> Result<(), ServiceError> {
+        self.vchiq.lock().unwrap().using_service(handle, |vchiq| {
+            ServiceError::from_vchiq_status(vchiq.bulk_receive(handle, buffer)?)
+        })
+    }
+
+    pub fn get_display_state(&self) -> Result<DisplayState, ServiceError> {
+        let _lock = self.tvservice_lock.lock().unwrap();
+        let resp =
+            self.send_tv_command_with_reply(&[Element::new(&TVServiceCommand::GetDisplayState)])?;
+        if resp.len() < DISPLAY_STATE_SIZE {
+            return Err(ServiceError::MissingStatus);
+        }
+        Ok(DisplayState::from_raw(&resp))
+    }
+
+    /// Powers on the HDMI output in the mode the display prefers.
+    pub fn hdmi_power_on_preferred(&self) -> Result<(), ServiceError> {
+        let _lock = self.tvservice_lock.lock().unwrap();
+        let in_3d = 0u32;
+        self.send_tv_command(&[
+            Element::new(&TVServiceCommand::HdmiOnPreferred),
+            Element::new(&in_3d),
+        ])
+    }
+
+    /// Powers on the HDMI output in a particular mode, such as one from
+    /// supported_modes.
+    pub fn hdmi_power_on_explicit(
+        &self,
+        hdmi_mode: HdmiMode,
+        group: ResolutionGroup,
+        code: u32,
+    ) -> Result<(), ServiceError> {
+        let _lock = self.tvservice_lock.lock().unwrap();
+        let params = [
+            (hdmi_mode as u32).to_le(),
+            (group as u32).to_le(),
+            code.to_le(),
+        ];
+        self.send_tv_command(&[
+            Element::new(&TVServiceCommand::HdmiOnExplicit),
+            Element::new(&params),
+        ])
+    }
+
+    /// Powers off the video output. The display stays attached, and CEC keeps
+    /// working.
+    pub fn power_off(&self) -> Result<(), ServiceError> {
+        let _lock = self.tvservice_lock.lock().unwrap();
+        self.send_tv_command(&[Element::new(&TVServiceCommand::Off)])
+    }
+
+    /// Reads `buffer.len()` bytes from the display's DDC bus, starting at
+    /// `offset`.
+    pub fn ddc_read(&self, offset: u32, buffer: &mut [u8]) -> Result<(), ServiceError> {
+        let _lock = self.tvservice_lock.lock().unwrap();
+        let params = [offset.to_le(), (buffer.len() as u32).to_le()];
+        self.send_tv_command(&[
+            Element::new(&TVServiceCommand::DdcRead),
+            Element::new(&params),
+        ])?;
+        self.bulk_receive(self.tvservice_client_handle, buffer)
+    }
+
+    /// Reads the display's whole EDID, including any extension blocks. See
+    /// edid::Edid for decoding it.
+    pub fn read_edid(&self) -> Result<Vec<u8>, ServiceError> {
+        let mut data = vec![0; edid::BLOCK_SIZE];
+        self.ddc_read(0, &mut data)?;
+        let extensions = edid::Edid::extension_count(&data);
+        if extensions > 0 {
+            let mut rest = vec![0; extensions * edid::BLOCK_SIZE];
+            self.ddc_read(edid::BLOCK_SIZE as u32, &mut rest)?;
+            data.extend(rest);
+        }
+        Ok(data)
+    }
+
+    /// Lists the modes in `group` that the display supports.
+    pub fn supported_modes(
+        &self,
+        group: ResolutionGroup,
+    ) -> Result<Vec<SupportedMode>, ServiceError> {
+        let _lock = self.tvservice_lock.lock().unwrap();
+        let params = [(group as u32).to_le(), (MAX_SUPPORTED_MODES as u32).to_le()];
+        let resp = self.send_tv_command_with_reply(&[
+            Element::new(&TVServiceCommand::QuerySupportedModesActual),
+            Element::new(&params),
+        ])?;
+        if resp.len() < SUPPORTED_MODES_RESP_SIZE {
+            return Err(ServiceError::MissingStatus);
+        }
+        let word = |i: usize| u32::from_le_bytes(resp[i..i + 4].try_into().unwrap());
+        let count = (word(0) as usize).min(MAX_SUPPORTED_MODES);
+        let preferred = (ResolutionGroup::try_from(word(4)).ok(), word(8));
+        if count == 0 {
+            return Ok(vec![]);
+        }
+
+        // The modes themselves follow as a bulk transfer.
+        let mut raw = vec![0; count * SUPPORTED_MODE_SIZE];
+        self.bulk_receive(self.tvservice_client_handle, &mut raw)?;
+        Ok(raw
+            .chunks(SUPPORTED_MODE_SIZE)
+            .map(|raw| {
+                let mut mode = SupportedMode::from_raw(raw);
+                mode.preferred = (mode.group, mode.code) == preferred;
+                mode
+            })
+            .collect())
+    }
 }
 impl CECConnection for HardwareInterface {
     fn transmit(&self, cmd: CECCommand) -> Result<(), CECError> {
@@ -890,7 +1534,226 @@ impl CECConnection for HardwareInterface {
     fn set_rx_callback(&self, func: Box<dyn FnMut(&CECCommand) + Send>) {
         *self.cec_rx_callback.lock().unwrap() = Some(func)
     }
-    fn set_tx_callback(&self, func: Box<dyn FnMut(&CECCommand) + Send>) {
+    fn set_tx_callback(&self, func: TxCallback) {
         *self.cec_tx_callback.lock().unwrap() = Some(func)
     }
+    fn set_topology_callback(&self, func: TopologyCallback) {
+        *self.cec_topology_callback.lock().unwrap() = Some(func)
+    }
+    fn set_hdmi_callback(&self, func: HdmiCallback) {
+        *self.hdmi_callback.lock().unwrap() = Some(func)
+    }
+    fn register_opcodes(&self, opcodes: &[Opcode]) -> Result<(), CECError> {
+        // Registering before deregistering means nothing we handle is ever
+        // left for the firmware to answer in between.
+        for &opcode in opcodes {
+            self.register_command(opcode)?;
+        }
+        for opcode in (0..=u8::MAX).filter_map(|b| Opcode::try_from(b).ok()) {
+            if !opcodes.contains(&opcode) {
+                self.deregister_command(opcode)?;
+            }
+        }
+        Ok(())
+    }
+    fn known_devices(&self) -> Result<Option<Vec<KnownDevice>>, CECError> {
+        Ok(Some(self.get_topology()?))
+    }
+}
+
+impl Drop for HardwareInterface {
+    fn drop(&mut self) {
+        self.shutdown();
+        for UserdataPtr(userdata) in self.userdata.drain(..) {
+            drop(unsafe { Box::from_raw(userdata) });
+        }
+    }
+}
+
+#[derive(Default)]
+struct SharedCallbacks {
+    rx: MessageCallback,
+    tx: SharedTxCallback,
+    topology: SharedTopologyCallback,
+    hdmi: SharedHdmiCallback,
+    reconnect: Arc<Mutex<Option<ReconnectCallback>>>,
+    // Registered again on each new interface.
+    opcodes: Mutex<Option<Vec<Opcode>>>,
+}
+
+type Setup = Box<dyn Fn(&HardwareInterface) -> Result<(), ServiceError> + Send>;
+
+/// A HardwareInterface that's reopened whenever it fails, running `setup`
+/// on each new one before putting it to use. Calls made while it's being
+/// reopened fail with ServiceError::Shutdown.
+pub struct ReconnectingInterface {
+    current: Arc<Mutex<Option<Arc<HardwareInterface>>>>,
+    callbacks: Arc<SharedCallbacks>,
+    health: Sender<ServiceError>,
+    stopping: Arc<AtomicBool>,
+}
+
+impl ReconnectingInterface {
+    pub fn init(
+        setup: impl Fn(&HardwareInterface) -> Result<(), ServiceError> + Send + 'static,
+    ) -> Result<ReconnectingInterface, CreationError> {
+        let setup: Setup = Box::new(setup);
+        let callbacks = Arc::new(SharedCallbacks::default());
+        let (health, failures) = mpsc::channel();
+        let hw = open(&setup, &callbacks, health.clone())?;
+        let current = Arc::new(Mutex::new(Some(Arc::new(hw))));
+        let stopping = Arc::new(AtomicBool::new(false));
+
+        let watched = current.clone();
+        let watchdog_callbacks = callbacks.clone();
+        let watchdog_health = health.clone();
+        let watchdog_stopping = stopping.clone();
+        thread::Builder::new()
+            .name("VCHIQ watchdog".into())
+            .spawn(move || {
+                for err in failures.iter() {
+                    if watchdog_stopping.load(Ordering::SeqCst) {
+                        return;
+                    }
+                    warn!("lost the VideoCore CEC service: {}", err);
+                    let failed = watched.lock().unwrap().take();
+                    if let Some(failed) = failed {
+                        failed.shutdown();
+                    }
+                    // Other threads may have reported the same failure.
+                    while failures.try_recv().is_ok() {}
+
+                    let mut delay = REOPEN_DELAY;
+                    loop {
+                        if watchdog_stopping.load(Ordering::SeqCst) {
+                            return;
+                        }
+                        match open(&setup, &watchdog_callbacks, watchdog_health.clone()) {
+                            Ok(hw) => {
+                                let mut current = watched.lock().unwrap();
+                                if watchdog_stopping.load(Ordering::SeqCst) {
+                                    return;
+                                }
+                                *current = Some(Arc::new(hw));
+                                break;
+                            }
+                            Err(e) => {
+                                warn!("reopening the VideoCore CEC service failed: {}", e);
+                                thread::sleep(delay);
+                                delay = (delay * 2).min(MAX_REOPEN_DELAY);
+                            }
+                        }
+                    }
+                    info!("reopened the VideoCore CEC service");
+                    if let Some(func) = &mut *watchdog_callbacks.reconnect.lock().unwrap() {
+                        func()
+                    }
+                }
+            })?;
+        Ok(ReconnectingInterface {
+            current,
+            callbacks,
+            health,
+            stopping,
+        })
+    }
+
+    /// The interface currently in use, for the calls CECConnection doesn't
+    /// cover. Fails while it's being reopened.
+    pub fn interface(&self) -> Result<Arc<HardwareInterface>, ServiceError> {
+        self.current
+            .lock()
+            .unwrap()
+            .clone()
+            .ok_or(ServiceError::Shutdown)
+    }
+}
+
+// Opens a new interface that passes everything it sees on to `callbacks`.
+fn open(
+    setup: &Setup,
+    callbacks: &Arc<SharedCallbacks>,
+    health: Sender<ServiceError>,
+) -> Result<HardwareInterface, CreationError> {
+    let hw = HardwareInterface::init_with_health(health)?;
+    let rx = callbacks.clone();
+    hw.set_rx_callback(Box::new(move |cmd| {
+        if let Some(func) = &mut *rx.rx.lock().unwrap() {
+            func(cmd)
+        }
+    }));
+    let tx = callbacks.clone();
+    hw.set_tx_callback(Box::new(move |cmd, result| {
+        if let Some(func) = &mut *tx.tx.lock().unwrap() {
+            func(cmd, result)
+        }
+    }));
+    let topology = callbacks.clone();
+    hw.set_topology_callback(Box::new(move |present| {
+        if let Some(func) = &mut *topology.topology.lock().unwrap() {
+            func(present)
+        }
+    }));
+    let hdmi = callbacks.clone();
+    hw.set_hdmi_callback(Box::new(move |event| {
+        if let Some(func) = &mut *hdmi.hdmi.lock().unwrap() {
+            func(event)
+        }
+    }));
+    setup(&hw).map_err(CreationError::SetupFailed)?;
+    if let Some(opcodes) = &*callbacks.opcodes.lock().unwrap() {
+        if let Err(e) = hw.register_opcodes(opcodes) {
+            warn!("couldn't register opcodes again: {}", e);
+        }
+    }
+    Ok(hw)
+}
+
+impl Drop for ReconnectingInterface {
+    fn drop(&mut self) {
+        self.stopping.store(true, Ordering::SeqCst);
+        // Wakes the watchdog so it can see we're stopping.
+        let _ = self.health.send(ServiceError::Shutdown);
+        let current = self.current.lock().unwrap().take();
+        if let Some(hw) = current {
+            hw.shutdown();
+        }
+    }
+}
+
+impl CECConnection for ReconnectingInterface {
+    fn transmit(&self, cmd: CECCommand) -> Result<(), CECError> {
+        self.interface()?.transmit(cmd)
+    }
+
+    fn get_logical_address(&self) -> Result<LogicalAddress, CECError> {
+        self.interface()?.get_logical_address()
+    }
+
+    fn get_physical_address(&self) -> Result<PhysicalAddress, CECError> {
+        self.interface()?.get_physical_address()
+    }
+
+    fn set_rx_callback(&self, func: Box<dyn FnMut(&CECCommand) + Send>) {
+        *self.callbacks.rx.lock().unwrap() = Some(func)
+    }
+    fn set_tx_callback(&self, func: TxCallback) {
+        *self.callbacks.tx.lock().unwrap() = Some(func)
+    }
+    fn set_topology_callback(&self, func: TopologyCallback) {
+        *self.callbacks.topology.lock().unwrap() = Some(func)
+    }
+    fn set_hdmi_callback(&self, func: HdmiCallback) {
+        *self.callbacks.hdmi.lock().unwrap() = Some(func)
+    }
+    fn set_reconnect_callback(&self, func: ReconnectCallback) {
+        *self.callbacks.reconnect.lock().unwrap() = Some(func)
+    }
+    fn register_opcodes(&self, opcodes: &[Opcode]) -> Result<(), CECError> {
+        *self.callbacks.opcodes.lock().unwrap() = Some(opcodes.to_vec());
+        self.interface()?.register_opcodes(opcodes)
+    }
+    fn known_devices(&self) -> Result<Option<Vec<KnownDevice>>, CECError> {
+        self.interface()?.known_devices()
+    }
 }
@@ -17,6 +17,9 @@ use std::ptr;
 macro_rules! ioctl_none_write_int {
     ($(#[$attr:meta])* $name:ident, $ioty:expr, $nr:expr) => (
         $(#[$attr])*
+        /// # Safety
+        ///
+        /// `fd` must be an open vchiq file descriptor.
         pub unsafe fn $name(fd:  std::os::raw::c_int,
                             data:usize)
                             -> nix::Result<std::os::raw::c_int> {
@@ -27,9 +30,9 @@ macro_rules! ioctl_none_write_int {
 
 const VCHIQ_IOC_MAGIC: u8 = 0xc4;
 ioctl_none_write_int!(connect, VCHIQ_IOC_MAGIC, 0);
-ioctl_none!(shutdown, VCHIQ_IOC_MAGIC, 1);
+ioctl_none_write_int!(shutdown, VCHIQ_IOC_MAGIC, 1);
 ioctl_readwrite!(create_service, VCHIQ_IOC_MAGIC, 2, CreateService);
-ioctl_none!(remove_service, VCHIQ_IOC_MAGIC, 3);
+ioctl_none_write_int!(remove_service, VCHIQ_IOC_MAGIC, 3);
 ioctl_write_ptr!(queue_message, VCHIQ_IOC_MAGIC, 4, QueueMessage);
 ioctl_readwrite!(queue_bulk_transmit, VCHIQ_IOC_MAGIC, 5, QueueBulkTransfer);
 ioctl_readwrite!(queue_bulk_receive, VCHIQ_IOC_MAGIC, 6, QueueBulkTransfer);
@@ -37,7 +40,7 @@ ioctl_readwrite!(await_completion, VCHIQ_IOC_MAGIC, 7, AwaitCompletion);
 ioctl_readwrite!(dequeue_message, VCHIQ_IOC_MAGIC, 8, DequeueMessage);
 ioctl_none!(get_client_id, VCHIQ_IOC_MAGIC, 9);
 ioctl_readwrite!(get_config, VCHIQ_IOC_MAGIC, 10, GetConfig);
-ioctl_none!(close_service, VCHIQ_IOC_MAGIC, 11);
+ioctl_none_write_int!(close_service, VCHIQ_IOC_MAGIC, 11);
 ioctl_none_write_int!(use_service, VCHIQ_IOC_MAGIC, 12);
 ioctl_none_write_int!(release_service, VCHIQ_IOC_MAGIC, 13);
 ioctl_write_ptr!(set_service_option, VCHIQ_IOC_MAGIC, 14, SetServiceOption);
@@ -70,7 +73,8 @@ pub enum Reason {
     BulkReceiveAborted,  // service, -, bulk_userdata
 }
 
-#[repr(u8)]
+// A C enum, so as wide as an int.
+#[repr(u32)]
 #[allow(dead_code)]
 pub enum BulkMode {
     Callback,
@@ -140,7 +144,7 @@ pub struct QueueMessage {
 impl QueueMessage {
     pub fn new(handle: ServiceHandle, elements: &[Element]) -> QueueMessage {
         QueueMessage {
-            handle: handle,
+            handle,
             count: elements.len() as u32,
             elements: elements.as_ptr(),
         }
@@ -0,0 +1,240 @@
+// Vendor-specific extensions to the protocol.
+//
+// Most TVs won't forward their remote's keys to a device until it has gone
+// through some vendor-defined ritual, and some of them send proprietary
+// commands that expect an answer. Which ritual applies depends on who made the
+// TV, so a handler is picked once the TV reports its <Device Vendor ID>.
+
+use crate::cec::{CECCommand, CECMessage, MenuState, PowerStatus, UserControl};
+
+pub const VENDOR_LG: u32 = 0x00e091;
+pub const VENDOR_SAMSUNG: u32 = 0x0000f0;
+pub const VENDOR_SONY: u32 = 0x080046;
+pub const VENDOR_PANASONIC: u32 = 0x008045;
+
+pub trait VendorHandler: Send {
+    /// The vendor ID of the TVs this handler talks to.
+    fn vendor_id(&self) -> u32;
+
+    /// Messages to send to the TV once it has identified itself.
+    fn handshake(&mut self) -> Vec<CECMessage> {
+        vec![]
+    }
+
+    /// Handles a message from the TV ahead of the standard processing.
+    /// Returns the replies to send back, or None to leave the message to the
+    /// standard processing.
+    fn handle(&mut self, cmd: &CECCommand) -> Option<Vec<CECMessage>>;
+
+    /// Translates a <Vendor Remote Button Down> into the equivalent standard
+    /// key, if there is one.
+    fn remote_button(&self, _rc_code: &[u8]) -> Option<UserControl> {
+        None
+    }
+}
+
+/// Returns the handler for TVs from the given vendor, if it has one.
+pub fn for_vendor(vendor_id: u32) -> Option<Box<dyn VendorHandler>> {
+    match vendor_id {
+        VENDOR_LG => Some(Box::new(LG)),
+        VENDOR_SAMSUNG => Some(Box::new(Samsung)),
+        VENDOR_SONY => Some(Box::new(Sony)),
+        VENDOR_PANASONIC => Some(Box::new(Panasonic)),
+        _ => None,
+    }
+}
+
+// Whether a message is a <Vendor Command With ID> from the given vendor.
+fn is_command_from(msg: &CECMessage, vendor: u32) -> bool {
+    matches!(msg, CECMessage::VendorCommandWithID { vendor_id, .. } if *vendor_id == vendor)
+}
+
+/// LG SimpLink.
+///
+/// The TV asks about the device's type and power state with vendor commands
+/// and only forwards keys once both have been answered. See
+/// https://github.com/Pulse-Eight/libcec/blob/master/src/libcec/implementations/SLCommandHandler.cpp
+pub struct LG;
+
+impl VendorHandler for LG {
+    fn vendor_id(&self) -> u32 {
+        VENDOR_LG
+    }
+
+    fn handle(&mut self, cmd: &CECCommand) -> Option<Vec<CECMessage>> {
+        let vendor_data = match &cmd.message {
+            CECMessage::VendorCommand { vendor_data } => vendor_data,
+            _ => return None,
+        };
+        Some(match vendor_data.first() {
+            Some(0x01) => vec![CECMessage::VendorCommand {
+                vendor_data: vec![0x02, 0x05],
+            }],
+            // Tells the TV which kind of device we claimed an address as.
+            Some(0x04) => vec![
+                CECMessage::VendorCommand {
+                    vendor_data: vec![0x05, cmd.destination.to_device_type() as u8],
+                },
+                CECMessage::ReportPowerStatus {
+                    power_status: PowerStatus::On,
+                },
+            ],
+            Some(0x03 | 0x0b | 0xa0) => vec![
+                CECMessage::ReportPowerStatus {
+                    power_status: PowerStatus::InTransitionStandbyToOn,
+                },
+                CECMessage::ReportPowerStatus {
+                    power_status: PowerStatus::On,
+                },
+            ],
+            // Left to be feature-aborted like any other unknown message.
+            _ => return None,
+        })
+    }
+}
+
+/// Samsung Anynet+.
+///
+/// Keys without a standard code, like Return, arrive as <Vendor Remote Button
+/// Down>, and the TV sends vendor commands that need no answer.
+pub struct Samsung;
+
+impl VendorHandler for Samsung {
+    fn vendor_id(&self) -> u32 {
+        VENDOR_SAMSUNG
+    }
+
+    fn handle(&mut self, cmd: &CECCommand) -> Option<Vec<CECMessage>> {
+        if is_command_from(&cmd.message, VENDOR_SAMSUNG) {
+            return Some(vec![]);
+        }
+        None
+    }
+
+    fn remote_button(&self, rc_code: &[u8]) -> Option<UserControl> {
+        match rc_code.first() {
+            Some(0x91) => Some(UserControl::Exit),
+            Some(0x96) => Some(UserControl::ContentsMenu),
+            _ => None,
+        }
+    }
+}
+
+// Sony and Panasonic TVs only forward keys to a device that claims to have its
+// menu up, so these handlers say so at every opportunity.
+fn menu_active(cmd: &CECCommand) -> Option<Vec<CECMessage>> {
+    match &cmd.message {
+        CECMessage::MenuRequest { .. } => Some(vec![CECMessage::MenuStatus {
+            menu_state: MenuState::Activated,
+        }]),
+        _ => None,
+    }
+}
+
+/// Sony Bravia Sync.
+pub struct Sony;
+
+impl VendorHandler for Sony {
+    fn vendor_id(&self) -> u32 {
+        VENDOR_SONY
+    }
+
+    fn handshake(&mut self) -> Vec<CECMessage> {
+        vec![CECMessage::MenuStatus {
+            menu_state: MenuState::Activated,
+        }]
+    }
+
+    fn handle(&mut self, cmd: &CECCommand) -> Option<Vec<CECMessage>> {
+        menu_active(cmd)
+    }
+}
+
+/// Panasonic VIERA Link.
+///
+/// As well as wanting an active menu, the TV announces its power changes with
+/// vendor commands that need no answer.
+pub struct Panasonic;
+
+impl VendorHandler for Panasonic {
+    fn vendor_id(&self) -> u32 {
+        VENDOR_PANASONIC
+    }
+
+    fn handshake(&mut self) -> Vec<CECMessage> {
+        vec![CECMessage::MenuStatus {
+            menu_state: MenuState::Activated,
+        }]
+    }
+
+    fn handle(&mut self, cmd: &CECCommand) -> Option<Vec<CECMessage>> {
+        if is_command_from(&cmd.message, VENDOR_PANASONIC) {
+            return Some(vec![]);
+        }
+        menu_active(cmd)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cec::vendor::*;
+    use crate::cec::LogicalAddress;
+
+    fn from_tv(message: CECMessage) -> CECCommand {
+        CECCommand {
+            initiator: Some(LogicalAddress::TV),
+            destination: LogicalAddress::RecordingDevice1,
+            message,
+        }
+    }
+
+    #[test]
+    fn lg_handshake() {
+        let mut lg = for_vendor(VENDOR_LG).unwrap();
+        assert_eq!(
+            lg.handle(&from_tv(CECMessage::VendorCommand {
+                vendor_data: vec![0x01]
+            })),
+            Some(vec![CECMessage::VendorCommand {
+                vendor_data: vec![0x02, 0x05]
+            }])
+        );
+        assert_eq!(
+            lg.handle(&from_tv(CECMessage::VendorCommand {
+                vendor_data: vec![0x7f]
+            })),
+            None
+        );
+        assert_eq!(lg.handle(&from_tv(CECMessage::GiveOSDName)), None);
+    }
+
+    #[test]
+    fn samsung_translates_vendor_keys() {
+        let mut samsung = for_vendor(VENDOR_SAMSUNG).unwrap();
+        assert_eq!(samsung.remote_button(&[0x91]), Some(UserControl::Exit));
+        assert_eq!(samsung.remote_button(&[]), None);
+        assert_eq!(
+            samsung.handle(&from_tv(CECMessage::VendorCommandWithID {
+                vendor_id: VENDOR_LG,
+                vendor_data: vec![0x01],
+            })),
+            None
+        );
+    }
+
+    #[test]
+    fn sony_claims_active_menu() {
+        let mut sony = for_vendor(VENDOR_SONY).unwrap();
+        let active = vec![CECMessage::MenuStatus {
+            menu_state: MenuState::Activated,
+        }];
+        assert_eq!(sony.handshake(), active);
+        assert_eq!(
+            sony.handle(&from_tv(CECMessage::MenuRequest {
+                menu_request_type: crate::cec::MenuRequestType::Query
+            })),
+            Some(active)
+        );
+        assert!(for_vendor(0x123456).is_none());
+    }
+}
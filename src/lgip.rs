@@ -89,7 +89,7 @@ impl LGTV {
             .unwrap();
         let mut stream = TcpStream::connect_timeout(&addr, Duration::from_millis(200))?;
         let payload = self.encrypt(cmd);
-        stream.write(&payload)?;
+        stream.write_all(&payload)?;
         let mut resp = [0; 512];
         let len = stream.read(&mut resp)?;
         // TODO: Convert error
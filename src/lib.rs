@@ -3,4 +3,5 @@ pub mod auth;
 pub mod cec;
 pub mod lgip;
 pub mod tv;
+pub mod uinput;
 pub mod wol;
@@ -0,0 +1,394 @@
+// Forwarding of remote control keys to a Linux uinput virtual keyboard.
+//
+// The TV tells us a key went down with <User Control Pressed>, repeats that
+// message roughly every 450ms while the key is held, and sends <User Control
+// Released> when it comes back up. Some TVs never send the release, so the
+// spec has followers treat a key as released if no repeat arrives within
+// 550ms. Auto-repeat itself is left to the kernel, which repeats any key held
+// down on a device that has EV_REP set.
+
+use crate::cec::UserControl;
+use log::{info, warn};
+use nix::{ioctl_none, ioctl_write_int, ioctl_write_ptr};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const UINPUT_IOC_MAGIC: u8 = b'U';
+
+ioctl_none!(ui_dev_create, UINPUT_IOC_MAGIC, 1);
+ioctl_none!(ui_dev_destroy, UINPUT_IOC_MAGIC, 2);
+ioctl_write_ptr!(ui_dev_setup, UINPUT_IOC_MAGIC, 3, libc::uinput_setup);
+ioctl_write_int!(ui_set_evbit, UINPUT_IOC_MAGIC, 100);
+ioctl_write_int!(ui_set_keybit, UINPUT_IOC_MAGIC, 101);
+
+const EV_SYN: u16 = 0x00;
+const EV_KEY: u16 = 0x01;
+const EV_REP: u16 = 0x14;
+const SYN_REPORT: u16 = 0;
+const BUS_VIRTUAL: u16 = 0x06;
+
+/// How long a key stays down without a repeated press.
+pub const RELEASE_TIMEOUT: Duration = Duration::from_millis(550);
+
+#[derive(thiserror::Error, Debug)]
+pub enum KeymapError {
+    #[error("line {0}: expected <user control>=<keycode>")]
+    Syntax(usize),
+    #[error("line {0}: unknown user control {1:?}")]
+    UnknownControl(usize, String),
+    #[error("line {0}: bad keycode {1:?}")]
+    BadKeycode(usize, String),
+}
+
+/// Maps CEC user control codes to Linux input keycodes.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Keymap {
+    keys: HashMap<u8, u16>,
+}
+
+impl Default for Keymap {
+    /// A keymap that covers navigation and media keys, enough to drive Kodi.
+    fn default() -> Self {
+        let keys = [
+            (UserControl::Select, 28),              // KEY_ENTER
+            (UserControl::Up, 103),                 // KEY_UP
+            (UserControl::Down, 108),               // KEY_DOWN
+            (UserControl::Left, 105),               // KEY_LEFT
+            (UserControl::Right, 106),              // KEY_RIGHT
+            (UserControl::RootMenu, 102),           // KEY_HOME
+            (UserControl::SetupMenu, 141),          // KEY_SETUP
+            (UserControl::ContentsMenu, 139),       // KEY_MENU
+            (UserControl::Exit, 14),                // KEY_BACKSPACE
+            (UserControl::Number0, 11),             // KEY_0
+            (UserControl::Number1, 2),              // KEY_1
+            (UserControl::Number2, 3),              // KEY_2
+            (UserControl::Number3, 4),              // KEY_3
+            (UserControl::Number4, 5),              // KEY_4
+            (UserControl::Number5, 6),              // KEY_5
+            (UserControl::Number6, 7),              // KEY_6
+            (UserControl::Number7, 8),              // KEY_7
+            (UserControl::Number8, 9),              // KEY_8
+            (UserControl::Number9, 10),             // KEY_9
+            (UserControl::Enter, 28),               // KEY_ENTER
+            (UserControl::Clear, 111),              // KEY_DELETE
+            (UserControl::ChannelUp, 402),          // KEY_CHANNELUP
+            (UserControl::ChannelDown, 403),        // KEY_CHANNELDOWN
+            (UserControl::DisplayInformation, 358), // KEY_INFO
+            (UserControl::Help, 138),               // KEY_HELP
+            (UserControl::PageUp, 104),             // KEY_PAGEUP
+            (UserControl::PageDown, 109),           // KEY_PAGEDOWN
+            (UserControl::Play, 200),               // KEY_PLAYCD
+            (UserControl::Stop, 166),               // KEY_STOPCD
+            (UserControl::Pause, 201),              // KEY_PAUSECD
+            (UserControl::Record, 167),             // KEY_RECORD
+            (UserControl::Rewind, 168),             // KEY_REWIND
+            (UserControl::FastForward, 208),        // KEY_FASTFORWARD
+            (UserControl::Eject, 161),              // KEY_EJECTCD
+            (UserControl::Forward, 163),            // KEY_NEXTSONG
+            (UserControl::Backward, 165),           // KEY_PREVIOUSSONG
+            (UserControl::Subpicture, 370),         // KEY_SUBTITLE
+            (UserControl::EPG, 365),                // KEY_EPG
+            (UserControl::F1Blue, 401),             // KEY_BLUE
+            (UserControl::F2Red, 398),              // KEY_RED
+            (UserControl::F3Green, 399),            // KEY_GREEN
+            (UserControl::F4Yellow, 400),           // KEY_YELLOW
+        ];
+        Keymap {
+            keys: keys.iter().map(|&(uc, key)| (uc as u8, key)).collect(),
+        }
+    }
+}
+
+impl Keymap {
+    /// Parses a keymap with one `<user control>=<keycode>` mapping per line,
+    /// such as `Select=28`. User controls can be given by name or as a hex
+    /// code like `0x00`, and keycodes are the decimal values from
+    /// linux/input-event-codes.h. Blank lines and lines starting with `#` are
+    /// ignored.
+    pub fn parse(input: &str) -> Result<Self, KeymapError> {
+        let mut keys = HashMap::new();
+        for (i, line) in input.lines().enumerate() {
+            let n = i + 1;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (control, key) = line.split_once('=').ok_or(KeymapError::Syntax(n))?;
+            let (control, key) = (control.trim(), key.trim());
+            let control = parse_user_control(control)
+                .ok_or_else(|| KeymapError::UnknownControl(n, control.to_string()))?;
+            let key = key
+                .parse()
+                .map_err(|_| KeymapError::BadKeycode(n, key.to_string()))?;
+            keys.insert(control as u8, key);
+        }
+        Ok(Keymap { keys })
+    }
+
+    pub fn get(&self, control: UserControl) -> Option<u16> {
+        self.keys.get(&(control as u8)).copied()
+    }
+
+    pub fn keycodes(&self) -> impl Iterator<Item = u16> + '_ {
+        self.keys.values().copied()
+    }
+}
+
+fn parse_user_control(s: &str) -> Option<UserControl> {
+    if let Some(hex) = s.strip_prefix("0x") {
+        return UserControl::try_from(u8::from_str_radix(hex, 16).ok()?).ok();
+    }
+    (0..=u8::MAX)
+        .filter_map(|b| UserControl::try_from(b).ok())
+        .find(|uc| format!("{:?}", uc).eq_ignore_ascii_case(s))
+}
+
+/// Somewhere to send key events.
+pub trait KeySink: Send {
+    fn key(&mut self, keycode: u16, pressed: bool) -> io::Result<()>;
+}
+
+/// A keyboard created through /dev/uinput that other apps see as a real one.
+pub struct VirtualKeyboard {
+    file: File,
+}
+
+impl VirtualKeyboard {
+    pub fn new(name: &str, keycodes: impl Iterator<Item = u16>) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .write(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open("/dev/uinput")?;
+        let fd = file.as_raw_fd();
+        // SAFETY: `fd` is an open uinput device for the duration of the calls
+        // and `setup` outlives the ioctl that reads it.
+        unsafe {
+            ui_set_evbit(fd, EV_KEY.into())?;
+            ui_set_evbit(fd, EV_REP.into())?;
+            for keycode in keycodes {
+                ui_set_keybit(fd, keycode.into())?;
+            }
+            let mut setup: libc::uinput_setup = std::mem::zeroed();
+            setup.id.bustype = BUS_VIRTUAL;
+            for (dst, src) in setup
+                .name
+                .iter_mut()
+                .zip(name.bytes().take(libc::UINPUT_MAX_NAME_SIZE - 1))
+            {
+                *dst = src as libc::c_char;
+            }
+            ui_dev_setup(fd, &setup)?;
+            ui_dev_create(fd)?;
+        }
+        info!("created uinput keyboard {:?}", name);
+        Ok(VirtualKeyboard { file })
+    }
+
+    fn emit(&mut self, type_: u16, code: u16, value: i32) -> io::Result<()> {
+        let event = libc::input_event {
+            time: libc::timeval {
+                tv_sec: 0,
+                tv_usec: 0,
+            },
+            type_,
+            code,
+            value,
+        };
+        // SAFETY: input_event is plain old data, so viewing it as bytes is
+        // fine.
+        let bytes = unsafe {
+            std::slice::from_raw_parts(
+                &event as *const libc::input_event as *const u8,
+                std::mem::size_of::<libc::input_event>(),
+            )
+        };
+        self.file.write_all(bytes)
+    }
+}
+
+impl KeySink for VirtualKeyboard {
+    fn key(&mut self, keycode: u16, pressed: bool) -> io::Result<()> {
+        self.emit(EV_KEY, keycode, pressed as i32)?;
+        self.emit(EV_SYN, SYN_REPORT, 0)
+    }
+}
+
+impl Drop for VirtualKeyboard {
+    fn drop(&mut self) {
+        // SAFETY: the file is still open.
+        if let Err(e) = unsafe { ui_dev_destroy(self.file.as_raw_fd()) } {
+            warn!("failed to destroy uinput keyboard: {}", e);
+        }
+    }
+}
+
+struct Held {
+    keycode: u16,
+    deadline: Instant,
+}
+
+struct KeyState {
+    sink: Box<dyn KeySink>,
+    held: Option<Held>,
+}
+
+impl KeyState {
+    fn send(&mut self, keycode: u16, pressed: bool) {
+        if let Err(e) = self.sink.key(keycode, pressed) {
+            warn!("failed to send key {}: {}", keycode, e);
+        }
+    }
+
+    fn press(&mut self, keycode: u16, now: Instant) {
+        match &mut self.held {
+            // A repeat of the key that's already down just keeps it down.
+            Some(held) if held.keycode == keycode => {}
+            _ => {
+                self.release();
+                self.send(keycode, true);
+            }
+        }
+        self.held = Some(Held {
+            keycode,
+            deadline: now + RELEASE_TIMEOUT,
+        });
+    }
+
+    fn release(&mut self) {
+        if let Some(held) = self.held.take() {
+            self.send(held.keycode, false);
+        }
+    }
+
+    // Releases the held key if it hasn't been repeated in time, returning how
+    // long to wait before checking again.
+    fn expire(&mut self, now: Instant) -> Duration {
+        match &self.held {
+            Some(held) if held.deadline > now => held.deadline - now,
+            Some(_) => {
+                self.release();
+                RELEASE_TIMEOUT
+            }
+            None => RELEASE_TIMEOUT,
+        }
+    }
+}
+
+/// Turns user control presses into key events on a sink.
+pub struct KeyForwarder {
+    keymap: Keymap,
+    state: Arc<(Mutex<KeyState>, Condvar)>,
+}
+
+impl KeyForwarder {
+    pub fn new(sink: Box<dyn KeySink>, keymap: Keymap) -> io::Result<Self> {
+        let state = Arc::new((Mutex::new(KeyState { sink, held: None }), Condvar::new()));
+        let weak: Weak<(Mutex<KeyState>, Condvar)> = Arc::downgrade(&state);
+        thread::Builder::new()
+            .name("Key release".into())
+            .spawn(move || {
+                let mut wait = RELEASE_TIMEOUT;
+                while let Some(state) = weak.upgrade() {
+                    let (lock, cvar) = &*state;
+                    let (mut key_state, _) = cvar.wait_timeout(lock.lock().unwrap(), wait).unwrap();
+                    wait = key_state.expire(Instant::now());
+                }
+            })?;
+        Ok(KeyForwarder { keymap, state })
+    }
+
+    pub fn pressed(&self, control: UserControl) {
+        let (lock, cvar) = &*self.state;
+        match self.keymap.get(control) {
+            Some(keycode) => lock.lock().unwrap().press(keycode, Instant::now()),
+            None => {
+                info!("no key mapped for {:?}", control);
+                lock.lock().unwrap().release();
+            }
+        }
+        cvar.notify_all();
+    }
+
+    pub fn released(&self) {
+        let (lock, cvar) = &*self.state;
+        lock.lock().unwrap().release();
+        cvar.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::uinput::*;
+
+    type Events = Arc<Mutex<Vec<(u16, bool)>>>;
+
+    struct Recorder(Events);
+
+    impl KeySink for Recorder {
+        fn key(&mut self, keycode: u16, pressed: bool) -> io::Result<()> {
+            self.0.lock().unwrap().push((keycode, pressed));
+            Ok(())
+        }
+    }
+
+    fn key_state() -> (KeyState, Events) {
+        let events = Arc::new(Mutex::new(vec![]));
+        let state = KeyState {
+            sink: Box::new(Recorder(events.clone())),
+            held: None,
+        };
+        (state, events)
+    }
+
+    #[test]
+    fn parses_keymap() {
+        let keymap = Keymap::parse("# comment\n\nselect = 28\n0x01=103\n").unwrap();
+        assert_eq!(keymap.get(UserControl::Select), Some(28));
+        assert_eq!(keymap.get(UserControl::Up), Some(103));
+        assert_eq!(keymap.get(UserControl::Down), None);
+        assert!(matches!(
+            Keymap::parse("Nonsense=1"),
+            Err(KeymapError::UnknownControl(1, _))
+        ));
+        assert!(matches!(
+            Keymap::parse("\nUp=KEY_UP"),
+            Err(KeymapError::BadKeycode(2, _))
+        ));
+        assert!(matches!(Keymap::parse("Up"), Err(KeymapError::Syntax(1))));
+    }
+
+    #[test]
+    fn repeats_hold_key_down() {
+        let (mut state, events) = key_state();
+        let start = Instant::now();
+        state.press(28, start);
+        state.press(28, start + Duration::from_millis(400));
+        assert_eq!(
+            state.expire(start + Duration::from_millis(800)),
+            Duration::from_millis(150)
+        );
+        state.release();
+        state.release();
+        assert_eq!(*events.lock().unwrap(), vec![(28, true), (28, false)]);
+    }
+
+    #[test]
+    fn releases_on_new_key_or_timeout() {
+        let (mut state, events) = key_state();
+        let start = Instant::now();
+        state.press(103, start);
+        state.press(108, start);
+        state.expire(start + RELEASE_TIMEOUT);
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![(103, true), (103, false), (108, true), (108, false)]
+        );
+    }
+}